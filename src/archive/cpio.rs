@@ -1,9 +1,11 @@
 use std::io::Cursor;
+use std::io::Read;
 
 use bytes::Bytes;
 use nix::sys::stat::Mode;
 use nix::sys::stat::SFlag;
 
+use crate::archive::ArchiveIndex;
 use crate::entry::Directory;
 use crate::entry::Metadata;
 use crate::entry::Symlink;
@@ -28,9 +30,11 @@ fn align_to_4_bytes(pos: usize) -> usize {
 }
 
 impl Filesystem {
-    /// Parse an uncompressed cpio
+    /// Parse an uncompressed cpio. The result is [hardened](crate::hardened),
+    /// since archive members are usually untrusted.
     pub fn parse_cpio(contents: &Bytes) -> std::io::Result<Self> {
         let mut fs = Self::new();
+        fs.set_hardened(true);
         let mut cursor = Cursor::new(&contents);
 
         let mut header_start_pos = 0;
@@ -49,7 +53,7 @@ impl Filesystem {
                 .gid(Gid::from_raw(entry.gid()))
                 .build();
             if sflag.contains(SFlag::S_IFDIR) {
-                fs.insert(path, Directory::builder().metadata(metadata).build());
+                fs.try_insert(path, Directory::builder().metadata(metadata).build())?;
             } else if sflag.contains(SFlag::S_IFLNK) {
                 let name_size = entry.file_size() as usize;
                 // the symlink target starts at the header_start + HEADER_LEN +
@@ -57,7 +61,7 @@ impl Filesystem {
                 let link_start =
                     align_to_4_bytes(header_start_pos + HEADER_LEN + entry.name().len() + 1);
                 let target = contents.slice(link_start..link_start + name_size);
-                fs.insert(path, Symlink::new(target, Some(metadata)));
+                fs.try_insert(path, Symlink::new(target, Some(metadata)))?;
             } else if sflag.contains(SFlag::S_IFREG) {
                 let file_size = entry.file_size() as usize;
                 // the file starts at the header_start + HEADER_LEN + path +
@@ -65,13 +69,13 @@ impl Filesystem {
                 let file_start =
                     align_to_4_bytes(header_start_pos + HEADER_LEN + entry.name().len() + 1);
                 let file_contents = contents.slice(file_start..file_start + file_size);
-                fs.insert(
+                fs.try_insert(
                     path,
                     File::builder()
                         .contents(file_contents)
                         .metadata(metadata)
                         .build(),
-                );
+                )?;
             } else {
                 todo!();
             }
@@ -82,6 +86,49 @@ impl Filesystem {
     }
 }
 
+impl ArchiveIndex {
+    /// Index an uncompressed cpio, reading it once from start to end.
+    pub fn from_cpio<R: Read>(mut reader: R) -> std::io::Result<Self> {
+        let mut index = Self::default();
+        loop {
+            let mut member = cpio::newc::Reader::new(reader)?;
+            let entry = member.entry();
+            if entry.is_trailer() {
+                break;
+            }
+            let path = Bytes::copy_from_slice(entry.name().as_bytes());
+            let sflag = SFlag::from_bits_truncate(entry.mode());
+            let metadata = Metadata::builder()
+                .mode(Mode::from_bits_truncate(entry.mode()))
+                .uid(Uid::from_raw(entry.uid()))
+                .gid(Gid::from_raw(entry.gid()))
+                .build();
+            if sflag.contains(SFlag::S_IFDIR) {
+                let dir = Directory::builder().metadata(metadata).build();
+                index.insert(path.into(), dir, std::io::empty())?;
+            } else if sflag.contains(SFlag::S_IFLNK) {
+                let mut target = Vec::new();
+                member.read_to_end(&mut target)?;
+                index.insert(
+                    path.into(),
+                    Symlink::new(Bytes::from(target), Some(metadata)),
+                    std::io::empty(),
+                )?;
+            } else if sflag.contains(SFlag::S_IFREG) {
+                let file = File::builder().metadata(metadata).build();
+                index.insert(path.into(), file, &mut member)?;
+            } else {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::Unsupported,
+                    format!("unhandled file type {sflag:?}"),
+                ));
+            }
+            reader = member.finish()?;
+        }
+        Ok(index)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use pretty_assertions::assert_eq;
@@ -111,7 +158,7 @@ mod tests {
         let fs = Filesystem::parse_cpio(&contents).expect("failed to parse cpio");
         let mut demo_fs = demo_fs();
         // cpio is missing the top-level directory
-        demo_fs.unlink(&BytesPath::from("")).unwrap();
+        demo_fs.unlink(BytesPath::from("")).unwrap();
         // cpio does not support xattrs
         assert_approx_eq!(demo_fs, fs, Fields::all() - Fields::XATTR);
     }
@@ -0,0 +1,162 @@
+//! Compare archives without loading them into a [Filesystem](crate::Filesystem).
+//!
+//! An [ArchiveIndex] is built by reading an archive once from start to end,
+//! keeping only the metadata of each member and a digest of its data, so two
+//! very large archives (even of different formats) can be compared while only
+//! holding a small buffer of file data at a time.
+
+use std::collections::BTreeMap;
+use std::hash::Hasher;
+use std::io::Read;
+use std::io::Result;
+
+use twox_hash::xxh3::Hash128;
+use twox_hash::xxh3::HasherExt;
+
+use crate::cmp::Comparison;
+use crate::cmp::Fields;
+use crate::cmp::Rules;
+use crate::BytesPath;
+use crate::Entry;
+
+/// Metadata and data digests of every member of an archive.
+#[derive(Debug, Clone, Default)]
+pub struct ArchiveIndex {
+    members: BTreeMap<BytesPath, Member>,
+}
+
+#[derive(Debug, Clone)]
+struct Member {
+    /// Entry without any file data
+    entry: Entry,
+    size: u64,
+    digest: u128,
+}
+
+impl ArchiveIndex {
+    /// Add a member, reading all of 'data' to compute its digest. Regular
+    /// files in 'entry' must not have any contents of their own. Later
+    /// members replace earlier ones with the same path, like when extracting.
+    pub(crate) fn insert(
+        &mut self,
+        path: BytesPath,
+        entry: impl Into<Entry>,
+        mut data: impl Read,
+    ) -> Result<()> {
+        let entry = entry.into();
+        let mut hasher = Hash128::with_seed(0);
+        let mut buf = vec![0; 64 * 1024];
+        let mut len = 0;
+        loop {
+            match data.read(&mut buf)? {
+                0 => break,
+                n => {
+                    hasher.write(&buf[..n]);
+                    len += n as u64;
+                }
+            }
+        }
+        let size = match entry.is_file() {
+            true => len,
+            false => entry.size(),
+        };
+        self.members.insert(
+            path,
+            Member {
+                entry,
+                size,
+                digest: hasher.finish_ext(),
+            },
+        );
+        Ok(())
+    }
+
+    /// Number of members in the archive.
+    pub fn len(&self) -> usize {
+        self.members.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.members.is_empty()
+    }
+
+    /// Like [Filesystem::compare](crate::Filesystem::compare), where this
+    /// archive is the left side. [Fields::DATA] is always compared by
+    /// digest, and [Fields::NLINK] is not compared since hardlinks are not
+    /// indexed.
+    pub fn compare(&self, other: &Self, rules: impl Into<Rules>) -> Comparison {
+        let rules = rules.into();
+        let mut comparison = Comparison::default();
+        for (path, member) in &self.members {
+            let fields = rules.fields(path);
+            match other.members.get(path) {
+                Some(other_member) => {
+                    let mut cmp = member
+                        .entry
+                        .cmp_with_precision(&other_member.entry, &rules.precision);
+                    if member.size != other_member.size {
+                        cmp.remove(Fields::SIZE | Fields::DATA);
+                    }
+                    if member.digest != other_member.digest {
+                        cmp.remove(Fields::DATA);
+                    }
+                    let different = fields - Fields::PATH - cmp;
+                    if !different.is_empty() {
+                        comparison.changed.insert(path.clone(), different);
+                    }
+                }
+                None => {
+                    if fields.contains(Fields::PATH) {
+                        comparison.removed.insert(path.clone());
+                    }
+                }
+            }
+        }
+        for path in other.members.keys() {
+            if !self.members.contains_key(path) && rules.fields(path).contains(Fields::PATH) {
+                comparison.added.insert(path.clone());
+            }
+        }
+        comparison
+    }
+
+    /// Like [ApproxEq::cmp](crate::cmp::ApproxEq::cmp), see
+    /// [ArchiveIndex::compare].
+    pub fn cmp_with(&self, other: &Self, rules: impl Into<Rules>) -> Fields {
+        self.compare(other, rules).differences().complement()
+    }
+}
+
+#[cfg(all(test, feature = "cpio", feature = "tar"))]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn compare() {
+        let tar = include_bytes!("../../testdata/testdata.tar");
+        let cpio = include_bytes!("../../testdata/testdata.cpio");
+        let tar_index = ArchiveIndex::from_tar(tar.as_slice()).unwrap();
+        let cpio_index = ArchiveIndex::from_cpio(cpio.as_slice()).unwrap();
+        assert_eq!(5, tar_index.len());
+        // cpio does not support xattrs
+        assert!(tar_index
+            .compare(&cpio_index, Fields::all() - Fields::XATTR)
+            .is_empty());
+
+        let mut modified = tar.to_vec();
+        let start = modified
+            .windows(5)
+            .position(|w| w == b"Lorem")
+            .expect("lorem.txt is in the tar");
+        modified[start..start + 5].copy_from_slice(b"LOREM");
+        let modified_index = ArchiveIndex::from_tar(modified.as_slice()).unwrap();
+        assert_eq!(
+            BTreeMap::from([(BytesPath::from("testdata/lorem.txt"), Fields::DATA)]),
+            *tar_index.compare(&modified_index, Fields::all()).changed()
+        );
+    }
+}
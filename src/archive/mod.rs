@@ -2,6 +2,9 @@
 
 #[cfg(feature = "cpio")]
 mod cpio;
+mod index;
 
 #[cfg(feature = "tar")]
 mod tar;
+
+pub use index::ArchiveIndex;
@@ -7,6 +7,7 @@ use nix::sys::stat::Mode;
 use tar::Archive;
 use tar::EntryType;
 
+use crate::archive::ArchiveIndex;
 use crate::entry::Directory;
 use crate::entry::Metadata;
 use crate::entry::Symlink;
@@ -21,9 +22,11 @@ use crate::Uid;
 // of the offsets used here to get borrows to the underlying slice
 
 impl Filesystem {
-    /// Load an uncompressed tarball.
+    /// Load an uncompressed tarball. The result is [hardened](crate::hardened),
+    /// since archive members are usually untrusted.
     pub fn parse_tar(contents: &Bytes) -> std::io::Result<Self> {
         let mut fs = Filesystem::new();
+        fs.set_hardened(true);
         for entry in Archive::new(Cursor::new(&contents)).entries_with_seek()? {
             let mut entry = entry?;
             let file_offset = entry.raw_file_position() as usize;
@@ -34,10 +37,10 @@ impl Filesystem {
                     // remove trailing / for consistency
                     let new_len = path.len() - 1;
                     path.bytes_mut().truncate(new_len);
-                    fs.insert(path, Directory::builder().metadata(metadata).build());
+                    fs.try_insert(path, Directory::builder().metadata(metadata).build())?;
                 }
                 EntryType::Regular => {
-                    fs.insert(
+                    fs.try_insert(
                         path,
                         File::builder()
                             .contents(
@@ -45,7 +48,7 @@ impl Filesystem {
                             )
                             .metadata(metadata)
                             .build(),
-                    );
+                    )?;
                 }
                 EntryType::Symlink => {
                     let link_target = contents.subslice_or_copy(
@@ -53,7 +56,7 @@ impl Filesystem {
                             .link_name_bytes()
                             .expect("symlink must have link target"),
                     );
-                    fs.insert(path, Symlink::new(link_target, Some(metadata)));
+                    fs.try_insert(path, Symlink::new(link_target, Some(metadata)))?;
                 }
                 ty => {
                     todo!("unhandled entry type {ty:?}");
@@ -64,6 +67,55 @@ impl Filesystem {
     }
 }
 
+impl ArchiveIndex {
+    /// Index an uncompressed tarball, reading it once from start to end.
+    pub fn from_tar<R: Read>(reader: R) -> std::io::Result<Self> {
+        let mut index = Self::default();
+        let mut archive = Archive::new(reader);
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let mut path = entry.path_bytes().into_owned();
+            let metadata = Metadata::try_from_entry(&Bytes::new(), &mut entry)?;
+            match entry.header().entry_type() {
+                EntryType::Directory => {
+                    // remove trailing / for consistency with parse_tar
+                    path.pop();
+                    let dir = Directory::builder().metadata(metadata).build();
+                    index.insert(Bytes::from(path).into(), dir, std::io::empty())?;
+                }
+                EntryType::Regular => {
+                    let file = File::builder().metadata(metadata).build();
+                    index.insert(Bytes::from(path).into(), file, &mut entry)?;
+                }
+                EntryType::Symlink => {
+                    let link_target =
+                        Bytes::copy_from_slice(&entry.link_name_bytes().ok_or_else(|| {
+                            std::io::Error::new(
+                                std::io::ErrorKind::InvalidData,
+                                format!(
+                                    "symlink '{}' has no link target",
+                                    String::from_utf8_lossy(&path)
+                                ),
+                            )
+                        })?);
+                    index.insert(
+                        Bytes::from(path).into(),
+                        Symlink::new(link_target, Some(metadata)),
+                        std::io::empty(),
+                    )?;
+                }
+                ty => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::Unsupported,
+                        format!("unhandled entry type {ty:?}"),
+                    ));
+                }
+            }
+        }
+        Ok(index)
+    }
+}
+
 impl Metadata {
     fn try_from_entry<R: Read>(
         contents: &Bytes,
@@ -103,7 +155,27 @@ mod tests {
         let fs = Filesystem::parse_tar(&contents).expect("failed to parse tar");
         let mut demo_fs = demo_fs();
         // tar is missing the top-level directory
-        demo_fs.unlink(&BytesPath::from("")).unwrap();
+        demo_fs.unlink(BytesPath::from("")).unwrap();
         assert_eq!(demo_fs, fs);
     }
+
+    #[test]
+    fn from_tar_missing_link_target() {
+        let mut header = tar::Header::new_gnu();
+        header.set_path("link").unwrap();
+        header.set_entry_type(EntryType::Symlink);
+        header.set_size(0);
+        header.set_mode(0o777);
+        header.set_uid(0);
+        header.set_gid(0);
+        header.set_mtime(0);
+        header.set_cksum();
+        let mut builder = tar::Builder::new(Vec::new());
+        builder.append(&header, std::io::empty()).unwrap();
+        let tar = builder.into_inner().unwrap();
+        assert_eq!(
+            std::io::ErrorKind::InvalidData,
+            ArchiveIndex::from_tar(tar.as_slice()).unwrap_err().kind()
+        );
+    }
 }
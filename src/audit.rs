@@ -0,0 +1,168 @@
+//! Search a [Filesystem] by metadata, and check it against common image
+//! security policies without having to extract it to disk and run find(1).
+
+use std::path::Path;
+
+use nix::sys::stat::Mode;
+use nix::sys::stat::SFlag;
+
+use crate::BytesPath;
+use crate::Entry;
+use crate::Filesystem;
+use crate::Uid;
+
+/// A single problem found by [Filesystem::audit].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[remain::sorted]
+pub enum Finding {
+    /// A block or character device outside of /dev
+    DeviceOutsideDev(BytesPath),
+    /// A regular file with the setgid bit
+    Setgid(BytesPath),
+    /// A regular file with the setuid bit
+    Setuid(BytesPath),
+    /// An entry owned by a uid that is not in the allowed list
+    UnexpectedOwner(BytesPath, Uid),
+    /// An entry that anyone can write to
+    WorldWritable(BytesPath),
+}
+
+impl Filesystem {
+    /// All the entries for which 'predicate' returns true, in path order.
+    pub fn find<'f, F>(&'f self, mut predicate: F) -> impl Iterator<Item = (&'f Path, &'f Entry)>
+    where
+        F: FnMut(&Path, &Entry) -> bool + 'f,
+    {
+        self.iter()
+            .filter(move |(path, entry)| predicate(path, entry))
+    }
+
+    /// Regular files with the setuid bit set.
+    pub fn setuid_files(&self) -> impl Iterator<Item = (&Path, &Entry)> {
+        self.find(|_, entry| entry.is_file() && entry.metadata().mode().contains(Mode::S_ISUID))
+    }
+
+    /// Regular files with the setgid bit set.
+    pub fn setgid_files(&self) -> impl Iterator<Item = (&Path, &Entry)> {
+        self.find(|_, entry| entry.is_file() && entry.metadata().mode().contains(Mode::S_ISGID))
+    }
+
+    /// Entries that are writable by other users. Symlinks (whose permissions
+    /// are meaningless) and sticky directories like /tmp are not included.
+    pub fn world_writable(&self) -> impl Iterator<Item = (&Path, &Entry)> {
+        self.find(|_, entry| {
+            let mode = entry.metadata().mode();
+            mode.contains(Mode::S_IWOTH)
+                && !entry.is_symlink()
+                && !(entry.is_directory() && mode.contains(Mode::S_ISVTX))
+        })
+    }
+
+    /// Entries that are not owned by any of 'allowed'.
+    pub fn unexpected_owners<'f>(
+        &'f self,
+        allowed: &'f [Uid],
+    ) -> impl Iterator<Item = (&'f Path, &'f Entry)> {
+        self.find(|_, entry| !allowed.contains(&entry.metadata().uid()))
+    }
+
+    /// Block and character devices that are not underneath /dev.
+    pub fn devices_outside_dev(&self) -> impl Iterator<Item = (&Path, &Entry)> {
+        self.find(|path, entry| match entry {
+            Entry::Special(s) => {
+                matches!(s.file_type(), SFlag::S_IFBLK | SFlag::S_IFCHR) && !path.starts_with("dev")
+            }
+            _ => false,
+        })
+    }
+
+    /// Run all of the built-in audits, allowing only entries owned by one of
+    /// 'allowed_uids'.
+    pub fn audit(&self, allowed_uids: &[Uid]) -> Vec<Finding> {
+        let mut findings: Vec<Finding> = self
+            .setuid_files()
+            .map(|(p, _)| Finding::Setuid(p.into()))
+            .chain(self.setgid_files().map(|(p, _)| Finding::Setgid(p.into())))
+            .chain(
+                self.world_writable()
+                    .map(|(p, _)| Finding::WorldWritable(p.into())),
+            )
+            .chain(
+                self.unexpected_owners(allowed_uids)
+                    .map(|(p, e)| Finding::UnexpectedOwner(p.into(), e.metadata().uid())),
+            )
+            .chain(
+                self.devices_outside_dev()
+                    .map(|(p, _)| Finding::DeviceOutsideDev(p.into())),
+            )
+            .collect();
+        findings.sort_by(|a, b| a.path().cmp(b.path()));
+        findings
+    }
+}
+
+impl Finding {
+    pub fn path(&self) -> &Path {
+        #[remain::sorted]
+        match self {
+            Self::DeviceOutsideDev(p) => p,
+            Self::Setgid(p) => p,
+            Self::Setuid(p) => p,
+            Self::UnexpectedOwner(p, _) => p,
+            Self::WorldWritable(p) => p,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::entry::Metadata;
+    use crate::entry::Special;
+    use crate::fs;
+
+    #[test]
+    fn audit() {
+        let mut fs = fs! {
+            "bin": dir(0o755) {
+                "su": file("", 0o4755),
+                "wall": file("", 0o2755),
+                "ls": file("", 0o755),
+            },
+            "tmp": dir(0o1777) {},
+            "var": dir(0o777) {},
+            "dev": {},
+        };
+        let dev = |uid| {
+            Special::new(
+                SFlag::S_IFCHR,
+                0,
+                Metadata::builder()
+                    .mode(Mode::from_bits_truncate(0o600))
+                    .uid(Uid::from_raw(uid))
+                    .build(),
+            )
+        };
+        fs.insert("dev/null", dev(0));
+        fs.insert("home/evil", dev(1000));
+
+        assert_eq!(
+            vec![Path::new("bin/ls")],
+            fs.find(|_, e| e.metadata().mode().bits() == 0o755 && e.is_file())
+                .map(|(p, _)| p)
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec![
+                Finding::Setuid("bin/su".into()),
+                Finding::Setgid("bin/wall".into()),
+                Finding::UnexpectedOwner("home/evil".into(), Uid::from_raw(1000)),
+                Finding::DeviceOutsideDev("home/evil".into()),
+                Finding::WorldWritable("var".into()),
+            ],
+            fs.audit(&[Uid::from_raw(0)])
+        );
+    }
+}
@@ -1,27 +1,308 @@
 use std::collections::BTreeMap;
-use std::io::Seek;
-use std::io::SeekFrom;
-use std::ops::Deref;
+use std::collections::BTreeSet;
+use std::ffi::OsStr;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::ErrorKind;
+use std::io::Read;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::sync::Arc;
 
 use bytes::Bytes;
 use sendstream_parser::Command;
 use sendstream_parser::Sendstream;
+#[cfg(feature = "serde")]
+use serde::Deserialize;
+#[cfg(feature = "serde")]
+use serde::Serialize;
 use uuid::Uuid;
 
+#[cfg(feature = "diff")]
+use crate::cmp::Rules;
+#[cfg(feature = "diff")]
+use crate::diff::FilesystemDiff;
 use crate::entry::Directory;
 use crate::entry::Special;
 use crate::entry::Symlink;
+#[cfg(any(feature = "gzip", feature = "zstd"))]
+use crate::file::extent::Compression;
+use crate::file::extent::Extent;
 use crate::file::File;
+use crate::BytesPath;
+use crate::Entry;
 use crate::Filesystem;
 
+/// Every sendstream starts with this, followed by a little-endian u32
+/// version.
+const SENDSTREAM_MAGIC: &[u8] = b"btrfs-stream\0";
+
+/// The only sendstream version that the parser understands.
+const SUPPORTED_VERSION: u32 = 1;
+
+/// Newest sendstream version that [Subvols::receive_from_with] understands,
+/// by handling the commands that changed in it without the parser.
+const MAX_VERSION: u32 = 2;
+
+/// Every command starts with its little-endian u32 length (excluding this
+/// header), u16 type and u32 crc32c.
+const CMD_HEADER_LEN: usize = 10;
+
+/// Type of the last command in version 1 sendstreams (UpdateExtent).
+const MAX_CMD_TYPE: u16 = 22;
+
+/// Type of the last command in version 2 sendstreams (EncodedWrite).
+const MAX_V2_CMD_TYPE: u16 = 25;
+
+/// Type of the command that ends each sendstream.
+const END_CMD_TYPE: u16 = 21;
+
+const WRITE_CMD_TYPE: u16 = 15;
+const FALLOCATE_CMD_TYPE: u16 = 23;
+const FILEATTR_CMD_TYPE: u16 = 24;
+const ENCODED_WRITE_CMD_TYPE: u16 = 25;
+
+const SIZE_ATTR: u16 = 4;
+const PATH_ATTR: u16 = 15;
+const FILE_OFFSET_ATTR: u16 = 18;
+/// Since version 2, this attribute has no length and takes up the rest of
+/// the command, so that it can hold more than 64KiB.
+const DATA_ATTR: u16 = 19;
+const FALLOCATE_MODE_ATTR: u16 = 25;
+const UNENCODED_FILE_LEN_ATTR: u16 = 27;
+const UNENCODED_LEN_ATTR: u16 = 28;
+const UNENCODED_OFFSET_ATTR: u16 = 29;
+const COMPRESSION_ATTR: u16 = 30;
+const ENCRYPTION_ATTR: u16 = 31;
+
+// fallocate(2) modes that version 2 sendstreams use
+const FALLOC_FL_KEEP_SIZE: u32 = 0x1;
+const FALLOC_FL_PUNCH_HOLE_KEEP_SIZE: u32 = 0x3;
+
+/// crc32c (without the usual inversions, like btrfs-progs) of a whole
+/// command, with the crc field of its header treated as zero.
+fn cmd_crc(cmd: &[u8]) -> u32 {
+    let mut header = [0; CMD_HEADER_LEN];
+    header[..6].copy_from_slice(&cmd[..6]);
+    !crc32c::crc32c_append(crc32c::crc32c_append(!0, &header), &cmd[CMD_HEADER_LEN..])
+}
+
+/// Check the version of every sendstream and the crc and type of every
+/// command in 'data', without parsing any of them, since the parser panics
+/// on versions and command types that it does not know. Truncated streams
+/// are left to the parser to complain about.
+fn verify_commands(data: &[u8]) -> Result<(), Error<'static>> {
+    let mut offset = 0;
+    while let Some(mut cmds) = data[offset..].strip_prefix(SENDSTREAM_MAGIC) {
+        let version = cmds
+            .get(..4)
+            .map(|v| u32::from_le_bytes(v.try_into().expect("4 bytes")));
+        if let Some(version) = version.filter(|v| *v != SUPPORTED_VERSION) {
+            return Err(Error::UnsupportedVersion(version));
+        }
+        offset += SENDSTREAM_MAGIC.len() + 4;
+        cmds = cmds.get(4..).unwrap_or_default();
+        while cmds.len() >= CMD_HEADER_LEN {
+            let len = u32::from_le_bytes(cmds[..4].try_into().expect("4 bytes")) as usize;
+            let ty = u16::from_le_bytes(cmds[4..6].try_into().expect("2 bytes"));
+            let Some(cmd) = cmds.get(..CMD_HEADER_LEN + len) else {
+                return Ok(());
+            };
+            let expected = u32::from_le_bytes(cmd[6..10].try_into().expect("4 bytes"));
+            let actual = cmd_crc(cmd);
+            if actual != expected {
+                return Err(Error::Checksum {
+                    offset: offset as u64,
+                    expected,
+                    actual,
+                });
+            }
+            if ty == 0 || ty > MAX_CMD_TYPE {
+                return Err(Error::UnknownCommand {
+                    offset: offset as u64,
+                    ty,
+                });
+            }
+            offset += cmd.len();
+            cmds = &cmds[cmd.len()..];
+            if ty == END_CMD_TYPE {
+                break;
+            }
+        }
+        if offset >= data.len() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// A command that the parser cannot handle, because it is new in version 2
+/// or its data is framed the version 2 way.
+#[derive(Debug)]
+enum V2Command<'c> {
+    Write {
+        path: &'c Path,
+        offset: u64,
+        data: V2Data<'c>,
+    },
+    /// fallocate(2) with the 'mode' flags of a preallocated extent or a
+    /// hole.
+    Fallocate {
+        path: &'c Path,
+        mode: u32,
+        offset: u64,
+        len: u64,
+    },
+    /// Inode flags (like `chattr`), which are not represented in a
+    /// [Filesystem], so they are ignored just like `btrfs receive` does.
+    Fileattr { path: &'c Path },
+    /// 'data' decodes to 'unencoded_len' bytes, of which the
+    /// 'unencoded_file_len' bytes starting at 'unencoded_offset' are
+    /// written at 'offset'.
+    EncodedWrite {
+        path: &'c Path,
+        offset: u64,
+        unencoded_file_len: u64,
+        unencoded_len: u64,
+        unencoded_offset: u64,
+        compression: u32,
+        encryption: u32,
+        data: V2Data<'c>,
+    },
+}
+
+/// Debug-printed as just its length, since it can be large.
+struct V2Data<'c>(&'c [u8]);
+
+impl std::fmt::Debug for V2Data<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "<{} bytes>", self.0.len())
+    }
+}
+
+/// The data of an encoded write, which decompresses to 'len' bytes, as an
+/// [Extent::Compressed].
+#[cfg_attr(not(any(feature = "gzip", feature = "zstd")), allow(unused_variables))]
+fn encoded_extent(compression: u32, data: &[u8], len: u64) -> std::io::Result<Extent> {
+    match compression {
+        #[cfg(feature = "gzip")]
+        1 => Extent::compressed(Compression::Zlib, Bytes::copy_from_slice(data), len),
+        #[cfg(feature = "zstd")]
+        2 => {
+            // btrfs pads the frame with zeroes to a whole sector
+            let frame_len = zstd::zstd_safe::find_frame_compressed_size(data).map_err(|_| {
+                std::io::Error::new(ErrorKind::InvalidData, "invalid zstd frame in encoded write")
+            })?;
+            Extent::compressed(Compression::Zstd, Bytes::copy_from_slice(&data[..frame_len]), len)
+        }
+        _ => Err(std::io::Error::new(
+            ErrorKind::Unsupported,
+            format!(
+                "compression type {compression} is not supported (zlib and zstd need the gzip and zstd features)"
+            ),
+        )),
+    }
+}
+
+impl<'c> V2Command<'c> {
+    /// Parse the attributes (everything after the command header) of a
+    /// command of type 'ty' from a version 2 sendstream.
+    fn parse(ty: u16, mut attrs: &'c [u8]) -> Result<Self, String> {
+        let mut values = BTreeMap::new();
+        while !attrs.is_empty() {
+            let truncated = || format!("truncated attribute in command type {ty}");
+            let attr = u16::from_le_bytes(
+                attrs
+                    .get(..2)
+                    .ok_or_else(truncated)?
+                    .try_into()
+                    .expect("2 bytes"),
+            );
+            let value = match attr {
+                DATA_ATTR => std::mem::take(&mut attrs).get(2..).unwrap_or_default(),
+                _ => {
+                    let len = attrs.get(2..4).ok_or_else(truncated)?;
+                    let len = u16::from_le_bytes(len.try_into().expect("2 bytes")) as usize;
+                    let value = attrs.get(4..4 + len).ok_or_else(truncated)?;
+                    attrs = &attrs[4 + len..];
+                    value
+                }
+            };
+            values.insert(attr, value);
+        }
+        let get = |attr: u16| {
+            values
+                .get(&attr)
+                .copied()
+                .ok_or_else(|| format!("missing attribute {attr} in command type {ty}"))
+        };
+        let path = |attr: u16| get(attr).map(|p| Path::new(OsStr::from_bytes(p)));
+        let u64 = |attr: u16| {
+            get(attr)?
+                .try_into()
+                .map(u64::from_le_bytes)
+                .map_err(|_| format!("attribute {attr} in command type {ty} is not a u64"))
+        };
+        let u32 = |attr: u16| {
+            get(attr)?
+                .try_into()
+                .map(u32::from_le_bytes)
+                .map_err(|_| format!("attribute {attr} in command type {ty} is not a u32"))
+        };
+        match ty {
+            WRITE_CMD_TYPE => Ok(Self::Write {
+                path: path(PATH_ATTR)?,
+                offset: u64(FILE_OFFSET_ATTR)?,
+                data: V2Data(get(DATA_ATTR)?),
+            }),
+            FALLOCATE_CMD_TYPE => Ok(Self::Fallocate {
+                path: path(PATH_ATTR)?,
+                mode: u32(FALLOCATE_MODE_ATTR)?,
+                offset: u64(FILE_OFFSET_ATTR)?,
+                len: u64(SIZE_ATTR)?,
+            }),
+            FILEATTR_CMD_TYPE => Ok(Self::Fileattr {
+                path: path(PATH_ATTR)?,
+            }),
+            ENCODED_WRITE_CMD_TYPE => Ok(Self::EncodedWrite {
+                path: path(PATH_ATTR)?,
+                offset: u64(FILE_OFFSET_ATTR)?,
+                unencoded_file_len: u64(UNENCODED_FILE_LEN_ATTR)?,
+                unencoded_len: u64(UNENCODED_LEN_ATTR)?,
+                unencoded_offset: u64(UNENCODED_OFFSET_ATTR)?,
+                compression: u32(COMPRESSION_ATTR)?,
+                // the kernel leaves this out, since it never encrypts
+                encryption: match values.contains_key(&ENCRYPTION_ATTR) {
+                    true => u32(ENCRYPTION_ATTR)?,
+                    false => 0,
+                },
+                data: V2Data(get(DATA_ATTR)?),
+            }),
+            _ => Err(format!("command type {ty} is not supported")),
+        }
+    }
+}
+
 #[derive(thiserror::Error, Debug)]
 pub enum Error<'c> {
     #[error("invariant violated: {0}")]
     InvariantViolated(&'static str),
+    #[error("checksum mismatch in command at offset {offset}: expected {expected:#010x}, got {actual:#010x}")]
+    Checksum {
+        offset: u64,
+        expected: u32,
+        actual: u32,
+    },
+    #[error("unknown command type {ty} at offset {offset}")]
+    UnknownCommand { offset: u64, ty: u16 },
+    #[error("clone source subvol not yet received: {0}")]
+    MissingCloneSource(Uuid),
     #[error("parent subvol not yet received: {0}")]
     MissingParent(Uuid),
     #[error(transparent)]
     Parse(sendstream_parser::Error<'c>),
+    #[error("unsupported sendstream version {0}")]
+    UnsupportedVersion(u32),
     #[error("failed to apply {command:?}: {error:?}")]
     Apply {
         command: Command<'c>,
@@ -48,17 +329,246 @@ impl<'c> From<Error<'c>> for ApplyError<'c> {
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Subvol {
+    path: BytesPath,
+    received_uuid: Uuid,
+    ctransid: u64,
     parent_uuid: Option<Uuid>,
+    clone_ctransid: Option<u64>,
+    version: u32,
+    trace: Vec<TracedCommand>,
     fs: Filesystem,
 }
 
 impl Subvol {
-    fn new() -> Self {
+    fn new(path: &Path, uuid: Uuid, ctransid: u64) -> Self {
+        let mut fs = Filesystem::new();
+        fs.insert("", Directory::default());
         Subvol {
+            path: path.into(),
+            received_uuid: uuid,
+            ctransid,
             parent_uuid: None,
-            fs: Filesystem::new(),
+            clone_ctransid: None,
+            version: SUPPORTED_VERSION,
+            trace: Vec::new(),
+            fs,
         }
     }
+
+    /// Name of the subvolume in the sendstream.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// UUID of the subvolume on the sending side, which is what `btrfs
+    /// subvolume show` reports as the "Received UUID" after receiving it.
+    pub fn received_uuid(&self) -> Uuid {
+        self.received_uuid
+    }
+
+    /// Transaction id of the subvolume on the sending side.
+    pub fn ctransid(&self) -> u64 {
+        self.ctransid
+    }
+
+    /// UUID of the subvolume that this was snapshotted from, if any.
+    pub fn parent_uuid(&self) -> Option<Uuid> {
+        self.parent_uuid
+    }
+
+    /// Transaction id of the parent at the time of the snapshot, if this is a
+    /// snapshot.
+    pub fn clone_ctransid(&self) -> Option<u64> {
+        self.clone_ctransid
+    }
+
+    /// Version of the sendstream that this subvolume was received from.
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// Every command that was applied to this subvolume (starting with the
+    /// subvol or snapshot command that created it), if it was received with
+    /// [ReceiveOptions::trace].
+    pub fn trace(&self) -> &[TracedCommand] {
+        &self.trace
+    }
+
+    pub fn fs(&self) -> &Filesystem {
+        &self.fs
+    }
+
+    pub fn into_fs(self) -> Filesystem {
+        self.fs
+    }
+
+    fn apply_v2_cmd(&mut self, cmd: &V2Command, options: &ReceiveOptions) -> std::io::Result<()> {
+        match cmd {
+            V2Command::Write { path, offset, data } => {
+                self.fs.write(path, *offset, Bytes::copy_from_slice(data.0))
+            }
+            V2Command::Fallocate {
+                path,
+                mode,
+                offset,
+                len,
+            } => {
+                let file = self.fs.get_file_mut(path)?;
+                match *mode {
+                    0 => file.allocate(*offset, *len),
+                    // preallocated space past the end is not represented
+                    FALLOC_FL_KEEP_SIZE => Ok(()),
+                    FALLOC_FL_PUNCH_HOLE_KEEP_SIZE => {
+                        file.punch_hole(*offset, *len);
+                        Ok(())
+                    }
+                    _ => Err(std::io::Error::new(
+                        ErrorKind::Unsupported,
+                        format!("fallocate mode {mode:#x} is not supported"),
+                    )),
+                }
+            }
+            V2Command::Fileattr { path } => self.fs.get(path).map(|_| ()),
+            V2Command::EncodedWrite {
+                path,
+                offset,
+                unencoded_file_len,
+                unencoded_len,
+                unencoded_offset,
+                compression,
+                encryption,
+                data,
+            } => {
+                if *encryption != 0 {
+                    return Err(std::io::Error::new(
+                        ErrorKind::Unsupported,
+                        format!("encryption type {encryption} is not supported"),
+                    ));
+                }
+                let range = unencoded_offset
+                    .checked_add(*unencoded_file_len)
+                    .filter(|end| end <= unencoded_len)
+                    .map(|end| *unencoded_offset as usize..end as usize)
+                    .ok_or_else(|| {
+                        std::io::Error::new(
+                            ErrorKind::InvalidData,
+                            "encoded write extends past the end of its data",
+                        )
+                    })?;
+                let extent = match compression {
+                    0 => Extent::Owned(Bytes::copy_from_slice(
+                        data.0.get(range).ok_or(ErrorKind::UnexpectedEof)?,
+                    )),
+                    _ => {
+                        let extent = encoded_extent(*compression, data.0, *unencoded_len)?;
+                        // part of a compressed extent cannot stay compressed
+                        match options.decompress || range.len() as u64 != *unencoded_len {
+                            true => Extent::Owned(extent.bytes()?.slice(range)),
+                            false => extent,
+                        }
+                    }
+                };
+                self.fs.get_file_mut(path)?.write_at(extent, *offset);
+                Ok(())
+            }
+        }
+        .map_err(|e| std::io::Error::new(e.kind(), format!("failed to apply {cmd:?}: {e}")))
+    }
+}
+
+/// Options for [Subvols::receive_from_with].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReceiveOptions {
+    lenient: bool,
+    trace: bool,
+    decompress: bool,
+}
+
+impl ReceiveOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Skip commands that are unknown, cannot be parsed or fail to apply
+    /// (recording them in [ReceiveReport::skipped]) instead of failing the
+    /// whole receive. The start of a subvolume is never skipped.
+    pub fn lenient(&mut self, lenient: bool) -> &mut Self {
+        self.lenient = lenient;
+        self
+    }
+
+    /// Record every command that is applied to a subvolume in
+    /// [Subvol::trace].
+    pub fn trace(&mut self, trace: bool) -> &mut Self {
+        self.trace = trace;
+        self
+    }
+
+    /// Decompress the data of encoded writes (from `btrfs send --proto 2
+    /// --compressed-data`) as soon as they are received, instead of keeping
+    /// it as an [Extent::Compressed] that is only decompressed when read.
+    pub fn decompress(&mut self, decompress: bool) -> &mut Self {
+        self.decompress = decompress;
+        self
+    }
+}
+
+/// A command that was skipped by a [lenient](ReceiveOptions::lenient)
+/// receive.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SkippedCommand {
+    offset: u64,
+    ty: u16,
+    reason: String,
+}
+
+impl SkippedCommand {
+    /// Offset of the command in the stream.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Raw command type, as defined in the kernel's send.h.
+    pub fn ty(&self) -> u16 {
+        self.ty
+    }
+
+    pub fn reason(&self) -> &str {
+        &self.reason
+    }
+}
+
+/// A command that was applied to a subvolume, see [ReceiveOptions::trace].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+pub struct TracedCommand {
+    offset: u64,
+    command: String,
+}
+
+impl TracedCommand {
+    /// Offset of the command in the stream.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Debug representation of the command, including its paths, offsets and
+    /// (a possibly truncated version of) its data.
+    pub fn command(&self) -> &str {
+        &self.command
+    }
+}
+
+/// Everything that [Subvols::receive_from_with] noticed while receiving.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReceiveReport {
+    skipped: Vec<SkippedCommand>,
+}
+
+impl ReceiveReport {
+    pub fn skipped(&self) -> &[SkippedCommand] {
+        &self.skipped
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -69,12 +579,82 @@ impl Subvols {
         Self(BTreeMap::new())
     }
 
+    pub fn get(&self, uuid: &Uuid) -> Option<&Subvol> {
+        self.0.get(uuid)
+    }
+
+    /// Number of received subvolumes.
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&Uuid, &Subvol)> {
+        self.0.iter()
+    }
+
+    /// Subvolumes that were snapshotted directly from 'parent'.
+    pub fn children<'s>(
+        &'s self,
+        parent: &'s Uuid,
+    ) -> impl Iterator<Item = (&'s Uuid, &'s Subvol)> {
+        self.0
+            .iter()
+            .filter(move |(_, s)| s.parent_uuid.as_ref() == Some(parent))
+    }
+
+    /// What changed between 'parent' and 'child' (usually a snapshot of it),
+    /// or None if either of them has not been received.
+    #[cfg(feature = "diff")]
+    pub fn diff(
+        &self,
+        parent: &Uuid,
+        child: &Uuid,
+        rules: impl Into<Rules>,
+    ) -> Option<FilesystemDiff<'_>> {
+        Some(FilesystemDiff::diff(
+            &self.0.get(parent)?.fs,
+            &self.0.get(child)?.fs,
+            rules,
+        ))
+    }
+
+    /// Standalone copy of the filesystem of 'uuid', where every file has its
+    /// own copy of its data (see [File::unshare]) instead of sharing it with
+    /// the ancestors of 'uuid' or the sendstream it was received from.
+    pub fn flatten(&self, uuid: &Uuid) -> Option<Filesystem> {
+        let mut fs = self.0.get(uuid)?.fs.clone();
+        for entry in fs.inodes.values_mut() {
+            if let Entry::File(f) = Arc::make_mut(entry) {
+                f.unshare();
+            }
+        }
+        Some(fs)
+    }
+
+    /// The chain of snapshots that led to 'uuid', starting with 'uuid' itself
+    /// and followed by each parent in turn, up to the first subvolume that
+    /// was not a snapshot (or whose parent was not received).
+    pub fn snapshot_chain(&self, uuid: &Uuid) -> Vec<(&Uuid, &Subvol)> {
+        let mut chain = Vec::new();
+        let mut next = self.0.get_key_value(uuid);
+        while let Some((uuid, subvol)) = next {
+            chain.push((uuid, subvol));
+            next = subvol
+                .parent_uuid
+                .as_ref()
+                .and_then(|parent| self.0.get_key_value(parent))
+                // guard against a (corrupt) cycle of snapshots
+                .filter(|(parent, _)| !chain.iter().any(|(u, _)| u == parent));
+        }
+        chain
+    }
+
     #[remain::check]
-    fn apply_cmd<'c>(
-        &mut self,
-        subvol: &mut Subvol,
-        cmd: &Command<'c>,
-    ) -> Result<(), ApplyError<'c>> {
+    fn apply_cmd<'c>(&self, subvol: &mut Subvol, cmd: &Command<'c>) -> Result<(), ApplyError<'c>> {
         match cmd {
             Command::Chmod(c) => {
                 subvol.fs.chmod(c.path(), c.mode().mode())?;
@@ -84,17 +664,32 @@ impl Subvols {
                 subvol.fs.chown(c.path(), c.uid().into(), c.gid().into())?;
                 Ok(())
             }
+            Command::Clone(c) if c.uuid() == subvol.received_uuid => {
+                subvol.fs.copy_file_range(
+                    c.src_path(),
+                    c.src_offset().as_u64(),
+                    c.dst_path(),
+                    c.dst_offset().as_u64(),
+                    c.len().as_u64(),
+                )?;
+                Ok(())
+            }
             Command::Clone(c) => {
-                let src = subvol.fs.get_file(c.src_path())?;
-                let start = c.src_offset().as_u64();
-                let extents = src.clone_range(start..start + c.len().as_u64());
-                let dst = subvol.fs.get_file_mut(c.dst_path())?;
-                let mut wr = dst.writer();
-                wr.seek(SeekFrom::Start(c.dst_offset().as_u64()))
-                    .expect("infallible");
-                for ex in extents {
-                    wr.write(ex);
-                }
+                // cloned from another (already received) subvolume, which
+                // has to be read as it was received, not as the current
+                // snapshot of it looks now
+                let src = self
+                    .0
+                    .get(&c.uuid())
+                    .ok_or(Error::MissingCloneSource(c.uuid()))?;
+                subvol.fs.copy_file_range_from(
+                    src.fs.get_file(c.src_path())?,
+                    Some(&c.src_path().into()),
+                    c.src_offset().as_u64(),
+                    c.dst_path(),
+                    c.dst_offset().as_u64(),
+                    c.len().as_u64(),
+                )?;
                 Ok(())
             }
             Command::End => Ok(()),
@@ -134,10 +729,7 @@ impl Subvols {
             Command::RemoveXattr(r) => {
                 subvol
                     .fs
-                    .get_mut(r.path())?
-                    .metadata_mut()
-                    .xattrs
-                    .retain(|k, _| k != r.name().deref());
+                    .remove_xattr(r.path(), Bytes::copy_from_slice(r.name()))?;
                 Ok(())
             }
             Command::Rename(r) => {
@@ -150,10 +742,11 @@ impl Subvols {
                 Ok(())
             }
             Command::SetXattr(s) => {
-                subvol.fs.get_mut(s.path())?.metadata_mut().xattrs.insert(
+                subvol.fs.set_xattr(
+                    s.path(),
                     Bytes::copy_from_slice(s.name()),
                     Bytes::copy_from_slice(s.data()),
-                );
+                )?;
                 Ok(())
             }
             Command::Snapshot(_) => {
@@ -174,8 +767,14 @@ impl Subvols {
                 subvol.fs.unlink(u.path())?;
                 Ok(())
             }
-            Command::UpdateExtent(_) => {
-                Err(Error::InvariantViolated("UpdateExtent command is not supported").into())
+            Command::UpdateExtent(u) => {
+                // streams from `btrfs send --no-data` only say which ranges
+                // were written, so record them as holes of the right size
+                subvol
+                    .fs
+                    .get_file_mut(u.path())?
+                    .write_at(Extent::Hole(u.len()), u.offset().as_u64());
+                Ok(())
             }
             Command::Utimes(u) => {
                 subvol
@@ -184,77 +783,370 @@ impl Subvols {
                 Ok(())
             }
             Command::Write(w) => {
-                let f = subvol.fs.get_file_mut(w.path())?;
-                let mut wr = f.writer();
-                wr.seek(SeekFrom::Start(w.offset().as_u64()))
-                    .expect("infallible");
-                wr.write(Bytes::copy_from_slice(w.data().as_slice()));
+                subvol.fs.write(
+                    w.path(),
+                    w.offset().as_u64(),
+                    Bytes::copy_from_slice(w.data().as_slice()),
+                )?;
                 Ok(())
             }
         }
     }
 
-    /// Parse subvolumes from an uncompressed sendstream
+    /// Parse and receive all the (concatenated) sendstreams in 'data'. Only
+    /// version 1 sendstreams are supported by the parser, so anything else
+    /// (like the output of `btrfs send --proto 2`) fails with
+    /// [Error::UnsupportedVersion] instead of being parsed, and has to go
+    /// through [Subvols::receive_from] instead. The version of
+    /// every stream and the checksum of every command are verified before
+    /// anything is received.
+    pub fn receive_all<'f>(&mut self, data: &'f [u8]) -> Result<(), Error<'f>> {
+        verify_commands(data)?;
+        let sendstreams = Sendstream::parse_all(data).map_err(Error::Parse)?;
+        let mut staged = self.clone();
+        for sendstream in sendstreams {
+            staged.receive_staged(sendstream)?;
+        }
+        *self = staged;
+        Ok(())
+    }
+
+    /// Parse subvolumes from an uncompressed sendstream. Either all of the
+    /// subvolumes in the sendstream are received, or none of them are.
+    /// Untrusted data should go through [Subvols::receive_all] or
+    /// [Subvols::receive_from] instead, since the parser panics on command
+    /// types that it does not know.
     pub fn receive<'f>(&mut self, sendstream: Sendstream<'f>) -> Result<(), Error<'f>> {
-        let mut cmd_iter = sendstream.into_commands().into_iter();
-        let (mut subvol_uuid, mut subvol) =
-            #[remain::sorted]
-            match cmd_iter.next().expect("must have at least one command") {
-                Command::Snapshot(s) => {
-                    let mut subvol = self
-                        .0
-                        .get(&s.clone_uuid())
-                        .ok_or(Error::MissingParent(s.clone_uuid()))?
-                        .clone();
-                    subvol.parent_uuid = Some(s.clone_uuid());
-                    (s.uuid(), subvol)
+        let mut staged = self.clone();
+        staged.receive_staged(sendstream)?;
+        *self = staged;
+        Ok(())
+    }
+
+    /// Receive all of 'sendstreams', each one on its own thread, except for
+    /// streams that snapshot (or clone from) a subvolume that another one of
+    /// them creates, which wait for that stream to be received first. Like
+    /// [Subvols::receive], either everything is received or nothing is.
+    pub fn receive_parallel<'f>(
+        &mut self,
+        sendstreams: Vec<Sendstream<'f>>,
+    ) -> Result<(), Error<'f>> {
+        let mut pending: Vec<_> = sendstreams
+            .into_iter()
+            .map(|s| {
+                let mut produced = BTreeSet::new();
+                let mut referenced = BTreeSet::new();
+                for cmd in s.commands() {
+                    match cmd {
+                        Command::Clone(c) => {
+                            referenced.insert(c.uuid());
+                        }
+                        Command::Snapshot(snap) => {
+                            produced.insert(snap.uuid());
+                            referenced.insert(snap.clone_uuid());
+                        }
+                        Command::Subvol(subvol) => {
+                            produced.insert(subvol.uuid());
+                        }
+                        _ => (),
+                    }
                 }
-                Command::Subvol(s) => {
-                    let mut subvol = Subvol::new();
-                    subvol.fs.insert("", Directory::default());
-                    (s.uuid(), subvol)
+                referenced.retain(|uuid| !produced.contains(uuid));
+                (produced, referenced, s)
+            })
+            .collect();
+        let mut staged = self.clone();
+        while !pending.is_empty() {
+            let produced_later: BTreeSet<Uuid> = pending
+                .iter()
+                .flat_map(|(p, _, _)| p.iter().copied())
+                .collect();
+            let (mut ready, mut blocked): (Vec<_>, Vec<_>) = pending
+                .into_iter()
+                .partition(|(_, referenced, _)| referenced.is_disjoint(&produced_later));
+            if ready.is_empty() {
+                // a cycle can never be received, let the first stream fail
+                ready.push(blocked.remove(0));
+            }
+            pending = blocked;
+            let base = &staged;
+            let received: Vec<_> = std::thread::scope(|scope| {
+                let threads: Vec<_> = ready
+                    .into_iter()
+                    .map(|(produced, referenced, sendstream)| {
+                        scope.spawn(move || {
+                            // only the subvolumes that this stream needs
+                            let mut local = Subvols(
+                                referenced
+                                    .iter()
+                                    .filter_map(|uuid| base.0.get_key_value(uuid))
+                                    .map(|(uuid, subvol)| (*uuid, subvol.clone()))
+                                    .collect(),
+                            );
+                            local.receive_staged(sendstream).map(|()| (produced, local))
+                        })
+                    })
+                    .collect();
+                threads
+                    .into_iter()
+                    .map(|t| t.join().expect("receive thread panicked"))
+                    .collect()
+            });
+            for result in received {
+                let (produced, mut local) = result?;
+                for uuid in produced {
+                    if let Some(subvol) = local.0.remove(&uuid) {
+                        staged.0.insert(uuid, subvol);
+                    }
                 }
-                _ => {
-                    return Err(Error::InvariantViolated(
-                        "first command was not subvol start",
-                    ))
+            }
+        }
+        *self = staged;
+        Ok(())
+    }
+
+    /// Receive the (concatenated) sendstreams read from 'r', parsing and
+    /// applying one command at a time so that the whole stream never has to
+    /// be in memory at once. Like [Subvols::receive], either everything is
+    /// received or nothing is. Commands with a bad checksum fail with an
+    /// [ErrorKind::InvalidData] error that wraps an [Error::Checksum]. Unlike
+    /// [Subvols::receive_all], this also supports version 2 sendstreams
+    /// (`btrfs send --proto 2`).
+    pub fn receive_from(&mut self, r: impl Read) -> std::io::Result<()> {
+        self.receive_from_with(r, &ReceiveOptions::default())
+            .map(|_| ())
+    }
+
+    /// [Subvols::receive_from] with more control over how the stream is
+    /// received.
+    pub fn receive_from_with(
+        &mut self,
+        r: impl Read,
+        options: &ReceiveOptions,
+    ) -> std::io::Result<ReceiveReport> {
+        let mut r = BufReader::new(r);
+        let mut staged = self.clone();
+        let mut report = ReceiveReport::default();
+        let mut current: Option<(Uuid, Subvol)> = None;
+        let mut offset = 0;
+        // each command is re-framed as a single-command sendstream so that
+        // the parser can be reused
+        let mut buf = Vec::new();
+        while !r.fill_buf()?.is_empty() {
+            let mut header = [0; SENDSTREAM_MAGIC.len() + 4];
+            r.read_exact(&mut header)?;
+            offset += header.len() as u64;
+            let version = match header.strip_prefix(SENDSTREAM_MAGIC) {
+                Some(version) => u32::from_le_bytes(version.try_into().expect("4 bytes")),
+                None => {
+                    return Err(std::io::Error::new(
+                        ErrorKind::InvalidData,
+                        "missing sendstream magic",
+                    ));
                 }
             };
-        for cmd in cmd_iter {
-            match &cmd {
-                Command::Snapshot(s) => {
-                    self.0.insert(subvol_uuid, subvol);
-                    subvol = self
-                        .0
-                        .get(&s.clone_uuid())
-                        .ok_or(Error::MissingParent(s.clone_uuid()))?
-                        .clone();
-                    subvol.parent_uuid = Some(s.clone_uuid());
-                    subvol_uuid = s.uuid();
+            if version == 0 || version > MAX_VERSION {
+                return Err(Error::UnsupportedVersion(version).into());
+            }
+            let max_ty = match version {
+                1 => MAX_CMD_TYPE,
+                _ => MAX_V2_CMD_TYPE,
+            };
+            loop {
+                buf.clear();
+                // the parser only understands version 1 streams
+                buf.extend_from_slice(SENDSTREAM_MAGIC);
+                buf.extend_from_slice(&SUPPORTED_VERSION.to_le_bytes());
+                let mut cmd_header = [0; CMD_HEADER_LEN];
+                r.read_exact(&mut cmd_header)?;
+                let len = u32::from_le_bytes(cmd_header[..4].try_into().expect("4 bytes"));
+                let ty = u16::from_le_bytes(cmd_header[4..6].try_into().expect("2 bytes"));
+                buf.extend_from_slice(&cmd_header);
+                r.by_ref().take(len.into()).read_to_end(&mut buf)?;
+                if buf.len() != header.len() + CMD_HEADER_LEN + len as usize {
+                    return Err(ErrorKind::UnexpectedEof.into());
                 }
-                Command::Subvol(s) => {
-                    self.0.insert(subvol_uuid, subvol);
-                    subvol = Subvol::new();
-                    subvol.fs.insert("", Directory::default());
-                    subvol_uuid = s.uuid();
+                let cmd_offset = offset;
+                offset += (CMD_HEADER_LEN + len as usize) as u64;
+                let mut skip = |reason: String| match options.lenient {
+                    true => {
+                        report.skipped.push(SkippedCommand {
+                            offset: cmd_offset,
+                            ty,
+                            reason,
+                        });
+                        Ok(())
+                    }
+                    false => Err(std::io::Error::new(
+                        ErrorKind::InvalidData,
+                        format!("{reason} (command at offset {cmd_offset})"),
+                    )),
+                };
+                let expected = u32::from_le_bytes(cmd_header[6..10].try_into().expect("4 bytes"));
+                let actual = cmd_crc(&buf[header.len()..]);
+                if actual != expected {
+                    let e = Error::Checksum {
+                        offset: cmd_offset,
+                        expected,
+                        actual,
+                    };
+                    if !options.lenient {
+                        return Err(std::io::Error::new(ErrorKind::InvalidData, e));
+                    }
+                    skip(e.to_string())?;
+                    continue;
                 }
-                _ => {
-                    self.apply_cmd(&mut subvol, &cmd)
-                        .map_err(|error| match error {
-                            ApplyError::Apply(error) => Error::Apply {
-                                command: cmd,
-                                error,
-                            },
-                            ApplyError::Btrfs(error) => error,
-                        })?;
+                // the parser panics on command types that it does not know
+                if ty == 0 || ty > max_ty {
+                    skip(format!("unknown command type {ty}"))?;
+                    continue;
+                }
+                let (result, traced, starts_subvol, end) = if version >= 2
+                    && (ty == WRITE_CMD_TYPE || ty > MAX_CMD_TYPE)
+                {
+                    let cmd = match V2Command::parse(ty, &buf[header.len() + CMD_HEADER_LEN..]) {
+                        Ok(cmd) => cmd,
+                        Err(e) => {
+                            skip(e)?;
+                            continue;
+                        }
+                    };
+                    let traced = options.trace.then(|| format!("{cmd:?}"));
+                    let result = match &mut current {
+                        Some((_, subvol)) => subvol.apply_v2_cmd(&cmd, options),
+                        None => Err(
+                            Error::InvariantViolated("first command was not subvol start").into(),
+                        ),
+                    };
+                    (result, traced, false, false)
+                } else {
+                    let cmd = match Sendstream::parse_all(&buf) {
+                        Ok(mut s) => s
+                            .pop()
+                            .and_then(|s| s.into_commands().pop())
+                            .ok_or(Error::InvariantViolated("command did not parse"))?,
+                        Err(e) => {
+                            skip(e.to_string())?;
+                            continue;
+                        }
+                    };
+                    let end = cmd == Command::End;
+                    // skipping the start of a subvolume would apply
+                    // everything after it to the wrong subvolume
+                    let starts_subvol = matches!(cmd, Command::Snapshot(_) | Command::Subvol(_));
+                    let traced = options.trace.then(|| format!("{cmd:?}"));
+                    let result = staged
+                        .receive_cmd(&mut current, cmd)
+                        .map_err(std::io::Error::from);
+                    (result, traced, starts_subvol, end)
+                };
+                match result {
+                    Ok(()) => {
+                        if let Some((_, subvol)) = &mut current {
+                            if starts_subvol {
+                                subvol.version = version;
+                            }
+                            if let Some(command) = traced {
+                                subvol.trace.push(TracedCommand {
+                                    offset: cmd_offset,
+                                    command,
+                                });
+                            }
+                        }
+                    }
+                    Err(e) if !starts_subvol && options.lenient => skip(e.to_string())?,
+                    Err(e) => return Err(e),
+                }
+                if end {
+                    break;
                 }
             }
         }
-        self.0.insert(subvol_uuid, subvol);
+        if let Some((uuid, subvol)) = current {
+            staged.0.insert(uuid, subvol);
+        }
+        *self = staged;
+        Ok(report)
+    }
+
+    fn receive_staged<'f>(&mut self, sendstream: Sendstream<'f>) -> Result<(), Error<'f>> {
+        let mut current = None;
+        for cmd in sendstream.into_commands() {
+            self.receive_cmd(&mut current, cmd)?;
+        }
+        if let Some((uuid, subvol)) = current {
+            self.0.insert(uuid, subvol);
+        }
+        Ok(())
+    }
+
+    /// Apply a single command to the 'current' subvolume, which is finished
+    /// (and a new one started) when a subvol or snapshot command is seen.
+    fn receive_cmd<'c>(
+        &mut self,
+        current: &mut Option<(Uuid, Subvol)>,
+        cmd: Command<'c>,
+    ) -> Result<(), Error<'c>> {
+        if let Command::Snapshot(_) | Command::Subvol(_) = cmd {
+            if let Some((uuid, subvol)) = current.take() {
+                self.0.insert(uuid, subvol);
+            }
+        }
+        *current = match &cmd {
+            Command::Snapshot(s) => {
+                let parent = self
+                    .0
+                    .get(&s.clone_uuid())
+                    .ok_or(Error::MissingParent(s.clone_uuid()))?;
+                let subvol = Subvol {
+                    parent_uuid: Some(s.clone_uuid()),
+                    clone_ctransid: Some(s.clone_ctransid().0),
+                    fs: parent.fs.clone(),
+                    ..Subvol::new(s.path(), s.uuid(), s.ctransid().0)
+                };
+                Some((s.uuid(), subvol))
+            }
+            Command::Subvol(s) => Some((s.uuid(), Subvol::new(s.path(), s.uuid(), s.ctransid().0))),
+            _ => {
+                let subvol = match current {
+                    Some((_, subvol)) => subvol,
+                    None => {
+                        return Err(Error::InvariantViolated(
+                            "first command was not subvol start",
+                        ));
+                    }
+                };
+                return self.apply_cmd(subvol, &cmd).map_err(|error| match error {
+                    ApplyError::Apply(error) => Error::Apply {
+                        command: cmd,
+                        error,
+                    },
+                    ApplyError::Btrfs(error) => error,
+                });
+            }
+        };
         Ok(())
     }
 }
 
+impl From<Error<'_>> for std::io::Error {
+    fn from(e: Error<'_>) -> Self {
+        match e {
+            Error::Apply { ref error, .. } => Self::new(error.kind(), e.to_string()),
+            e => Self::new(ErrorKind::InvalidData, e.to_string()),
+        }
+    }
+}
+
+impl IntoIterator for Subvols {
+    type Item = (Uuid, Subvol);
+    type IntoIter = std::collections::btree_map::IntoIter<Uuid, Subvol>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.into_iter()
+    }
+}
+
 impl Default for Subvols {
     fn default() -> Self {
         Self::new()
@@ -288,8 +1180,8 @@ mod tests {
         }
         // drop the uuid which will change on every build and re-order so that
         // the parent is always first
-        let uuids: HashSet<Uuid> = subvols.0.keys().map(|u| *u).collect();
-        let mut subvols: Vec<_> = subvols.0.into_values().collect();
+        let uuids: HashSet<Uuid> = subvols.iter().map(|(uuid, _)| *uuid).collect();
+        let mut subvols: Vec<_> = subvols.into_iter().map(|(_, s)| s).collect();
         assert_eq!(2, subvols.len());
         subvols.sort_by_key(|s| s.parent_uuid);
         let parent_uuid = subvols[1].parent_uuid.unwrap();
@@ -311,4 +1203,501 @@ mod tests {
         );
         assert_approx_eq!(demo2, &subvols[1].fs, Fields::all() - Fields::TIME);
     }
+
+    #[test]
+    fn unsupported_version() {
+        let mut v2 = include_bytes!("../testdata/testdata.sendstream").to_vec();
+        v2[SENDSTREAM_MAGIC.len()..SENDSTREAM_MAGIC.len() + 4].copy_from_slice(&2u32.to_le_bytes());
+        let mut subvols = Subvols::new();
+        assert!(matches!(
+            subvols.receive_all(&v2),
+            Err(Error::UnsupportedVersion(2))
+        ));
+        // every stream is checked, not just the first one
+        let mut concatenated = stream(&[(21, &[])]);
+        let mut v2 = stream(&[(21, &[])]);
+        v2[SENDSTREAM_MAGIC.len()..SENDSTREAM_MAGIC.len() + 4].copy_from_slice(&2u32.to_le_bytes());
+        concatenated.extend(v2);
+        assert!(matches!(
+            subvols.receive_all(&concatenated),
+            Err(Error::UnsupportedVersion(2))
+        ));
+        subvols
+            .receive_all(include_bytes!("../testdata/testdata.sendstream"))
+            .unwrap();
+        assert_eq!(2, subvols.len());
+    }
+
+    #[test]
+    fn receive_from() {
+        let contents = include_bytes!("../testdata/testdata.sendstream");
+        let mut expected = Subvols::new();
+        expected.receive_all(contents).unwrap();
+        let mut subvols = Subvols::new();
+        subvols.receive_from(contents.as_slice()).unwrap();
+        assert_eq!(expected, subvols);
+        // a truncated stream does not receive anything
+        let mut subvols = Subvols::new();
+        assert!(subvols
+            .receive_from(&contents[..contents.len() - 1])
+            .is_err());
+        assert_eq!(Subvols::new(), subvols);
+    }
+
+    #[test]
+    fn hierarchy() {
+        let mut subvols = Subvols::new();
+        subvols
+            .receive_all(include_bytes!("../testdata/testdata.sendstream"))
+            .unwrap();
+        let (child_uuid, child) = subvols
+            .iter()
+            .find(|(_, s)| s.parent_uuid().is_some())
+            .expect("one subvol is a snapshot");
+        let parent_uuid = child.parent_uuid().unwrap();
+        assert!(subvols.get(&parent_uuid).unwrap().fs().get("wow").is_err());
+        assert!(child.fs().get("wow").is_ok());
+        assert_eq!(
+            vec![child_uuid],
+            subvols
+                .children(&parent_uuid)
+                .map(|(u, _)| u)
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec![child_uuid, &parent_uuid],
+            subvols
+                .snapshot_chain(child_uuid)
+                .into_iter()
+                .map(|(u, _)| u)
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn stream_metadata() {
+        let mut subvols = Subvols::new();
+        subvols
+            .receive_all(include_bytes!("../testdata/testdata.sendstream"))
+            .unwrap();
+        for (uuid, subvol) in subvols.iter() {
+            assert_eq!(*uuid, subvol.received_uuid());
+            assert_eq!(1, subvol.version());
+            assert_ne!(0, subvol.ctransid());
+            match subvol.parent_uuid() {
+                Some(parent) => assert_eq!(
+                    subvols.get(&parent).unwrap().ctransid(),
+                    subvol.clone_ctransid().unwrap()
+                ),
+                None => assert_eq!(None, subvol.clone_ctransid()),
+            }
+        }
+    }
+
+    /// Command type and its (attribute type, value) pairs.
+    type RawCmd<'a> = (u16, &'a [(u16, &'a [u8])]);
+
+    /// Hand-rolled sendstream made of the given commands, since there is no
+    /// way to create most interesting sendstreams without a real btrfs
+    /// filesystem.
+    fn stream(cmds: &[RawCmd]) -> Vec<u8> {
+        stream_version(1, cmds)
+    }
+
+    /// [stream] with the framing of the given 'version'.
+    fn stream_version(version: u32, cmds: &[RawCmd]) -> Vec<u8> {
+        let mut stream = SENDSTREAM_MAGIC.to_vec();
+        stream.extend_from_slice(&version.to_le_bytes());
+        for (ty, attrs) in cmds {
+            let mut data = Vec::new();
+            for (attr, value) in *attrs {
+                data.extend_from_slice(&attr.to_le_bytes());
+                if version == 1 || *attr != DATA_ATTR {
+                    data.extend_from_slice(&(value.len() as u16).to_le_bytes());
+                }
+                data.extend_from_slice(value);
+            }
+            let mut cmd = (data.len() as u32).to_le_bytes().to_vec();
+            cmd.extend_from_slice(&ty.to_le_bytes());
+            cmd.extend_from_slice(&0u32.to_le_bytes());
+            cmd.extend(data);
+            let crc = cmd_crc(&cmd);
+            cmd[6..10].copy_from_slice(&crc.to_le_bytes());
+            stream.extend(cmd);
+        }
+        stream
+    }
+
+    #[test]
+    fn v2() {
+        // more data than fits in a version 1 attribute
+        let data: Vec<u8> = (0..100_000u32).map(|i| i as u8).collect();
+        let cmds: &[RawCmd] = &[
+            (1, &[(15, b"vol"), (1, &[1; 16]), (2, &1u64.to_le_bytes())]),
+            (3, &[(15, b"f"), (3, &257u64.to_le_bytes())]),
+            (15, &[(15, b"f"), (18, &1u64.to_le_bytes()), (19, &data)]),
+            (24, &[(15, b"f"), (26, &0x10u64.to_le_bytes())]),
+            (18, &[(15, b"f"), (5, &0o600u64.to_le_bytes())]),
+            (21, &[]),
+        ];
+        let v2 = stream_version(2, cmds);
+        let mut subvols = Subvols::new();
+        assert!(matches!(
+            subvols.receive_all(&v2),
+            Err(Error::UnsupportedVersion(2))
+        ));
+        subvols
+            .receive_from_with(v2.as_slice(), ReceiveOptions::new().trace(true))
+            .unwrap();
+        let (_, subvol) = subvols.into_iter().next().unwrap();
+        assert_eq!(2, subvol.version());
+        let f = subvol.fs().get_file("f").unwrap();
+        assert_eq!(
+            0o600,
+            subvol.fs().get("f").unwrap().metadata().mode().bits() & 0o7777
+        );
+        let mut expected = vec![0];
+        expected.extend_from_slice(&data);
+        assert_eq!(expected, f.to_bytes().unwrap().as_ref());
+        assert!(subvol.trace()[2].command().contains("<100000 bytes>"));
+
+        // commands that are new in version 2 are unknown in version 1
+        let mut subvols = Subvols::new();
+        let err = subvols
+            .receive_from(stream_version(1, &[cmds[0], cmds[1], cmds[3], cmds[5]]).as_slice())
+            .unwrap_err();
+        assert!(err.to_string().contains("unknown command type 24"), "{err}");
+        // and version 3 is not supported at all
+        assert_eq!(
+            ErrorKind::InvalidData,
+            subvols
+                .receive_from(stream_version(3, &[cmds[0], cmds[5]]).as_slice())
+                .unwrap_err()
+                .kind()
+        );
+        assert!(subvols.is_empty());
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn encoded_write() {
+        let data: Vec<u8> = (0..8192u32).map(|i| (i / 100) as u8).collect();
+        let mut compressed = zstd::bulk::compress(&data, 3).unwrap();
+        // btrfs pads compressed extents to a whole sector
+        compressed.resize(4096, 0);
+        let receive = |unencoded_offset: u64, file_len: u64, options: &ReceiveOptions| {
+            let cmds: &[RawCmd] = &[
+                (1, &[(15, b"vol"), (1, &[1; 16]), (2, &1u64.to_le_bytes())]),
+                (3, &[(15, b"f"), (3, &257u64.to_le_bytes())]),
+                (
+                    25,
+                    &[
+                        (15, b"f"),
+                        (18, &4096u64.to_le_bytes()),
+                        (27, &file_len.to_le_bytes()),
+                        (28, &8192u64.to_le_bytes()),
+                        (29, &unencoded_offset.to_le_bytes()),
+                        (30, &2u32.to_le_bytes()),
+                        (19, &compressed),
+                    ],
+                ),
+                (21, &[]),
+            ];
+            let mut subvols = Subvols::new();
+            subvols.receive_from_with(stream_version(2, cmds).as_slice(), options)?;
+            let (_, subvol) = subvols.into_iter().next().unwrap();
+            Ok::<_, std::io::Error>(subvol.fs().get_file("f").unwrap().clone())
+        };
+
+        let f = receive(0, 8192, &ReceiveOptions::new()).unwrap();
+        let mut expected = vec![0; 4096];
+        expected.extend_from_slice(&data);
+        assert_eq!(expected, f.to_bytes().unwrap().as_ref());
+        assert!(matches!(
+            f.extents().last(),
+            Some((4096, Extent::Compressed(_)))
+        ));
+
+        let f = receive(0, 8192, ReceiveOptions::new().decompress(true)).unwrap();
+        assert_eq!(expected, f.to_bytes().unwrap().as_ref());
+        assert!(matches!(f.extents().last(), Some((4096, Extent::Owned(_)))));
+
+        // only part of the decompressed data belongs to the file
+        let f = receive(100, 1000, &ReceiveOptions::new()).unwrap();
+        let mut expected = vec![0; 4096];
+        expected.extend_from_slice(&data[100..1100]);
+        assert_eq!(expected, f.to_bytes().unwrap().as_ref());
+
+        let err = receive(8000, 1000, &ReceiveOptions::new()).unwrap_err();
+        assert!(err.to_string().contains("past the end"), "{err}");
+    }
+
+    #[test]
+    fn fallocate() {
+        let receive = |fallocates: &[(u32, u64, u64)]| {
+            let mut stream = stream_version(
+                2,
+                &[
+                    (1, &[(15, b"vol"), (1, &[1; 16]), (2, &1u64.to_le_bytes())]),
+                    (3, &[(15, b"f"), (3, &257u64.to_le_bytes())]),
+                    (
+                        15,
+                        &[(15, b"f"), (18, &0u64.to_le_bytes()), (19, &[1; 8192])],
+                    ),
+                ],
+            );
+            for (mode, offset, len) in fallocates {
+                let cmd: RawCmd = (
+                    23,
+                    &[
+                        (15, b"f"),
+                        (25, &mode.to_le_bytes()),
+                        (18, &offset.to_le_bytes()),
+                        (4, &len.to_le_bytes()),
+                    ],
+                );
+                stream.extend_from_slice(&stream_version(2, &[cmd])[SENDSTREAM_MAGIC.len() + 4..]);
+            }
+            stream
+                .extend_from_slice(&stream_version(2, &[(21, &[])])[SENDSTREAM_MAGIC.len() + 4..]);
+            let mut subvols = Subvols::new();
+            subvols.receive_from(stream.as_slice())?;
+            let (_, subvol) = subvols.into_iter().next().unwrap();
+            Ok::<_, std::io::Error>(subvol.fs().get_file("f").unwrap().to_bytes()?.to_vec())
+        };
+
+        let mut expected = vec![1; 8192];
+        expected[100..4196].fill(0);
+        expected.resize(16384, 0);
+        assert_eq!(
+            expected,
+            // punch a hole, preallocate past the end but keep the size, then
+            // grow the file
+            receive(&[(3, 100, 4096), (1, 8192, 100_000), (0, 12288, 4096)]).unwrap()
+        );
+        // punching a hole past the end does not grow the file
+        assert_eq!(vec![1; 8192], receive(&[(3, 8192, 4096)]).unwrap());
+        // FALLOC_FL_ZERO_RANGE
+        assert_eq!(
+            ErrorKind::Unsupported,
+            receive(&[(0x10, 0, 4096)]).unwrap_err().kind()
+        );
+    }
+
+    #[test]
+    fn update_extent() {
+        // `btrfs send --no-data`
+        let stream = stream(&[
+            (1, &[(15, b"vol"), (1, &[1; 16]), (2, &1u64.to_le_bytes())]),
+            (3, &[(15, b"f"), (3, &257u64.to_le_bytes())]),
+            (
+                22,
+                &[
+                    (15, b"f"),
+                    (18, &4096u64.to_le_bytes()),
+                    (4, &8192u64.to_le_bytes()),
+                ],
+            ),
+            (21, &[]),
+        ]);
+        let mut subvols = Subvols::new();
+        subvols.receive_all(&stream).unwrap();
+        let (_, subvol) = subvols.into_iter().next().unwrap();
+        let f = subvol.fs().get_file("f").unwrap();
+        assert_eq!(12288, f.len());
+        assert_eq!(vec![0; 12288], f.to_bytes().unwrap().as_ref());
+    }
+
+    #[test]
+    fn clone_from_other_subvol() {
+        let parent = stream(&[
+            (1, &[(15, b"a"), (1, &[1; 16]), (2, &1u64.to_le_bytes())]),
+            (3, &[(15, b"f"), (3, &257u64.to_le_bytes())]),
+            (
+                15,
+                &[(15, b"f"), (18, &0u64.to_le_bytes()), (19, b"hello world")],
+            ),
+            (21, &[]),
+        ]);
+        let child = stream(&[
+            (
+                2,
+                &[
+                    (15, b"b"),
+                    (1, &[2; 16]),
+                    (2, &2u64.to_le_bytes()),
+                    (20, &[1; 16]),
+                    (21, &1u64.to_le_bytes()),
+                ],
+            ),
+            (15, &[(15, b"f"), (18, &0u64.to_le_bytes()), (19, b"HELLO")]),
+            (3, &[(15, b"g"), (3, &258u64.to_le_bytes())]),
+            (
+                16,
+                &[
+                    (18, &0u64.to_le_bytes()),
+                    (24, &11u64.to_le_bytes()),
+                    (15, b"g"),
+                    (20, &[1; 16]),
+                    (21, &1u64.to_le_bytes()),
+                    (22, b"f"),
+                    (23, &0u64.to_le_bytes()),
+                ],
+            ),
+            (21, &[]),
+        ]);
+        let mut subvols = Subvols::new();
+        subvols.receive_all(&parent).unwrap();
+        subvols.receive_all(&child).unwrap();
+        let child = subvols.get(&Uuid::from_bytes([2; 16])).unwrap();
+        assert_eq!(
+            b"HELLO world",
+            child
+                .fs()
+                .get_file("f")
+                .unwrap()
+                .to_bytes()
+                .unwrap()
+                .as_ref()
+        );
+        assert_eq!(
+            b"hello world",
+            child
+                .fs()
+                .get_file("g")
+                .unwrap()
+                .to_bytes()
+                .unwrap()
+                .as_ref()
+        );
+    }
+
+    #[cfg(feature = "diff")]
+    #[test]
+    fn diff() {
+        let mut subvols = Subvols::new();
+        subvols
+            .receive_all(include_bytes!("../testdata/testdata.sendstream"))
+            .unwrap();
+        let (child, parent) = subvols
+            .iter()
+            .find_map(|(uuid, s)| s.parent_uuid().map(|parent| (*uuid, parent)))
+            .expect("one subvol is a snapshot");
+        let diff = subvols
+            .diff(&parent, &child, Fields::all() - Fields::TIME)
+            .unwrap();
+        assert!(diff.to_string().contains("wow"));
+        assert!(subvols.diff(&parent, &Uuid::nil(), Fields::all()).is_none());
+    }
+
+    #[test]
+    fn flatten() {
+        let mut subvols = Subvols::new();
+        subvols
+            .receive_all(include_bytes!("../testdata/testdata.sendstream"))
+            .unwrap();
+        let (uuid, subvol) = subvols
+            .iter()
+            .find(|(_, s)| s.parent_uuid().is_some())
+            .expect("one subvol is a snapshot");
+        let flat = subvols.flatten(uuid).unwrap();
+        assert_approx_eq!(subvol.fs(), &flat, Fields::all() - Fields::EXTENTS);
+        for (_, entry) in &flat {
+            if let Entry::File(f) = entry {
+                assert!(f.extents().all(|(_, e)| !matches!(e, Extent::Cloned(_))));
+            }
+        }
+        drop(subvols);
+        assert!(flat.get_file("testdata/dir/lorem.txt").is_ok());
+    }
+
+    #[test]
+    fn lenient() {
+        let stream = stream(&[
+            (1, &[(15, b"vol"), (1, &[1; 16]), (2, &1u64.to_le_bytes())]),
+            (3, &[(15, b"f"), (3, &257u64.to_le_bytes())]),
+            (100, &[(15, b"f")]),
+            (11, &[(15, b"missing")]),
+            (17, &[(15, b"f"), (4, &3u64.to_le_bytes())]),
+            (21, &[]),
+        ]);
+        let mut subvols = Subvols::new();
+        assert_eq!(
+            ErrorKind::InvalidData,
+            subvols.receive_from(stream.as_slice()).unwrap_err().kind()
+        );
+        assert!(matches!(
+            subvols.receive_all(&stream),
+            Err(Error::UnknownCommand { ty: 100, .. })
+        ));
+        assert!(subvols.is_empty());
+        let report = subvols
+            .receive_from_with(stream.as_slice(), ReceiveOptions::new().lenient(true))
+            .unwrap();
+        assert_eq!(
+            vec![100, 11],
+            report.skipped().iter().map(|s| s.ty()).collect::<Vec<_>>()
+        );
+        let (_, subvol) = subvols.into_iter().next().unwrap();
+        assert_eq!(3, subvol.fs().get_file("f").unwrap().len());
+    }
+
+    #[test]
+    fn trace() {
+        let contents = include_bytes!("../testdata/testdata.sendstream");
+        let mut subvols = Subvols::new();
+        subvols
+            .receive_from_with(contents.as_slice(), ReceiveOptions::new().trace(true))
+            .unwrap();
+        let (_, child) = subvols
+            .iter()
+            .find(|(_, s)| s.parent_uuid().is_some())
+            .expect("one subvol is a snapshot");
+        let trace = child.trace();
+        assert!(trace[0].command().starts_with("Snapshot("));
+        assert!(trace.iter().any(|c| c.command().contains("\"wow\"")));
+        assert!(trace.windows(2).all(|w| w[0].offset() < w[1].offset()));
+    }
+
+    #[test]
+    fn checksum() {
+        let mut corrupt = include_bytes!("../testdata/testdata.sendstream").to_vec();
+        let lorem = corrupt
+            .windows(5)
+            .position(|w| w == b"Lorem")
+            .expect("lorem.txt is in the sendstream");
+        corrupt[lorem] = b'l';
+        let mut subvols = Subvols::new();
+        assert!(matches!(
+            subvols.receive_all(&corrupt),
+            Err(Error::Checksum { offset, .. }) if offset < lorem as u64
+        ));
+        let err = subvols.receive_from(corrupt.as_slice()).unwrap_err();
+        assert_eq!(ErrorKind::InvalidData, err.kind());
+        assert!(matches!(
+            err.get_ref().and_then(|e| e.downcast_ref::<Error>()),
+            Some(Error::Checksum { .. })
+        ));
+        assert!(subvols.is_empty());
+    }
+
+    #[test]
+    fn receive_parallel() {
+        let contents = include_bytes!("../testdata/testdata.sendstream");
+        let independent = stream(&[
+            (1, &[(15, b"vol"), (1, &[1; 16]), (2, &1u64.to_le_bytes())]),
+            (3, &[(15, b"f"), (3, &257u64.to_le_bytes())]),
+            (21, &[]),
+        ]);
+        let mut expected = Subvols::new();
+        expected.receive_all(contents).unwrap();
+        expected.receive_all(&independent).unwrap();
+        let mut sendstreams = Sendstream::parse_all(contents).unwrap();
+        // the snapshot comes first, but still has to wait for its parent
+        sendstreams.reverse();
+        sendstreams.extend(Sendstream::parse_all(&independent).unwrap());
+        let mut subvols = Subvols::new();
+        subvols.receive_parallel(sendstreams).unwrap();
+        assert_eq!(expected, subvols);
+    }
 }
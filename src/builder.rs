@@ -0,0 +1,304 @@
+//! Concise construction of [Filesystem]s, mainly for test fixtures.
+
+use std::path::Path;
+
+use bytes::Bytes;
+use nix::sys::stat::Mode;
+
+use crate::entry::Directory;
+use crate::entry::Metadata;
+use crate::entry::Symlink;
+use crate::file::extent::Extent;
+use crate::BytesPath;
+use crate::Entry;
+use crate::File;
+use crate::Filesystem;
+use crate::Gid;
+use crate::Uid;
+
+/// Mode of any parent directories that are created implicitly.
+const IMPLICIT_DIR_MODE: u32 = 0o755;
+
+/// Build a [Filesystem] one entry at a time. Any missing parent directories
+/// (including the root) are created with mode 0755, and can be overridden by
+/// explicitly adding them later.
+///
+/// ```
+/// use filesystem_in_a_file::Filesystem;
+///
+/// let fs = Filesystem::builder()
+///     .dir("etc", 0o755)
+///     .file("etc/passwd", "root:x:0:0::/root:/bin/sh\n", 0o644)
+///     .symlink("bin", "usr/bin")
+///     .build();
+/// assert!(fs.get("etc/passwd").is_ok());
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct FilesystemBuilder {
+    fs: Filesystem,
+}
+
+impl Filesystem {
+    pub fn builder() -> FilesystemBuilder {
+        FilesystemBuilder::default()
+    }
+}
+
+impl FilesystemBuilder {
+    /// Add a directory. If the directory already exists (for example, because
+    /// it was implicitly created as a parent) its mode is replaced.
+    pub fn dir(&mut self, path: impl AsRef<Path>, mode: u32) -> &mut Self {
+        let path = path.as_ref();
+        match self.fs.get_mut(path) {
+            Ok(entry) if entry.is_directory() => entry.chmod(Mode::from_bits_truncate(mode)),
+            _ => {
+                self.entry(path, directory(mode));
+            }
+        }
+        self
+    }
+
+    /// Add a regular file with the given contents.
+    pub fn file(
+        &mut self,
+        path: impl AsRef<Path>,
+        contents: impl Into<Extent>,
+        mode: u32,
+    ) -> &mut Self {
+        self.entry(
+            path,
+            File::builder()
+                .contents(contents)
+                .metadata(
+                    Metadata::builder()
+                        .mode(Mode::from_bits_truncate(mode))
+                        .build(),
+                )
+                .build(),
+        )
+    }
+
+    /// Add a symlink pointing at 'target'.
+    pub fn symlink(&mut self, path: impl AsRef<Path>, target: impl Into<BytesPath>) -> &mut Self {
+        self.entry(path, Symlink::new(target, None))
+    }
+
+    /// Add a hardlink to the existing entry at 'target'.
+    ///
+    /// Panics if 'target' does not exist or is a directory.
+    pub fn hardlink(&mut self, path: impl AsRef<Path>, target: impl AsRef<Path>) -> &mut Self {
+        let path = path.as_ref();
+        self.create_parents(path);
+        self.fs
+            .link(target.as_ref(), path)
+            .unwrap_or_else(|e| panic!("cannot link {}: {e}", path.display()));
+        self
+    }
+
+    /// Add an arbitrary [Entry], for anything not covered by the other
+    /// methods.
+    pub fn entry(&mut self, path: impl AsRef<Path>, entry: impl Into<Entry>) -> &mut Self {
+        let path = path.as_ref();
+        self.create_parents(path);
+        self.fs.insert(path, entry);
+        self
+    }
+
+    /// Set an xattr on an entry that was already added.
+    pub fn xattr(
+        &mut self,
+        path: impl AsRef<Path>,
+        name: impl Into<Bytes>,
+        value: impl Into<Bytes>,
+    ) -> &mut Self {
+        self.existing(path.as_ref()).set_xattr(name, value);
+        self
+    }
+
+    /// Change the owner of an entry that was already added.
+    pub fn owner(
+        &mut self,
+        path: impl AsRef<Path>,
+        uid: impl Into<Uid>,
+        gid: impl Into<Gid>,
+    ) -> &mut Self {
+        self.existing(path.as_ref()).chown(uid.into(), gid.into());
+        self
+    }
+
+    pub fn build(&mut self) -> Filesystem {
+        self.fs.clone()
+    }
+
+    fn existing(&mut self, path: &Path) -> &mut Entry {
+        self.fs
+            .get_mut(path)
+            .unwrap_or_else(|_| panic!("{} has not been added yet", path.display()))
+    }
+
+    /// Panics if any ancestor of 'path' already exists as something other
+    /// than a directory.
+    fn create_parents(&mut self, path: &Path) {
+        let parents: Vec<_> = path.ancestors().skip(1).collect();
+        for parent in parents.into_iter().rev() {
+            match self.fs.get(parent) {
+                Ok(entry) => assert!(
+                    entry.is_directory(),
+                    "{} is not a directory",
+                    parent.display()
+                ),
+                Err(_) => {
+                    self.fs.insert(parent, directory(IMPLICIT_DIR_MODE));
+                }
+            }
+        }
+    }
+}
+
+/// Declare a [Filesystem] literal, as a more compact alternative to
+/// [FilesystemBuilder] for deeply nested fixtures.
+///
+/// Each entry is a quoted name followed by one of:
+/// * `{ ... }` or `dir(mode) { ... }`: a directory with nested entries (mode
+///   0755 by default)
+/// * `file(contents)` or `file(contents, mode)`: a regular file (mode 0644 by
+///   default)
+/// * `symlink(target)`
+/// * `link(target)`: a hardlink to a path (relative to the root) that was
+///   declared earlier
+///
+/// Any entry may be followed by a list of xattrs in square brackets.
+///
+/// ```
+/// use filesystem_in_a_file::fs;
+///
+/// let fs = fs! {
+///     "etc": {
+///         "passwd": file("root:x:0:0::/root:/bin/sh\n"),
+///         "shadow": file("root:!::::::\n", 0o600) ["security.selinux" = "shadow_t"],
+///     },
+///     "usr": dir(0o755) {
+///         "bin": {},
+///     },
+///     "bin": symlink("usr/bin"),
+/// };
+/// assert!(fs.get("etc/shadow").is_ok());
+/// ```
+#[macro_export]
+macro_rules! fs {
+    (@entries $b:ident $parent:expr;) => {};
+    (@entries $b:ident $parent:expr; $name:literal : { $($children:tt)* } $([$($xattrs:tt)*])? $(, $($rest:tt)*)?) => {
+        $crate::fs!(@entries $b $parent; $name: dir(0o755) { $($children)* } $([$($xattrs)*])? $(, $($rest)*)?);
+    };
+    (@entries $b:ident $parent:expr; $name:literal : dir($mode:expr) { $($children:tt)* } $([$($xattrs:tt)*])? $(, $($rest:tt)*)?) => {
+        {
+            let path = ::std::path::Path::new($parent).join($name);
+            $b.dir(&path, $mode);
+            $crate::fs!(@xattrs $b &path; $($($xattrs)*)?);
+            $crate::fs!(@entries $b &path; $($children)*);
+        }
+        $crate::fs!(@entries $b $parent; $($($rest)*)?);
+    };
+    (@entries $b:ident $parent:expr; $name:literal : file($contents:expr) $([$($xattrs:tt)*])? $(, $($rest:tt)*)?) => {
+        $crate::fs!(@entries $b $parent; $name: file($contents, 0o644) $([$($xattrs)*])? $(, $($rest)*)?);
+    };
+    (@entries $b:ident $parent:expr; $name:literal : file($contents:expr, $mode:expr) $([$($xattrs:tt)*])? $(, $($rest:tt)*)?) => {
+        {
+            let path = ::std::path::Path::new($parent).join($name);
+            $b.file(&path, $contents, $mode);
+            $crate::fs!(@xattrs $b &path; $($($xattrs)*)?);
+        }
+        $crate::fs!(@entries $b $parent; $($($rest)*)?);
+    };
+    (@entries $b:ident $parent:expr; $name:literal : symlink($target:expr) $([$($xattrs:tt)*])? $(, $($rest:tt)*)?) => {
+        {
+            let path = ::std::path::Path::new($parent).join($name);
+            $b.symlink(&path, $target);
+            $crate::fs!(@xattrs $b &path; $($($xattrs)*)?);
+        }
+        $crate::fs!(@entries $b $parent; $($($rest)*)?);
+    };
+    (@entries $b:ident $parent:expr; $name:literal : link($target:expr) $([$($xattrs:tt)*])? $(, $($rest:tt)*)?) => {
+        {
+            let path = ::std::path::Path::new($parent).join($name);
+            $b.hardlink(&path, $target);
+            $crate::fs!(@xattrs $b &path; $($($xattrs)*)?);
+        }
+        $crate::fs!(@entries $b $parent; $($($rest)*)?);
+    };
+    (@xattrs $b:ident $path:expr; $($name:literal = $value:expr),* $(,)?) => {
+        $($b.xattr($path, $name, $value);)*
+    };
+    ($($entries:tt)*) => {{
+        let mut builder = $crate::Filesystem::builder();
+        $crate::fs!(@entries builder ""; $($entries)*);
+        builder.build()
+    }};
+}
+
+fn directory(mode: u32) -> Directory {
+    Directory::builder()
+        .metadata(
+            Metadata::builder()
+                .mode(Mode::from_bits_truncate(mode))
+                .build(),
+        )
+        .build()
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::tests::demo_fs;
+
+    #[test]
+    fn builder() {
+        let fs = Filesystem::builder()
+            .file("testdata/lorem.txt", "Lorem ipsum\n", 0o644)
+            .xattr("testdata/lorem.txt", "user.demo", "lorem ipsum")
+            .file(
+                "testdata/dir/lorem.txt",
+                "Lorem ipsum dolor sit amet\n",
+                0o644,
+            )
+            .symlink("testdata/dir/symlink", "../lorem.txt")
+            .build();
+        assert_eq!(demo_fs(), fs);
+
+        let fs = Filesystem::builder()
+            .file("a/b", "", 0o600)
+            .dir("a", 0o700)
+            .hardlink("c/d", "a/b")
+            .owner("c/d", 1000, 1000)
+            .build();
+        assert_eq!(
+            Mode::from_bits_truncate(0o700),
+            fs.get("a").unwrap().metadata().mode()
+        );
+        assert!(fs.get("a/b").unwrap().is_file());
+        assert_eq!(Uid::from_raw(1000), fs.get("a/b").unwrap().metadata().uid());
+    }
+
+    #[test]
+    fn fs_macro() {
+        let fs = fs! {
+            "testdata": {
+                "lorem.txt": file("Lorem ipsum\n") ["user.demo" = "lorem ipsum"],
+                "dir": dir(0o755) {
+                    "lorem.txt": file("Lorem ipsum dolor sit amet\n", 0o644),
+                    "symlink": symlink("../lorem.txt"),
+                },
+            },
+        };
+        assert_eq!(demo_fs(), fs);
+
+        let fs = fs! {
+            "a": file("a"),
+            "b": link("a"),
+        };
+        assert_eq!(fs.get("a").unwrap(), fs.get("b").unwrap());
+        assert_eq!(Filesystem::new(), fs! {});
+    }
+}
@@ -0,0 +1,252 @@
+//! Content-defined chunking of file data (with [FastCDC](fastcdc)), to find
+//! out how much data two filesystems share even when it was inserted or
+//! removed somewhere in the middle of a file, for example how much of image
+//! N+1 is reused from image N.
+
+use std::collections::HashMap;
+use std::hash::Hasher;
+use std::io::Result;
+
+use bytes::Bytes;
+use fastcdc::v2020::StreamCDC;
+use fastcdc::v2020::AVERAGE_MAX;
+use fastcdc::v2020::AVERAGE_MIN;
+use fastcdc::v2020::MAXIMUM_MAX;
+use fastcdc::v2020::MAXIMUM_MIN;
+use fastcdc::v2020::MINIMUM_MAX;
+use fastcdc::v2020::MINIMUM_MIN;
+use twox_hash::xxh3::Hash128;
+use twox_hash::xxh3::HasherExt;
+
+use crate::file::extent::Extent;
+use crate::Entry;
+use crate::File;
+use crate::Filesystem;
+
+/// Minimum, average and maximum size of each chunk, within the limits of
+/// [fastcdc::v2020] (sizes outside of them are clamped). Defaults to 16 KiB,
+/// 64 KiB and 256 KiB.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkSizes {
+    min: u32,
+    avg: u32,
+    max: u32,
+}
+
+impl Default for ChunkSizes {
+    fn default() -> Self {
+        Self {
+            min: 16 * 1024,
+            avg: 64 * 1024,
+            max: 256 * 1024,
+        }
+    }
+}
+
+impl ChunkSizes {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Clamped to [MINIMUM_MIN]..=[MINIMUM_MAX].
+    pub fn min(&mut self, size: u32) -> &mut Self {
+        self.min = size.clamp(MINIMUM_MIN, MINIMUM_MAX);
+        self
+    }
+
+    /// Clamped to [AVERAGE_MIN]..=[AVERAGE_MAX].
+    pub fn avg(&mut self, size: u32) -> &mut Self {
+        self.avg = size.clamp(AVERAGE_MIN, AVERAGE_MAX);
+        self
+    }
+
+    /// Clamped to [MAXIMUM_MIN]..=[MAXIMUM_MAX].
+    pub fn max(&mut self, size: u32) -> &mut Self {
+        self.max = size.clamp(MAXIMUM_MIN, MAXIMUM_MAX);
+        self
+    }
+}
+
+/// A single content-defined chunk of a [File].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Chunk {
+    offset: u64,
+    len: u64,
+    digest: u128,
+}
+
+impl Chunk {
+    /// Offset of this chunk in the file.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Non-cryptographic 128-bit hash of the data in this chunk.
+    pub fn digest(&self) -> u128 {
+        self.digest
+    }
+}
+
+/// Calls 'f' with each chunk of 'file' and its data.
+fn for_each_chunk(
+    file: &File,
+    sizes: &ChunkSizes,
+    mut f: impl FnMut(Chunk, Vec<u8>),
+) -> Result<()> {
+    for chunk in StreamCDC::new(file.reader(), sizes.min, sizes.avg, sizes.max) {
+        let chunk = chunk?;
+        let mut hasher = Hash128::with_seed(0);
+        hasher.write(&chunk.data);
+        f(
+            Chunk {
+                offset: chunk.offset,
+                len: chunk.length as u64,
+                digest: hasher.finish_ext(),
+            },
+            chunk.data,
+        );
+    }
+    Ok(())
+}
+
+impl File {
+    /// Split the contents into content-defined chunks, without changing the
+    /// extents of the file.
+    pub fn chunks(&self, sizes: &ChunkSizes) -> Result<Vec<Chunk>> {
+        let mut chunks = Vec::new();
+        for_each_chunk(self, sizes, |chunk, _| chunks.push(chunk))?;
+        Ok(chunks)
+    }
+
+    /// Replace the extents of this file with one [Extent::Owned] for each
+    /// content-defined chunk. Holes are filled in with zeroes.
+    /// The file is left unchanged if its data cannot be read.
+    pub fn rechunk(&mut self, sizes: &ChunkSizes) -> Result<Vec<Chunk>> {
+        let mut chunks = Vec::new();
+        let mut extents = Vec::new();
+        for_each_chunk(self, sizes, |chunk, data| {
+            extents.push((chunk.offset, Extent::Owned(Bytes::from(data))));
+            chunks.push(chunk);
+        })?;
+        self.extents = extents.into_iter().collect();
+        Ok(chunks)
+    }
+}
+
+/// Digests of all the distinct chunks of every regular file in a
+/// [Filesystem], see [Filesystem::chunk_index].
+#[derive(Debug, Clone, Default)]
+pub struct ChunkIndex {
+    /// Length of each distinct chunk
+    chunks: HashMap<u128, u64>,
+    total_bytes: u64,
+}
+
+impl ChunkIndex {
+    /// Size of all the file data, counting hardlinked files once.
+    pub fn total_bytes(&self) -> u64 {
+        self.total_bytes
+    }
+
+    /// Size of the file data if every chunk was only stored once.
+    pub fn unique_bytes(&self) -> u64 {
+        self.chunks.values().sum()
+    }
+
+    /// Size of the distinct chunks that are also in 'base', which is how
+    /// much of this filesystem could be reused from 'base'.
+    pub fn reused_bytes(&self, base: &Self) -> u64 {
+        self.chunks
+            .iter()
+            .filter(|(digest, _)| base.chunks.contains_key(digest))
+            .map(|(_, len)| len)
+            .sum()
+    }
+}
+
+impl Filesystem {
+    /// Chunk every regular file with [File::chunks].
+    pub fn chunk_index(&self, sizes: &ChunkSizes) -> Result<ChunkIndex> {
+        let mut index = ChunkIndex::default();
+        for entry in self.inodes.values() {
+            if let Entry::File(f) = entry.as_ref() {
+                for_each_chunk(f, sizes, |chunk, _| {
+                    index.total_bytes += chunk.len;
+                    index.chunks.insert(chunk.digest, chunk.len);
+                })?;
+            }
+        }
+        Ok(index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    fn data(seed: u64, len: usize) -> Vec<u8> {
+        // xorshift, so that there are content-defined boundaries to find
+        let mut x = seed;
+        (0..len)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 7;
+                x ^= x << 17;
+                x as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn chunk_index() {
+        let sizes = *ChunkSizes::new().min(256).avg(1024).max(4096);
+        let original = data(1, 64 * 1024);
+        let mut modified = data(2, 100);
+        modified.extend_from_slice(&original);
+
+        let mut base = Filesystem::new();
+        base.insert("f", File::builder().contents(original.clone()).build());
+        let mut next = Filesystem::new();
+        next.insert("f", File::builder().contents(modified.clone()).build());
+        next.insert("copy", File::builder().contents(modified.clone()).build());
+
+        let (base, next) = (
+            base.chunk_index(&sizes).unwrap(),
+            next.chunk_index(&sizes).unwrap(),
+        );
+        assert_eq!(2 * modified.len() as u64, next.total_bytes());
+        assert_eq!(modified.len() as u64, next.unique_bytes());
+        // only the chunks around the inserted prefix are new
+        let reused = next.reused_bytes(&base);
+        assert!(reused < original.len() as u64, "{reused}");
+        assert!(reused > original.len() as u64 * 3 / 4, "{reused}");
+
+        let mut f = File::builder().contents(modified.clone()).build();
+        let chunks = f.rechunk(&sizes).unwrap();
+        assert_eq!(modified, f.to_bytes().unwrap().as_ref());
+        assert_eq!(chunks.len(), f.extents().count());
+        assert_eq!(chunks, f.chunks(&sizes).unwrap());
+
+        // sizes out of range are clamped instead of panicking in fastcdc
+        let clamped = *ChunkSizes::new().min(0).avg(0).max(u32::MAX);
+        assert_eq!(
+            ChunkSizes {
+                min: MINIMUM_MIN,
+                avg: AVERAGE_MIN,
+                max: MAXIMUM_MAX,
+            },
+            clamped
+        );
+        assert!(!f.chunks(&clamped).unwrap().is_empty());
+    }
+}
@@ -1,9 +1,32 @@
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::hash::Hash;
+use std::io::Error;
+use std::io::ErrorKind;
+use std::io::Result;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::SystemTime;
+
 use bitflags::bitflags;
+use bytes::Bytes;
+use globset::GlobBuilder;
+use globset::GlobMatcher;
+use nix::sys::stat::Mode;
+
+use crate::BytesPath;
+use crate::Filesystem;
+use crate::Gid;
+use crate::Uid;
 
 bitflags! {
     /// Some attributes are expected to be dynamic or unsupported for certain
     /// formats, so we need a way to exclude them from comparisons instead of
     /// always demanding full equality.
+    #[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
     pub struct Fields: u32 {
         /// Complete set of paths in two filesystems must be equal
         const PATH      = 0b1;
@@ -23,8 +46,13 @@ bitflags! {
         const OWNER     = 0b10000000;
         /// Device number (st_rdev)
         const RDEV      = 0b100000000;
+        /// Apparent size (st_size) of regular files and symlinks, which can
+        /// be compared even when the data itself is not available.
+        const SIZE      = 0b1000000000;
+        /// Number of hardlinks (st_nlink) to each path
+        const NLINK     = 0b10000000000;
         /// Metadata accessible with stat(2)
-        const STAT      = Self::TYPE.bits | Self::TIME.bits | Self::MODE.bits | Self::OWNER.bits;
+        const STAT      = Self::TYPE.bits | Self::TIME.bits | Self::MODE.bits | Self::OWNER.bits | Self::SIZE.bits;
     }
 }
 
@@ -36,6 +64,437 @@ impl Fields {
     }
 }
 
+/// Choose which [Fields] are compared depending on the path, for example to
+/// ignore TIME everywhere but also DATA under `var/log`. Paths are matched
+/// against globs relative to the root of the filesystem, and when multiple
+/// rules match the same path the one that was added last wins.
+#[derive(Debug, Clone)]
+pub struct Rules {
+    default: Fields,
+    rules: Vec<(Matcher, Fields)>,
+    pub(crate) precision: Precision,
+}
+
+/// How strictly values are compared for the fields where that is a choice.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Precision {
+    pub(crate) data: DataComparison,
+    pub(crate) time: TimeComparison,
+    /// Only these bits are compared for [Fields::MODE]
+    pub(crate) mode_mask: Mode,
+    /// Names for the left and right side, to compare [Fields::OWNER] by name
+    pub(crate) owners: Option<Arc<(IdNames, IdNames)>>,
+    /// xattr names or namespaces that are left out of [Fields::XATTR]
+    pub(crate) ignored_xattrs: Vec<Bytes>,
+}
+
+impl Default for Precision {
+    fn default() -> Self {
+        Self {
+            data: DataComparison::default(),
+            time: TimeComparison::default(),
+            mode_mask: Mode::all(),
+            owners: None,
+            ignored_xattrs: Vec::new(),
+        }
+    }
+}
+
+impl Precision {
+    pub(crate) fn same_mode(&self, left: Mode, right: Mode) -> bool {
+        left & self.mode_mask == right & self.mode_mask
+    }
+
+    pub(crate) fn same_xattrs(
+        &self,
+        left: &BTreeMap<Bytes, Bytes>,
+        right: &BTreeMap<Bytes, Bytes>,
+    ) -> bool {
+        if self.ignored_xattrs.is_empty() {
+            return left == right;
+        }
+        let compared = |(name, _): &(&Bytes, &Bytes)| !self.is_xattr_ignored(name);
+        left.iter()
+            .filter(compared)
+            .eq(right.iter().filter(compared))
+    }
+
+    fn is_xattr_ignored(&self, name: &[u8]) -> bool {
+        self.ignored_xattrs.iter().any(|ignored| {
+            name.strip_prefix(ignored.as_ref()).is_some_and(|rest| {
+                rest.is_empty() || ignored.ends_with(b".") || rest.starts_with(b".")
+            })
+        })
+    }
+
+    pub(crate) fn same_owner(&self, left: (Uid, Gid), right: (Uid, Gid)) -> bool {
+        match self.owners.as_deref() {
+            None => left == right,
+            Some((l, r)) => {
+                same_id(&l.users, left.0, &r.users, right.0)
+                    && same_id(&l.groups, left.1, &r.groups, right.1)
+            }
+        }
+    }
+}
+
+/// Ids are equal if they map to the same name, or are numerically equal if
+/// either of them does not have a name.
+fn same_id<I>(left: &HashMap<I, String>, l: I, right: &HashMap<I, String>, r: I) -> bool
+where
+    I: Eq + Hash,
+{
+    match (left.get(&l), right.get(&r)) {
+        (Some(l), Some(r)) => l == r,
+        _ => l == r,
+    }
+}
+
+/// User and group names, used to compare [Fields::OWNER] by name instead of
+/// by numeric id (see [Rules::owner_names]). Images built on different hosts
+/// often assign different ids to the same service accounts.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IdNames {
+    users: HashMap<Uid, String>,
+    groups: HashMap<Gid, String>,
+}
+
+impl IdNames {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn user(&mut self, uid: Uid, name: impl Into<String>) -> &mut Self {
+        self.users.insert(uid, name.into());
+        self
+    }
+
+    pub fn group(&mut self, gid: Gid, name: impl Into<String>) -> &mut Self {
+        self.groups.insert(gid, name.into());
+        self
+    }
+
+    /// Parse the contents of passwd(5) and group(5) databases.
+    pub fn parse(passwd: &str, group: &str) -> Result<Self> {
+        let mut names = Self::new();
+        for (name, id) in parse_db(passwd)? {
+            names.user(Uid::from_raw(id), name);
+        }
+        for (name, id) in parse_db(group)? {
+            names.group(Gid::from_raw(id), name);
+        }
+        Ok(names)
+    }
+
+    /// Parse `etc/passwd` and `etc/group` from 'fs'.
+    pub fn from_fs(fs: &Filesystem) -> Result<Self> {
+        let read = |path| -> Result<String> {
+            String::from_utf8(fs.get_file(path)?.to_bytes()?.to_vec())
+                .map_err(|e| Error::new(ErrorKind::InvalidData, e))
+        };
+        Self::parse(&read("etc/passwd")?, &read("etc/group")?)
+    }
+}
+
+/// Name and id (the first and third fields) of each line in a passwd(5) or
+/// group(5) database.
+fn parse_db(db: &str) -> Result<Vec<(&str, u32)>> {
+    db.lines()
+        .filter(|line| !line.trim().is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            let mut fields = line.split(':');
+            let name = fields.next();
+            let id = fields.nth(1).and_then(|id| id.parse().ok());
+            match (name, id) {
+                (Some(name), Some(id)) => Ok((name, id)),
+                _ => Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("invalid database entry '{line}'"),
+                )),
+            }
+        })
+        .collect()
+}
+
+/// How [Fields::DATA] of regular files is compared.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum DataComparison {
+    /// Compare every byte, streaming through both files.
+    #[default]
+    Exact,
+    /// Compare a 128-bit non-cryptographic hash of each file, which is
+    /// cached until the file is modified. This is much faster when the same
+    /// files are compared repeatedly, but could in theory miss a difference.
+    Digest,
+}
+
+/// How [Fields::TIME] is compared. Formats store times with different
+/// precision (for example tar only has whole seconds), so exact equality is
+/// often too strict.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum TimeComparison {
+    #[default]
+    Exact,
+    /// Times are equal if they are at most this far apart.
+    Tolerance(Duration),
+    /// Only whole seconds are compared, any fractional part is ignored.
+    Seconds,
+}
+
+impl TimeComparison {
+    /// Whether 'a' and 'b' are considered equal under this mode.
+    pub fn same_time(&self, a: SystemTime, b: SystemTime) -> bool {
+        match self {
+            Self::Exact => a == b,
+            Self::Tolerance(tolerance) => {
+                let diff = a.duration_since(b).unwrap_or_else(|e| e.duration());
+                diff <= *tolerance
+            }
+            Self::Seconds => {
+                let secs = |t: SystemTime| match t.duration_since(SystemTime::UNIX_EPOCH) {
+                    Ok(d) => d.as_secs() as i64,
+                    // round towards negative infinity, like st_mtime does
+                    Err(e) => -(e.duration().as_secs_f64().ceil() as i64),
+                };
+                secs(a) == secs(b)
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+enum Matcher {
+    Glob(GlobMatcher),
+    Predicate(Arc<dyn Fn(&Path) -> bool + Send + Sync>),
+}
+
+impl Matcher {
+    fn is_match(&self, path: &Path) -> bool {
+        match self {
+            Self::Glob(glob) => glob.is_match(path),
+            Self::Predicate(predicate) => predicate(path),
+        }
+    }
+}
+
+impl Debug for Matcher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Glob(glob) => write!(f, "{:?}", glob.glob().glob()),
+            Self::Predicate(_) => f.write_str("<predicate>"),
+        }
+    }
+}
+
+impl Rules {
+    /// Compare 'default' for any path that does not match a more specific
+    /// rule.
+    pub fn new(default: Fields) -> Self {
+        Self {
+            default,
+            rules: Vec::new(),
+            precision: Precision::default(),
+        }
+    }
+
+    /// Choose how the contents of regular files are compared. Defaults to
+    /// [DataComparison::Exact].
+    pub fn data_comparison(&mut self, mode: DataComparison) -> &mut Self {
+        self.precision.data = mode;
+        self
+    }
+
+    /// Only compare the bits of [Fields::MODE] that are in 'mask', for
+    /// example to ignore a setgid bit that was inherited from the build
+    /// directory with `Mode::all() - Mode::S_ISGID`.
+    pub fn mode_mask(&mut self, mask: Mode) -> &mut Self {
+        self.precision.mode_mask = mask;
+        self
+    }
+
+    /// Leave the xattr 'name' out of [Fields::XATTR]. This can also be a
+    /// namespace, so `security` ignores `security.selinux` and every other
+    /// xattr that starts with `security.`, and so does `security.`.
+    pub fn ignore_xattr(&mut self, name: impl Into<Bytes>) -> &mut Self {
+        self.precision.ignored_xattrs.push(name.into());
+        self
+    }
+
+    /// Compare [Fields::OWNER] by user and group name, looking up the ids of
+    /// the left side in 'left' and the right side in 'right'. Ids that do not
+    /// have a name on both sides are still compared numerically.
+    pub fn owner_names(&mut self, left: IdNames, right: IdNames) -> &mut Self {
+        self.precision.owners = Some(Arc::new((left, right)));
+        self
+    }
+
+    /// Choose how times are compared. Defaults to [TimeComparison::Exact].
+    pub fn time_comparison(&mut self, mode: TimeComparison) -> &mut Self {
+        self.precision.time = mode;
+        self
+    }
+
+    /// Compare only 'fields' for paths matching 'glob'. `*` does not match
+    /// across `/`, use `**` for that. A leading `/` is ignored.
+    pub fn rule(&mut self, glob: &str, fields: Fields) -> Result<&mut Self> {
+        let glob = glob.strip_prefix('/').unwrap_or(glob);
+        let matcher = GlobBuilder::new(glob)
+            .literal_separator(true)
+            .build()
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?
+            .compile_matcher();
+        self.rules.push((Matcher::Glob(matcher), fields));
+        Ok(self)
+    }
+
+    /// Compare only 'fields' for paths where 'predicate' returns true.
+    pub fn rule_if<F>(&mut self, predicate: F, fields: Fields) -> &mut Self
+    where
+        F: Fn(&Path) -> bool + Send + Sync + 'static,
+    {
+        self.rules
+            .push((Matcher::Predicate(Arc::new(predicate)), fields));
+        self
+    }
+
+    /// Completely ignore paths matching 'glob', including whether they exist
+    /// on both sides at all. This is the same as a rule with
+    /// [Fields::empty], so it can still be overridden by a later rule.
+    pub fn ignore(&mut self, glob: &str) -> Result<&mut Self> {
+        self.rule(glob, Fields::empty())
+    }
+
+    /// Completely ignore paths where 'predicate' returns true.
+    pub fn ignore_if<F>(&mut self, predicate: F) -> &mut Self
+    where
+        F: Fn(&Path) -> bool + Send + Sync + 'static,
+    {
+        self.rule_if(predicate, Fields::empty())
+    }
+
+    /// The fields that should be compared for 'path'.
+    pub fn fields(&self, path: &Path) -> Fields {
+        self.rules
+            .iter()
+            .rev()
+            .find(|(matcher, _)| matcher.is_match(path))
+            .map_or(self.default, |(_, fields)| *fields)
+    }
+}
+
+impl From<Fields> for Rules {
+    fn from(fields: Fields) -> Self {
+        Self::new(fields)
+    }
+}
+
+impl From<&Rules> for Rules {
+    fn from(rules: &Rules) -> Self {
+        rules.clone()
+    }
+}
+
+/// Detailed result of [Filesystem::compare].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Comparison {
+    pub(crate) changed: BTreeMap<BytesPath, Fields>,
+    pub(crate) added: BTreeSet<BytesPath>,
+    pub(crate) removed: BTreeSet<BytesPath>,
+}
+
+impl Comparison {
+    /// Paths that exist on both sides, with the selected fields that are
+    /// different there.
+    pub fn changed(&self) -> &BTreeMap<BytesPath, Fields> {
+        &self.changed
+    }
+
+    /// Paths that only exist on the right side.
+    pub fn added(&self) -> &BTreeSet<BytesPath> {
+        &self.added
+    }
+
+    /// Paths that only exist on the left side.
+    pub fn removed(&self) -> &BTreeSet<BytesPath> {
+        &self.removed
+    }
+
+    /// True if there are no differences at all.
+    pub fn is_empty(&self) -> bool {
+        self.changed.is_empty() && self.added.is_empty() && self.removed.is_empty()
+    }
+
+    /// Every field that is different anywhere, including [Fields::PATH] if
+    /// any path was added or removed.
+    pub fn differences(&self) -> Fields {
+        let mut f = self
+            .changed
+            .values()
+            .fold(Fields::empty(), |acc, f| acc | *f);
+        if !self.added.is_empty() || !self.removed.is_empty() {
+            f |= Fields::PATH;
+        }
+        f
+    }
+}
+
+impl Filesystem {
+    /// Like [ApproxEq::approx_eq], but the fields that must be equal can be
+    /// different for every path. A path that only exists on one side is only
+    /// a difference if its rule includes [Fields::PATH].
+    pub fn approx_eq_with(&self, other: &Filesystem, rules: &Rules) -> bool {
+        self.compare(other, rules).is_empty()
+    }
+
+    /// Like [ApproxEq::cmp], but fields that are not selected by 'rules' for
+    /// a path are always considered equal there.
+    pub fn cmp_with(&self, other: &Filesystem, rules: &Rules) -> Fields {
+        self.compare(other, rules).differences().complement()
+    }
+
+    /// Compare every path in this filesystem against 'other', reporting
+    /// exactly which fields are different where. 'rules' can be a single
+    /// [Fields] that applies to the entire filesystem.
+    pub fn compare(&self, other: &Filesystem, rules: impl Into<Rules>) -> Comparison {
+        let rules = rules.into();
+        let mut comparison = Comparison::default();
+        for (path, entry) in self {
+            let fields = rules.fields(path);
+            match other.get(path) {
+                Ok(other_entry) => {
+                    let mut cmp = entry.cmp_with_precision(other_entry, &rules.precision);
+                    if self.nlink(path).ok() != other.nlink(path).ok() {
+                        cmp.remove(Fields::NLINK);
+                    }
+                    let different = fields - Fields::PATH - cmp;
+                    if !different.is_empty() {
+                        comparison.changed.insert(path.into(), different);
+                    }
+                }
+                Err(_) => {
+                    if fields.contains(Fields::PATH) {
+                        comparison.removed.insert(path.into());
+                    }
+                }
+            }
+        }
+        for (path, _) in other {
+            if self.get(path).is_err() && rules.fields(path).contains(Fields::PATH) {
+                comparison.added.insert(path.into());
+            }
+        }
+        comparison
+    }
+
+    /// Whether every path in 'other' also exists in this filesystem and
+    /// matches on the selected fields, allowing this filesystem to have extra
+    /// paths. Paths that are ignored by 'rules' do not need to exist.
+    pub fn is_superset_of(&self, other: &Filesystem, rules: impl Into<Rules>) -> bool {
+        let comparison = other.compare(self, rules);
+        comparison.changed.is_empty() && comparison.removed.is_empty()
+    }
+}
+
 pub trait ApproxEq<O = Self>: PartialEq<O> {
     /// Return all the flags for fields that are equal. This will be ANDed
     /// together with other comparisons, so should return [Fields::all] with any
@@ -62,31 +521,219 @@ impl<T: ApproxEq> ApproxEq for &'_ T {
     }
 }
 
-#[cfg(test)]
+/// Assert that two [Filesystem]s are equal in the given [Fields] (or
+/// [Rules]), panicking with a diff of the two filesystems if they are not.
+///
+/// ```
+/// use filesystem_in_a_file::assert_approx_eq;
+/// use filesystem_in_a_file::cmp::Fields;
+/// use filesystem_in_a_file::fs;
+///
+/// let left = fs! { "motd": file("hello\n", 0o644) };
+/// let right = fs! { "motd": file("hello\n", 0o600) };
+/// assert_approx_eq!(left, right, Fields::all() - Fields::MODE);
+/// assert_approx_eq!(left, right, Fields::DATA, "motd should be unchanged");
+/// ```
+#[macro_export]
 macro_rules! assert_approx_eq {
-    ($left:expr, $right:expr, $fields:expr) => {
-        let cmp = crate::cmp::ApproxEq::cmp(&$left, &$right);
-        let cmp = cmp | $fields.complement();
-        if !cmp.is_all() {
-            panic!(
-                "These attributes are causing the test failure: {:?}\nThere may be other differences that appear here, that is ok.\n{}",
-                cmp.complement(),
-                similar_asserts::SimpleDiff::from_str(
-                    &format!("{:#?}", $left),
-                    &format!("{:#?}", $right),
-                    stringify!($left),
-                    stringify!($right),
-                )
-            );
-        }
+    ($left:expr, $right:expr, $fields:expr $(,)?) => {
+        $crate::cmp::assert_approx_eq_impl(
+            ::std::borrow::Borrow::borrow(&$left),
+            ::std::borrow::Borrow::borrow(&$right),
+            $fields,
+            stringify!($left),
+            stringify!($right),
+            None,
+        )
     };
     ($left:expr, $right:expr, $fields:expr, $($arg:tt)+) => {
-        assert!(
-            crate::cmp::ApproxEq::approx_eq(&$left, &$right, $fields),
-            $($arg)*
-        );
+        $crate::cmp::assert_approx_eq_impl(
+            ::std::borrow::Borrow::borrow(&$left),
+            ::std::borrow::Borrow::borrow(&$right),
+            $fields,
+            stringify!($left),
+            stringify!($right),
+            Some(format_args!($($arg)+)),
+        )
     };
 }
 
+pub use crate::assert_approx_eq;
+
+#[doc(hidden)]
+#[track_caller]
+pub fn assert_approx_eq_impl(
+    left: &Filesystem,
+    right: &Filesystem,
+    rules: impl Into<Rules>,
+    left_expr: &str,
+    right_expr: &str,
+    msg: Option<std::fmt::Arguments>,
+) {
+    let rules = rules.into();
+    let cmp = left.cmp_with(right, &rules);
+    if cmp.is_all() {
+        return;
+    }
+    let msg = msg.map(|m| format!(": {m}")).unwrap_or_default();
+    #[cfg(feature = "diff")]
+    let diff = crate::diff::FilesystemDiff::diff(left, right, &rules).to_string();
+    #[cfg(not(feature = "diff"))]
+    let diff = format!("{left_expr}: {left:#?}\n{right_expr}: {right:#?}");
+    panic!(
+        "assertion `{left_expr} ≈ {right_expr}` failed{msg}\nFields that differ: {:?}\n{diff}",
+        cmp.complement()
+    );
+}
+
 #[cfg(test)]
-pub(crate) use assert_approx_eq;
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::fs;
+
+    #[test]
+    #[should_panic(expected = "Fields that differ: MODE")]
+    fn assert_approx_eq() {
+        let left = fs! { "motd": file("hello\n", 0o644) };
+        let right = fs! { "motd": file("hello\n", 0o600) };
+        assert_approx_eq!(left, &right, Fields::all() - Fields::MODE);
+        assert_approx_eq!(&left, right, Fields::all());
+    }
+
+    #[test]
+    fn compare() {
+        let left = fs! {
+            "etc": {
+                "motd": file("hello\n", 0o644),
+                "issue": file("welcome\n"),
+            },
+            "old": file(""),
+        };
+        let mut right = left.clone();
+        right
+            .chmod("etc/motd", Mode::from_bits_truncate(0o600))
+            .unwrap();
+        right
+            .chown("etc/issue", Uid::from_raw(1), Gid::from_raw(1))
+            .unwrap();
+        right.write("etc/issue", 0, "W").unwrap();
+        right.unlink("old").unwrap();
+        right.insert("new", crate::File::default());
+
+        let comparison = left.compare(&right, Fields::all() - Fields::EXTENTS);
+        assert_eq!(
+            BTreeMap::from([
+                (BytesPath::from("etc/issue"), Fields::OWNER | Fields::DATA),
+                (BytesPath::from("etc/motd"), Fields::MODE),
+            ]),
+            *comparison.changed()
+        );
+        assert_eq!(BTreeSet::from(["new".into()]), *comparison.added());
+        assert_eq!(BTreeSet::from(["old".into()]), *comparison.removed());
+        assert_eq!(
+            Fields::PATH | Fields::MODE | Fields::OWNER | Fields::DATA,
+            comparison.differences()
+        );
+        assert!(left.compare(&left.clone(), Fields::all()).is_empty());
+    }
+
+    #[test]
+    fn time_comparison() {
+        let mut left = fs! { "motd": file("hello\n") };
+        let mut right = left.clone();
+        let t = |ms| SystemTime::UNIX_EPOCH + Duration::from_millis(ms);
+        left.set_times("motd", t(1000), t(1000), t(1000)).unwrap();
+        right.set_times("motd", t(1500), t(1500), t(2500)).unwrap();
+
+        let mut rules = Rules::new(Fields::all());
+        assert_eq!(Fields::all() - Fields::TIME, left.cmp_with(&right, &rules));
+        rules.time_comparison(TimeComparison::Seconds);
+        assert_eq!(Fields::all() - Fields::TIME, left.cmp_with(&right, &rules));
+        rules.time_comparison(TimeComparison::Tolerance(Duration::from_secs(2)));
+        assert_eq!(Fields::all(), left.cmp_with(&right, &rules));
+        assert!(left.compare(&right, &rules).is_empty());
+    }
+
+    #[test]
+    fn owner_names() {
+        let mut left = fs! { "srv": file("") };
+        left.chown("srv", Uid::from_raw(100), Gid::from_raw(100))
+            .unwrap();
+        let mut right = left.clone();
+        right
+            .chown("srv", Uid::from_raw(200), Gid::from_raw(100))
+            .unwrap();
+        let left_names = IdNames::parse(
+            "root:x:0:0::/root:/bin/sh\nsvc:x:100:100::/:/bin/false\n",
+            "svc:x:100:\n",
+        )
+        .unwrap();
+        let mut right_names = IdNames::new();
+        right_names
+            .user(Uid::from_raw(200), "svc")
+            .group(Gid::from_raw(100), "svc");
+
+        let mut rules = Rules::new(Fields::all());
+        assert_eq!(Fields::all() - Fields::OWNER, left.cmp_with(&right, &rules));
+        rules.owner_names(left_names.clone(), right_names.clone());
+        assert_eq!(Fields::all(), left.cmp_with(&right, &rules));
+        right_names = IdNames::new();
+        right_names.user(Uid::from_raw(200), "nobody");
+        rules.owner_names(left_names, right_names);
+        assert_eq!(Fields::all() - Fields::OWNER, left.cmp_with(&right, &rules));
+        assert!(IdNames::parse("nope\n", "").is_err());
+    }
+
+    #[test]
+    fn mode_mask() {
+        let left = fs! { "srv": dir(0o2755) {} };
+        let right = fs! { "srv": dir(0o755) {} };
+        let mut rules = Rules::new(Fields::all());
+        assert_eq!(Fields::all() - Fields::MODE, left.cmp_with(&right, &rules));
+        rules.mode_mask(Mode::all() - Mode::S_ISGID);
+        assert_eq!(Fields::all(), left.cmp_with(&right, &rules));
+        let right = fs! { "srv": dir(0o2700) {} };
+        assert_eq!(Fields::all() - Fields::MODE, left.cmp_with(&right, &rules));
+    }
+
+    #[test]
+    fn is_superset_of() {
+        let image = fs! {
+            "etc": {
+                "motd": file("hello\n"),
+                "issue": file("welcome\n"),
+            },
+        };
+        let expected = fs! { "etc": { "motd": file("hello\n") } };
+        assert!(image.is_superset_of(&expected, Fields::all()));
+        assert!(!expected.is_superset_of(&image, Fields::all()));
+        let expected = fs! { "etc": { "motd": file("hello\n", 0o600) } };
+        assert!(!image.is_superset_of(&expected, Fields::all()));
+        assert!(image.is_superset_of(&expected, Fields::all() - Fields::MODE));
+    }
+
+    #[test]
+    fn ignore_xattr() {
+        let left = fs! {
+            "motd": file("hello\n") ["user.a" = "a", "security.selinux" = "etc_t"],
+        };
+        let right = fs! {
+            "motd": file("hello\n") ["user.a" = "a", "security.selinux" = "user_t", "securityx" = "x"],
+        };
+        let mut rules = Rules::new(Fields::all());
+        rules.ignore_xattr("security");
+        assert_eq!(Fields::all() - Fields::XATTR, left.cmp_with(&right, &rules));
+        rules.ignore_xattr("securityx");
+        assert_eq!(Fields::all(), left.cmp_with(&right, &rules));
+
+        // the namespace can also be written with a trailing dot
+        let mut rules = Rules::new(Fields::all());
+        rules.ignore_xattr("security.").ignore_xattr("securityx");
+        assert_eq!(Fields::all(), left.cmp_with(&right, &rules));
+        let mut rules = Rules::new(Fields::all());
+        rules.ignore_xattr("security.");
+        assert_eq!(Fields::all() - Fields::XATTR, left.cmp_with(&right, &rules));
+    }
+}
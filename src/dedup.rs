@@ -0,0 +1,294 @@
+//! Find regular files that are exact duplicates of each other and optionally
+//! turn them into hardlinks of a single inode, or share identical extents
+//! between files like a reflink-based dedup would.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::hash::Hasher;
+use std::io::Result;
+use std::sync::Arc;
+
+use bytes::Bytes;
+
+use crate::BytesPath;
+use crate::Entry;
+use crate::Filesystem;
+use crate::InodeKey;
+
+/// A set of regular files with identical contents and metadata that are not
+/// (yet) hardlinks of each other.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DuplicateGroup {
+    paths: Vec<BytesPath>,
+    inodes: usize,
+    size: u64,
+}
+
+impl DuplicateGroup {
+    /// All the paths that share this content, in sorted order. When
+    /// deduplicating, the inode of the first path is kept.
+    pub fn paths(&self) -> &[BytesPath] {
+        &self.paths
+    }
+
+    /// Size of a single copy of the file.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Bytes that would be saved by storing only one copy.
+    pub fn wasted_bytes(&self) -> u64 {
+        self.size * (self.inodes as u64 - 1)
+    }
+}
+
+/// What [Filesystem::dedup_extents] changed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ExtentDedup {
+    extents: usize,
+    bytes_saved: u64,
+}
+
+impl ExtentDedup {
+    /// Number of extents that now share the data of another extent.
+    pub fn extents(&self) -> usize {
+        self.extents
+    }
+
+    /// Bytes that are no longer stored separately.
+    pub fn bytes_saved(&self) -> u64 {
+        self.bytes_saved
+    }
+}
+
+impl Filesystem {
+    /// Report all the groups of regular files that have identical contents and
+    /// metadata, without modifying anything.
+    pub fn duplicates(&self) -> Result<Vec<DuplicateGroup>> {
+        Ok(self
+            .duplicate_inodes()?
+            .into_iter()
+            .map(|(inodes, paths)| DuplicateGroup {
+                size: self.inodes[inodes[0]].size(),
+                inodes: inodes.len(),
+                paths,
+            })
+            .collect())
+    }
+
+    /// Replace every group of duplicate regular files with hardlinks to a
+    /// single inode. Returns the groups that were merged.
+    pub fn dedup(&mut self) -> Result<Vec<DuplicateGroup>> {
+        let groups = self.duplicates()?;
+        for group in &groups {
+            let keep = self.paths[&group.paths[0]];
+            for path in &group.paths[1..] {
+                if self.paths[path] != keep {
+                    self.unlink(path).expect("path must exist");
+                    self.link(&group.paths[0], path.clone())
+                        .expect("regular files can always be linked");
+                }
+            }
+        }
+        Ok(groups)
+    }
+
+    /// Find extents (in any regular file) with identical data, and replace
+    /// all but the first of them (in path order) with an
+    /// [Extent::Cloned](crate::file::extent::Extent::Cloned) that shares the
+    /// data of the first one, like `FIDEDUPERANGE` would.
+    /// Extents are only compared as a whole, they are not split up to find
+    /// smaller identical ranges.
+    pub fn dedup_extents(&mut self) -> Result<ExtentDedup> {
+        let mut seen: HashMap<Bytes, (InodeKey, u64)> = HashMap::new();
+        let mut replacements = Vec::new();
+        let mut report = ExtentDedup::default();
+        let mut visited = HashSet::new();
+        for key in self.paths.values() {
+            if !visited.insert(*key) {
+                continue;
+            }
+            let Entry::File(f) = self.inodes[*key].as_ref() else {
+                continue;
+            };
+            for (start, ext) in &f.extents {
+                if ext.is_hole() || ext.is_empty() {
+                    continue;
+                }
+                let data = ext.bytes()?;
+                match seen.get_key_value(&data) {
+                    None => {
+                        seen.insert(data, (*key, *start));
+                    }
+                    // already sharing the same buffer
+                    Some((first, _)) if first.as_ptr() == data.as_ptr() => (),
+                    Some((_, (src_key, src_start))) => {
+                        let Entry::File(src) = self.inodes[*src_key].as_ref() else {
+                            unreachable!("only files are in 'seen'");
+                        };
+                        let mut cloned = src.clone_range(*src_start..*src_start + ext.len())?;
+                        debug_assert_eq!(1, cloned.len());
+                        replacements.push((*key, *start, cloned.remove(0)));
+                        report.extents += 1;
+                        report.bytes_saved += ext.len();
+                    }
+                }
+            }
+        }
+        for (key, start, ext) in replacements {
+            if let Entry::File(f) = Arc::make_mut(&mut self.inodes[key]) {
+                f.extents.insert(start, ext);
+            }
+        }
+        Ok(report)
+    }
+
+    /// Groups of distinct inodes that are duplicates, alongside all the paths
+    /// that refer to any of them.
+    fn duplicate_inodes(&self) -> Result<Vec<(Vec<InodeKey>, Vec<BytesPath>)>> {
+        let mut paths: HashMap<InodeKey, Vec<&BytesPath>> = HashMap::new();
+        for (path, key) in &self.paths {
+            paths.entry(*key).or_default().push(path);
+        }
+        // cheaply bucket files by size and a hash of their contents, then
+        // compare the full contents within each bucket
+        let mut buckets: BTreeMap<(u64, u64), Vec<InodeKey>> = BTreeMap::new();
+        for (key, entry) in &self.inodes {
+            if let Entry::File(f) = entry.as_ref() {
+                let mut hasher = DefaultHasher::new();
+                hasher.write(&f.to_bytes()?);
+                buckets
+                    .entry((f.len(), hasher.finish()))
+                    .or_default()
+                    .push(key);
+            }
+        }
+        let mut groups = Vec::new();
+        for (_, mut keys) in buckets {
+            while let Some(first) = keys.pop() {
+                let (mut same, mut different) = (Vec::new(), Vec::new());
+                for k in keys {
+                    match same_file(&self.inodes[k], &self.inodes[first])? {
+                        true => same.push(k),
+                        false => different.push(k),
+                    }
+                }
+                keys = different;
+                if !same.is_empty() {
+                    let mut inodes = vec![first];
+                    inodes.extend(same);
+                    let mut group_paths: Vec<BytesPath> = inodes
+                        .iter()
+                        .flat_map(|k| paths[k].iter().map(|p| (*p).clone()))
+                        .collect();
+                    group_paths.sort();
+                    groups.push((inodes, group_paths));
+                }
+            }
+        }
+        groups.sort_by(|a, b| a.1.cmp(&b.1));
+        Ok(groups)
+    }
+}
+
+/// Files are duplicates if their contents and metadata are equal, regardless
+/// of how the data is split up into extents.
+fn same_file(a: &Entry, b: &Entry) -> Result<bool> {
+    match (a, b) {
+        (Entry::File(a), Entry::File(b)) => {
+            Ok(a.metadata == b.metadata && a.to_bytes()? == b.to_bytes()?)
+        }
+        _ => Ok(false),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::cmp::ApproxEq;
+    use crate::cmp::Fields;
+    use crate::file::extent::Extent;
+    use crate::tests::demo_fs;
+    use crate::File;
+
+    #[test]
+    fn dedup() {
+        let mut fs = demo_fs();
+        let lorem = fs.get("testdata/lorem.txt").unwrap().clone();
+        fs.insert("testdata/copy1.txt", lorem.clone());
+        // same contents, but split into multiple extents
+        let mut split = lorem.clone();
+        if let Entry::File(f) = &mut split {
+            f.truncate(5);
+            f.writer().write(" ipsum\n");
+        }
+        fs.insert("testdata/dir/copy2.txt", split);
+        fs.link("testdata/copy1.txt", "testdata/copy1.hardlink")
+            .unwrap();
+        let mut different = fs.get("testdata/lorem.txt").unwrap().clone();
+        different.chmod(nix::sys::stat::Mode::from_bits_truncate(0o600));
+        fs.insert("testdata/different.txt", different);
+
+        let dupes = fs.duplicates().unwrap();
+        assert_eq!(1, dupes.len(), "{dupes:?}");
+        assert_eq!(
+            vec![
+                BytesPath::from("testdata/copy1.hardlink"),
+                BytesPath::from("testdata/copy1.txt"),
+                BytesPath::from("testdata/dir/copy2.txt"),
+                BytesPath::from("testdata/lorem.txt"),
+            ],
+            dupes[0].paths()
+        );
+        assert_eq!("Lorem ipsum\n".len() as u64 * 2, dupes[0].wasted_bytes());
+
+        let (len, inodes) = (fs.len(), fs.inode_count());
+        let before = fs.clone();
+        assert_eq!(dupes, fs.dedup().unwrap());
+        assert_eq!(len, fs.len());
+        assert_eq!(inodes - 2, fs.inode_count());
+        assert!(fs.duplicates().unwrap().is_empty());
+        // only the hardlink structure changed
+        assert_eq!(
+            Fields::all() - Fields::NLINK,
+            ApproxEq::cmp(&before, &fs) | Fields::EXTENTS
+        );
+    }
+
+    #[test]
+    fn dedup_extents() {
+        let mut fs = demo_fs();
+        // separate copies of the same data, not just clones of the same buffer
+        let lorem = fs
+            .get_file("testdata/lorem.txt")
+            .unwrap()
+            .to_bytes()
+            .unwrap()
+            .to_vec();
+        fs.insert(
+            "testdata/copy.txt",
+            File::builder().contents(lorem.clone()).build(),
+        );
+        let mut appended = File::builder().contents(lorem).build();
+        appended.writer().write("more\n");
+        fs.insert("testdata/appended.txt", appended);
+        let before = fs.clone();
+
+        let report = fs.dedup_extents().unwrap();
+        assert_eq!(2, report.extents());
+        assert_eq!("Lorem ipsum\n".len() as u64 * 2, report.bytes_saved());
+        // the first path keeps its data
+        let f = fs.get_file("testdata/appended.txt").unwrap();
+        assert!(matches!(f.extents[&0], Extent::Owned(_)));
+        for path in ["testdata/copy.txt", "testdata/lorem.txt"] {
+            let f = fs.get_file(path).unwrap();
+            assert!(matches!(f.extents[&0], Extent::Cloned(_)), "{f:?}");
+        }
+        assert_eq!(Fields::all(), ApproxEq::cmp(&before, &fs) | Fields::EXTENTS);
+        assert_eq!(ExtentDedup::default(), fs.dedup_extents().unwrap());
+    }
+}
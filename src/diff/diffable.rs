@@ -6,6 +6,7 @@ use std::os::unix::prelude::OsStrExt;
 
 use twox_hash::XxHash64;
 
+use super::DiffOptions;
 use crate::cmp::ApproxEq;
 use crate::entry::Entry;
 use crate::entry::Special;
@@ -17,21 +18,21 @@ pub trait Diffable<'a, const N: usize>: Sized + Debug + ApproxEq {
     /// Return a string representation of this object. Not directly used for
     /// comparison ([ApproxEq] will be used for that), but will be used to
     /// display the diff to the user.
-    fn to_diffable_sections(&'a self) -> [Cow<'a, str>; N];
+    fn to_diffable_sections(&'a self, options: &DiffOptions) -> [Cow<'a, str>; N];
 }
 
 impl<'a, T: Diffable<'a, N>, const N: usize> Diffable<'a, N> for &'_ T {
     const SECTIONS: [&'static str; N] = <T as Diffable<'a, N>>::SECTIONS;
 
-    fn to_diffable_sections(&'a self) -> [Cow<'a, str>; N] {
-        (**self).to_diffable_sections()
+    fn to_diffable_sections(&'a self, options: &DiffOptions) -> [Cow<'a, str>; N] {
+        (**self).to_diffable_sections(options)
     }
 }
 
 impl<'a> Diffable<'a, 3> for Entry {
     const SECTIONS: [&'static str; 3] = ["Type", "Metadata", "Contents"];
 
-    fn to_diffable_sections(&'a self) -> [Cow<'a, str>; 3] {
+    fn to_diffable_sections(&'a self, options: &DiffOptions) -> [Cow<'a, str>; 3] {
         [
             Cow::Borrowed(match self {
                 Self::File(_) => "File",
@@ -41,7 +42,7 @@ impl<'a> Diffable<'a, 3> for Entry {
             }),
             Cow::Owned(format!("{:#?}", self.metadata())),
             match self {
-                Self::File(x) => x.diffable_contents(),
+                Self::File(x) => x.diffable_contents(options.max_content_size),
                 Self::Directory(_) => Cow::Borrowed(""),
                 Self::Special(x) => Cow::Owned(x.diffable_contents()),
                 Self::Symlink(x) => Cow::Borrowed(x.diffable_contents()),
@@ -50,30 +51,71 @@ impl<'a> Diffable<'a, 3> for Entry {
     }
 }
 
+/// Binary files are shown as a hexdump of at most this many bytes.
+const MAX_HEXDUMP_LEN: usize = 1024 * 1024;
+
 impl File {
-    fn diffable_contents(&self) -> Cow<'_, str> {
-        let contents = self.to_bytes();
+    /// Files larger than 'max_size' are only shown as their size and a
+    /// digest, which is computed without loading all of the contents. Data
+    /// that cannot be read is shown as the error instead.
+    fn diffable_contents(&self, max_size: Option<u64>) -> Cow<'_, str> {
+        let unreadable = |e: std::io::Error| Cow::Owned(format!("cannot read contents: {e}\n"));
+        if max_size.is_some_and(|max| self.len() > max) {
+            return match self.data_digest() {
+                Ok(digest) => {
+                    Cow::Owned(format!("{} bytes, xxHash = {:032x}\n", self.len(), digest))
+                }
+                Err(e) => unreadable(e),
+            };
+        }
         match self.to_bytes() {
-            Cow::Borrowed(b) => match std::str::from_utf8(b) {
+            Ok(Cow::Borrowed(b)) => match std::str::from_utf8(b) {
                 Ok(contents) => Cow::Borrowed(contents),
-                Err(_) => {
-                    let mut hasher = XxHash64::with_seed(0);
-                    hasher.write(&contents);
-                    Cow::Owned(format!("binary data: xxHash = {}", hasher.finish()))
-                }
+                Err(_) => Cow::Owned(hexdump(b)),
             },
-            Cow::Owned(v) => match String::from_utf8(v) {
+            Ok(Cow::Owned(v)) => match String::from_utf8(v) {
                 Ok(contents) => Cow::Owned(contents),
-                Err(_) => {
-                    let mut hasher = XxHash64::with_seed(0);
-                    hasher.write(&contents);
-                    Cow::Owned(format!("binary data: xxHash = {}", hasher.finish()))
-                }
+                Err(e) => Cow::Owned(hexdump(e.as_bytes())),
             },
+            Err(e) => unreadable(e),
         }
     }
 }
 
+/// Like `hexdump -C`, so that a diff shows where two binary files diverge.
+/// The first line has a hash of all of 'data', since the hexdump stops after
+/// [MAX_HEXDUMP_LEN] bytes.
+fn hexdump(data: &[u8]) -> String {
+    let mut hasher = XxHash64::with_seed(0);
+    hasher.write(data);
+    let mut s = format!("binary data: xxHash = {}\n", hasher.finish());
+    for (i, line) in data.chunks(16).take(MAX_HEXDUMP_LEN / 16).enumerate() {
+        write!(s, "{:08x} ", i * 16).expect("infallible");
+        for j in 0..16 {
+            if j % 8 == 0 {
+                s.push(' ');
+            }
+            match line.get(j) {
+                Some(b) => write!(s, "{b:02x} ").expect("infallible"),
+                None => s.push_str("   "),
+            }
+        }
+        s.push_str(" |");
+        s.extend(
+            line.iter()
+                .map(|b| match b.is_ascii_graphic() || *b == b' ' {
+                    true => *b as char,
+                    false => '.',
+                }),
+        );
+        s.push_str("|\n");
+    }
+    if data.len() > MAX_HEXDUMP_LEN {
+        writeln!(s, "... {} more bytes", data.len() - MAX_HEXDUMP_LEN).expect("infallible");
+    }
+    s
+}
+
 impl Special {
     fn diffable_contents(&self) -> String {
         let mut s = String::new();
@@ -158,4 +200,19 @@ Contents
 "#,
         )
     }
+
+    #[test]
+    fn binary_file_entry_diff_is_useful() {
+        let mut contents = b"\x7fELF\x02\x01\x01\x80".repeat(8);
+        let left = Entry::from(File::builder().contents(contents.clone()).build());
+        contents[40] = 0xff;
+        let right = Entry::from(File::builder().contents(contents).build());
+        let diff = Diff::Changed { left, right }.to_string();
+        assert!(diff.contains(
+            "-00000020  7f 45 4c 46 02 01 01 80  7f 45 4c 46 02 01 01 80  |.ELF.....ELF....|\n\
+             +00000020  7f 45 4c 46 02 01 01 80  ff 45 4c 46 02 01 01 80  |.ELF.....ELF....|\n"
+        ));
+        assert!(diff.contains(" 00000010  7f 45 4c 46"));
+        assert!(diff.contains("-binary data: xxHash"));
+    }
 }
@@ -0,0 +1,177 @@
+//! Standalone HTML report of a [FilesystemDiff], which stays readable even
+//! when hundreds of paths changed, since every path can be collapsed.
+
+use std::fmt::Write;
+use std::ops::Range;
+
+use similar::Algorithm;
+use similar::DiffTag;
+use similar::TextDiff;
+
+use super::Diff;
+use super::Diffable;
+use super::FilesystemDiff;
+use crate::entry::Entry;
+
+const STYLE: &str = "body{font-family:sans-serif}\
+summary{cursor:pointer;font-family:monospace}\
+.added>summary{color:#22863a}.removed>summary{color:#cb2431}\
+table{border-collapse:collapse;width:100%;table-layout:fixed}\
+td{font-family:monospace;white-space:pre-wrap;vertical-align:top;padding:0 4px}\
+td.del{background:#ffeef0}td.ins{background:#e6ffed}";
+
+impl<'b> FilesystemDiff<'b> {
+    /// Render this diff as a self-contained HTML page, with a collapsible
+    /// section for each path and side-by-side diffs of each part of the
+    /// entries that changed.
+    pub fn to_html(&self) -> String {
+        let mut html = String::new();
+        self.write_html(&mut html).expect("infallible");
+        html
+    }
+
+    fn write_html(&self, html: &mut String) -> std::fmt::Result {
+        writeln!(html, "<!DOCTYPE html>")?;
+        writeln!(html, "<html><head><meta charset=\"utf-8\">")?;
+        writeln!(html, "<title>Filesystem diff</title><style>{STYLE}</style>")?;
+        writeln!(html, "</head><body>")?;
+        writeln!(html, "<p>{} paths differ</p>", self.entry_diffs.len())?;
+        for (path, diff) in &self.entry_diffs {
+            let (class, summary) = match diff {
+                Diff::Added(_) => ("added", "added".to_owned()),
+                Diff::Removed(_) => ("removed", "removed".to_owned()),
+                Diff::Changed { .. } if self.whitespace_only.contains(path) => {
+                    ("changed", "whitespace-only change".to_owned())
+                }
+                Diff::Changed { .. } => (
+                    "changed",
+                    format!("changed: {:?}", self.changed_fields[path]),
+                ),
+                Diff::Renamed { from, .. } => (
+                    "renamed",
+                    format!("renamed from {}", escape(&from.to_string_lossy())),
+                ),
+            };
+            writeln!(html, "<details class=\"{class}\">")?;
+            writeln!(
+                html,
+                "<summary>{} ({summary})</summary>",
+                escape(&path.to_string_lossy())
+            )?;
+            let empty = || std::array::from_fn(|_| "".into());
+            let (left, right) = match diff {
+                Diff::Added(entry) => (empty(), entry.to_diffable_sections(&self.options)),
+                Diff::Removed(entry) => (entry.to_diffable_sections(&self.options), empty()),
+                Diff::Changed { left, right } => (
+                    left.to_diffable_sections(&self.options),
+                    right.to_diffable_sections(&self.options),
+                ),
+                Diff::Renamed { entry, .. } => (
+                    entry.to_diffable_sections(&self.options),
+                    entry.to_diffable_sections(&self.options),
+                ),
+            };
+            for (title, (left, right)) in
+                <&Entry>::SECTIONS.iter().zip(left.iter().zip(right.iter()))
+            {
+                if left == right {
+                    continue;
+                }
+                writeln!(html, "<h4>{title}</h4>")?;
+                match self.options.inline(left, right) {
+                    true => write_side_by_side(html, left, right, self.options.algorithm)?,
+                    false => writeln!(
+                        html,
+                        "<p>{} bytes -&gt; {} bytes, too large to show</p>",
+                        left.len(),
+                        right.len()
+                    )?,
+                }
+            }
+            writeln!(html, "</details>")?;
+        }
+        writeln!(html, "</body></html>")
+    }
+}
+
+/// Two-column table of 'left' and 'right', with lines that only exist on one
+/// side highlighted and changed lines lined up next to each other.
+fn write_side_by_side(
+    html: &mut String,
+    left: &str,
+    right: &str,
+    algorithm: Algorithm,
+) -> std::fmt::Result {
+    let diff = TextDiff::configure()
+        .algorithm(algorithm)
+        .diff_lines(left, right);
+    let (old, new) = (diff.old_slices(), diff.new_slices());
+    writeln!(html, "<table>")?;
+    for op in diff.ops() {
+        let (tag, old_range, new_range) = op.as_tag_tuple();
+        let (del, ins) = match tag {
+            DiffTag::Equal => ("", ""),
+            _ => ("del", "ins"),
+        };
+        // a replaced block is shown as pairs of lines, padding the shorter
+        // side with empty cells
+        let cell =
+            |lines: &[&str], range: &Range<usize>, i: usize, class: &str| match i < range.len() {
+                true => format!(
+                    "<td class=\"{class}\">{}</td>",
+                    escape(lines[range.start + i].trim_end_matches('\n'))
+                ),
+                false => "<td></td>".to_owned(),
+            };
+        for i in 0..old_range.len().max(new_range.len()) {
+            writeln!(
+                html,
+                "<tr>{}{}</tr>",
+                cell(old, &old_range, i, del),
+                cell(new, &new_range, i, ins)
+            )?;
+        }
+    }
+    writeln!(html, "</table>")
+}
+
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cmp::Fields;
+    use crate::fs;
+
+    #[test]
+    fn to_html() {
+        let left = fs! {
+            "etc": {
+                "motd": file("hello\nworld\n"),
+                "<old>": file(""),
+            },
+        };
+        let right = fs! {
+            "etc": {
+                "motd": file("hello\nthere!\n"),
+            },
+        };
+        let html = FilesystemDiff::diff(&left, &right, Fields::all() - Fields::EXTENTS).to_html();
+        assert!(html.contains("<summary>etc/motd (changed: DATA | SIZE)</summary>"));
+        assert!(html.contains("<summary>etc/&lt;old&gt; (removed)</summary>"));
+        assert!(html.contains("<tr><td class=\"\">hello</td><td class=\"\">hello</td></tr>"));
+        assert!(html.contains("<tr><td class=\"del\">world</td><td class=\"ins\">there!</td></tr>"));
+    }
+}
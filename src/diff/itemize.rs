@@ -0,0 +1,135 @@
+//! Terse summary of a [FilesystemDiff] with one line per path, in the same
+//! format as `rsync --itemize-changes`, to get an overview of a big diff
+//! before looking at the contents.
+
+use std::fmt::Display;
+
+use super::Diff;
+use super::FilesystemDiff;
+use crate::cmp::Fields;
+use crate::entry::Entry;
+
+/// [Display]s a [FilesystemDiff] like `rsync --itemize-changes`, see
+/// [FilesystemDiff::itemize].
+pub struct Itemize<'d, 'b>(&'d FilesystemDiff<'b>);
+
+impl<'b> FilesystemDiff<'b> {
+    /// Display one line per path in the format `YXcstpoguax path` (see
+    /// `--itemize-changes` in rsync(1)), for example `>fcs....... etc/motd`
+    /// for a file whose contents changed, or `.d....og... srv` for a
+    /// directory that was chowned. Removed paths are shown as `*deleting`
+    /// and renamed paths as `*renaming`.
+    pub fn itemize(&self) -> Itemize<'_, 'b> {
+        Itemize(self)
+    }
+}
+
+fn file_type(entry: &Entry) -> char {
+    match entry {
+        Entry::Directory(_) => 'd',
+        Entry::File(_) => 'f',
+        Entry::Special(_) => 'D',
+        Entry::Symlink(_) => 'L',
+    }
+}
+
+impl Display for Itemize<'_, '_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (path, diff) in &self.0.entry_diffs {
+            let name = path.display();
+            let (left, right) = match diff {
+                Diff::Removed(_) => {
+                    writeln!(f, "*deleting   {name}")?;
+                    continue;
+                }
+                Diff::Added(entry) => {
+                    let y = match entry {
+                        Entry::File(_) => '>',
+                        _ => 'c',
+                    };
+                    writeln!(f, "{y}{}+++++++++ {name}", file_type(entry))?;
+                    continue;
+                }
+                Diff::Changed { left, right } => (left, right),
+                Diff::Renamed { from, .. } => {
+                    writeln!(f, "*renaming   {} -> {name}", from.display())?;
+                    continue;
+                }
+            };
+            let fields = self.0.changed_fields[path];
+            if fields.contains(Fields::TYPE) {
+                writeln!(f, "c{}+++++++++ {name}", file_type(right))?;
+                continue;
+            }
+            let flag = |field: Fields, c: char| match fields.contains(field) {
+                true => c,
+                false => '.',
+            };
+            let (l, r) = (left.metadata(), right.metadata());
+            let owner = |same: bool, c: char| match fields.contains(Fields::OWNER) && !same {
+                true => c,
+                false => '.',
+            };
+            let y = match (right, fields.contains(Fields::DATA)) {
+                (Entry::File(_), true) => '>',
+                (_, true) => 'c',
+                (_, false) => '.',
+            };
+            // the 'u' (atime) and 'a' (ACL) columns are covered by 't' and
+            // 'x' respectively, so they are always unchanged
+            writeln!(
+                f,
+                "{y}{}{}{}{}{}{}{}..{} {name}",
+                file_type(right),
+                flag(Fields::DATA, 'c'),
+                flag(Fields::SIZE, 's'),
+                flag(Fields::TIME, 't'),
+                flag(Fields::MODE, 'p'),
+                owner(l.uid() == r.uid(), 'o'),
+                owner(l.gid() == r.gid(), 'g'),
+                flag(Fields::XATTR, 'x'),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nix::sys::stat::Mode;
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::fs;
+    use crate::Gid;
+    use crate::Uid;
+
+    #[test]
+    fn itemize() {
+        let left = fs! {
+            "etc": {
+                "motd": file("hello\n"),
+                "old": file(""),
+            },
+            "srv": {},
+        };
+        let mut right = left.clone();
+        right.write("etc/motd", 5, "!\n").unwrap();
+        right
+            .chmod("etc/motd", Mode::from_bits_truncate(0o600))
+            .unwrap();
+        right.unlink("etc/old").unwrap();
+        right
+            .chown("srv", Uid::from_raw(0), Gid::from_raw(1))
+            .unwrap();
+        right.insert("new", crate::File::default());
+        let diff = FilesystemDiff::diff(&left, &right, Fields::all() - Fields::EXTENTS);
+        assert_eq!(
+            ">fcs.p..... etc/motd\n\
+             *deleting   etc/old\n\
+             >f+++++++++ new\n\
+             .d.....g... srv\n",
+            diff.itemize().to_string()
+        );
+    }
+}
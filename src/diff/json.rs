@@ -0,0 +1,188 @@
+//! Machine-readable version of a [FilesystemDiff], for tools (like CI bots)
+//! that want to annotate individual paths instead of showing a text diff.
+
+use serde_json::json;
+use serde_json::Map;
+use serde_json::Value;
+
+use super::Diff;
+use super::DiffOptions;
+use super::Diffable;
+use super::FilesystemDiff;
+use crate::cmp::Fields;
+use crate::entry::Entry;
+
+/// Fields that have a value in the JSON output, along with their key.
+const FIELDS: [(Fields, &str); 8] = [
+    (Fields::TYPE, "type"),
+    (Fields::DATA, "data"),
+    (Fields::TIME, "time"),
+    (Fields::XATTR, "xattrs"),
+    (Fields::MODE, "mode"),
+    (Fields::OWNER, "owner"),
+    (Fields::RDEV, "rdev"),
+    (Fields::SIZE, "size"),
+];
+
+fn value(entry: &Entry, field: Fields, options: &DiffOptions) -> Value {
+    let metadata = entry.metadata();
+    match field {
+        Fields::TYPE | Fields::DATA => {
+            let [ty, _, contents] = entry.to_diffable_sections(options);
+            match field {
+                Fields::TYPE => json!(ty),
+                _ => json!(contents),
+            }
+        }
+        Fields::TIME => json!({
+            "created": metadata.created(),
+            "accessed": metadata.accessed(),
+            "modified": metadata.modified(),
+        }),
+        Fields::XATTR => Value::Object(
+            metadata
+                .xattrs()
+                .iter()
+                .map(|(k, v)| {
+                    (
+                        String::from_utf8_lossy(k).into_owned(),
+                        json!(String::from_utf8_lossy(v)),
+                    )
+                })
+                .collect(),
+        ),
+        Fields::MODE => json!(format!("{:04o}", metadata.mode().bits())),
+        Fields::OWNER => json!({
+            "uid": metadata.uid().as_u32(),
+            "gid": metadata.gid().as_u32(),
+        }),
+        Fields::RDEV => match entry {
+            Entry::Special(s) => json!(s.rdev()),
+            _ => Value::Null,
+        },
+        Fields::SIZE => json!(entry.size()),
+        _ => unreachable!("only called for FIELDS"),
+    }
+}
+
+fn entry_values(entry: &Entry, options: &DiffOptions) -> Map<String, Value> {
+    FIELDS
+        .iter()
+        .map(|(field, key)| (key.to_string(), value(entry, *field, options)))
+        .collect()
+}
+
+impl<'b> FilesystemDiff<'b> {
+    /// Every added, removed and changed path as JSON. Changed paths list
+    /// which fields differ along with the old and new value of each of them
+    /// (except for [Fields::EXTENTS], which is only listed).
+    ///
+    /// ```json
+    /// {
+    ///   "added": [{"path": "etc/new", "entry": {"type": "File", ...}}],
+    ///   "removed": [{"path": "etc/old", "entry": {...}}],
+    ///   "changed": [{
+    ///     "path": "etc/motd",
+    ///     "fields": ["mode"],
+    ///     "changes": {"mode": {"old": "0644", "new": "0600"}}
+    ///   }],
+    ///   "renamed": [{"from": "usr/lib/a", "to": "usr/lib64/a"}],
+    ///   "whitespace_only": ["etc/generated.conf"]
+    /// }
+    /// ```
+    pub fn to_json(&self) -> String {
+        let mut added = Vec::new();
+        let mut removed = Vec::new();
+        let mut changed = Vec::new();
+        let mut renamed = Vec::new();
+        let mut whitespace_only = Vec::new();
+        for (path, diff) in &self.entry_diffs {
+            let name = path.to_string_lossy();
+            match diff {
+                Diff::Added(entry) => added.push(json!({
+                    "path": name,
+                    "entry": entry_values(entry, &self.options),
+                })),
+                Diff::Removed(entry) => removed.push(json!({
+                    "path": name,
+                    "entry": entry_values(entry, &self.options),
+                })),
+                Diff::Renamed { from, .. } => renamed.push(json!({
+                    "from": from.to_string_lossy(),
+                    "to": name,
+                })),
+                Diff::Changed { .. } if self.whitespace_only.contains(path) => {
+                    whitespace_only.push(name)
+                }
+                Diff::Changed { left, right } => {
+                    let fields = self.changed_fields[path];
+                    let mut names = Vec::new();
+                    if fields.contains(Fields::EXTENTS) {
+                        names.push("extents");
+                    }
+                    let mut changes = Map::new();
+                    for (field, key) in FIELDS {
+                        if fields.contains(field) {
+                            names.push(key);
+                            changes.insert(
+                                key.to_string(),
+                                json!({
+                                    "old": value(left, field, &self.options),
+                                    "new": value(right, field, &self.options),
+                                }),
+                            );
+                        }
+                    }
+                    changed.push(json!({
+                        "path": name,
+                        "fields": names,
+                        "changes": changes,
+                    }));
+                }
+            }
+        }
+        json!({
+            "added": added,
+            "removed": removed,
+            "changed": changed,
+            "renamed": renamed,
+            "whitespace_only": whitespace_only,
+        })
+        .to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nix::sys::stat::Mode;
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::fs;
+
+    #[test]
+    fn to_json() {
+        let left = fs! {
+            "motd": file("hello\n"),
+            "old": file(""),
+        };
+        let mut right = left.clone();
+        right
+            .chmod("motd", Mode::from_bits_truncate(0o600))
+            .unwrap();
+        right.unlink("old").unwrap();
+        let diff = FilesystemDiff::diff(&left, &right, Fields::all());
+        let json: Value = serde_json::from_str(&diff.to_json()).unwrap();
+        assert_eq!(json!([]), json["added"]);
+        assert_eq!(json!("old"), json["removed"][0]["path"]);
+        assert_eq!(json!("File"), json["removed"][0]["entry"]["type"]);
+        assert_eq!(
+            json!([{
+                "path": "motd",
+                "fields": ["mode"],
+                "changes": {"mode": {"old": "0644", "new": "0600"}},
+            }]),
+            json["changed"]
+        );
+    }
+}
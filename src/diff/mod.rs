@@ -1,22 +1,37 @@
 use std::borrow::Cow;
 use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::collections::HashMap;
 use std::fmt::Debug;
 use std::fmt::Display;
 use std::fmt::Write;
 use std::path::Path;
 
+#[cfg(feature = "serde")]
+use serde::Deserialize;
+#[cfg(feature = "serde")]
+use serde::Serialize;
 use similar::udiff::unified_diff;
-use similar::Algorithm;
+pub use similar::Algorithm;
 
-use crate::cmp::ApproxEq;
 use crate::cmp::Fields;
+use crate::cmp::Rules;
 use crate::entry::Entry;
+use crate::BytesPath;
 use crate::Filesystem;
 
 mod diffable;
 use diffable::Diffable;
+mod html;
+mod itemize;
+pub use itemize::Itemize;
+mod owned;
+pub use owned::OwnedFilesystemDiff;
+#[cfg(feature = "json")]
+mod json;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
 pub enum Diff<T, const N: usize>
 where
     T: for<'a> Diffable<'a, N>,
@@ -27,6 +42,9 @@ where
     Added(T),
     /// Left and right side both contain this object but it is different somehow
     Changed { left: T, right: T },
+    /// Right side has this object at a new path, and it was removed from the
+    /// path 'from'
+    Renamed { from: BytesPath, entry: T },
 }
 
 impl<T, const N: usize> Display for Diff<T, N>
@@ -34,18 +52,37 @@ where
     T: for<'a> Diffable<'a, N>,
 {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.write(f, &DiffOptions::default())
+    }
+}
+
+impl<T, const N: usize> Diff<T, N>
+where
+    T: for<'a> Diffable<'a, N>,
+{
+    /// Like [Display], but with the algorithm, context and size limit from
+    /// 'options'.
+    pub fn render(&self, options: &DiffOptions) -> String {
+        let mut s = String::new();
+        self.write(&mut s, options).expect("infallible");
+        s
+    }
+
+    fn write(&self, f: &mut impl Write, options: &DiffOptions) -> std::fmt::Result {
         let (left, right) = match self {
             Self::Removed(removed) => (
-                removed.to_diffable_sections(),
+                removed.to_diffable_sections(options),
                 std::array::from_fn(|_| Cow::Borrowed("")),
             ),
             Self::Added(added) => (
                 std::array::from_fn(|_| Cow::Borrowed("")),
-                added.to_diffable_sections(),
+                added.to_diffable_sections(options),
             ),
-            Self::Changed { left, right } => {
-                (left.to_diffable_sections(), right.to_diffable_sections())
-            }
+            Self::Changed { left, right } => (
+                left.to_diffable_sections(options),
+                right.to_diffable_sections(options),
+            ),
+            Self::Renamed { .. } => return Ok(()),
         };
 
         for (title, (left, right)) in T::SECTIONS.iter().zip(left.iter().zip(right.iter())) {
@@ -53,11 +90,18 @@ where
                 continue;
             }
             writeln!(f, "{title}")?;
-            if left.matches('\n').count() <= 1 && right.matches('\n').count() <= 1 {
+            if !options.inline(left, right) {
+                writeln!(
+                    f,
+                    "{} bytes -> {} bytes, too large to show",
+                    left.len(),
+                    right.len()
+                )?;
+            } else if left.matches('\n').count() <= 1 && right.matches('\n').count() <= 1 {
                 writeln!(f, "-{left}")?;
                 writeln!(f, "+{right}")?;
             } else {
-                let diff = unified_diff(Algorithm::Patience, left, right, 3, None);
+                let diff = unified_diff(options.algorithm, left, right, options.context, None);
                 f.write_str(&diff)?;
             }
         }
@@ -65,17 +109,240 @@ where
     }
 }
 
+/// Options that control which paths [FilesystemDiff::diff_with] looks at,
+/// and how they are shown.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiffOptions {
+    prefix: Option<BytesPath>,
+    max_depth: Option<usize>,
+    collapse: bool,
+    renames: bool,
+    ignore_whitespace: bool,
+    algorithm: Algorithm,
+    context: usize,
+    max_inline_size: Option<usize>,
+    max_content_size: Option<u64>,
+}
+
+impl Default for DiffOptions {
+    fn default() -> Self {
+        Self {
+            prefix: None,
+            max_depth: None,
+            collapse: false,
+            renames: false,
+            ignore_whitespace: false,
+            algorithm: Algorithm::Patience,
+            context: 3,
+            max_inline_size: None,
+            max_content_size: None,
+        }
+    }
+}
+
+impl DiffOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only diff 'prefix' and the paths underneath it.
+    pub fn prefix(&mut self, prefix: impl Into<BytesPath>) -> &mut Self {
+        self.prefix = Some(prefix.into());
+        self
+    }
+
+    /// Only diff paths that are at most 'depth' components below the
+    /// [DiffOptions::prefix] (or the root), so `0` is only the prefix itself.
+    pub fn max_depth(&mut self, depth: usize) -> &mut Self {
+        self.max_depth = Some(depth);
+        self
+    }
+
+    /// Show an added or removed directory as a single entry, instead of
+    /// also listing everything underneath it.
+    pub fn collapse(&mut self, collapse: bool) -> &mut Self {
+        self.collapse = collapse;
+        self
+    }
+
+    /// Show a removed path and an added path that are otherwise equal as a
+    /// single [Diff::Renamed]. Directories and empty files are never
+    /// considered renamed, since they are rarely distinguishable.
+    pub fn renames(&mut self, renames: bool) -> &mut Self {
+        self.renames = renames;
+        self
+    }
+
+    /// Report files whose contents only differ in trailing whitespace,
+    /// trailing blank lines or line endings (CRLF vs LF) as a whitespace-only
+    /// change, instead of showing their contents.
+    pub fn ignore_whitespace(&mut self, ignore: bool) -> &mut Self {
+        self.ignore_whitespace = ignore;
+        self
+    }
+
+    /// Algorithm used to diff contents, defaults to [Algorithm::Patience].
+    pub fn algorithm(&mut self, algorithm: Algorithm) -> &mut Self {
+        self.algorithm = algorithm;
+        self
+    }
+
+    /// Number of unchanged lines to show around each change, defaults to 3.
+    pub fn context(&mut self, lines: usize) -> &mut Self {
+        self.context = lines;
+        self
+    }
+
+    /// Only show the sizes instead of diffing contents (or any other part of
+    /// an entry) that are more than 'size' bytes long once rendered as text.
+    pub fn max_inline_size(&mut self, size: usize) -> &mut Self {
+        self.max_inline_size = Some(size);
+        self
+    }
+
+    /// Show files larger than 'size' bytes as their size and a digest,
+    /// without reading all of their contents into memory to diff them.
+    pub fn max_content_size(&mut self, size: u64) -> &mut Self {
+        self.max_content_size = Some(size);
+        self
+    }
+
+    fn inline(&self, left: &str, right: &str) -> bool {
+        self.max_inline_size
+            .is_none_or(|max| left.len() <= max && right.len() <= max)
+    }
+
+    fn in_scope(&self, path: &Path) -> bool {
+        let relative = match &self.prefix {
+            Some(prefix) => match path.strip_prefix(prefix) {
+                Ok(relative) => relative,
+                Err(_) => return false,
+            },
+            None => path,
+        };
+        self.max_depth
+            .is_none_or(|depth| relative.components().count() <= depth)
+    }
+}
+
 pub struct FilesystemDiff<'b> {
     entry_diffs: BTreeMap<&'b Path, Diff<&'b Entry, 3>>,
+    /// Selected fields that differ for each [Diff::Changed] path
+    changed_fields: BTreeMap<&'b Path, Fields>,
+    /// Number and total size of paths that were left out underneath a
+    /// collapsed directory
+    collapsed: BTreeMap<&'b Path, (usize, u64)>,
+    /// [Diff::Changed] paths that are only different in whitespace
+    whitespace_only: BTreeSet<&'b Path>,
+    options: DiffOptions,
+}
+
+/// Whether 'left' and 'right' are regular files with the same contents when
+/// ignoring trailing whitespace (including `\r`) on every line. Files larger
+/// than 'max_size' (or that cannot be read) are not considered the same.
+fn same_except_whitespace(left: &Entry, right: &Entry, max_size: Option<u64>) -> bool {
+    match (left, right) {
+        (Entry::File(left), Entry::File(right))
+            if max_size.is_none_or(|max| left.len() <= max && right.len() <= max) =>
+        {
+            let (Ok(left), Ok(right)) = (left.to_bytes(), right.to_bytes()) else {
+                return false;
+            };
+            fn lines(b: &[u8]) -> impl Iterator<Item = &[u8]> {
+                b.trim_ascii_end()
+                    .split(|c| *c == b'\n')
+                    .map(<[u8]>::trim_ascii_end)
+            }
+            lines(&left).eq(lines(&right))
+        }
+        _ => false,
+    }
+}
+
+/// Replace pairs of [Diff::Removed] and [Diff::Added] that are equal (except
+/// for the path) with a [Diff::Renamed]. When there are multiple candidates,
+/// one with the same file name is preferred.
+fn detect_renames<'b>(diffs: &mut BTreeMap<&'b Path, Diff<&'b Entry, 3>>, rules: &Rules) {
+    let renameable = |entry: &Entry| !entry.is_directory() && entry.size() > 0;
+    let mut removed: HashMap<u64, Vec<(&'b Path, &'b Entry)>> = HashMap::new();
+    let mut added = Vec::new();
+    for (path, diff) in diffs.iter() {
+        match diff {
+            Diff::Removed(entry) if renameable(entry) => removed
+                .entry(entry.size())
+                .or_default()
+                .push((*path, *entry)),
+            Diff::Added(entry) if renameable(entry) => added.push((*path, *entry)),
+            _ => (),
+        }
+    }
+    for (to, entry) in added {
+        let Some(candidates) = removed.get_mut(&entry.size()) else {
+            continue;
+        };
+        let fields = rules.fields(to) - Fields::PATH;
+        let same = |(_, from): &(&Path, &Entry)| {
+            from.cmp_with_precision(entry, &rules.precision)
+                .contains(fields)
+        };
+        let found = candidates
+            .iter()
+            .position(|c| c.0.file_name() == to.file_name() && same(c))
+            .or_else(|| candidates.iter().position(same));
+        if let Some(i) = found {
+            let (from, _) = candidates.remove(i);
+            diffs.remove(from);
+            diffs.insert(
+                to,
+                Diff::Renamed {
+                    from: from.into(),
+                    entry,
+                },
+            );
+        }
+    }
 }
 
 impl<'b> FilesystemDiff<'b> {
-    pub fn diff(left: &'b Filesystem, right: &'b Filesystem, fields: Fields) -> Self {
+    /// Diff two filesystems, comparing only the [Fields] selected by 'rules'
+    /// (which can be a single [Fields] for the entire filesystem).
+    pub fn diff(left: &'b Filesystem, right: &'b Filesystem, rules: impl Into<Rules>) -> Self {
+        Self::diff_with(left, right, rules, &DiffOptions::default())
+    }
+
+    /// Like [FilesystemDiff::diff], but with more control over which paths
+    /// are diffed.
+    pub fn diff_with(
+        left: &'b Filesystem,
+        right: &'b Filesystem,
+        rules: impl Into<Rules>,
+        options: &DiffOptions,
+    ) -> Self {
+        let rules = rules.into();
         let mut diffs = BTreeMap::new();
+        let mut changed_fields = BTreeMap::new();
+        let mut whitespace_only = BTreeSet::new();
         for (path, left_entry) in left.iter() {
+            if !options.in_scope(path) {
+                continue;
+            }
+            let fields = rules.fields(path);
             match right.get(path) {
                 Ok(right_entry) => {
-                    if !left_entry.approx_eq(right_entry, fields) {
+                    let cmp = left_entry.cmp_with_precision(right_entry, &rules.precision);
+                    if !cmp.contains(fields) {
+                        let different = fields - cmp;
+                        if options.ignore_whitespace
+                            && (Fields::DATA | Fields::SIZE | Fields::EXTENTS).contains(different)
+                            && same_except_whitespace(
+                                left_entry,
+                                right_entry,
+                                options.max_content_size,
+                            )
+                        {
+                            whitespace_only.insert(path);
+                        }
+                        changed_fields.insert(path, different);
                         diffs.insert(
                             path,
                             Diff::Changed {
@@ -86,16 +353,115 @@ impl<'b> FilesystemDiff<'b> {
                     }
                 }
                 Err(_) => {
-                    diffs.insert(path, Diff::Removed(left_entry));
+                    if fields.contains(Fields::PATH) {
+                        diffs.insert(path, Diff::Removed(left_entry));
+                    }
                 }
             };
         }
         for (path, right_entry) in right.iter() {
-            if left.get(path).is_err() {
+            if options.in_scope(path)
+                && left.get(path).is_err()
+                && rules.fields(path).contains(Fields::PATH)
+            {
                 diffs.insert(path, Diff::Added(right_entry));
             }
         }
-        Self { entry_diffs: diffs }
+        if options.renames {
+            detect_renames(&mut diffs, &rules);
+        }
+        let mut collapsed = BTreeMap::new();
+        if options.collapse {
+            // paths are sorted so that everything underneath a directory
+            // immediately follows it
+            let mut root: Option<(&Path, bool)> = None;
+            diffs.retain(|path, diff| {
+                let added = match diff {
+                    Diff::Added(e) => (true, e.is_directory()),
+                    Diff::Removed(e) => (false, e.is_directory()),
+                    Diff::Changed { .. } | Diff::Renamed { .. } => {
+                        root = None;
+                        return true;
+                    }
+                };
+                if let Some((dir, dir_added)) = root {
+                    if path.starts_with(dir) && dir_added == added.0 {
+                        let (count, bytes) = collapsed.entry(dir).or_insert((0, 0));
+                        *count += 1;
+                        *bytes += match diff {
+                            Diff::Added(e) | Diff::Removed(e) => e.size(),
+                            Diff::Changed { .. } | Diff::Renamed { .. } => unreachable!(),
+                        };
+                        return false;
+                    }
+                }
+                root = added.1.then_some((*path, added.0));
+                true
+            });
+        }
+        Self {
+            entry_diffs: diffs,
+            changed_fields,
+            collapsed,
+            whitespace_only,
+            options: options.clone(),
+        }
+    }
+}
+
+/// Summary of a [FilesystemDiff], see [FilesystemDiff::stats].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DiffStats {
+    /// Number of added paths, including those in collapsed directories
+    pub added: usize,
+    /// Number of removed paths, including those in collapsed directories
+    pub removed: usize,
+    /// Number of paths that exist on both sides but are different
+    pub changed: usize,
+    /// Number of paths that were renamed, see [DiffOptions::renames]
+    pub renamed: usize,
+    /// Number of paths that only changed in whitespace (and are not included
+    /// in any of the other counts), see [DiffOptions::ignore_whitespace]
+    pub whitespace_only: usize,
+    /// Size of all added paths, plus the growth of changed paths
+    pub bytes_added: u64,
+    /// Size of all removed paths, plus the shrinkage of changed paths
+    pub bytes_removed: u64,
+    /// Number of changed paths by the exact set of [Fields] that differ, so
+    /// paths that only have a different TIME are counted separately from
+    /// paths with a different TIME and DATA
+    pub fields: BTreeMap<Fields, usize>,
+}
+
+impl<'b> FilesystemDiff<'b> {
+    /// Count what changed, without rendering any of it.
+    pub fn stats(&self) -> DiffStats {
+        let mut stats = DiffStats::default();
+        for (path, diff) in &self.entry_diffs {
+            let (count, bytes) = self.collapsed.get(path).copied().unwrap_or_default();
+            match diff {
+                Diff::Added(e) => {
+                    stats.added += 1 + count;
+                    stats.bytes_added += e.size() + bytes;
+                }
+                Diff::Removed(e) => {
+                    stats.removed += 1 + count;
+                    stats.bytes_removed += e.size() + bytes;
+                }
+                Diff::Changed { .. } if self.whitespace_only.contains(path) => {
+                    stats.whitespace_only += 1;
+                }
+                Diff::Changed { left, right } => {
+                    stats.changed += 1;
+                    let (left, right) = (left.size(), right.size());
+                    stats.bytes_added += right.saturating_sub(left);
+                    stats.bytes_removed += left.saturating_sub(right);
+                    *stats.fields.entry(self.changed_fields[path]).or_default() += 1;
+                }
+                Diff::Renamed { .. } => stats.renamed += 1,
+            }
+        }
+        stats
     }
 }
 
@@ -116,8 +482,19 @@ impl<'b> Display for FilesystemDiff<'b> {
                     writeln!(f, "---  left/{}", path.display())?;
                     writeln!(f, "+++ right/{}", path.display())?;
                 }
+                Diff::Renamed { from, .. } => {
+                    writeln!(f, "---  left/{}", from.display())?;
+                    writeln!(f, "+++ right/{}", path.display())?;
+                    writeln!(f, "Renamed")?;
+                }
+            }
+            match self.whitespace_only.contains(path) {
+                true => writeln!(f, "Whitespace-only change")?,
+                false => writeln!(f, "{}", diff.render(&self.options).trim_end_matches('\n'))?,
+            }
+            if let Some((n, _)) = self.collapsed.get(path) {
+                writeln!(f, "... and {n} paths underneath")?;
             }
-            writeln!(f, "{}", diff.to_string().trim_end_matches('\n'))?;
             if iter.peek().is_some() {
                 f.write_char('\n')?;
             }
@@ -128,12 +505,16 @@ impl<'b> Display for FilesystemDiff<'b> {
 
 #[cfg(test)]
 mod tests {
+    use std::io::ErrorKind;
+
     use nix::sys::stat::Mode;
     use similar_asserts::assert_eq;
 
     use super::*;
+    use crate::entry::Directory;
     use crate::entry::Metadata;
     use crate::entry::Symlink;
+    use crate::fs;
     use crate::tests::demo_fs;
     use crate::File;
     use crate::Gid;
@@ -180,4 +561,233 @@ mod tests {
         let diff = FilesystemDiff::diff(&left, &right, Fields::all());
         assert_eq!(diff.to_string(), include_str!("testdata/passwd_diff.txt"),);
     }
+
+    #[test]
+    fn rules() {
+        let left = demo_fs();
+        let mut right = demo_fs();
+        right
+            .chown("testdata/dir/lorem.txt", Uid::from_raw(1), Gid::from_raw(1))
+            .unwrap();
+        right.truncate("testdata/lorem.txt", 0).unwrap();
+        right.insert("tmp/junk", File::default());
+        let mut rules = Rules::new(Fields::all());
+        rules
+            .rule("testdata/dir/**", Fields::all() - Fields::OWNER)
+            .unwrap()
+            .rule("/tmp/**", Fields::empty())
+            .unwrap();
+        assert!(!left.approx_eq_with(&right, &rules));
+        assert_eq!(
+            vec![Path::new("testdata/lorem.txt")],
+            FilesystemDiff::diff(&left, &right, &rules)
+                .entry_diffs
+                .into_keys()
+                .collect::<Vec<_>>()
+        );
+
+        // '*' does not cross directories, and the last matching rule wins
+        rules
+            .rule("testdata/*", Fields::STAT - Fields::SIZE)
+            .unwrap();
+        assert!(left.approx_eq_with(&right, &rules));
+        rules.rule("testdata/*", Fields::all()).unwrap();
+        assert!(!left.approx_eq_with(&right, &rules));
+        assert_eq!(
+            ErrorKind::InvalidInput,
+            rules.rule("a[", Fields::all()).unwrap_err().kind()
+        );
+    }
+
+    #[test]
+    fn ignore() {
+        let mut left = demo_fs();
+        left.insert("etc/machine-id", File::builder().contents("abc").build());
+        let mut right = demo_fs();
+        right.insert("etc/machine-id", File::builder().contents("def").build());
+        right.insert("testdata/dir/.uuid", File::default());
+        right.insert("var/cache/x", File::default());
+        assert!(!left.approx_eq_with(&right, &Fields::all().into()));
+
+        let mut rules = Rules::new(Fields::all());
+        rules
+            .ignore("/etc/machine-id")
+            .unwrap()
+            .ignore("**/.uuid")
+            .unwrap()
+            .ignore_if(|p| p.starts_with("var/cache"));
+        assert!(left.approx_eq_with(&right, &rules));
+        assert!(FilesystemDiff::diff(&left, &right, &rules)
+            .entry_diffs
+            .is_empty());
+        assert!(!left
+            .cmp_with(&right, &Fields::all().into())
+            .contains(Fields::PATH));
+    }
+
+    #[test]
+    fn options() {
+        let left = demo_fs();
+        let mut right = demo_fs();
+        right.chmod("", Mode::from_bits_truncate(0o700)).unwrap();
+        right.unlink("testdata/dir/symlink").unwrap();
+        for dir in ["usr", "usr/lib", "usr/lib/pkg", "usr/lib/pkg/a"] {
+            right.insert(dir, Directory::default());
+        }
+        right.insert("usr/lib/pkg/a/b", File::default());
+        right.insert("usr/lib/pkg/c", File::default());
+        let paths = |options: &DiffOptions| {
+            FilesystemDiff::diff_with(&left, &right, Fields::all(), options)
+                .entry_diffs
+                .into_keys()
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            vec![Path::new("testdata/dir/symlink")],
+            paths(DiffOptions::new().prefix("testdata"))
+        );
+        assert_eq!(
+            vec![Path::new(""), Path::new("usr")],
+            paths(DiffOptions::new().max_depth(1))
+        );
+        assert_eq!(
+            vec![Path::new("usr/lib"), Path::new("usr/lib/pkg")],
+            paths(DiffOptions::new().prefix("usr/lib").max_depth(1))
+        );
+        let diff = FilesystemDiff::diff_with(
+            &left,
+            &right,
+            Fields::all(),
+            DiffOptions::new().prefix("usr").collapse(true),
+        );
+        assert_eq!(
+            vec![Path::new("usr")],
+            diff.entry_diffs.keys().copied().collect::<Vec<_>>()
+        );
+        assert!(diff.to_string().ends_with("... and 5 paths underneath\n"));
+    }
+
+    #[test]
+    fn stats() {
+        let left = demo_fs();
+        let mut right = demo_fs();
+        right.truncate("testdata/lorem.txt", 5).unwrap();
+        right
+            .chmod("testdata/dir/lorem.txt", Mode::from_bits_truncate(0o600))
+            .unwrap();
+        right
+            .chmod("testdata", Mode::from_bits_truncate(0o700))
+            .unwrap();
+        right.unlink("testdata/dir/symlink").unwrap();
+        right.insert("new", Directory::default());
+        right.insert("new/file", File::builder().contents("hello").build());
+        let diff = FilesystemDiff::diff_with(
+            &left,
+            &right,
+            Fields::all() - Fields::EXTENTS,
+            DiffOptions::new().collapse(true),
+        );
+        assert_eq!(
+            DiffStats {
+                added: 2,
+                removed: 1,
+                changed: 3,
+                renamed: 0,
+                whitespace_only: 0,
+                bytes_added: 5,
+                bytes_removed: 7 + 12,
+                fields: BTreeMap::from([(Fields::MODE, 2), (Fields::DATA | Fields::SIZE, 1)]),
+            },
+            diff.stats()
+        );
+    }
+
+    #[test]
+    fn render_options() {
+        let diff = Diff::<Entry, 3>::Changed {
+            left: File::builder().contents("a\nb\nc\nd\n").build().into(),
+            right: File::builder().contents("a\nb\nc\nD\n").build().into(),
+        };
+        assert_eq!(
+            "Contents\n@@ -3,2 +3,2 @@\n c\n-d\n+D\n",
+            diff.render(DiffOptions::new().context(1).algorithm(Algorithm::Myers))
+        );
+        assert_eq!(
+            "Contents\n8 bytes -> 8 bytes, too large to show\n",
+            diff.render(DiffOptions::new().max_inline_size(4))
+        );
+    }
+
+    #[test]
+    fn max_content_size() {
+        let diff = Diff::<Entry, 3>::Changed {
+            left: File::builder().contents("short\n").build().into(),
+            right: File::builder().contents("much longer\n").build().into(),
+        };
+        let rendered = diff.render(DiffOptions::new().max_content_size(8));
+        assert!(rendered.contains("-short\n"), "{rendered}");
+        assert!(rendered.contains("+12 bytes, xxHash = "), "{rendered}");
+        assert!(!rendered.contains("much longer"), "{rendered}");
+    }
+
+    #[test]
+    fn renames() {
+        let left = demo_fs();
+        let mut right = demo_fs();
+        right
+            .rename("testdata/dir/lorem.txt", "testdata/lorem2.txt")
+            .unwrap();
+        right.rename("testdata/lorem.txt", "lorem.txt").unwrap();
+        right.insert("empty", File::default());
+        let diff = FilesystemDiff::diff_with(
+            &left,
+            &right,
+            Fields::all(),
+            DiffOptions::new().renames(true),
+        );
+        assert_eq!(
+            vec![
+                (Path::new("empty"), None),
+                (Path::new("lorem.txt"), Some("testdata/lorem.txt")),
+                (
+                    Path::new("testdata/lorem2.txt"),
+                    Some("testdata/dir/lorem.txt")
+                ),
+            ],
+            diff.entry_diffs
+                .iter()
+                .map(|(path, diff)| match diff {
+                    Diff::Renamed { from, .. } => (*path, from.to_str()),
+                    _ => (*path, None),
+                })
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(2, diff.stats().renamed);
+        assert!(diff
+            .to_string()
+            .contains("---  left/testdata/lorem.txt\n+++ right/lorem.txt\nRenamed\n"));
+    }
+
+    #[test]
+    fn ignore_whitespace() {
+        let left = fs! {
+            "a.conf": file("key = value\nother = 1\n"),
+            "b.conf": file("key = value\n"),
+        };
+        let right = fs! {
+            "a.conf": file("key = value  \r\nother = 1\r\n\n"),
+            "b.conf": file("key = other\n"),
+        };
+        let diff = FilesystemDiff::diff_with(
+            &left,
+            &right,
+            Fields::all(),
+            DiffOptions::new().ignore_whitespace(true),
+        );
+        let stats = diff.stats();
+        assert_eq!((1, 1), (stats.changed, stats.whitespace_only));
+        assert!(diff
+            .to_string()
+            .starts_with("---  left/a.conf\n+++ right/a.conf\nWhitespace-only change\n"));
+    }
 }
@@ -0,0 +1,205 @@
+//! A [FilesystemDiff] that owns all of its paths and entries, so that it can
+//! outlive the filesystems it was created from, be serialized (with the
+//! `serde` feature) and applied to another [Filesystem] later.
+
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::io::Error;
+use std::io::ErrorKind;
+use std::io::Result;
+use std::path::Path;
+
+#[cfg(feature = "serde")]
+use serde::Deserialize;
+#[cfg(feature = "serde")]
+use serde::Serialize;
+
+use super::Diff;
+use super::DiffOptions;
+use super::FilesystemDiff;
+use crate::cmp::Fields;
+use crate::entry::Entry;
+use crate::BytesPath;
+use crate::Filesystem;
+
+/// Owned version of a [FilesystemDiff].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+pub struct OwnedFilesystemDiff {
+    #[cfg_attr(feature = "serde", serde(with = "crate::ser::pairs"))]
+    entry_diffs: BTreeMap<BytesPath, Diff<Entry, 3>>,
+    #[cfg_attr(feature = "serde", serde(with = "crate::ser::pairs"))]
+    changed_fields: BTreeMap<BytesPath, Fields>,
+    #[cfg_attr(feature = "serde", serde(with = "crate::ser::pairs"))]
+    collapsed: BTreeMap<BytesPath, (usize, u64)>,
+    whitespace_only: BTreeSet<BytesPath>,
+}
+
+impl From<&FilesystemDiff<'_>> for OwnedFilesystemDiff {
+    fn from(diff: &FilesystemDiff<'_>) -> Self {
+        Self {
+            entry_diffs: diff
+                .entry_diffs
+                .iter()
+                .map(|(path, d)| {
+                    let d = match d {
+                        Diff::Removed(e) => Diff::Removed((*e).clone()),
+                        Diff::Added(e) => Diff::Added((*e).clone()),
+                        Diff::Changed { left, right } => Diff::Changed {
+                            left: (*left).clone(),
+                            right: (*right).clone(),
+                        },
+                        Diff::Renamed { from, entry } => Diff::Renamed {
+                            from: from.clone(),
+                            entry: (*entry).clone(),
+                        },
+                    };
+                    ((*path).into(), d)
+                })
+                .collect(),
+            changed_fields: diff
+                .changed_fields
+                .iter()
+                .map(|(path, fields)| ((*path).into(), *fields))
+                .collect(),
+            collapsed: diff
+                .collapsed
+                .iter()
+                .map(|(path, collapsed)| ((*path).into(), *collapsed))
+                .collect(),
+            whitespace_only: diff
+                .whitespace_only
+                .iter()
+                .map(|path| (*path).into())
+                .collect(),
+        }
+    }
+}
+
+impl OwnedFilesystemDiff {
+    /// Borrow this as a [FilesystemDiff] to render it in any of the
+    /// supported formats.
+    pub fn as_diff(&self, options: &DiffOptions) -> FilesystemDiff<'_> {
+        fn path(p: &BytesPath) -> &Path {
+            p
+        }
+        FilesystemDiff {
+            entry_diffs: self
+                .entry_diffs
+                .iter()
+                .map(|(p, d)| {
+                    let d = match d {
+                        Diff::Removed(e) => Diff::Removed(e),
+                        Diff::Added(e) => Diff::Added(e),
+                        Diff::Changed { left, right } => Diff::Changed { left, right },
+                        Diff::Renamed { from, entry } => Diff::Renamed {
+                            from: from.clone(),
+                            entry,
+                        },
+                    };
+                    (path(p), d)
+                })
+                .collect(),
+            changed_fields: self
+                .changed_fields
+                .iter()
+                .map(|(p, fields)| (path(p), *fields))
+                .collect(),
+            collapsed: self
+                .collapsed
+                .iter()
+                .map(|(p, collapsed)| (path(p), *collapsed))
+                .collect(),
+            whitespace_only: self.whitespace_only.iter().map(path).collect(),
+            options: options.clone(),
+        }
+    }
+
+    /// Apply this diff to 'fs' (which is expected to look like the left side
+    /// of the diff), so that it matches the right side. If anything fails,
+    /// 'fs' is left unchanged. Diffs with
+    /// [collapsed](DiffOptions::collapse) directories cannot be applied,
+    /// since they do not have the collapsed paths anymore.
+    pub fn apply(&self, fs: &mut Filesystem) -> Result<()> {
+        if !self.collapsed.is_empty() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "cannot apply a diff with collapsed directories",
+            ));
+        }
+        fs.transaction(|fs| {
+            // parents are created before their children...
+            for (path, diff) in &self.entry_diffs {
+                match diff {
+                    Diff::Added(entry)
+                    | Diff::Changed { right: entry, .. }
+                    | Diff::Renamed { entry, .. } => {
+                        fs.insert(path.clone(), entry.clone());
+                    }
+                    Diff::Removed(_) => (),
+                }
+            }
+            // ...and removed after them
+            for (path, diff) in self.entry_diffs.iter().rev() {
+                match diff {
+                    Diff::Removed(_) => fs.unlink(path)?,
+                    Diff::Renamed { from, .. } => fs.unlink(from)?,
+                    Diff::Added(_) | Diff::Changed { .. } => (),
+                }
+            }
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nix::sys::stat::Mode;
+
+    use super::*;
+    use crate::cmp::ApproxEq;
+    use crate::tests::demo_fs;
+    use crate::File;
+
+    fn owned_diff() -> (Filesystem, OwnedFilesystemDiff) {
+        let left = demo_fs();
+        let mut right = demo_fs();
+        right
+            .chmod("testdata/dir", Mode::from_bits_truncate(0o700))
+            .unwrap();
+        right.unlink("testdata/dir/symlink").unwrap();
+        right
+            .rename("testdata/dir/lorem.txt", "testdata/moved.txt")
+            .unwrap();
+        right.insert("new", File::builder().contents("new\n").build());
+        let diff = FilesystemDiff::diff_with(
+            &left,
+            &right,
+            Fields::all(),
+            DiffOptions::new().renames(true),
+        );
+        (right.clone(), OwnedFilesystemDiff::from(&diff))
+    }
+
+    #[test]
+    fn apply() {
+        let (right, diff) = owned_diff();
+        assert!(diff
+            .as_diff(&DiffOptions::default())
+            .to_string()
+            .contains("Renamed"));
+        let mut fs = demo_fs();
+        diff.apply(&mut fs).unwrap();
+        assert!(fs.approx_eq(&right, Fields::all()));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde() {
+        use pretty_assertions::assert_eq;
+
+        let (_, diff) = owned_diff();
+        let json = serde_json::to_string(&diff).unwrap();
+        assert_eq!(diff, serde_json::from_str(&json).unwrap());
+    }
+}
@@ -0,0 +1,327 @@
+//! Load a directory on the host into a [Filesystem], or compare a
+//! [Filesystem] directly against one without loading it into memory first.
+
+use std::collections::hash_map;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::io::Read;
+use std::io::Result;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::path::PathBuf;
+
+use bytes::Bytes;
+use nix::sys::stat::SFlag;
+
+use crate::cmp::Comparison;
+use crate::cmp::Fields;
+use crate::cmp::Precision;
+use crate::cmp::Rules;
+use crate::entry::Directory;
+use crate::entry::Metadata;
+use crate::entry::Special;
+use crate::entry::Symlink;
+use crate::Entry;
+use crate::File;
+use crate::Filesystem;
+
+impl Filesystem {
+    /// Load the directory 'dir' on the host into a new [Filesystem], with
+    /// 'dir' itself as the root. File contents are read into memory, paths
+    /// that share an inode become hardlinks, and device nodes, fifos and
+    /// sockets become [Special] entries.
+    pub fn from_dir<P>(dir: P) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let dir = dir.as_ref();
+        let mut fs = Self::new();
+        // first path of every (dev, ino) that has more than one link
+        let mut inodes: HashMap<(u64, u64), PathBuf> = HashMap::new();
+        let mut pending = vec![PathBuf::new()];
+        while let Some(path) = pending.pop() {
+            let host = dir.join(&path);
+            let meta = std::fs::symlink_metadata(&host)?;
+            let ft = meta.file_type();
+            if !ft.is_dir() && meta.nlink() > 1 {
+                match inodes.entry((meta.dev(), meta.ino())) {
+                    hash_map::Entry::Occupied(first) => {
+                        fs.link(first.get(), path)?;
+                        continue;
+                    }
+                    hash_map::Entry::Vacant(first) => {
+                        first.insert(path.clone());
+                    }
+                }
+            }
+            let mut metadata = Metadata::from(meta.clone());
+            for name in xattr::list(&host)? {
+                if let Some(value) = xattr::get(&host, &name)? {
+                    metadata
+                        .xattrs
+                        .insert(Bytes::copy_from_slice(name.as_bytes()), value.into());
+                }
+            }
+            let entry: Entry = if ft.is_dir() {
+                for child in std::fs::read_dir(&host)? {
+                    pending.push(path.join(child?.file_name()));
+                }
+                Directory::builder().metadata(metadata).build().into()
+            } else if ft.is_file() {
+                let mut file = File::from_reader(std::fs::File::open(&host)?)?;
+                file.metadata = metadata;
+                file.into()
+            } else if ft.is_symlink() {
+                Symlink::new(std::fs::read_link(&host)?, Some(metadata)).into()
+            } else {
+                let file_type = SFlag::from_bits_truncate(meta.mode() & SFlag::S_IFMT.bits());
+                Special::new(file_type, meta.rdev(), metadata).into()
+            };
+            fs.insert(path, entry);
+        }
+        Ok(fs)
+    }
+
+    /// Like [ApproxEq::cmp](crate::cmp::ApproxEq::cmp), but against the directory 'dir' on the host. See
+    /// [Filesystem::compare_dir].
+    pub fn cmp_dir<P>(&self, dir: P, rules: impl Into<Rules>) -> Result<Fields>
+    where
+        P: AsRef<Path>,
+    {
+        Ok(self.compare_dir(dir, rules)?.differences().complement())
+    }
+
+    /// Like [Filesystem::compare], but against the directory 'dir' on the
+    /// host, where this filesystem is the left side. The directory is walked
+    /// one entry at a time, and file contents are only read when
+    /// [Fields::DATA] is selected and the sizes match, in which case they
+    /// are streamed instead of being loaded into memory.
+    ///
+    /// [Fields::EXTENTS] is never compared, since the physical layout of
+    /// files on the host is not visible. The root directory is only compared
+    /// if this filesystem has an entry for it.
+    pub fn compare_dir<P>(&self, dir: P, rules: impl Into<Rules>) -> Result<Comparison>
+    where
+        P: AsRef<Path>,
+    {
+        let dir = dir.as_ref();
+        let rules = rules.into();
+        let mut comparison = Comparison::default();
+        let mut seen = HashSet::new();
+        let mut pending = vec![PathBuf::new()];
+        while let Some(path) = pending.pop() {
+            let host = dir.join(&path);
+            let meta = std::fs::symlink_metadata(&host)?;
+            if meta.is_dir() {
+                for child in std::fs::read_dir(&host)? {
+                    pending.push(path.join(child?.file_name()));
+                }
+            }
+            let fields = rules.fields(&path);
+            match self.get(&path) {
+                Ok(entry) => {
+                    let cmp =
+                        self.cmp_host_entry(&path, entry, &host, &meta, fields, &rules.precision)?;
+                    let different = fields - Fields::PATH - cmp;
+                    if !different.is_empty() {
+                        comparison.changed.insert(path.as_path().into(), different);
+                    }
+                }
+                Err(_) => {
+                    if !path.as_os_str().is_empty() && fields.contains(Fields::PATH) {
+                        comparison.added.insert(path.as_path().into());
+                    }
+                }
+            }
+            seen.insert(path);
+        }
+        for (path, _) in self {
+            if !seen.contains(path) && rules.fields(path).contains(Fields::PATH) {
+                comparison.removed.insert(path.into());
+            }
+        }
+        Ok(comparison)
+    }
+
+    /// Compare a single entry against its counterpart on the host, only
+    /// doing the expensive work for the selected 'fields'.
+    fn cmp_host_entry(
+        &self,
+        path: &Path,
+        entry: &Entry,
+        host: &Path,
+        meta: &std::fs::Metadata,
+        fields: Fields,
+        precision: &Precision,
+    ) -> Result<Fields> {
+        let ft = meta.file_type();
+        let same_type = match entry {
+            Entry::Directory(_) => ft.is_dir(),
+            Entry::File(_) => ft.is_file(),
+            Entry::Special(s) => {
+                SFlag::from_bits_truncate(meta.mode() & SFlag::S_IFMT.bits()) == s.file_type()
+            }
+            Entry::Symlink(_) => ft.is_symlink(),
+        };
+        let mut host_metadata = Metadata::from(meta.clone());
+        if fields.contains(Fields::XATTR) {
+            for name in xattr::list(host)? {
+                if let Some(value) = xattr::get(host, &name)? {
+                    host_metadata
+                        .xattrs
+                        .insert(Bytes::copy_from_slice(name.as_bytes()), value.into());
+                }
+            }
+        }
+        let mut f = entry
+            .metadata()
+            .cmp_with_precision(&host_metadata, precision);
+        if !same_type {
+            f.remove(Fields::TYPE | Fields::DATA);
+        }
+        let host_size = match ft.is_file() || ft.is_symlink() {
+            true => meta.len(),
+            false => 0,
+        };
+        if entry.size() != host_size {
+            f.remove(Fields::SIZE | Fields::DATA);
+        }
+        if let Entry::Special(s) = entry {
+            if s.rdev().unwrap_or(0) != meta.rdev() {
+                f.remove(Fields::RDEV);
+            }
+        }
+        // the link count of a directory on the host includes its
+        // subdirectories, which is not a hardlink
+        if !entry.is_directory() && self.nlink(path)? as u64 != meta.nlink() {
+            f.remove(Fields::NLINK);
+        }
+        if fields.contains(Fields::DATA) && f.contains(Fields::DATA) {
+            let same = match entry {
+                Entry::File(file) => same_contents(file, host)?,
+                Entry::Symlink(s) => std::fs::read_link(host)? == s.target(),
+                _ => true,
+            };
+            if !same {
+                f.remove(Fields::DATA);
+            }
+        }
+        Ok(f)
+    }
+}
+
+/// Stream the contents of 'host' and compare them against 'file', which must
+/// already be known to have the same length.
+fn same_contents(file: &File, host: &Path) -> Result<bool> {
+    let mut host = std::fs::File::open(host)?;
+    let mut reader = file.reader();
+    let mut host_buf = vec![0; 64 * 1024];
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let n = host.read(&mut host_buf)?;
+        if n == 0 {
+            return Ok(true);
+        }
+        reader.read_exact(&mut buf[..n])?;
+        if host_buf[..n] != buf[..n] {
+            return Ok(false);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::collections::BTreeSet;
+
+    use nix::sys::stat::Mode;
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::fs;
+    use crate::BytesPath;
+
+    #[test]
+    fn from_dir() {
+        let tmp = tempfile::tempdir().unwrap();
+        std::fs::create_dir(tmp.path().join("dev")).unwrap();
+        std::fs::write(tmp.path().join("motd"), "hello\n").unwrap();
+        std::fs::hard_link(tmp.path().join("motd"), tmp.path().join("issue")).unwrap();
+        std::os::unix::fs::symlink("motd", tmp.path().join("link")).unwrap();
+        nix::unistd::mkfifo(
+            &tmp.path().join("dev/fifo"),
+            Mode::from_bits_truncate(0o600),
+        )
+        .unwrap();
+        let _socket =
+            std::os::unix::net::UnixListener::bind(tmp.path().join("dev/socket")).unwrap();
+        // only root can create device nodes
+        let null = nix::sys::stat::mknod(
+            &tmp.path().join("dev/null"),
+            SFlag::S_IFCHR,
+            Mode::from_bits_truncate(0o666),
+            nix::sys::stat::makedev(1, 3),
+        )
+        .is_ok();
+
+        let fs = Filesystem::from_dir(tmp.path()).unwrap();
+        // reading the files may have changed their atime
+        assert!(fs
+            .cmp_dir(tmp.path(), Fields::all() - Fields::TIME)
+            .unwrap()
+            .is_all());
+        assert_eq!(2, fs.nlink("issue").unwrap());
+        let special = |path| match fs.get(path).unwrap() {
+            Entry::Special(s) => (s.file_type(), s.rdev()),
+            e => panic!("{path} is not special: {e:?}"),
+        };
+        assert_eq!((SFlag::S_IFIFO, None), special("dev/fifo"));
+        assert_eq!((SFlag::S_IFSOCK, None), special("dev/socket"));
+        if null {
+            assert_eq!(
+                (SFlag::S_IFCHR, Some(nix::sys::stat::makedev(1, 3))),
+                special("dev/null")
+            );
+        }
+    }
+
+    #[test]
+    fn compare_dir() {
+        let tmp = tempfile::tempdir().unwrap();
+        std::fs::create_dir(tmp.path().join("etc")).unwrap();
+        std::fs::write(tmp.path().join("etc/motd"), "hello\n").unwrap();
+        std::fs::write(tmp.path().join("etc/issue"), "welcome\n").unwrap();
+        std::fs::write(tmp.path().join("extra"), "").unwrap();
+        std::os::unix::fs::symlink("etc/motd", tmp.path().join("motd")).unwrap();
+        let fs = fs! {
+            "etc": {
+                "motd": file("hello\n"),
+                "issue": file("WELCOME\n"),
+                "missing": file(""),
+            },
+            "motd": symlink("etc/motd"),
+        };
+        let fields = Fields::PATH | Fields::TYPE | Fields::DATA | Fields::SIZE;
+        let comparison = fs.compare_dir(tmp.path(), fields).unwrap();
+        assert_eq!(
+            BTreeMap::from([(BytesPath::from("etc/issue"), Fields::DATA)]),
+            *comparison.changed()
+        );
+        assert_eq!(BTreeSet::from(["extra".into()]), *comparison.added());
+        assert_eq!(
+            BTreeSet::from(["etc/missing".into()]),
+            *comparison.removed()
+        );
+
+        let mut rules = Rules::new(fields);
+        rules
+            .ignore("extra")
+            .unwrap()
+            .ignore("etc/missing")
+            .unwrap()
+            .rule("etc/issue", fields - Fields::DATA)
+            .unwrap();
+        assert!(fs.cmp_dir(tmp.path(), rules).unwrap().is_all());
+    }
+}
@@ -14,9 +14,14 @@ use getset::Getters;
 use nix::sys::stat::FileStat;
 use nix::sys::stat::Mode;
 use nix::sys::stat::SFlag;
+#[cfg(feature = "serde")]
+use serde::Deserialize;
+#[cfg(feature = "serde")]
+use serde::Serialize;
 
 use crate::cmp::ApproxEq;
 use crate::cmp::Fields;
+use crate::cmp::Precision;
 use crate::BytesPath;
 use crate::File;
 use crate::Gid;
@@ -24,6 +29,7 @@ use crate::Uid;
 
 /// A single directory entry in the filesystem.
 #[derive(Debug, Clone, PartialEq, Eq, From, IsVariant)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
 #[remain::sorted]
 pub enum Entry {
     Directory(Directory),
@@ -54,6 +60,19 @@ impl Entry {
         }
     }
 
+    /// Apparent size of this entry, as it would be reported in st_size. This
+    /// is the length of a regular file or symlink target, and 0 for
+    /// everything else.
+    pub fn size(&self) -> u64 {
+        #[remain::sorted]
+        match self {
+            Self::Directory(_) => 0,
+            Self::File(f) => f.len(),
+            Self::Special(_) => 0,
+            Self::Symlink(s) => s.target.len() as u64,
+        }
+    }
+
     pub fn chown(&mut self, uid: Uid, gid: Gid) {
         self.metadata_mut().chown(uid, gid);
     }
@@ -73,24 +92,43 @@ impl Entry {
 
 impl ApproxEq for Entry {
     fn cmp(&self, other: &Self) -> Fields {
-        let f = self.metadata().cmp(other.metadata());
+        self.cmp_with_precision(other, &Precision::default())
+    }
+}
+
+impl Entry {
+    /// [ApproxEq::cmp] with the given 'precision'.
+    pub(crate) fn cmp_with_precision(&self, other: &Self, precision: &Precision) -> Fields {
+        let mut f = self
+            .metadata()
+            .cmp_with_precision(other.metadata(), precision);
+        if self.size() != other.size() {
+            f.remove(Fields::SIZE);
+        }
+        // metadata was already compared above with the requested precision,
+        // so only the type-specific fields matter here
+        let metadata = Fields::MODE | Fields::OWNER | Fields::XATTR | Fields::TIME;
         match (self, other) {
-            (Self::Directory(s), Self::Directory(o)) => f.intersection(s.cmp(o)),
+            (Self::Directory(s), Self::Directory(o)) => f.intersection(s.cmp(o) | metadata),
             (Self::Directory(_), _) => f - Fields::TYPE,
-            (Self::File(s), Self::File(o)) => f.intersection(s.cmp(o)),
+            (Self::File(s), Self::File(o)) => {
+                f.intersection(s.cmp_with_precision(o, precision) | metadata)
+            }
             (Self::File(_), _) => f - Fields::TYPE,
-            (Self::Special(_), Self::Special(_)) => self.metadata().cmp(other.metadata()),
+            (Self::Special(s), Self::Special(o)) => f.intersection(s.cmp(o) | metadata),
             (Self::Special(_), _) => f - Fields::TYPE,
-            (Self::Symlink(s), Self::Symlink(o)) => f.intersection(s.cmp(o)),
+            (Self::Symlink(s), Self::Symlink(o)) => f.intersection(s.cmp(o) | metadata),
             (Self::Symlink(_), _) => f - Fields::TYPE,
         }
     }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Getters, CopyGetters, Builder)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
 #[builder(default, setter(into), build_fn(private, name = "fallible_build"))]
 pub struct Metadata {
     #[get_copy = "pub"]
+    #[cfg_attr(feature = "serde", serde(with = "crate::ser::mode"))]
     pub(crate) mode: Mode,
     #[get_copy = "pub"]
     pub(crate) uid: Uid,
@@ -204,8 +242,15 @@ impl MetadataBuilder {
 }
 
 impl ApproxEq for Metadata {
-    #[deny(unused_variables)]
     fn cmp(&self, other: &Self) -> Fields {
+        self.cmp_with_precision(other, &Precision::default())
+    }
+}
+
+impl Metadata {
+    /// [ApproxEq::cmp] with the given 'precision'.
+    #[deny(unused_variables)]
+    pub(crate) fn cmp_with_precision(&self, other: &Self, precision: &Precision) -> Fields {
         let Self {
             mode,
             uid,
@@ -216,16 +261,20 @@ impl ApproxEq for Metadata {
             modified,
         } = self;
         let mut f = Fields::all();
-        if *mode != other.mode {
+        if !precision.same_mode(*mode, other.mode) {
             f.remove(Fields::MODE);
         }
-        if *uid != other.uid || *gid != other.gid {
+        if !precision.same_owner((*uid, *gid), (other.uid, other.gid)) {
             f.remove(Fields::OWNER);
         }
-        if *xattrs != other.xattrs {
+        if !precision.same_xattrs(xattrs, &other.xattrs) {
             f.remove(Fields::XATTR);
         }
-        if *created != other.created || *accessed != other.accessed || *modified != other.modified {
+        let same_time = |a, b| precision.time.same_time(a, b);
+        if !same_time(*created, other.created)
+            || !same_time(*accessed, other.accessed)
+            || !same_time(*modified, other.modified)
+        {
             f.remove(Fields::TIME);
         }
         f
@@ -233,6 +282,7 @@ impl ApproxEq for Metadata {
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Default, Builder)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
 #[builder(default, setter(into), build_fn(private, name = "fallible_build"))]
 pub struct Directory {
     metadata: Metadata,
@@ -260,8 +310,10 @@ impl ApproxEq for Directory {
 
 /// A special file (device node, socket, fifo, etc)
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
 pub struct Special {
     /// Special file type
+    #[cfg_attr(feature = "serde", serde(with = "crate::ser::sflag"))]
     file_type: SFlag,
     rdev: u64,
     metadata: Metadata,
@@ -312,6 +364,7 @@ impl ApproxEq for Special {
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
 pub struct Symlink {
     /// Target path
     target: BytesPath,
@@ -351,3 +404,25 @@ impl ApproxEq for Symlink {
         f
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn size() {
+        assert_eq!(0, Entry::from(Directory::default()).size());
+        assert_eq!(
+            "Lorem ipsum".len() as u64,
+            Entry::from(File::builder().contents("Lorem ipsum").build()).size()
+        );
+        assert_eq!(
+            "../lorem.txt".len() as u64,
+            Entry::from(Symlink::new("../lorem.txt", None)).size()
+        );
+        assert_eq!(
+            0,
+            Entry::from(Special::new(SFlag::S_IFIFO, 0, Metadata::default())).size()
+        );
+    }
+}
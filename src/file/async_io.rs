@@ -0,0 +1,91 @@
+//! [tokio] adapters for [Reader] and [Writer], so that files can be served
+//! directly from async code. All of the data is already in memory, so every
+//! operation completes immediately.
+
+use std::io::Read;
+use std::io::Result;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::pin::Pin;
+use std::task::Context;
+use std::task::Poll;
+
+use tokio::io::AsyncRead;
+use tokio::io::AsyncSeek;
+use tokio::io::AsyncWrite;
+use tokio::io::ReadBuf;
+
+use super::reader::Reader;
+use super::writer::Writer;
+
+impl AsyncRead for Reader<'_> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<()>> {
+        let n = Read::read(self.get_mut(), buf.initialize_unfilled())?;
+        buf.advance(n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncSeek for Reader<'_> {
+    fn start_seek(self: Pin<&mut Self>, position: SeekFrom) -> Result<()> {
+        Seek::seek(self.get_mut(), position).map(|_| ())
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<u64>> {
+        Poll::Ready(Seek::stream_position(self.get_mut()))
+    }
+}
+
+impl AsyncWrite for Writer<'_> {
+    fn poll_write(self: Pin<&mut Self>, _cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize>> {
+        Poll::Ready(Ok(self.get_mut().write_copy(&[buf])))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncSeek for Writer<'_> {
+    fn start_seek(self: Pin<&mut Self>, position: SeekFrom) -> Result<()> {
+        Seek::seek(self.get_mut(), position).map(|_| ())
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<u64>> {
+        Poll::Ready(Seek::stream_position(self.get_mut()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::SeekFrom;
+
+    use tokio::io::AsyncReadExt;
+    use tokio::io::AsyncSeekExt;
+    use tokio::io::AsyncWriteExt;
+
+    use crate::File;
+
+    #[tokio::test]
+    async fn read_write_seek() {
+        let mut f = File::new_empty();
+        let mut w = f.writer();
+        w.write_all(b"Lorem dolor").await.unwrap();
+        w.seek(SeekFrom::Start(6)).await.unwrap();
+        w.write_all(b"ipsum").await.unwrap();
+
+        let mut r = f.reader();
+        assert_eq!(6, r.seek(SeekFrom::Start(6)).await.unwrap());
+        let mut s = String::new();
+        r.read_to_string(&mut s).await.unwrap();
+        assert_eq!("ipsum", s);
+    }
+}
@@ -1,11 +1,26 @@
+use std::io::Error;
+use std::io::ErrorKind;
+use std::io::Read;
+use std::io::Result;
+use std::ops::Range;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::OnceLock;
+
 use bytes::Bytes;
+#[cfg(feature = "serde")]
+use serde::Deserialize;
+#[cfg(feature = "serde")]
+use serde::Serialize;
 
-use super::File;
+use crate::BytesPath;
 
 /// A single piece of data that makes up a file. Immutable but can be composed
 /// with other Extents in order to implement mutable files on top of immutable
 /// extent chunks.
 #[derive(Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
 pub enum Extent {
     /// The source-of-truth for this data is the file that contains it. It
     /// originated from a write to that File, not a clone from another.
@@ -14,33 +29,97 @@ pub enum Extent {
     Cloned(Cloned),
     /// This extent was created with 'truncate' and is actually empty
     Hole(u64),
+    /// A region of a file on the host that is only read the first time the
+    /// data is needed.
+    Backed(Backed),
+    /// Compressed data that is only decompressed the first time the data is
+    /// needed.
+    Compressed(Compressed),
 }
 
 impl Extent {
+    /// Reference 'len' bytes at 'offset' of 'file' without reading them yet.
+    /// The data must not change while this extent (or any [File](super::File) containing
+    /// it) is alive. Fails with [ErrorKind::UnexpectedEof] if 'file' is too
+    /// short.
+    pub fn backed(file: Arc<std::fs::File>, offset: u64, len: u64) -> Result<Self> {
+        let end = offset
+            .checked_add(len)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "offset + len overflows"))?;
+        if end > file.metadata()?.len() {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "backed extent extends past the end of the file",
+            ));
+        }
+        Ok(Self::Backed(Backed {
+            file: Some(file),
+            offset,
+            len,
+            data: Default::default(),
+        }))
+    }
+
+    /// Compressed 'data' that decompresses to 'len' bytes, which is not kept
+    /// in memory until it is read. The data is checked by decompressing it
+    /// (without keeping the output) up front, and fails with
+    /// [ErrorKind::InvalidData] if it is corrupt or has the wrong length.
+    pub fn compressed(compression: Compression, data: impl Into<Bytes>, len: u64) -> Result<Self> {
+        Compressed::new(compression, data.into(), len).map(Self::Compressed)
+    }
+
     pub fn len(&self) -> u64 {
         match self {
             Self::Hole(s) => *s,
-            _ => self.data().len() as u64,
+            Self::Backed(b) => b.len,
+            Self::Compressed(c) => c.len,
+            Self::Owned(o) => o.len() as u64,
+            Self::Cloned(c) => c.data.len() as u64,
         }
     }
 
     pub fn is_empty(&self) -> bool {
-        self.data().is_empty()
+        self.len() == 0
     }
 
-    pub fn data(&self) -> &[u8] {
+    pub fn is_hole(&self) -> bool {
+        matches!(self, Self::Hole(_))
+    }
+
+    /// Fails only if this is [Extent::Backed] and the backing file can no
+    /// longer be read.
+    pub fn data(&self) -> Result<&[u8]> {
         match self {
-            Self::Owned(c) => c,
-            Self::Cloned(c) => &c.data,
-            Self::Hole(_) => &[],
+            Self::Owned(c) => Ok(c),
+            Self::Cloned(c) => Ok(&c.data),
+            Self::Hole(_) => Ok(&[]),
+            Self::Backed(b) => b.load().map(|data| &data[..]),
+            Self::Compressed(c) => Ok(c.data()),
         }
     }
 
-    pub fn bytes(&self) -> Bytes {
+    /// See [Extent::data]
+    pub fn bytes(&self) -> Result<Bytes> {
         match self {
-            Self::Owned(c) => c.clone(),
-            Self::Cloned(c) => c.data.clone(),
-            Self::Hole(_) => Bytes::new(),
+            Self::Owned(c) => Ok(c.clone()),
+            Self::Cloned(c) => Ok(c.data.clone()),
+            Self::Hole(_) => Ok(Bytes::new()),
+            Self::Backed(b) => b.load().cloned(),
+            Self::Compressed(c) => Ok(c.data().clone()),
+        }
+    }
+
+    /// Whether 'other' is the same extent, sharing the same memory, so that
+    /// it is equal without having to compare the data.
+    pub(super) fn same_storage(&self, other: &Self) -> bool {
+        let same = |a: &Bytes, b: &Bytes| a.as_ptr() == b.as_ptr() && a.len() == b.len();
+        match (self, other) {
+            (Self::Owned(a), Self::Owned(b)) => same(a, b),
+            (Self::Cloned(a), Self::Cloned(b)) => {
+                same(&a.data, &b.data) && a.src_path == b.src_path && a.src_range == b.src_range
+            }
+            (Self::Hole(a), Self::Hole(b)) => a == b,
+            _ => false,
         }
     }
 
@@ -52,13 +131,12 @@ impl Extent {
             }
             Self::Cloned(ref mut c) => {
                 let right = c.data.split_off(at);
-                c.src_range = (c.src_range.0, std::cmp::min(at as u64, c.src_range.1));
+                let mid = c.src_range.0 + at as u64;
+                let right_range = (mid, c.src_range.1);
+                c.src_range.1 = mid;
                 Self::Cloned(Cloned {
-                    src_file: c.src_file.clone(),
-                    src_range: (
-                        std::cmp::max(at as u64, c.src_range.0),
-                        std::cmp::min(at as u64, c.src_range.1),
-                    ),
+                    src_path: c.src_path.clone(),
+                    src_range: right_range,
                     data: right,
                 })
             }
@@ -67,17 +145,243 @@ impl Extent {
                 *h = at as u64;
                 right
             }
+            Self::Backed(ref mut b) => {
+                // anything that was already read is kept by both halves
+                let right_data = match b.data.get() {
+                    Some(data) => {
+                        let mut left = data.clone();
+                        let right = left.split_off(at);
+                        b.data = Arc::new(OnceLock::from(left));
+                        Arc::new(OnceLock::from(right))
+                    }
+                    None => Default::default(),
+                };
+                let right = Self::Backed(Backed {
+                    file: b.file.clone(),
+                    offset: b.offset + at as u64,
+                    len: b.len - at as u64,
+                    data: right_data,
+                });
+                b.len = at as u64;
+                right
+            }
+            // compressed data cannot be split without decompressing it
+            Self::Compressed(c) => {
+                let mut left = c.data().clone();
+                let right = left.split_off(at);
+                *self = Self::Owned(left);
+                Self::Owned(right)
+            }
+        }
+    }
+}
+
+/// Compression formats supported by [Extent::Compressed], each behind a
+/// feature of the same name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+pub enum Compression {
+    /// A single gzip member
+    #[cfg(feature = "gzip")]
+    Gzip,
+    /// A single zlib stream, like btrfs uses
+    #[cfg(feature = "gzip")]
+    Zlib,
+    /// One or more zstd frames
+    #[cfg(feature = "zstd")]
+    Zstd,
+}
+
+/// A [Compressed](Extent::Compressed) extent decompresses its data on first
+/// access, and shares it with all of its clones after that.
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct Compressed {
+    compression: Compression,
+    data: Bytes,
+    len: u64,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    decompressed: Arc<OnceLock<Bytes>>,
+}
+
+impl Compressed {
+    /// Make sure that 'data' decompresses to exactly 'len' bytes, so that
+    /// decompressing it later cannot fail.
+    fn new(compression: Compression, data: Bytes, len: u64) -> Result<Self> {
+        let compressed = Self {
+            compression,
+            data,
+            len,
+            decompressed: Default::default(),
+        };
+        let invalid = |msg: String| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("{compression:?} extent {msg}"),
+            )
+        };
+        let decompressed_len = std::io::copy(
+            &mut compressed.decoder()?.take(len.saturating_add(1)),
+            &mut std::io::sink(),
+        )
+        .map_err(|e| invalid(format!("cannot be decompressed: {e}")))?;
+        if decompressed_len != len {
+            return Err(invalid(format!(
+                "decompressed to {decompressed_len} bytes instead of {len}"
+            )));
+        }
+        Ok(compressed)
+    }
+
+    fn decoder(&self) -> Result<Box<dyn Read + '_>> {
+        match self.compression {
+            #[cfg(feature = "gzip")]
+            Compression::Gzip => Ok(Box::new(flate2::read::GzDecoder::new(&*self.data))),
+            #[cfg(feature = "gzip")]
+            Compression::Zlib => Ok(Box::new(flate2::read::ZlibDecoder::new(&*self.data))),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => Ok(Box::new(zstd::Decoder::with_buffer(&*self.data)?)),
+        }
+    }
+
+    fn data(&self) -> &Bytes {
+        self.decompressed.get_or_init(|| {
+            let mut buf = Vec::with_capacity(self.len as usize);
+            self.decoder()
+                .and_then(|mut decoder| decoder.read_to_end(&mut buf))
+                .expect("data was checked when the extent was created");
+            buf.into()
+        })
+    }
+}
+
+/// Deserialized data is checked just like in [Extent::compressed].
+#[cfg(feature = "serde")]
+impl<'de> Deserialize<'de> for Compressed {
+    fn deserialize<D: serde::Deserializer<'de>>(
+        deserializer: D,
+    ) -> std::result::Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        struct Raw {
+            compression: Compression,
+            data: Bytes,
+            len: u64,
+        }
+        let Raw {
+            compression,
+            data,
+            len,
+        } = Raw::deserialize(deserializer)?;
+        Self::new(compression, data, len).map_err(serde::de::Error::custom)
+    }
+}
+
+impl PartialEq for Compressed {
+    fn eq(&self, other: &Self) -> bool {
+        self.len == other.len && self.data() == other.data()
+    }
+}
+
+impl Eq for Compressed {}
+
+impl Cloned {
+    /// Path of the file this extent was cloned from, if it is known.
+    pub fn src_path(&self) -> Option<&Path> {
+        self.src_path.as_deref()
+    }
+
+    /// Range of the original file that this extent was cloned from.
+    pub fn src_range(&self) -> Range<u64> {
+        self.src_range.0..self.src_range.1
+    }
+}
+
+/// A [Backed](Extent::Backed) extent reads its data from a host file on
+/// first access, and shares it with all of its clones after that.
+#[derive(Clone)]
+pub struct Backed {
+    /// Only [None] after deserializing, when the data is already loaded
+    pub(super) file: Option<Arc<std::fs::File>>,
+    pub(super) offset: u64,
+    len: u64,
+    data: Arc<OnceLock<Bytes>>,
+}
+
+impl Backed {
+    /// The backing file and the offset of this extent in it, if the data
+    /// did not come from somewhere else.
+    pub(crate) fn source(&self) -> Option<(&std::fs::File, u64)> {
+        self.file.as_deref().map(|file| (file, self.offset))
+    }
+
+    /// Read the data from the backing file, unless that already happened.
+    fn load(&self) -> Result<&Bytes> {
+        if let Some(data) = self.data.get() {
+            return Ok(data);
         }
+        let file = self.file.as_ref().expect("data is loaded without a file");
+        let mut buf = vec![0; self.len as usize];
+        file.read_exact_at(&mut buf, self.offset)?;
+        Ok(self.data.get_or_init(|| buf.into()))
+    }
+}
+
+/// Extents of the same region of the same file are equal without reading
+/// them. Otherwise the data is compared, and an extent that cannot be read
+/// is not equal to any other.
+impl PartialEq for Backed {
+    fn eq(&self, other: &Self) -> bool {
+        if self.len != other.len {
+            return false;
+        }
+        match (&self.file, &other.file) {
+            (Some(a), Some(b)) if Arc::ptr_eq(a, b) && self.offset == other.offset => true,
+            _ => match (self.load(), other.load()) {
+                (Ok(a), Ok(b)) => a == b,
+                _ => false,
+            },
+        }
+    }
+}
+
+impl Eq for Backed {}
+
+/// Serialized as just the data, which is loaded if it was not already.
+#[cfg(feature = "serde")]
+impl Serialize for Backed {
+    fn serialize<S: serde::Serializer>(
+        &self,
+        serializer: S,
+    ) -> std::result::Result<S::Ok, S::Error> {
+        self.load()
+            .map_err(serde::ser::Error::custom)?
+            .serialize(serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> Deserialize<'de> for Backed {
+    fn deserialize<D: serde::Deserializer<'de>>(
+        deserializer: D,
+    ) -> std::result::Result<Self, D::Error> {
+        let data = Bytes::deserialize(deserializer)?;
+        Ok(Self {
+            file: None,
+            offset: 0,
+            len: data.len() as u64,
+            data: Arc::new(OnceLock::from(data)),
+        })
     }
 }
 
-/// A Cloned [Extent] comes from another file. This extent references the
-/// original [File] and the location in that file for debuggability of BTRFS
-/// sendstreams.
+/// A Cloned [Extent] comes from another file. This extent shares the data of
+/// the original extent, and remembers where it came from (when cloned with
+/// [Filesystem::copy_file_range](crate::Filesystem::copy_file_range)) for
+/// debuggability of BTRFS sendstreams.
 #[derive(Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
 pub struct Cloned {
-    // TODO: figure out a way to reference the original file better
-    pub(super) src_file: File,
+    pub(super) src_path: Option<BytesPath>,
     pub(super) src_range: (u64, u64),
     pub(super) data: Bytes,
 }
@@ -92,13 +396,26 @@ impl std::fmt::Debug for Extent {
                         d.field(&s);
                     }
                     Err(_) => {
-                        d.field(&self.data());
+                        d.field(&&o[..]);
                     }
                 }
                 d.finish()
             }
             Self::Cloned(c) => f.debug_tuple("Cloned").field(&c).finish(),
             Self::Hole(h) => f.debug_tuple("Hole").field(&h).finish(),
+            Self::Backed(b) => f
+                .debug_struct("Backed")
+                .field("offset", &b.offset)
+                .field("len", &b.len)
+                .field("loaded", &b.data.get().is_some())
+                .finish(),
+            Self::Compressed(c) => f
+                .debug_struct("Compressed")
+                .field("compression", &c.compression)
+                .field("compressed_len", &c.data.len())
+                .field("len", &c.len)
+                .field("decompressed", &c.decompressed.get().is_some())
+                .finish(),
         }
     }
 }
@@ -107,12 +424,12 @@ impl std::fmt::Debug for Cloned {
     #[deny(unused_variables)]
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         let Self {
-            src_file,
+            src_path,
             src_range,
             data,
         } = self;
         let mut d = f.debug_struct("Cloned");
-        d.field("src_file", &src_file);
+        d.field("src_path", &src_path);
         d.field("src_range", &src_range);
         match std::str::from_utf8(data) {
             Ok(s) => {
@@ -138,6 +455,7 @@ where
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::File;
 
     #[test]
     fn extent_split() {
@@ -148,4 +466,104 @@ mod tests {
         assert_eq!(left, "Lorem".into());
         assert_eq!(right, " ipsum".into());
     }
+
+    #[test]
+    fn cloned_split() {
+        let src = File::builder().contents("Lorem ipsum dolor").build();
+        let mut ext = src.clone_range(6..17).unwrap().remove(0);
+        let right = ext.split_at("ipsum".len());
+        for (ext, data, range) in [(ext, "ipsum", 6..11), (right, " dolor", 11..17)] {
+            match ext {
+                Extent::Cloned(c) => {
+                    assert_eq!(data.as_bytes(), c.data);
+                    assert_eq!(range, c.src_range());
+                }
+                e => panic!("expected a clone, got {e:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn backed() {
+        let path = "testdata/testdata.tar";
+        let offset = std::fs::read(path)
+            .unwrap()
+            .windows(5)
+            .position(|w| w == b"Lorem")
+            .expect("lorem.txt is in the tar") as u64;
+        let backed = || {
+            let host = Arc::new(std::fs::File::open(path).unwrap());
+            Extent::backed(host, offset + "Lorem ".len() as u64, "ipsum".len() as u64).unwrap()
+        };
+        let mut ext = backed();
+        assert_eq!(5, ext.len());
+        let Extent::Backed(b) = &ext else {
+            unreachable!()
+        };
+        assert!(b.data.get().is_none(), "nothing is read up front");
+        let clone = ext.clone();
+        assert_eq!(b"ipsum", ext.data().unwrap());
+        let Extent::Backed(b) = &clone else {
+            unreachable!()
+        };
+        assert!(b.data.get().is_some(), "clones share the data");
+
+        let right = backed().split_at(2);
+        assert_eq!(b"sum", right.data().unwrap());
+        assert_eq!(ext.split_at(2), right);
+        assert_eq!(b"ip", ext.data().unwrap());
+
+        let host = Arc::new(std::fs::File::open(path).unwrap());
+        let len = host.metadata().unwrap().len();
+        assert_eq!(
+            ErrorKind::UnexpectedEof,
+            Extent::backed(host, len - 1, 2).unwrap_err().kind()
+        );
+
+        // a backing file that shrinks later is an error when reading
+        let tmp = tempfile::tempfile().unwrap();
+        tmp.write_all_at(b"Lorem ipsum", 0).unwrap();
+        let tmp = Arc::new(tmp);
+        let f = File::builder()
+            .contents(Extent::backed(tmp.clone(), 0, 11).unwrap())
+            .build();
+        tmp.set_len(5).unwrap();
+        let mut buf = Vec::new();
+        assert_eq!(
+            ErrorKind::UnexpectedEof,
+            f.reader().read_to_end(&mut buf).unwrap_err().kind()
+        );
+        assert_eq!(ErrorKind::UnexpectedEof, f.to_bytes().unwrap_err().kind());
+        // comparing does not panic, and only the same region is still equal
+        assert_ne!(f, File::builder().contents("Lorem ipsum").build());
+        assert_eq!(f, f.clone());
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn compressed() {
+        let data = "Lorem ipsum dolor sit amet\n".repeat(100);
+        let zstd = zstd::encode_all(data.as_bytes(), 0).unwrap();
+        let mut ext =
+            Extent::compressed(Compression::Zstd, zstd.clone(), data.len() as u64).unwrap();
+        assert_eq!(data.len() as u64, ext.len());
+        let clone = ext.clone();
+        assert_eq!(data.as_bytes(), ext.data().unwrap());
+        let Extent::Compressed(c) = &clone else {
+            unreachable!()
+        };
+        assert!(c.decompressed.get().is_some(), "clones share the data");
+        let right = ext.split_at(6);
+        assert_eq!(Extent::from("Lorem "), ext);
+        assert_eq!(&data.as_bytes()[6..], right.data().unwrap());
+
+        for (data, len) in [(&b"not zstd"[..], 4), (&zstd[..], data.len() as u64 - 1)] {
+            assert_eq!(
+                ErrorKind::InvalidData,
+                Extent::compressed(Compression::Zstd, data.to_vec(), len)
+                    .unwrap_err()
+                    .kind()
+            );
+        }
+    }
 }
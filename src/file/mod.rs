@@ -1,20 +1,48 @@
 use std::borrow::Cow;
 use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::hash::Hasher;
+use std::io::ErrorKind;
 use std::io::Read;
 use std::ops::Range;
+use std::sync::OnceLock;
 
+use bytes::Bytes;
+use bytes::BytesMut;
 use derive_builder::Builder;
+use digest::Digest;
+use digest::Output;
+#[cfg(feature = "serde")]
+use serde::Deserialize;
+#[cfg(feature = "serde")]
+use serde::Serialize;
+use twox_hash::xxh3::Hash128;
+use twox_hash::xxh3::HasherExt;
 
+#[cfg(feature = "async")]
+mod async_io;
 pub mod extent;
+pub mod provenance;
 pub mod reader;
+pub mod slice;
 pub mod writer;
 
 use extent::Cloned;
 use extent::Extent;
 
 use crate::cmp::ApproxEq;
+use crate::cmp::DataComparison;
 use crate::cmp::Fields;
+use crate::cmp::Precision;
 use crate::entry::Metadata;
+use crate::BytesPath;
+
+/// Size of each [Extent] created by [File::from_reader].
+const DEFAULT_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Part of a range of a [File] and the extent that covers it (along with
+/// where that extent starts), or [None] for a gap between extents.
+type Piece<'f> = (Range<u64>, Option<(u64, &'f Extent)>);
 
 /// A single file in the filesystem. This has a number of metadata attributes
 /// alongside the file contents.
@@ -22,13 +50,64 @@ use crate::entry::Metadata;
 /// be a completely zero-copy reference to the underlying filesystem-in-a-file
 /// but also be mutable (useful for things like BTRFS sendstreams that contain a
 /// sequence of mutation operations instead of raw file contents).
-#[derive(Debug, Clone, PartialEq, Eq, Default, Builder)]
+#[derive(Debug, Clone, Default, Builder)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
 #[builder(default, setter(into), build_fn(private, name = "fallible_build"))]
 pub struct File {
     pub(crate) extents: BTreeMap<u64, Extent>,
     pub(crate) metadata: Metadata,
+    #[builder(setter(skip))]
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub(crate) digest: DigestCache,
+}
+
+/// Lazily computed hash of the contents of a [File], which is reset whenever
+/// the contents change. This is purely a cache, so it never affects equality.
+#[derive(Clone, Default)]
+pub(crate) struct DigestCache(OnceLock<u128>);
+
+impl PartialEq for DigestCache {
+    fn eq(&self, _other: &Self) -> bool {
+        true
+    }
+}
+
+impl Eq for DigestCache {}
+
+impl Debug for DigestCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("DigestCache")
+    }
 }
 
+/// Files that share all of their extents (like clones of each other) are
+/// equal without comparing their data, and files whose digests were already
+/// computed and differ are unequal without comparing it either.
+impl PartialEq for File {
+    fn eq(&self, other: &Self) -> bool {
+        #[deny(unused_variables)]
+        let Self {
+            extents,
+            metadata,
+            digest,
+        } = self;
+        if *metadata != other.metadata {
+            return false;
+        }
+        if self.shares_extents(other) {
+            return true;
+        }
+        if let (Some(left), Some(right)) = (digest.0.get(), other.digest.0.get()) {
+            if left != right {
+                return false;
+            }
+        }
+        *extents == other.extents
+    }
+}
+
+impl Eq for File {}
+
 impl FileBuilder {
     /// Set the contents of the [File] to a single [Extent] blob.
     pub fn contents(&mut self, contents: impl Into<Extent>) -> &mut Self {
@@ -49,6 +128,48 @@ impl File {
         Self::builder().build()
     }
 
+    /// Read all of 'reader' into a new file, see
+    /// [File::from_reader_with_chunk_size].
+    pub fn from_reader(reader: impl Read) -> std::io::Result<Self> {
+        Self::from_reader_with_chunk_size(reader, DEFAULT_CHUNK_SIZE)
+    }
+
+    /// Read all of 'reader' into a new file, with one [Extent] for every
+    /// 'chunk_size' bytes, so the caller never needs to buffer the whole
+    /// stream. A 'chunk_size' of 0 is an [ErrorKind::InvalidInput] error.
+    pub fn from_reader_with_chunk_size(
+        mut reader: impl Read,
+        chunk_size: usize,
+    ) -> std::io::Result<Self> {
+        if chunk_size == 0 {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                "chunk_size must not be 0",
+            ));
+        }
+        let mut file = Self::new_empty();
+        let mut writer = file.writer();
+        let mut buf = vec![0; chunk_size];
+        loop {
+            let mut len = 0;
+            while len < chunk_size {
+                match reader.read(&mut buf[len..]) {
+                    Ok(0) => break,
+                    Ok(n) => len += n,
+                    Err(e) if e.kind() == ErrorKind::Interrupted => (),
+                    Err(e) => return Err(e),
+                }
+            }
+            if len > 0 {
+                writer.write(Bytes::copy_from_slice(&buf[..len]));
+            }
+            if len < chunk_size {
+                break;
+            }
+        }
+        Ok(file)
+    }
+
     pub fn is_empty(&self) -> bool {
         self.extents.is_empty()
     }
@@ -60,18 +181,111 @@ impl File {
             .unwrap_or(0)
     }
 
+    /// Every extent in this file along with its logical offset, in order,
+    /// like FIEMAP. Gaps between extents (left by writing past the end of
+    /// the file) are not extents, so they are not included.
+    pub fn extents(&self) -> impl Iterator<Item = (u64, &Extent)> + '_ {
+        self.extents.iter().map(|(start, ext)| (*start, ext))
+    }
+
     /// Copy all of the extents in this file into a single contiguous array of
-    /// bytes.
-    pub fn to_bytes(&self) -> Cow<'_, [u8]> {
-        match self.extents.len() {
-            0 => Cow::Borrowed(&[]),
-            1 => Cow::Borrowed(self.extents[&0].data()),
+    /// bytes. Fails only if an [Extent::Backed] cannot be read.
+    pub fn to_bytes(&self) -> std::io::Result<Cow<'_, [u8]>> {
+        match self.extents.first_key_value() {
+            None => Ok(Cow::Borrowed(&[])),
+            Some((0, ext)) if self.extents.len() == 1 && !ext.is_hole() => {
+                ext.data().map(Cow::Borrowed)
+            }
             _ => {
                 let mut v = Vec::with_capacity(self.len() as usize);
-                self.reader().read_to_end(&mut v).expect("infallible");
-                Cow::Owned(v)
+                self.reader().read_to_end(&mut v)?;
+                Ok(Cow::Owned(v))
+            }
+        }
+    }
+
+    /// Hash the contents of this file as they would appear to read(2), without
+    /// first copying them into a contiguous buffer.
+    pub fn digest<D: Digest>(&self) -> std::io::Result<Output<D>> {
+        let mut d = D::new();
+        let mut reader = self.reader();
+        let mut buf = [0; 64 * 1024];
+        loop {
+            match reader.read(&mut buf)? {
+                0 => break,
+                n => d.update(&buf[..n]),
             }
         }
+        Ok(d.finalize())
+    }
+
+    /// Whether this file has the same contents as 'other' as they would
+    /// appear to read(2), regardless of how they are split into extents.
+    /// Neither file is ever copied into a contiguous buffer.
+    pub fn same_data(&self, other: &File, mode: DataComparison) -> std::io::Result<bool> {
+        if self.len() != other.len() {
+            return Ok(false);
+        }
+        if self.shares_extents(other) {
+            return Ok(true);
+        }
+        // digests are only used to tell that files are different if they
+        // were already computed, since computing them reads all the data
+        if let (Some(left), Some(right)) = (self.digest.0.get(), other.digest.0.get()) {
+            if left != right {
+                return Ok(false);
+            }
+        }
+        if self.extents == other.extents {
+            return Ok(true);
+        }
+        match mode {
+            DataComparison::Exact => {
+                let (mut left, mut right) = (self.reader(), other.reader());
+                let mut left_buf = vec![0; 64 * 1024];
+                let mut right_buf = vec![0; 64 * 1024];
+                loop {
+                    let n = left.read(&mut left_buf)?;
+                    if n == 0 {
+                        return Ok(true);
+                    }
+                    right.read_exact(&mut right_buf[..n])?;
+                    if left_buf[..n] != right_buf[..n] {
+                        return Ok(false);
+                    }
+                }
+            }
+            DataComparison::Digest => Ok(self.data_digest()? == other.data_digest()?),
+        }
+    }
+
+    /// Whether both files have exactly the same extents sharing the same
+    /// memory, which is very cheap to check compared to the data.
+    fn shares_extents(&self, other: &Self) -> bool {
+        self.extents.len() == other.extents.len()
+            && self
+                .extents
+                .iter()
+                .zip(&other.extents)
+                .all(|((l_start, l), (r_start, r))| l_start == r_start && l.same_storage(r))
+    }
+
+    /// Non-cryptographic 128-bit hash of the contents, which is computed at
+    /// most once until the file is modified.
+    pub(crate) fn data_digest(&self) -> std::io::Result<u128> {
+        if let Some(digest) = self.digest.0.get() {
+            return Ok(*digest);
+        }
+        let mut hasher = Hash128::with_seed(0);
+        let mut reader = self.reader();
+        let mut buf = [0; 64 * 1024];
+        loop {
+            match reader.read(&mut buf)? {
+                0 => break,
+                n => hasher.write(&buf[..n]),
+            }
+        }
+        Ok(*self.digest.0.get_or_init(|| hasher.finish_ext()))
     }
 
     /// Find the extent that contains the byte at 'pos'
@@ -80,7 +294,7 @@ impl File {
             .range(..pos + 1)
             .next_back()
             .map(|(start, e)| (*start, e))
-            .filter(|(start, e)| pos <= start + e.len())
+            .filter(|(start, e)| pos < start + e.len())
     }
 
     /// See [File::extent_for_byte]
@@ -89,30 +303,61 @@ impl File {
             .range_mut(..pos + 1)
             .next_back()
             .map(|(start, e)| (*start, e))
-            .filter(|(start, e)| pos <= start + e.len())
+            .filter(|(start, e)| pos < start + e.len())
+    }
+
+    /// Extents that make up 'range' of this file, which share its data.
+    /// Holes (and gaps between extents) in the range stay holes. Fails only
+    /// if an [Extent::Backed] in the range cannot be read.
+    pub fn clone_range(&self, range: Range<u64>) -> std::io::Result<Vec<Extent>> {
+        self.clone_range_from(range, None)
     }
 
-    pub fn clone_range(&self, range: Range<u64>) -> Vec<Extent> {
+    /// [File::clone_range], recording that this file is at 'src_path'.
+    pub(crate) fn clone_range_from(
+        &self,
+        range: Range<u64>,
+        src_path: Option<&BytesPath>,
+    ) -> std::io::Result<Vec<Extent>> {
+        self.pieces(range)
+            .into_iter()
+            .map(|(range, ext)| match ext {
+                Some((ext_start, ext)) if !ext.is_hole() => Ok(Extent::Cloned(Cloned {
+                    src_path: src_path.cloned(),
+                    src_range: (range.start, range.end),
+                    data: ext.bytes()?.slice(
+                        (range.start - ext_start) as usize..(range.end - ext_start) as usize,
+                    ),
+                })),
+                _ => Ok(Extent::Hole(range.end - range.start)),
+            })
+            .collect()
+    }
+
+    /// Split 'range' (clamped to the length of the file) into the parts that
+    /// are covered by each extent (along with where that extent starts), and
+    /// the gaps between extents.
+    pub(self) fn pieces(&self, range: Range<u64>) -> Vec<Piece<'_>> {
         let mut v = Vec::new();
-        let (start, _) = self.extent_for_byte(range.start).expect("invalid range");
-        for (ext_start, ext) in self.extents.range(start..).take_while(|(start, ext)| {
-            [
-                std::cmp::max(**start, range.start),
-                std::cmp::min(**start + (ext.len()), range.end),
-            ]
-            .iter()
-            .any(|point| range.contains(point))
-        }) {
+        let mut pos = range.start;
+        let first = self
+            .extent_for_byte(range.start)
+            .map_or(range.start, |(start, _)| start);
+        for (ext_start, ext) in self.extents.range(first..range.end) {
             let start = std::cmp::max(range.start, *ext_start);
             let end = std::cmp::min(range.end, ext_start + ext.len());
-            let cloned = Extent::Cloned(Cloned {
-                src_file: self.clone(),
-                src_range: (start, end),
-                data: ext
-                    .bytes()
-                    .slice((start - ext_start) as usize..(end - ext_start) as usize),
-            });
-            v.push(cloned);
+            if start >= end {
+                continue;
+            }
+            if start > pos {
+                v.push((pos..start, None));
+            }
+            v.push((start..end, Some((*ext_start, ext))));
+            pos = end;
+        }
+        let end = std::cmp::min(range.end, self.len());
+        if end > pos {
+            v.push((pos..end, None));
         }
         v
     }
@@ -121,40 +366,153 @@ impl File {
     /// the new size is smaller. If the new size is larger, an extent of
     /// all-zeroes is created at the end of the file
     pub fn truncate(&mut self, len: u64) {
+        self.digest = DigestCache::default();
         if len < self.len() {
             self.extents.retain(|k, _| *k < len);
-            let last_start = self.extents.last_key_value().map(|(k, _)| *k);
-            if let Some(start) = last_start {
-                let pos = len - start;
-                self.extents
-                    .get_mut(&start)
-                    .expect("definitely exists")
-                    .split_at(pos as usize);
-            }
-        } else {
+            let end = match self.extents.last_entry() {
+                Some(mut last) if *last.key() + last.get().len() > len => {
+                    let pos = len - *last.key();
+                    last.get_mut().split_at(pos as usize);
+                    len
+                }
+                Some(last) => *last.key() + last.get().len(),
+                None => 0,
+            };
+            // 'len' falls in a gap between extents, which has to be kept
+            // so that the file does not get even shorter
+            if end < len {
+                self.extents.insert(end, Extent::Hole(len - end));
+            }
+        } else if len > self.len() {
             self.extents
                 .insert(self.len(), Extent::Hole(len - self.len()));
         }
     }
+
+    /// Make sure the file is at least 'offset' + 'len' bytes long, like
+    /// posix_fallocate(3). The file is extended with a hole, so existing data
+    /// is never moved or overwritten and the new range reads as zeroes until
+    /// it is written. Fails with [ErrorKind::FileTooLarge] (EFBIG) if
+    /// 'offset' + 'len' overflows.
+    pub fn allocate(&mut self, offset: u64, len: u64) -> std::io::Result<()> {
+        let end = offset.checked_add(len).ok_or_else(|| {
+            std::io::Error::new(ErrorKind::FileTooLarge, "offset + len overflows")
+        })?;
+        if end > self.len() {
+            self.truncate(end);
+        }
+        Ok(())
+    }
+
+    /// Replace 'len' bytes starting at 'offset' with a hole, like
+    /// `fallocate(FALLOC_FL_PUNCH_HOLE | FALLOC_FL_KEEP_SIZE)`. The size of
+    /// the file never changes, so anything past the end is ignored.
+    pub fn punch_hole(&mut self, offset: u64, len: u64) {
+        let end = std::cmp::min(offset.saturating_add(len), self.len());
+        if end <= offset {
+            return;
+        }
+        self.write_at(Extent::Hole(end - offset), offset);
+    }
+
+    /// Merge adjacent [Extent::Owned] extents and adjacent holes, and drop
+    /// empty extents. [Extent::Cloned] extents are left alone so that they
+    /// still reference where they were cloned from. This does not change
+    /// the contents, but makes reading a heavily fragmented file faster.
+    pub fn compact(&mut self) {
+        let mut extents: BTreeMap<u64, Extent> = BTreeMap::new();
+        let mut owned: Option<(u64, BytesMut)> = None;
+        for (start, ext) in std::mem::take(&mut self.extents) {
+            if ext.is_empty() {
+                continue;
+            }
+            if let Some((owned_start, data)) = &mut owned {
+                match &ext {
+                    Extent::Owned(b) if *owned_start + data.len() as u64 == start => {
+                        data.extend_from_slice(b);
+                        continue;
+                    }
+                    _ => {
+                        extents.insert(*owned_start, Extent::Owned(data.split().freeze()));
+                        owned = None;
+                    }
+                }
+            }
+            let prev = extents.last_entry();
+            match (ext, prev) {
+                (Extent::Owned(b), _) => owned = Some((start, BytesMut::from(b.as_ref()))),
+                (Extent::Hole(h), Some(mut prev))
+                    if prev.get().is_hole() && prev.key() + prev.get().len() == start =>
+                {
+                    let len = prev.get().len();
+                    prev.insert(Extent::Hole(len + h));
+                }
+                (ext, _) => {
+                    extents.insert(start, ext);
+                }
+            }
+        }
+        if let Some((start, data)) = owned {
+            extents.insert(start, Extent::Owned(data.freeze()));
+        }
+        self.extents = extents;
+    }
+
+    /// Replace all the extents with a single [Extent::Owned] of the full
+    /// contents, so holes become explicit zeroes.
+    pub fn linearize(&mut self) -> std::io::Result<()> {
+        self.extents = match self.len() {
+            0 => BTreeMap::new(),
+            _ => BTreeMap::from([(0, Extent::Owned(self.to_bytes()?.into_owned().into()))]),
+        };
+        Ok(())
+    }
+
+    /// Copy the data of every [Extent::Owned] and [Extent::Cloned] extent
+    /// into a new buffer that only this file refers to, so that it no longer
+    /// keeps the memory of the file (or archive) it came from alive. Cloned
+    /// extents become owned, since their source may not exist anymore.
+    pub fn unshare(&mut self) {
+        for ext in self.extents.values_mut() {
+            if let Extent::Owned(data) | Extent::Cloned(Cloned { data, .. }) = ext {
+                *ext = Extent::Owned(Bytes::copy_from_slice(data));
+            }
+        }
+    }
 }
 
-impl ApproxEq for File {
-    #[deny(unused_variables)]
-    fn cmp(&self, other: &Self) -> Fields {
-        let Self { metadata, extents } = self;
-        let mut f = metadata.cmp(&other.metadata);
-        if *extents != other.extents {
+impl File {
+    /// [ApproxEq::cmp] with the given 'precision'.
+    pub(crate) fn cmp_with_precision(&self, other: &Self, precision: &Precision) -> Fields {
+        #[deny(unused_variables)]
+        let Self {
+            metadata,
+            extents,
+            digest: _,
+        } = self;
+        let mut f = metadata.cmp_with_precision(&other.metadata, precision);
+        if !self.shares_extents(other) && *extents != other.extents {
             f.remove(Fields::EXTENTS);
         }
-        if self.to_bytes() != other.to_bytes() {
+        if self.len() != other.len() {
+            f.remove(Fields::SIZE);
+        }
+        // data that cannot be read is never the same
+        if !matches!(self.same_data(other, precision.data), Ok(true)) {
             f.remove(Fields::DATA);
         }
         f
     }
 }
 
+impl ApproxEq for File {
+    fn cmp(&self, other: &Self) -> Fields {
+        self.cmp_with_precision(other, &Precision::default())
+    }
+}
+
 #[cfg(test)]
-pub(self) mod tests {
+mod tests {
     use super::*;
 
     pub(crate) fn test_file() -> File {
@@ -164,6 +522,7 @@ pub(self) mod tests {
                 ("Lorem ipsum".len() as u64, " dolor sit amet".into()),
             ]),
             metadata: Default::default(),
+            digest: Default::default(),
         }
     }
 
@@ -171,7 +530,7 @@ pub(self) mod tests {
     fn to_bytes() {
         let f = test_file();
         assert_eq!(
-            f.to_bytes().as_ref(),
+            f.to_bytes().unwrap().as_ref(),
             b"Lorem ipsum dolor sit amet",
             "{f:?}"
         );
@@ -181,7 +540,8 @@ pub(self) mod tests {
     fn cloning() {
         let f = test_file();
         let extents = f
-            .clone_range("Lorem ".len() as u64.."Lorem ".len() as u64 + "ipsum dolor".len() as u64);
+            .clone_range("Lorem ".len() as u64.."Lorem ".len() as u64 + "ipsum dolor".len() as u64)
+            .unwrap();
         let mut f2 = File::new_empty();
         let mut w = f2.writer();
         assert_eq!(extents.len(), 2, "{extents:?}");
@@ -189,7 +549,7 @@ pub(self) mod tests {
             w.write(ex)
         }
         assert_eq!(
-            std::str::from_utf8(&f2.to_bytes()).expect("valid"),
+            std::str::from_utf8(&f2.to_bytes().unwrap()).expect("valid"),
             "ipsum dolor",
             "{f2:?}"
         );
@@ -199,12 +559,215 @@ pub(self) mod tests {
     fn truncate() {
         let mut f = test_file();
         f.truncate(5);
-        assert_eq!(f.to_bytes().as_ref(), b"Lorem");
+        assert_eq!(f.to_bytes().unwrap().as_ref(), b"Lorem");
         assert_eq!(f.extents.len(), 1);
 
         let mut f = test_file();
         f.truncate(("Lorem ipsum dolor sit amet".len() + 128) as u64);
         assert_eq!(f.len(), ("Lorem ipsum dolor sit amet".len() + 128) as u64);
         assert_eq!(f.extents.len(), 3);
+
+        // truncating into the gap left by writing past the end
+        let mut f = File::builder().contents("abc").build();
+        f.write_at("x", 10);
+        f.truncate(5);
+        assert_eq!(b"abc\0\0", f.to_bytes().unwrap().as_ref());
+        let extents = f.extents.len();
+        f.truncate(5);
+        assert_eq!(extents, f.extents.len(), "no empty hole is added");
+    }
+
+    #[test]
+    fn cmp_size() {
+        let f = test_file();
+        let mut same_size = File::new_empty();
+        same_size
+            .writer()
+            .write("LOREM IPSUM DOLOR SIT AMET".as_bytes());
+        let cmp = ApproxEq::cmp(&f, &same_size);
+        assert!(cmp.contains(Fields::SIZE));
+        assert!(!cmp.contains(Fields::DATA));
+
+        let mut shorter = test_file();
+        shorter.truncate(5);
+        assert!(!ApproxEq::cmp(&f, &shorter).contains(Fields::SIZE));
+    }
+
+    #[test]
+    fn same_data() {
+        let f = test_file();
+        let contiguous = File::builder()
+            .contents("Lorem ipsum dolor sit amet")
+            .build();
+        for mode in [DataComparison::Exact, DataComparison::Digest] {
+            assert!(f.same_data(&contiguous, mode).unwrap());
+        }
+        // the cached digest is reset when the file changes
+        let mut changed = contiguous.clone();
+        changed.writer().write("!");
+        assert!(!f.same_data(&changed, DataComparison::Digest).unwrap());
+        changed.truncate(f.len());
+        assert!(f.same_data(&changed, DataComparison::Digest).unwrap());
+        let mut w = changed.writer();
+        std::io::Seek::seek(&mut w, std::io::SeekFrom::Start(0)).unwrap();
+        w.write("l");
+        assert!(!f.same_data(&changed, DataComparison::Digest).unwrap());
+        assert!(!f.same_data(&changed, DataComparison::Exact).unwrap());
+    }
+
+    #[test]
+    fn compact() {
+        let mut f = File::new_empty();
+        let mut w = f.writer();
+        w.write("Lorem");
+        w.write(" ipsum");
+        w.write(Extent::Hole(2));
+        w.write(Extent::Hole(3));
+        w.write("dolor");
+        w.write(test_file().clone_range(0..5).unwrap().remove(0));
+        w.write("sit");
+        w.write("");
+        let bytes = f.to_bytes().unwrap().into_owned();
+        f.compact();
+        assert_eq!(bytes, f.to_bytes().unwrap().as_ref());
+        assert_eq!(
+            vec![
+                (0, Extent::from("Lorem ipsum")),
+                (11, Extent::Hole(5)),
+                (16, Extent::from("dolor")),
+            ],
+            f.extents
+                .iter()
+                .take(3)
+                .map(|(s, e)| (*s, e.clone()))
+                .collect::<Vec<_>>(),
+        );
+        assert_eq!(5, f.extents.len(), "{f:?}");
+
+        f.linearize().unwrap();
+        assert_eq!(1, f.extents.len());
+        assert_eq!(bytes, f.to_bytes().unwrap().as_ref());
+    }
+
+    #[test]
+    fn from_reader() {
+        let data = b"Lorem ipsum dolor sit amet";
+        let f = File::from_reader(data.as_slice()).unwrap();
+        assert_eq!(1, f.extents.len());
+        assert_eq!(data, f.to_bytes().unwrap().as_ref());
+
+        let f = File::from_reader_with_chunk_size(data.as_slice(), 10).unwrap();
+        assert_eq!(3, f.extents.len());
+        assert_eq!(data, f.to_bytes().unwrap().as_ref());
+        // no empty extent after the last full chunk
+        let f = File::from_reader_with_chunk_size(&data[..20], 10).unwrap();
+        assert_eq!(2, f.extents.len());
+        assert_eq!(&data[..20], f.to_bytes().unwrap().as_ref());
+        assert!(File::from_reader(std::io::empty()).unwrap().is_empty());
+        assert_eq!(
+            ErrorKind::InvalidInput,
+            File::from_reader_with_chunk_size(data.as_slice(), 0)
+                .unwrap_err()
+                .kind()
+        );
+    }
+
+    #[test]
+    fn punch_hole() {
+        let mut f = test_file();
+        f.punch_hole(3, 10);
+        assert_eq!(
+            b"Lor\0\0\0\0\0\0\0\0\0\0olor sit amet",
+            f.to_bytes().unwrap().as_ref()
+        );
+        assert_eq!(
+            vec![(0, 3, false), (3, 10, true), (13, 13, false)],
+            f.extents
+                .iter()
+                .map(|(start, e)| (*start, e.len(), e.is_hole()))
+                .collect::<Vec<_>>()
+        );
+        f.punch_hole(20, 100);
+        assert_eq!(26, f.len());
+        assert_eq!(Some(20), f.seek_hole(14));
+    }
+
+    #[test]
+    fn allocate() {
+        let mut f = test_file();
+        f.allocate(0, 5).unwrap();
+        assert_eq!(f, test_file());
+        f.allocate(20, 16).unwrap();
+        assert_eq!(36, f.len());
+        assert_eq!(Some(26), f.seek_hole(0));
+        let mut w = f.writer();
+        std::io::Seek::seek(&mut w, std::io::SeekFrom::Start(30)).unwrap();
+        w.write("!");
+        assert_eq!(36, f.len());
+        assert_eq!(b"amet\0\0\0\0!\0\0\0\0\0", &f.to_bytes().unwrap()[22..]);
+        assert_eq!(
+            ErrorKind::FileTooLarge,
+            f.allocate(u64::MAX, 1).unwrap_err().kind()
+        );
+    }
+
+    #[test]
+    fn extents() {
+        let mut f = File::new_empty();
+        let mut w = f.writer();
+        w.write("Lorem ");
+        for ext in test_file().clone_range(6..11).unwrap() {
+            w.write(ext);
+        }
+        f.truncate(16);
+        let extents: Vec<_> = f.extents().collect();
+        assert_eq!(3, extents.len());
+        assert_eq!((0, &Extent::from("Lorem ")), extents[0]);
+        match extents[1] {
+            (6, Extent::Cloned(c)) => {
+                assert_eq!(None, c.src_path());
+                assert_eq!(6..11, c.src_range());
+            }
+            e => panic!("expected a clone, got {e:?}"),
+        }
+        assert_eq!((11, &Extent::Hole(5)), extents[2]);
+    }
+
+    #[test]
+    fn unshare() {
+        let src = test_file();
+        let mut f = File::new_empty();
+        f.writer()
+            .write_extents(src.clone_range(0..src.len()).unwrap());
+        f.write_at(Extent::Hole(2), 3);
+        let expected = f.to_bytes().unwrap().into_owned();
+        f.unshare();
+        assert_eq!(expected, f.to_bytes().unwrap().as_ref());
+        for (_, ext) in f.extents() {
+            assert!(matches!(ext, Extent::Owned(_) | Extent::Hole(_)));
+            assert!(!src.extents().any(|(_, s)| s.same_storage(ext)));
+        }
+    }
+
+    #[test]
+    fn cached_digests() {
+        let f = File::builder().contents(vec![b'a'; 64 * 1024]).build();
+        let mut other = f.clone();
+        assert!(f.shares_extents(&other));
+        assert_eq!(f, other);
+        other.write_at("a", 0);
+        assert!(!f.shares_extents(&other));
+        assert!(f.same_data(&other, DataComparison::Exact).unwrap());
+        // a cached digest that differs is enough to tell that the data does
+        other.write_at("b", 0);
+        f.data_digest().unwrap();
+        other.data_digest().unwrap();
+        assert!(!f.same_data(&other, DataComparison::Exact).unwrap());
+        assert_ne!(f, other);
+        // but the same digests still need the extents to match
+        other.write_at("a", 0);
+        other.data_digest().unwrap();
+        assert_eq!(f.data_digest().unwrap(), other.data_digest().unwrap());
+        assert_ne!(f, other);
     }
 }
@@ -0,0 +1,112 @@
+//! Trace the bytes of a [File] back to where they were loaded from, so that a
+//! problem found in a file can be pointed at the exact location in the
+//! original archive.
+
+use std::ops::Range;
+
+use super::extent::Extent;
+use super::File;
+use crate::BytesPath;
+
+/// Where a range of the bytes of a [File] came from, see [File::provenance].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Provenance {
+    /// Stored at this offset of the archive the filesystem was parsed from.
+    Archive { offset: u64 },
+    /// Read from this offset of the host file of an
+    /// [Extent::Backed](super::extent::Extent::Backed).
+    Backed { offset: u64 },
+    /// Cloned from 'src_range' of another file (at 'src_path', if it is
+    /// known).
+    Cloned {
+        src_path: Option<BytesPath>,
+        src_range: Range<u64>,
+    },
+    /// A hole (or a gap between extents) that reads as zeroes.
+    Hole,
+    /// Written after the filesystem was loaded, or otherwise not stored as-is
+    /// in the archive (for example compressed data).
+    Synthesized,
+}
+
+impl File {
+    /// Where each part of 'range' came from. 'archive' is the buffer that the
+    /// filesystem was parsed from (like the argument to
+    /// [Filesystem::parse_tar](crate::Filesystem::parse_tar)), or an empty
+    /// slice if there is none. Only extents that still share memory with
+    /// 'archive' are reported as [Provenance::Archive].
+    pub fn provenance(&self, range: Range<u64>, archive: &[u8]) -> Vec<(Range<u64>, Provenance)> {
+        let archive_range = archive.as_ptr_range();
+        self.pieces(range)
+            .into_iter()
+            .map(|(range, ext)| {
+                let provenance = match ext {
+                    None => Provenance::Hole,
+                    Some((ext_start, ext)) => {
+                        let skip = range.start - ext_start;
+                        match ext {
+                            Extent::Hole(_) => Provenance::Hole,
+                            Extent::Cloned(c) => Provenance::Cloned {
+                                src_path: c.src_path.clone(),
+                                src_range: c.src_range.0 + skip
+                                    ..c.src_range.0 + skip + (range.end - range.start),
+                            },
+                            Extent::Backed(b) if b.file.is_some() => Provenance::Backed {
+                                offset: b.offset + skip,
+                            },
+                            Extent::Owned(data) if archive_range.contains(&data.as_ptr()) => {
+                                Provenance::Archive {
+                                    offset: (data.as_ptr() as usize - archive.as_ptr() as usize)
+                                        as u64
+                                        + skip,
+                                }
+                            }
+                            _ => Provenance::Synthesized,
+                        }
+                    }
+                };
+                (range, provenance)
+            })
+            .collect()
+    }
+}
+
+#[cfg(all(test, feature = "tar"))]
+mod tests {
+    use bytes::Bytes;
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::Filesystem;
+
+    #[test]
+    fn provenance() {
+        let tar = Bytes::from_static(include_bytes!("../../testdata/testdata.tar"));
+        let mut fs = Filesystem::parse_tar(&tar).unwrap();
+        let offset = tar
+            .windows(5)
+            .position(|w| w == b"Lorem")
+            .expect("lorem.txt is in the tar") as u64;
+        fs.write("testdata/lorem.txt", 6, "IPSUM").unwrap();
+        fs.truncate("testdata/lorem.txt", 16).unwrap();
+        let f = fs.get_file("testdata/lorem.txt").unwrap();
+        assert_eq!(
+            vec![
+                (2..6, Provenance::Archive { offset: offset + 2 }),
+                (6..11, Provenance::Synthesized),
+                (
+                    11..12,
+                    Provenance::Archive {
+                        offset: offset + 11
+                    }
+                ),
+                (12..16, Provenance::Hole),
+            ],
+            f.provenance(2..100, &tar)
+        );
+        assert_eq!(
+            vec![(0..6, Provenance::Synthesized)],
+            f.provenance(0..6, &[])
+        );
+    }
+}
@@ -1,5 +1,13 @@
+use std::io::BufRead;
+use std::io::Error;
+use std::io::ErrorKind;
 use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
 
+use nix::errno::Errno;
+
+use super::Extent;
 use super::File;
 
 /// [Read] implementation for [File]
@@ -12,40 +20,146 @@ impl File {
     pub fn reader(&self) -> Reader<'_> {
         Reader { file: self, pos: 0 }
     }
+
+    /// Read into 'buf' starting at 'offset', like pread(2). Returns the
+    /// number of bytes read, which is only less than the length of 'buf' at
+    /// the end of the file. Fails only if an [Extent::Backed] cannot be
+    /// read.
+    pub fn read_at(&self, buf: &mut [u8], offset: u64) -> std::io::Result<usize> {
+        let mut reader = Reader {
+            file: self,
+            pos: offset,
+        };
+        let mut n = 0;
+        while n < buf.len() {
+            match reader.read(&mut buf[n..])? {
+                0 => break,
+                read => n += read,
+            }
+        }
+        Ok(n)
+    }
+
+    /// Like `lseek(SEEK_DATA)`, find the start of the first data region at
+    /// or after 'offset'. Returns [None] if there is no more data.
+    pub fn seek_data(&self, offset: u64) -> Option<u64> {
+        if offset >= self.len() {
+            return None;
+        }
+        self.extents
+            .iter()
+            .find(|(start, ext)| *start + ext.len() > offset && !ext.is_hole() && !ext.is_empty())
+            .map(|(start, _)| std::cmp::max(*start, offset))
+    }
+
+    /// Like `lseek(SEEK_HOLE)`, find the start of the first hole (or gap
+    /// between extents) at or after 'offset'. The end of the file always
+    /// counts as a hole, so this only returns [None] if 'offset' is past the
+    /// end of the file.
+    pub fn seek_hole(&self, offset: u64) -> Option<u64> {
+        let len = self.len();
+        if offset >= len {
+            return None;
+        }
+        let mut pos = offset;
+        while pos < len {
+            match self.extent_for_byte(pos) {
+                Some((start, ext)) if !ext.is_hole() => pos = start + ext.len(),
+                _ => return Some(pos),
+            }
+        }
+        Some(len)
+    }
 }
 
+impl<'r> Reader<'r> {
+    /// Move to [File::seek_data] from the current position, failing with
+    /// `ENXIO` if there is no more data, just like lseek(2).
+    pub fn seek_data(&mut self) -> std::io::Result<u64> {
+        self.pos = self.file.seek_data(self.pos).ok_or(Errno::ENXIO)?;
+        Ok(self.pos)
+    }
+
+    /// Move to [File::seek_hole] from the current position, failing with
+    /// `ENXIO` if the current position is past the end of the file, just
+    /// like lseek(2).
+    pub fn seek_hole(&mut self) -> std::io::Result<u64> {
+        self.pos = self.file.seek_hole(self.pos).ok_or(Errno::ENXIO)?;
+        Ok(self.pos)
+    }
+}
+
+/// Holes and gaps are returned from [BufRead::fill_buf] as (at most) this many
+/// zeroes at a time.
+static ZEROES: [u8; 64 * 1024] = [0; 64 * 1024];
+
 impl<'r> Read for Reader<'r> {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        if self.pos >= self.file.len() {
-            return Ok(0);
+        let available = self.fill_buf()?;
+        let read_len = std::cmp::min(buf.len(), available.len());
+        buf[..read_len].copy_from_slice(&available[..read_len]);
+        self.consume(read_len);
+        Ok(read_len)
+    }
+}
+
+/// Returns the rest of the current extent without copying it.
+impl<'r> BufRead for Reader<'r> {
+    fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+        let len = self.file.len();
+        if self.pos >= len {
+            return Ok(&[]);
         }
-        match self.file.extent_for_byte(self.pos) {
-            Some((extent_start, ext)) => {
-                let remaining_in_extent = extent_start + ext.len() - self.pos;
-                let read_len = std::cmp::min(buf.len(), remaining_in_extent as usize);
-                let extent_offset = self.pos - extent_start;
-                buf[..read_len].copy_from_slice(
-                    &ext.data()[extent_offset as usize..extent_offset as usize + read_len],
-                );
-                self.pos += read_len as u64;
-                Ok(read_len)
-            }
-            // this is impossible due to the length check above
+        let zeroes = |n: u64| &ZEROES[..std::cmp::min(ZEROES.len() as u64, n) as usize];
+        Ok(match self.file.extent_for_byte(self.pos) {
+            Some((extent_start, ext)) => match ext {
+                Extent::Hole(_) => zeroes(extent_start + ext.len() - self.pos),
+                _ => &ext.data()?[(self.pos - extent_start) as usize..],
+            },
+            // a gap between extents (left by writing past the end of the
+            // file) reads as zeroes, just like a hole
             None => {
-                unreachable!(
-                    "cannot read past end of file (pos = {}, file = {:?}",
-                    self.pos, self.file,
-                );
+                let next_start = self
+                    .file
+                    .extents
+                    .range(self.pos..)
+                    .next()
+                    .map_or(len, |(start, _)| *start);
+                zeroes(next_start - self.pos)
             }
+        })
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pos += amt as u64;
+    }
+}
+
+impl<'r> Seek for Reader<'r> {
+    fn seek(&mut self, seek: SeekFrom) -> std::io::Result<u64> {
+        let (base_pos, offset) = match seek {
+            SeekFrom::Start(n) => {
+                self.pos = n;
+                return Ok(n);
+            }
+            SeekFrom::End(n) => (self.file.len(), n),
+            SeekFrom::Current(n) => (self.pos, n),
+        };
+        match base_pos.checked_add_signed(offset) {
+            Some(n) => {
+                self.pos = n;
+                Ok(self.pos)
+            }
+            None => Err(Error::new(
+                ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )),
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::io::Seek;
-    use std::io::SeekFrom;
-
     use super::super::tests::test_file;
     use super::*;
 
@@ -55,7 +169,7 @@ mod tests {
         let mut buf = Vec::new();
         f.reader().read_to_end(&mut buf).expect("infallible");
         // file::tests::to_bytes already ensures that to_bytes is correct
-        assert_eq!(buf, f.to_bytes().as_ref());
+        assert_eq!(buf, f.to_bytes().unwrap().as_ref());
     }
 
     #[test]
@@ -75,4 +189,58 @@ mod tests {
         );
         assert_eq!(f.extents.len(), 2);
     }
+
+    #[test]
+    fn holes_and_gaps() {
+        let mut f = File::new_empty();
+        let mut w = f.writer();
+        w.write("Lorem");
+        w.write(Extent::Hole(2));
+        w.seek(SeekFrom::Current(3)).expect("infallible");
+        w.write("ipsum");
+        let mut buf = Vec::new();
+        f.reader().read_to_end(&mut buf).expect("infallible");
+        assert_eq!(buf, b"Lorem\0\0\0\0\0ipsum");
+    }
+
+    #[test]
+    fn seek_hole_and_data() {
+        let mut f = File::new_empty();
+        let mut w = f.writer();
+        w.write("Lorem");
+        w.write(Extent::Hole(2));
+        w.seek(SeekFrom::Current(3)).expect("infallible");
+        w.write("ipsum");
+        w.write(" dolor");
+        assert_eq!(Some(0), f.seek_data(0));
+        assert_eq!(Some(5), f.seek_hole(0));
+        assert_eq!(Some(10), f.seek_data(5));
+        assert_eq!(Some(7), f.seek_hole(7));
+        assert_eq!(Some(12), f.seek_data(12));
+        // adjacent data extents are a single data region
+        assert_eq!(Some(21), f.seek_hole(10));
+        assert_eq!(None, f.seek_data(21));
+        assert_eq!(None, f.seek_hole(21));
+
+        f.truncate(30);
+        let mut r = f.reader();
+        r.seek(SeekFrom::Start(12)).expect("infallible");
+        assert_eq!(21, r.seek_hole().expect("hole at the end"));
+        let err = r.seek_data().expect_err("no more data");
+        assert_eq!(Some(Errno::ENXIO as i32), err.raw_os_error());
+    }
+
+    #[test]
+    fn buf_read() {
+        let mut f = test_file();
+        f.writer().write("\nsecond line\n");
+        f.truncate(f.len() + 2);
+        let mut r = f.reader();
+        // the first extent is returned as-is
+        assert_eq!(b"Lorem ipsum", r.fill_buf().unwrap());
+        assert_eq!(
+            vec!["Lorem ipsum dolor sit amet", "second line", "\0\0"],
+            r.lines().collect::<std::io::Result<Vec<_>>>().unwrap()
+        );
+    }
 }
@@ -0,0 +1,135 @@
+use std::borrow::Cow;
+use std::io::BufRead;
+use std::io::Error;
+use std::io::ErrorKind;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::ops::Range;
+
+use super::reader::Reader;
+use super::File;
+
+/// Read-only view of a byte range of a [File], see [File::slice].
+pub struct FileSlice<'f> {
+    file: &'f File,
+    reader: Reader<'f>,
+    range: Range<u64>,
+    /// Position relative to the start of the range
+    pos: u64,
+}
+
+impl File {
+    /// View the bytes in 'range' without copying them. The range is clamped
+    /// to the length of the file.
+    pub fn slice(&self, range: Range<u64>) -> FileSlice<'_> {
+        let end = std::cmp::min(range.end, self.len());
+        let start = std::cmp::min(range.start, end);
+        let mut reader = self.reader();
+        reader
+            .seek(SeekFrom::Start(start))
+            .expect("seeking from the start cannot fail");
+        FileSlice {
+            file: self,
+            reader,
+            range: start..end,
+            pos: 0,
+        }
+    }
+}
+
+impl<'f> FileSlice<'f> {
+    pub fn len(&self) -> u64 {
+        self.range.end - self.range.start
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Contents of the whole slice (regardless of the current position),
+    /// which are only copied if they span more than one extent. Fails only
+    /// if an [Extent::Backed](super::extent::Extent::Backed) cannot be read.
+    pub fn to_bytes(&self) -> std::io::Result<Cow<'f, [u8]>> {
+        if self.is_empty() {
+            return Ok(Cow::Borrowed(&[]));
+        }
+        match self.file.extent_for_byte(self.range.start) {
+            Some((start, ext)) if !ext.is_hole() && self.range.end <= start + ext.len() => {
+                Ok(Cow::Borrowed(
+                    &ext.data()?
+                        [(self.range.start - start) as usize..(self.range.end - start) as usize],
+                ))
+            }
+            _ => {
+                let mut v = Vec::with_capacity(self.len() as usize);
+                self.file.slice(self.range.clone()).read_to_end(&mut v)?;
+                Ok(Cow::Owned(v))
+            }
+        }
+    }
+}
+
+impl<'f> Read for FileSlice<'f> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let available = self.fill_buf()?;
+        let read_len = std::cmp::min(buf.len(), available.len());
+        buf[..read_len].copy_from_slice(&available[..read_len]);
+        self.consume(read_len);
+        Ok(read_len)
+    }
+}
+
+impl<'f> BufRead for FileSlice<'f> {
+    fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+        let remaining = self.len().saturating_sub(self.pos);
+        let buf = self.reader.fill_buf()?;
+        Ok(&buf[..std::cmp::min(buf.len() as u64, remaining) as usize])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.reader.consume(amt);
+        self.pos += amt as u64;
+    }
+}
+
+impl<'f> Seek for FileSlice<'f> {
+    fn seek(&mut self, seek: SeekFrom) -> std::io::Result<u64> {
+        let (base_pos, offset) = match seek {
+            SeekFrom::Start(n) => (n, 0),
+            SeekFrom::End(n) => (self.len(), n),
+            SeekFrom::Current(n) => (self.pos, n),
+        };
+        let pos = base_pos.checked_add_signed(offset).ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )
+        })?;
+        self.reader
+            .seek(SeekFrom::Start(self.range.start.saturating_add(pos)))?;
+        self.pos = pos;
+        Ok(pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::test_file;
+    use super::*;
+
+    #[test]
+    fn slice() {
+        let f = test_file();
+        let within = f.slice(6..11);
+        assert!(matches!(within.to_bytes(), Ok(Cow::Borrowed(b"ipsum"))));
+        let mut across = f.slice(6..17);
+        assert!(matches!(across.to_bytes(), Ok(Cow::Owned(_))));
+        assert_eq!(b"ipsum dolor", across.to_bytes().unwrap().as_ref());
+        across.seek(SeekFrom::End(-5)).unwrap();
+        let mut s = String::new();
+        across.read_to_string(&mut s).unwrap();
+        assert_eq!("dolor", s);
+        assert_eq!(b"amet", f.slice(22..100).to_bytes().unwrap().as_ref());
+    }
+}
@@ -1,9 +1,12 @@
 use std::io::Error;
 use std::io::ErrorKind;
+use std::io::IoSlice;
 use std::io::Result;
 use std::io::Seek;
 use std::io::SeekFrom;
 
+use bytes::BytesMut;
+
 use super::Extent;
 use super::File;
 
@@ -17,11 +20,19 @@ impl File {
     /// Open a [Writer] at the end of the file. Use [Seek] to move around if you
     /// want to write somewhere in the middle
     pub fn writer(&mut self) -> Writer<'_> {
+        self.digest = Default::default();
         Writer {
             pos: self.len(),
             file: self,
         }
     }
+
+    /// Write 'extent' starting at 'offset', like pwrite(2).
+    pub fn write_at(&mut self, extent: impl Into<Extent>, offset: u64) {
+        let mut writer = self.writer();
+        writer.pos = offset;
+        writer.write(extent);
+    }
 }
 
 impl<'r> Writer<'r> {
@@ -31,28 +42,122 @@ impl<'r> Writer<'r> {
     where
         E: Into<Extent>,
     {
-        let extent = extent.into();
-        let ext_len = extent.len();
+        self.write_extents([extent]);
+    }
+
+    /// Write all of 'extents' one after the other, like calling
+    /// [Writer::write] for each of them, but only splitting the existing
+    /// extents at the start and end of the whole written range.
+    pub fn write_extents<E>(&mut self, extents: impl IntoIterator<Item = E>)
+    where
+        E: Into<Extent>,
+    {
+        let extents: Vec<Extent> = extents
+            .into_iter()
+            .map(Into::into)
+            .filter(|e| !e.is_empty())
+            .collect();
+        if extents.is_empty() {
+            return;
+        }
         let write_start = self.pos;
-        let write_end = write_start + ext_len;
+        let write_end = write_start + extents.iter().map(Extent::len).sum::<u64>();
+        self.clear(write_start, write_end);
+        for extent in extents {
+            let len = extent.len();
+            self.file.extents.insert(self.pos, extent);
+            self.pos += len;
+        }
+    }
+
+    /// Remove everything between 'write_start' and 'write_end', splitting the
+    /// extents that are only partially in that range.
+    fn clear(&mut self, write_start: u64, write_end: u64) {
+        // keep the part of any existing extent that continues past the end of
+        // this write
         if let Some((existing_start, existing_ext)) = self.file.extent_for_byte_mut(write_end) {
-            let right = existing_ext.split_at((write_end - existing_start) as usize);
-            self.file.extents.insert(write_end, right);
+            if existing_start < write_end {
+                let right = existing_ext.split_at((write_end - existing_start) as usize);
+                self.file.extents.insert(write_end, right);
+            }
         }
-        if let Some((existing_start, existing_ext)) = self.file.extent_for_byte_mut(self.pos) {
-            // TODO: handle overlapping writes after implementing seek
-            // shrink this extent to end where the overlap is
-            let split_idx = write_start - existing_start;
-            let right_split_idx = write_end - split_idx;
-            let mut right = existing_ext.split_at(split_idx as usize);
-            if right_split_idx < right.len() {
-                right.split_at(right_split_idx as usize);
-                let right_start = write_end;
-                self.file.extents.insert(right_start, right);
+        // shrink any extent that starts before this write to end where the
+        // overlap begins
+        if let Some((existing_start, existing_ext)) = self.file.extent_for_byte_mut(write_start) {
+            if existing_start < write_start {
+                existing_ext.split_at((write_start - existing_start) as usize);
             }
         }
-        self.file.extents.insert(self.pos, extent);
-        self.pos += ext_len;
+        // everything else in the written range is completely replaced
+        let overwritten: Vec<u64> = self
+            .file
+            .extents
+            .range(write_start..write_end)
+            .map(|(start, _)| *start)
+            .collect();
+        for start in overwritten {
+            self.file.extents.remove(&start);
+        }
+    }
+}
+
+impl<'r> Writer<'r> {
+    /// Copy 'bufs' into the file, appending to the [Extent::Owned] right
+    /// before the current position (if there is one) instead of creating a
+    /// new extent, so that many small writes do not leave behind just as many
+    /// tiny extents.
+    pub(super) fn write_copy(&mut self, bufs: &[&[u8]]) -> usize {
+        let len: usize = bufs.iter().map(|b| b.len()).sum();
+        if len == 0 {
+            return 0;
+        }
+        let (start, end) = (self.pos, self.pos + len as u64);
+        self.clear(start, end);
+        let prev = self
+            .file
+            .extents
+            .range(..start)
+            .next_back()
+            .filter(|(prev_start, ext)| {
+                matches!(ext, Extent::Owned(_)) && **prev_start + ext.len() == start
+            })
+            .map(|(prev_start, _)| *prev_start);
+        let (ext_start, mut data) =
+            match prev.and_then(|p| self.file.extents.remove(&p).map(|e| (p, e))) {
+                Some((prev_start, Extent::Owned(b))) => {
+                    // this only copies if the data is shared with another extent
+                    let mut data = b.try_into_mut().unwrap_or_else(|b| BytesMut::from(&b[..]));
+                    data.reserve(len);
+                    (prev_start, data)
+                }
+                _ => (start, BytesMut::with_capacity(len)),
+            };
+        for buf in bufs {
+            data.extend_from_slice(buf);
+        }
+        self.file
+            .extents
+            .insert(ext_start, Extent::Owned(data.freeze()));
+        self.pos = end;
+        len
+    }
+}
+
+/// Copies the data into the file, see [Writer::write_copy]. Prefer
+/// [Writer::write] when the data is already in a [Bytes](bytes::Bytes) to
+/// avoid the copy.
+impl<'r> std::io::Write for Writer<'r> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        Ok(self.write_copy(&[buf]))
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> Result<usize> {
+        let bufs: Vec<&[u8]> = bufs.iter().map(|b| &**b).collect();
+        Ok(self.write_copy(&bufs))
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
     }
 }
 
@@ -82,6 +187,7 @@ impl<'r> Seek for Writer<'r> {
 #[cfg(test)]
 mod tests {
     use std::collections::BTreeMap;
+    use std::io::Write;
 
     use super::*;
 
@@ -91,7 +197,10 @@ mod tests {
         let mut w = f.writer();
         w.write("Lorem ipsum");
         w.write(" dolor sit amet");
-        assert_eq!(f.to_bytes().as_ref(), b"Lorem ipsum dolor sit amet");
+        assert_eq!(
+            f.to_bytes().unwrap().as_ref(),
+            b"Lorem ipsum dolor sit amet"
+        );
         assert_eq!(f.extents.len(), 2);
     }
 
@@ -103,7 +212,10 @@ mod tests {
         w.seek(SeekFrom::Start("Lorem ".len() as u64))
             .expect("infallible");
         w.write("ipsum dolor sit amet");
-        assert_eq!(f.to_bytes().as_ref(), b"Lorem ipsum dolor sit amet");
+        assert_eq!(
+            f.to_bytes().unwrap().as_ref(),
+            b"Lorem ipsum dolor sit amet"
+        );
         assert_eq!(f.extents.len(), 2);
         assert_eq!(
             &f.extents,
@@ -123,7 +235,7 @@ mod tests {
             .expect("infallible");
         w.write("ipsum");
         assert_eq!(
-            std::str::from_utf8(&f.to_bytes()).expect("valid"),
+            std::str::from_utf8(&f.to_bytes().unwrap()).expect("valid"),
             "Lorem ipsum dolor sit amet",
             "{f:?}",
         );
@@ -137,4 +249,75 @@ mod tests {
             ]),
         );
     }
+
+    #[test]
+    fn overwrite_many_extents() {
+        let mut f = File::new_empty();
+        let mut w = f.writer();
+        w.write("Lorem");
+        w.write(" ipsum");
+        w.write(" dolor");
+        w.write(" sit amet");
+        w.seek(SeekFrom::Start(2)).expect("infallible");
+        std::io::Write::write_all(&mut w, b"REM IPSUM DOL").expect("infallible");
+        assert_eq!(
+            std::str::from_utf8(&f.to_bytes().unwrap()).expect("valid"),
+            "LoREM IPSUM DOLor sit amet",
+            "{f:?}",
+        );
+        // the copied data is appended to "Lo"
+        assert_eq!(f.extents.len(), 3, "{f:?}");
+    }
+
+    #[test]
+    fn write_extents() {
+        let mut f = super::super::tests::test_file();
+        let mut w = f.writer();
+        w.seek(SeekFrom::Start(6)).expect("infallible");
+        w.write_extents(["IPSUM", " ", "DOLOR"]);
+        assert_eq!(
+            b"Lorem IPSUM DOLOR sit amet",
+            f.to_bytes().unwrap().as_ref()
+        );
+        assert_eq!(5, f.extents.len(), "{f:?}");
+
+        let mut w = f.writer();
+        let written = w
+            .write_vectored(&[IoSlice::new(b"!"), IoSlice::new(b"?")])
+            .expect("infallible");
+        assert_eq!(2, written);
+        assert_eq!(
+            b"Lorem IPSUM DOLOR sit amet!?",
+            f.to_bytes().unwrap().as_ref()
+        );
+        // appended to the last extent
+        assert_eq!(5, f.extents.len(), "{f:?}");
+    }
+
+    #[test]
+    fn read_write_at() {
+        let mut f = super::super::tests::test_file();
+        f.write_at("IPSUM", 6);
+        f.write_at("!", 28);
+        let mut buf = [0; 8];
+        assert_eq!(8, f.read_at(&mut buf, 6).unwrap());
+        assert_eq!(b"IPSUM do", &buf);
+        assert_eq!(5, f.read_at(&mut buf, 24).unwrap());
+        assert_eq!(b"et\0\0!", &buf[..5]);
+        assert_eq!(0, f.read_at(&mut buf, 29).unwrap());
+    }
+
+    #[test]
+    fn small_writes_are_coalesced() {
+        let mut f = File::new_empty();
+        let mut w = f.writer();
+        for b in b"Lorem ipsum" {
+            std::io::Write::write_all(&mut w, &[*b]).expect("infallible");
+        }
+        // overwriting the end of the extent still appends to it
+        w.seek(SeekFrom::Start(6)).expect("infallible");
+        std::io::Write::write_all(&mut w, b"dolor sit amet").expect("infallible");
+        assert_eq!(b"Lorem dolor sit amet", f.to_bytes().unwrap().as_ref());
+        assert_eq!(1, f.extents.len(), "{f:?}");
+    }
 }
@@ -0,0 +1,213 @@
+//! Protection against path traversal (zip-slip / tar-slip) when building a
+//! [Filesystem] out of untrusted input.
+//!
+//! In hardened mode, every path that is added to the filesystem must stay
+//! within its root: absolute paths, `..` components, paths underneath a
+//! symlink and relative symlink targets that climb above the root are all
+//! rejected with an [UnsafePath] error.
+
+use std::fmt::Display;
+use std::io::Error;
+use std::io::ErrorKind;
+use std::io::Result;
+use std::path::Component;
+use std::path::Path;
+
+use crate::BytesPath;
+use crate::Entry;
+use crate::Filesystem;
+use crate::InodeKey;
+
+/// Why a path was rejected in hardened mode.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum UnsafePathReason {
+    /// The path starts with `/`
+    Absolute,
+    /// The path contains a `..` component
+    ParentDir,
+    /// One of the parents of the path is a symlink, so writing to it could end
+    /// up anywhere once the filesystem is extracted
+    ThroughSymlink,
+    /// A relative symlink target climbs above the root of the filesystem
+    SymlinkEscapesRoot,
+}
+
+/// Error returned by any operation that would let a path escape the root of a
+/// hardened [Filesystem]. This is always wrapped in a [std::io::Error] with
+/// [ErrorKind::InvalidInput], and can be recovered with
+/// [std::io::Error::get_ref] and `downcast_ref`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnsafePath {
+    path: BytesPath,
+    reason: UnsafePathReason,
+}
+
+impl UnsafePath {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn reason(&self) -> UnsafePathReason {
+        self.reason
+    }
+}
+
+impl Display for UnsafePath {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let reason = match self.reason {
+            UnsafePathReason::Absolute => "is absolute",
+            UnsafePathReason::ParentDir => "contains '..'",
+            UnsafePathReason::ThroughSymlink => "is underneath a symlink",
+            UnsafePathReason::SymlinkEscapesRoot => "is a symlink that escapes the root",
+        };
+        write!(f, "unsafe path '{}' {reason}", self.path.display())
+    }
+}
+
+impl std::error::Error for UnsafePath {}
+
+impl From<UnsafePath> for Error {
+    fn from(e: UnsafePath) -> Self {
+        Error::new(ErrorKind::InvalidInput, e)
+    }
+}
+
+impl Filesystem {
+    /// Whether this filesystem rejects paths that could escape its root. See
+    /// the [module-level docs](crate::hardened).
+    pub fn is_hardened(&self) -> bool {
+        self.hardened
+    }
+
+    pub fn set_hardened(&mut self, hardened: bool) {
+        self.hardened = hardened;
+    }
+
+    /// Like [Filesystem::insert], but in hardened mode fails with [UnsafePath]
+    /// instead of inserting an entry that could escape the root. This should
+    /// be used for any untrusted input.
+    pub fn try_insert(
+        &mut self,
+        path: impl Into<BytesPath>,
+        entry: impl Into<Entry>,
+    ) -> Result<InodeKey> {
+        let path = path.into();
+        let entry = entry.into();
+        self.check_path(&path)?;
+        if let Entry::Symlink(s) = &entry {
+            self.check_symlink(&path, s.target())?;
+        }
+        Ok(self.insert(path, entry))
+    }
+
+    /// Fail if 'path' could escape the root in hardened mode.
+    pub(crate) fn check_path(&self, path: &Path) -> Result<()> {
+        if !self.hardened {
+            return Ok(());
+        }
+        let unsafe_path = |reason| UnsafePath {
+            path: path.into(),
+            reason,
+        };
+        for component in path.components() {
+            match component {
+                Component::RootDir | Component::Prefix(_) => {
+                    return Err(unsafe_path(UnsafePathReason::Absolute).into());
+                }
+                Component::ParentDir => {
+                    return Err(unsafe_path(UnsafePathReason::ParentDir).into());
+                }
+                Component::CurDir | Component::Normal(_) => (),
+            }
+        }
+        if path
+            .ancestors()
+            .skip(1)
+            .any(|parent| self.get(parent).is_ok_and(Entry::is_symlink))
+        {
+            return Err(unsafe_path(UnsafePathReason::ThroughSymlink).into());
+        }
+        Ok(())
+    }
+
+    /// Absolute targets are always resolved relative to the root of the
+    /// filesystem, so only relative targets can climb above it.
+    fn check_symlink(&self, path: &Path, target: &Path) -> Result<()> {
+        if !self.hardened || target.is_absolute() {
+            return Ok(());
+        }
+        let mut depth = path.parent().map_or(0, |p| p.components().count() as isize);
+        for component in target.components() {
+            match component {
+                Component::ParentDir => depth -= 1,
+                Component::Normal(_) => depth += 1,
+                _ => (),
+            }
+            if depth < 0 {
+                return Err(UnsafePath {
+                    path: path.into(),
+                    reason: UnsafePathReason::SymlinkEscapesRoot,
+                }
+                .into());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::entry::Symlink;
+    use crate::File;
+
+    fn reason(e: Error) -> UnsafePathReason {
+        assert_eq!(ErrorKind::InvalidInput, e.kind());
+        e.get_ref()
+            .and_then(|e| e.downcast_ref::<UnsafePath>())
+            .expect("not an UnsafePath")
+            .reason()
+    }
+
+    #[test]
+    fn hardened() {
+        let mut fs = Filesystem::new();
+        fs.set_hardened(true);
+        fs.try_insert("a/b", File::default()).unwrap();
+        fs.try_insert("a/c", Symlink::new("../b", None)).unwrap();
+        fs.try_insert("a/d", Symlink::new("/etc", None)).unwrap();
+        assert_eq!(
+            UnsafePathReason::Absolute,
+            reason(fs.try_insert("/etc/passwd", File::default()).unwrap_err())
+        );
+        assert_eq!(
+            UnsafePathReason::ParentDir,
+            reason(fs.try_insert("a/../../x", File::default()).unwrap_err())
+        );
+        assert_eq!(
+            UnsafePathReason::ThroughSymlink,
+            reason(fs.try_insert("a/d/passwd", File::default()).unwrap_err())
+        );
+        assert_eq!(
+            UnsafePathReason::SymlinkEscapesRoot,
+            reason(
+                fs.try_insert("a/e", Symlink::new("../../etc", None))
+                    .unwrap_err()
+            )
+        );
+        assert_eq!(
+            UnsafePathReason::ParentDir,
+            reason(fs.link("a/b", "../b").unwrap_err())
+        );
+        assert_eq!(
+            UnsafePathReason::Absolute,
+            reason(fs.rename("a/b", "/b").unwrap_err())
+        );
+        assert!(fs.get("a/b").is_ok());
+
+        fs.set_hardened(false);
+        fs.try_insert("/etc/passwd", File::default()).unwrap();
+    }
+}
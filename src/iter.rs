@@ -1,3 +1,8 @@
+use std::collections::btree_map::Range;
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::ops::Bound;
+use std::os::unix::ffi::OsStrExt;
 use std::path::Path;
 
 use crate::BytesPath;
@@ -14,29 +19,177 @@ impl<'f> IntoIterator for &'f Filesystem {
     }
 }
 
+/// Order in which to visit the entries of a [Filesystem].
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum IterOrder {
+    /// Depth-first, where every directory is immediately followed by all of
+    /// its children, sorted by name. This is the order of [Filesystem::iter].
+    #[default]
+    DepthFirst,
+    /// Like [IterOrder::DepthFirst], but within each directory all the
+    /// subdirectories are visited before any other entries.
+    DirectoriesFirst,
+    /// Sorted by the raw bytes of the full path. Parents still come before
+    /// their children, but not necessarily immediately before (`a/b` sorts
+    /// after `a.txt`).
+    Lexicographic,
+}
+
 impl Filesystem {
-    pub fn iter(&self) -> Iter {
+    pub fn iter(&self) -> Iter<'_> {
+        Iter {
+            iter: self.paths.range::<Path, _>(..),
+            fs: self,
+            prefix: None,
+        }
+    }
+
+    /// Iterate over 'prefix' and everything underneath it, without visiting
+    /// any other entries. If 'prefix' does not exist, any entries that would
+    /// be underneath it are still returned.
+    pub fn iter_prefix<'f>(&'f self, prefix: &'f Path) -> Iter<'f> {
         Iter {
-            iter: self.paths.iter(),
+            iter: range_prefix(&self.paths, prefix),
             fs: self,
+            prefix: Some(prefix),
         }
     }
+
+    /// Iterate over all the entries in the given order. See [IterOrder] for
+    /// details on what each order guarantees.
+    pub fn iter_ordered(
+        &self,
+        order: IterOrder,
+    ) -> impl Iterator<Item = (&'_ Path, &'_ Entry)> + '_ {
+        let mut entries: Vec<_> = self.iter().collect();
+        match order {
+            IterOrder::DepthFirst => (),
+            IterOrder::DirectoriesFirst => {
+                entries.sort_by_cached_key(|(path, entry)| {
+                    let mut key: Vec<(bool, &OsStr)> =
+                        path.iter().map(|component| (false, component)).collect();
+                    // every component but the last is a directory, so the only
+                    // one that might need to sort later is the leaf
+                    if let Some(last) = key.last_mut() {
+                        last.0 = !entry.is_directory();
+                    }
+                    key
+                })
+            }
+            IterOrder::Lexicographic => {
+                entries.sort_by_key(|(path, _)| path.as_os_str().as_bytes())
+            }
+        }
+        entries.into_iter()
+    }
+}
+
+/// Since [BytesPath]s are ordered by their components, all the descendants of
+/// 'prefix' immediately follow it in the map, so this range starts at 'prefix'
+/// and the caller must stop at the first path that does not start with it.
+pub(crate) fn range_prefix<'f, V>(
+    paths: &'f BTreeMap<BytesPath, V>,
+    prefix: &Path,
+) -> Range<'f, BytesPath, V> {
+    paths.range::<Path, _>((Bound::Included(prefix), Bound::Unbounded))
 }
 
 pub struct Iter<'f> {
     fs: &'f Filesystem,
-    iter: std::collections::btree_map::Iter<'f, BytesPath, InodeKey>,
+    iter: Range<'f, BytesPath, InodeKey>,
+    prefix: Option<&'f Path>,
 }
 
 impl<'f> Iterator for Iter<'f> {
     type Item = (&'f Path, &'f Entry);
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.iter.next().map(|(path, inode)| {
-            (
-                path.as_ref(),
-                self.fs.inodes.get(*inode).expect("must exist"),
-            )
-        })
+        let (path, inode) = self.iter.next()?;
+        if let Some(prefix) = self.prefix {
+            if !path.starts_with(prefix) {
+                return None;
+            }
+        }
+        Some((
+            path.as_ref(),
+            self.fs.inodes.get(*inode).expect("must exist"),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::entry::Directory;
+    use crate::File;
+
+    fn fs() -> Filesystem {
+        Filesystem::from([
+            ("", Directory::default().into()),
+            ("a", Directory::default().into()),
+            ("a.txt", File::default().into()),
+            ("a/b.txt", File::default().into()),
+            ("a/c", Directory::default().into()),
+            ("a/c/d", File::default().into()),
+            ("a-b", Directory::default().into()),
+        ])
+    }
+
+    fn paths(fs: &Filesystem, order: IterOrder) -> Vec<&str> {
+        fs.iter_ordered(order)
+            .map(|(p, _)| p.to_str().expect("utf8"))
+            .collect()
+    }
+
+    #[test]
+    fn orders() {
+        let fs = fs();
+        assert_eq!(
+            vec!["", "a", "a/b.txt", "a/c", "a/c/d", "a-b", "a.txt"],
+            paths(&fs, IterOrder::DepthFirst),
+        );
+        assert_eq!(
+            fs.iter().map(|(p, _)| p).collect::<Vec<_>>(),
+            fs.iter_ordered(IterOrder::DepthFirst)
+                .map(|(p, _)| p)
+                .collect::<Vec<_>>(),
+        );
+        assert_eq!(
+            vec!["", "a", "a/c", "a/c/d", "a/b.txt", "a-b", "a.txt"],
+            paths(&fs, IterOrder::DirectoriesFirst),
+        );
+        assert_eq!(
+            vec!["", "a", "a-b", "a.txt", "a/b.txt", "a/c", "a/c/d"],
+            paths(&fs, IterOrder::Lexicographic),
+        );
+    }
+
+    #[test]
+    fn iter_prefix() {
+        let fs = fs();
+        assert_eq!(
+            vec!["a", "a/b.txt", "a/c", "a/c/d"],
+            fs.iter_prefix(Path::new("a"))
+                .map(|(p, _)| p.to_str().expect("utf8"))
+                .collect::<Vec<_>>(),
+        );
+        assert_eq!(
+            vec!["a/c", "a/c/d"],
+            fs.iter_prefix(Path::new("a/c"))
+                .map(|(p, _)| p.to_str().expect("utf8"))
+                .collect::<Vec<_>>(),
+        );
+        assert_eq!(fs.iter().count(), fs.iter_prefix(Path::new("")).count());
+        assert_eq!(0, fs.iter_prefix(Path::new("b")).count());
+    }
+
+    #[test]
+    fn lookup_with_similar_siblings() {
+        let fs = fs();
+        for (path, _) in fs.iter_ordered(IterOrder::Lexicographic) {
+            assert!(fs.get(path).is_ok(), "{}", path.display());
+        }
     }
 }
@@ -0,0 +1,440 @@
+//! Record the mutations made to a [Filesystem] as a sequence of [Op]s that
+//! can be inspected, serialized (with the `serde` feature) and replayed onto
+//! another [Filesystem]. This is a format-agnostic equivalent of a btrfs
+//! sendstream.
+//!
+//! Only mutations made through [Filesystem] methods (and [Filesystem::open]
+//! handles) are recorded. Changes made directly to an [Entry] through
+//! [Filesystem::get_mut] are invisible to the journal.
+
+use std::io::Result;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use bytes::Bytes;
+use nix::sys::stat::Mode;
+#[cfg(feature = "serde")]
+use serde::Deserialize;
+#[cfg(feature = "serde")]
+use serde::Serialize;
+
+use crate::BytesPath;
+use crate::Entry;
+use crate::File;
+use crate::Filesystem;
+use crate::Gid;
+use crate::Uid;
+
+/// A single recorded mutation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+#[remain::sorted]
+pub enum Op {
+    Chmod {
+        path: BytesPath,
+        mode: u32,
+    },
+    Chown {
+        path: BytesPath,
+        uid: Uid,
+        gid: Gid,
+    },
+    CopyFileRange {
+        src: BytesPath,
+        src_offset: u64,
+        dst: BytesPath,
+        dst_offset: u64,
+        len: u64,
+    },
+    Insert {
+        path: BytesPath,
+        entry: Entry,
+    },
+    Link {
+        old: BytesPath,
+        new: BytesPath,
+    },
+    RemoveXattr {
+        path: BytesPath,
+        name: Bytes,
+    },
+    Rename {
+        from: BytesPath,
+        to: BytesPath,
+    },
+    SetTimes {
+        path: BytesPath,
+        created: SystemTime,
+        accessed: SystemTime,
+        modified: SystemTime,
+    },
+    SetXattr {
+        path: BytesPath,
+        name: Bytes,
+        value: Bytes,
+    },
+    Truncate {
+        path: BytesPath,
+        len: u64,
+    },
+    Unlink {
+        path: BytesPath,
+    },
+    Write {
+        path: BytesPath,
+        offset: u64,
+        data: Bytes,
+    },
+}
+
+impl Op {
+    /// The path that is changed by this operation. For [Op::Link] and
+    /// [Op::Rename] this is the new path, and for [Op::CopyFileRange] it is
+    /// the destination.
+    pub fn path(&self) -> &Path {
+        #[remain::sorted]
+        match self {
+            Self::Chmod { path, .. } => path,
+            Self::Chown { path, .. } => path,
+            Self::CopyFileRange { dst, .. } => dst,
+            Self::Insert { path, .. } => path,
+            Self::Link { new, .. } => new,
+            Self::RemoveXattr { path, .. } => path,
+            Self::Rename { to, .. } => to,
+            Self::SetTimes { path, .. } => path,
+            Self::SetXattr { path, .. } => path,
+            Self::Truncate { path, .. } => path,
+            Self::Unlink { path } => path,
+            Self::Write { path, .. } => path,
+        }
+    }
+
+    /// Apply this single operation to 'fs'.
+    pub fn apply(&self, fs: &mut Filesystem) -> Result<()> {
+        #[remain::sorted]
+        match self {
+            Self::Chmod { path, mode } => fs.chmod(path, Mode::from_bits_truncate(*mode)),
+            Self::Chown { path, uid, gid } => fs.chown(path, *uid, *gid),
+            Self::CopyFileRange {
+                src,
+                src_offset,
+                dst,
+                dst_offset,
+                len,
+            } => fs
+                .copy_file_range(src, *src_offset, dst, *dst_offset, *len)
+                .map(|_| ()),
+            Self::Insert { path, entry } => fs.try_insert(path.clone(), entry.clone()).map(|_| ()),
+            Self::Link { old, new } => fs.link(old, new.clone()),
+            Self::RemoveXattr { path, name } => fs.remove_xattr(path, name.clone()).map(|_| ()),
+            Self::Rename { from, to } => fs.rename(from, to.clone()),
+            Self::SetTimes {
+                path,
+                created,
+                accessed,
+                modified,
+            } => fs.set_times(path, *created, *accessed, *modified),
+            Self::SetXattr { path, name, value } => {
+                fs.set_xattr(path, name.clone(), value.clone()).map(|_| ())
+            }
+            Self::Truncate { path, len } => fs.truncate(path, *len),
+            Self::Unlink { path } => fs.unlink(path),
+            Self::Write { path, offset, data } => fs.write(path, *offset, data.clone()),
+        }
+    }
+}
+
+impl Filesystem {
+    /// Start recording every mutation into the journal, discarding anything
+    /// that was previously recorded.
+    pub fn start_journal(&mut self) {
+        self.journal = Some(Vec::new());
+    }
+
+    /// Operations recorded since [Filesystem::start_journal], or [None] if
+    /// the journal is not enabled.
+    pub fn journal(&self) -> Option<&[Op]> {
+        self.journal.as_deref()
+    }
+
+    /// Stop recording and return everything that was recorded.
+    pub fn stop_journal(&mut self) -> Vec<Op> {
+        self.journal.take().unwrap_or_default()
+    }
+
+    /// Apply all of 'ops' in order. If any of them fails, none of them are
+    /// applied.
+    pub fn replay<'o>(&mut self, ops: impl IntoIterator<Item = &'o Op>) -> Result<()> {
+        self.transaction(|fs| {
+            for op in ops {
+                op.apply(fs)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Record a mutation that was just made, notifying any
+    /// [watchers](crate::watch) along the way. 'old' is the entry that was at
+    /// [Op::path] before the mutation, as returned by [Filesystem::watched].
+    pub(crate) fn record(&mut self, old: Option<Arc<Entry>>, op: impl FnOnce(&Self) -> Op) {
+        if self.journal.is_none() && !self.is_watched() {
+            return;
+        }
+        let op = op(self);
+        self.notify(&op, old);
+        if let Some(journal) = &mut self.journal {
+            journal.push(op);
+        }
+    }
+
+    /// Write 'data' into the regular file at 'path', starting at 'offset'.
+    pub fn write<P>(&mut self, path: P, offset: u64, data: impl Into<Bytes>) -> Result<()>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+        let data = data.into();
+        let old = self.watched(path);
+        self.get_file_mut(path)?.write_at(data.clone(), offset);
+        self.record(old, |_| Op::Write {
+            path: path.into(),
+            offset,
+            data,
+        });
+        Ok(())
+    }
+
+    /// Share 'len' bytes of the regular file 'src' starting at 'src_offset'
+    /// with 'dst' at 'dst_offset' (as [Extent::Cloned](crate::file::extent::Extent::Cloned)
+    /// extents), like a reflink with copy_file_range(2) or a btrfs clone.
+    /// The range is cut short at the end of 'src', and the number of bytes
+    /// that were actually cloned is returned.
+    pub fn copy_file_range<P1, P2>(
+        &mut self,
+        src: P1,
+        src_offset: u64,
+        dst: P2,
+        dst_offset: u64,
+        len: u64,
+    ) -> Result<u64>
+    where
+        P1: AsRef<Path>,
+        P2: AsRef<Path>,
+    {
+        let (src, dst) = (BytesPath::from(src.as_ref()), dst.as_ref());
+        let src_file = self.get_file(&src)?;
+        let end = std::cmp::min(src_offset.saturating_add(len), src_file.len());
+        let len = end.saturating_sub(src_offset);
+        // check that 'dst' is a file even if there is nothing to clone
+        self.get_file(dst)?;
+        if len == 0 {
+            return Ok(0);
+        }
+        let extents = src_file.clone_range_from(src_offset..end, Some(&src))?;
+        let old = self.watched(dst);
+        let mut writer = self.get_file_mut(dst)?.writer();
+        std::io::Seek::seek(&mut writer, std::io::SeekFrom::Start(dst_offset))?;
+        for extent in extents {
+            writer.write(extent);
+        }
+        self.record(old, |_| Op::CopyFileRange {
+            src,
+            src_offset,
+            dst: dst.into(),
+            dst_offset,
+            len,
+        });
+        Ok(len)
+    }
+
+    /// [Filesystem::copy_file_range] from 'src', a file that is not in this
+    /// filesystem (like one in another btrfs subvolume), which the cloned
+    /// extents record as being at 'src_path'. The journal cannot refer to
+    /// 'src', so this is recorded as an [Op::Write] of the data instead.
+    pub fn copy_file_range_from<P>(
+        &mut self,
+        src: &File,
+        src_path: Option<&BytesPath>,
+        src_offset: u64,
+        dst: P,
+        dst_offset: u64,
+        len: u64,
+    ) -> Result<u64>
+    where
+        P: AsRef<Path>,
+    {
+        let dst = dst.as_ref();
+        let end = std::cmp::min(src_offset.saturating_add(len), src.len());
+        let len = end.saturating_sub(src_offset);
+        self.get_file(dst)?;
+        if len == 0 {
+            return Ok(0);
+        }
+        let extents = src.clone_range_from(src_offset..end, src_path)?;
+        let data = match self.journal.is_some() || self.is_watched() {
+            true => {
+                let mut data = vec![0; len as usize];
+                src.read_at(&mut data, src_offset)?;
+                Bytes::from(data)
+            }
+            false => Bytes::new(),
+        };
+        let old = self.watched(dst);
+        let mut writer = self.get_file_mut(dst)?.writer();
+        std::io::Seek::seek(&mut writer, std::io::SeekFrom::Start(dst_offset))?;
+        for extent in extents {
+            writer.write(extent);
+        }
+        self.record(old, |_| Op::Write {
+            path: dst.into(),
+            offset: dst_offset,
+            data,
+        });
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::open::OpenOptions;
+    use crate::tests::demo_fs;
+    use crate::File;
+
+    #[test]
+    fn replay() {
+        let mut fs = demo_fs();
+        fs.start_journal();
+        fs.insert("testdata/new", File::builder().contents("new\n").build());
+        fs.write("testdata/new", 4, "er\n").unwrap();
+        fs.rename("testdata/new", "testdata/newer").unwrap();
+        fs.chmod("testdata/newer", Mode::from_bits_truncate(0o600))
+            .unwrap();
+        fs.link("testdata/newer", "testdata/dir/newer").unwrap();
+        fs.set_xattr("testdata/newer", "user.a", "b").unwrap();
+        fs.remove_xattr("testdata/lorem.txt", "user.demo").unwrap();
+        fs.chown_recursive("testdata/dir", Uid::from_raw(1), Gid::from_raw(1))
+            .unwrap();
+        std::io::Write::write_all(
+            &mut fs
+                .open("testdata/lorem.txt", OpenOptions::new().append(true))
+                .unwrap(),
+            b"dolor\n",
+        )
+        .unwrap();
+        fs.rmdir("testdata/dir").unwrap_err();
+        let ops = fs.stop_journal();
+        assert!(fs.journal().is_none());
+        assert_eq!(
+            Some(&Op::Write {
+                path: "testdata/new".into(),
+                offset: 4,
+                data: "er\n".into()
+            }),
+            ops.get(1)
+        );
+
+        let mut replayed = demo_fs();
+        replayed.replay(&ops).unwrap();
+        assert_eq!(fs, replayed);
+        assert_eq!(
+            b"new\ner\n".as_slice(),
+            replayed
+                .get_file("testdata/dir/newer")
+                .unwrap()
+                .to_bytes()
+                .unwrap()
+                .as_ref()
+        );
+
+        // replay is all-or-nothing
+        let mut empty = Filesystem::new();
+        assert!(empty.replay(&ops).is_err());
+        assert!(empty.is_empty());
+    }
+
+    #[test]
+    fn copy_file_range() {
+        let mut fs = demo_fs();
+        fs.insert("copy", File::builder().contents("0123456789").build());
+        fs.truncate("copy", 16).unwrap();
+        fs.start_journal();
+        let cloned = fs
+            .copy_file_range("testdata/lorem.txt", 6, "copy", 2, 100)
+            .unwrap();
+        assert_eq!("ipsum\n".len() as u64, cloned);
+        let copy = fs.get_file("copy").unwrap().clone();
+        assert_eq!(
+            b"01ipsum\n89\0\0\0\0\0\0",
+            copy.to_bytes().unwrap().as_ref()
+        );
+        assert!(matches!(
+            copy.extents[&2],
+            crate::file::extent::Extent::Cloned(_)
+        ));
+
+        let ops = fs.stop_journal();
+        let mut replayed = demo_fs();
+        replayed.insert("copy", File::builder().contents("0123456789").build());
+        replayed.truncate("copy", 16).unwrap();
+        replayed.replay(&ops).unwrap();
+        assert_eq!(&copy, replayed.get_file("copy").unwrap());
+        assert_eq!(0, fs.copy_file_range("copy", 16, "copy", 0, 1).unwrap());
+    }
+
+    #[test]
+    fn copy_file_range_from() {
+        let other = demo_fs();
+        let src = other.get_file("testdata/lorem.txt").unwrap();
+        let mut fs = Filesystem::new();
+        fs.insert("copy", File::builder().contents("0123456789").build());
+        fs.start_journal();
+        let cloned = fs
+            .copy_file_range_from(src, Some(&"lorem.txt".into()), 6, "copy", 2, 100)
+            .unwrap();
+        assert_eq!("ipsum\n".len() as u64, cloned);
+        let copy = fs.get_file("copy").unwrap().clone();
+        assert_eq!(b"01ipsum\n89", copy.to_bytes().unwrap().as_ref());
+        let crate::file::extent::Extent::Cloned(c) = &copy.extents[&2] else {
+            panic!("{:?} is not cloned", copy.extents[&2]);
+        };
+        assert_eq!(Some(Path::new("lorem.txt")), c.src_path());
+
+        let ops = fs.stop_journal();
+        assert_eq!(
+            vec![Op::Write {
+                path: "copy".into(),
+                offset: 2,
+                data: "ipsum\n".into()
+            }],
+            ops
+        );
+        let mut replayed = Filesystem::new();
+        replayed.insert("copy", File::builder().contents("0123456789").build());
+        replayed.replay(&ops).unwrap();
+        assert_eq!(
+            copy.to_bytes().unwrap(),
+            replayed.get_file("copy").unwrap().to_bytes().unwrap()
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde() {
+        let mut fs = Filesystem::new();
+        fs.start_journal();
+        fs.insert("", crate::entry::Directory::default());
+        fs.insert("f", File::builder().contents("hello").build());
+        fs.write("f", 5, " world").unwrap();
+        let ops = fs.stop_journal();
+        let json = serde_json::to_string(&ops).unwrap();
+        let deserialized: Vec<Op> = serde_json::from_str(&json).unwrap();
+        assert_eq!(ops, deserialized);
+        let mut replayed = Filesystem::new();
+        replayed.replay(&deserialized).unwrap();
+        assert_eq!(fs, replayed);
+    }
+}
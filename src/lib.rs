@@ -7,50 +7,83 @@
 //! can be represented in the archive format).
 
 #![feature(io_error_more)]
-#![feature(io_error_other)]
 #![feature(proc_macro_hygiene)]
 #![feature(stmt_expr_attributes)]
-#![feature(unix_chown)]
 
 use std::collections::BTreeMap;
+use std::collections::HashMap;
 use std::collections::HashSet;
 use std::fmt::Debug;
 use std::io::Error;
 use std::io::ErrorKind;
 use std::io::Result;
 use std::path::Path;
+use std::sync::Arc;
 use std::time::SystemTime;
 
+use bytes::Bytes;
 use nix::sys::stat::Mode;
 use slotmap::SecondaryMap;
 use slotmap::SlotMap;
 
 #[cfg(feature = "archive")]
 pub mod archive;
+pub mod audit;
 #[cfg(feature = "btrfs")]
 pub mod btrfs;
+mod builder;
 mod bytes_ext;
+#[cfg(feature = "cdc")]
+pub mod chunk;
 pub mod cmp;
+pub mod dedup;
 #[cfg(feature = "diff")]
 pub mod diff;
+mod dir;
 mod entry;
 pub mod file;
+pub mod hardened;
 mod iter;
+pub mod journal;
+pub mod materialize;
+pub mod merkle;
+pub mod open;
 mod path;
-
+mod resolve;
+#[cfg(feature = "serde")]
+mod ser;
+pub mod snapshot;
+pub mod tree;
+pub mod vfs;
+pub mod watch;
+pub mod whiteout;
+
+pub use builder::FilesystemBuilder;
 pub(crate) use bytes_ext::BytesExt;
 pub use entry::Entry;
 use file::File;
+pub use iter::IterOrder;
+use journal::Op;
 pub use path::BytesPath;
+pub use resolve::DEFAULT_MAX_SYMLINK_HOPS;
+use watch::PendingEvent;
+use watch::WatchKey;
+use watch::Watcher;
 
 slotmap::new_key_type! { pub struct InodeKey; }
 
 /// Full view of a filesystem.
 #[derive(Clone)]
 pub struct Filesystem {
-    inodes: SlotMap<InodeKey, Entry>,
+    inodes: SlotMap<InodeKey, Arc<Entry>>,
     refcounts: SecondaryMap<InodeKey, usize>,
     paths: BTreeMap<BytesPath, InodeKey>,
+    max_symlink_hops: usize,
+    hardened: bool,
+    journal: Option<Vec<Op>>,
+    watchers: SlotMap<WatchKey, Watcher>,
+    /// Events that watchers will see once the current transaction commits
+    pending: Option<Vec<PendingEvent>>,
 }
 
 impl Filesystem {
@@ -59,22 +92,85 @@ impl Filesystem {
             inodes: SlotMap::with_key(),
             refcounts: SecondaryMap::new(),
             paths: BTreeMap::new(),
+            max_symlink_hops: DEFAULT_MAX_SYMLINK_HOPS,
+            hardened: false,
+            journal: None,
+            watchers: SlotMap::with_key(),
+            pending: None,
         }
     }
 
+    /// Number of paths in this filesystem. Hardlinks are counted once for
+    /// each path that refers to the same inode.
+    pub fn len(&self) -> usize {
+        self.paths.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.paths.is_empty()
+    }
+
+    /// Number of distinct inodes that are reachable from at least one path.
+    /// This is smaller than [Filesystem::len] when there are hardlinks.
+    pub fn inode_count(&self) -> usize {
+        self.inodes.len()
+    }
+
+    /// Number of paths that refer to the same inode as 'path' (st_nlink).
+    pub fn nlink<P>(&self, path: P) -> Result<usize>
+    where
+        P: AsRef<Path>,
+    {
+        self.paths
+            .get(path.as_ref())
+            .map(|key| self.refcounts[*key])
+            .ok_or_else(|| {
+                Error::new(
+                    ErrorKind::NotFound,
+                    format!("'{}' not found", path.as_ref().display()),
+                )
+            })
+    }
+
+    /// Add an entry at 'path', replacing anything that was already there. This
+    /// does not do any of the checks of hardened mode, use
+    /// [Filesystem::try_insert] for untrusted paths.
     pub fn insert(&mut self, path: impl Into<BytesPath>, entry: impl Into<Entry>) -> InodeKey {
-        let key = self.inodes.insert(entry.into());
-        self.paths.insert(path.into(), key);
+        let path = path.into();
+        let entry = entry.into();
+        let old = self.watched(&path);
+        let key = self.inodes.insert(Arc::new(entry));
+        if let Some(old) = self.paths.insert(path.clone(), key) {
+            self.decref(old);
+        }
         self.refcounts.insert(key, 1);
+        self.record(old, |fs| Op::Insert {
+            path,
+            entry: Entry::clone(&fs.inodes[key]),
+        });
         key
     }
 
+    /// Drop one reference to an inode, removing it completely when there are
+    /// no paths left that point to it.
+    fn decref(&mut self, key: InodeKey) {
+        self.refcounts[key] -= 1;
+        if self.refcounts[key] == 0 {
+            self.refcounts.remove(key);
+            self.inodes.remove(key);
+        }
+    }
+
     pub fn unlink<P>(&mut self, path: P) -> Result<()>
     where
         P: AsRef<Path>,
     {
+        let old = self.watched(path.as_ref());
         if let Some(key) = self.paths.remove(path.as_ref()) {
-            self.refcounts[key] -= 1;
+            self.decref(key);
+            self.record(old, |_| Op::Unlink {
+                path: path.as_ref().into(),
+            });
             Ok(())
         } else {
             Err(Error::new(
@@ -91,6 +187,7 @@ impl Filesystem {
         self.paths
             .get(path.as_ref())
             .and_then(|key| self.inodes.get(*key))
+            .map(Arc::as_ref)
             .ok_or_else(|| {
                 Error::new(
                     ErrorKind::NotFound,
@@ -106,6 +203,7 @@ impl Filesystem {
         self.paths
             .get(path.as_ref())
             .and_then(|key| self.inodes.get_mut(*key))
+            .map(Arc::make_mut)
             .ok_or_else(|| {
                 Error::new(
                     ErrorKind::NotFound,
@@ -144,7 +242,12 @@ impl Filesystem {
     where
         P: AsRef<Path>,
     {
-        self.get_mut(path)?.chmod(mode);
+        let old = self.watched(path.as_ref());
+        self.get_mut(path.as_ref())?.chmod(mode);
+        self.record(old, |_| Op::Chmod {
+            path: path.as_ref().into(),
+            mode: mode.bits(),
+        });
         Ok(())
     }
 
@@ -152,7 +255,32 @@ impl Filesystem {
     where
         P: AsRef<Path>,
     {
-        self.get_mut(path)?.chown(uid, gid);
+        let old = self.watched(path.as_ref());
+        self.get_mut(path.as_ref())?.chown(uid, gid);
+        self.record(old, |_| Op::Chown {
+            path: path.as_ref().into(),
+            uid,
+            gid,
+        });
+        Ok(())
+    }
+
+    /// Change the ownership of 'path' and everything underneath it. Like
+    /// `chown -R`, symlinks are not followed, the link itself is changed.
+    pub fn chown_recursive<P>(&mut self, path: P, uid: Uid, gid: Gid) -> Result<()>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+        // fail if the top of the tree does not exist
+        self.get(path)?;
+        let paths: Vec<BytesPath> = iter::range_prefix(&self.paths, path)
+            .take_while(|(p, _)| p.starts_with(path))
+            .map(|(p, _)| p.clone())
+            .collect();
+        for p in paths {
+            self.chown(p, uid, gid)?;
+        }
         Ok(())
     }
 
@@ -161,20 +289,26 @@ impl Filesystem {
         P1: AsRef<Path>,
         P2: Into<BytesPath>,
     {
-        let inode = self.paths.remove(from.as_ref()).ok_or_else(|| {
-            Error::new(
+        let to = to.into();
+        self.check_path(&to)?;
+        if !self.paths.contains_key(from.as_ref()) {
+            return Err(Error::new(
                 ErrorKind::NotFound,
                 format!("'{}' not found", from.as_ref().display()),
-            )
-        })?;
-        let to = to.into();
+            ));
+        }
         if self.paths.contains_key(&to) {
             return Err(Error::new(
                 ErrorKind::AlreadyExists,
                 format!("'{}' already exists", to.display()),
             ));
         }
-        self.paths.insert(to, inode);
+        let inode = self.paths.remove(from.as_ref()).expect("checked above");
+        self.paths.insert(to.clone(), inode);
+        self.record(None, |_| Op::Rename {
+            from: from.as_ref().into(),
+            to,
+        });
         Ok(())
     }
 
@@ -188,9 +322,16 @@ impl Filesystem {
     where
         P: AsRef<Path>,
     {
-        self.get_mut(path)?
+        let old = self.watched(path.as_ref());
+        self.get_mut(path.as_ref())?
             .metadata_mut()
             .set_times(created, accessed, modified);
+        self.record(old, |_| Op::SetTimes {
+            path: path.as_ref().into(),
+            created,
+            accessed,
+            modified,
+        });
         Ok(())
     }
 
@@ -202,23 +343,32 @@ impl Filesystem {
         P1: AsRef<Path>,
         P2: Into<BytesPath>,
     {
-        let key = self.paths.get(old.as_ref()).ok_or_else(|| {
+        let key = *self.paths.get(old.as_ref()).ok_or_else(|| {
             Error::new(
                 ErrorKind::NotFound,
                 format!("'{}' not found", old.as_ref().display()),
             )
         })?;
-        if !self.inodes[*key].is_directory() {
+        if self.inodes[key].is_directory() {
             return Err(Error::new(
                 ErrorKind::IsADirectory,
                 "directory cannot be hardlink target",
             ));
         }
-        self.refcounts
-            .entry(*key)
-            .expect("refcount impossibly None")
-            .and_modify(|r| *r += 1);
-        self.paths.insert(new.into(), *key);
+        let new = new.into();
+        self.check_path(&new)?;
+        if self.paths.contains_key(&new) {
+            return Err(Error::new(
+                ErrorKind::AlreadyExists,
+                format!("'{}' already exists", new.display()),
+            ));
+        }
+        self.refcounts[key] += 1;
+        self.paths.insert(new.clone(), key);
+        self.record(None, |_| Op::Link {
+            old: old.as_ref().into(),
+            new,
+        });
         Ok(())
     }
 
@@ -226,10 +376,51 @@ impl Filesystem {
     where
         P: AsRef<Path>,
     {
-        self.get_file_mut(path)?.truncate(len);
+        let old = self.watched(path.as_ref());
+        self.get_file_mut(path.as_ref())?.truncate(len);
+        self.record(old, |_| Op::Truncate {
+            path: path.as_ref().into(),
+            len,
+        });
         Ok(())
     }
 
+    pub fn set_xattr<P>(
+        &mut self,
+        path: P,
+        name: impl Into<Bytes>,
+        value: impl Into<Bytes>,
+    ) -> Result<Option<Bytes>>
+    where
+        P: AsRef<Path>,
+    {
+        let (name, value) = (name.into(), value.into());
+        let old_entry = self.watched(path.as_ref());
+        let old = self
+            .get_mut(path.as_ref())?
+            .set_xattr(name.clone(), value.clone());
+        self.record(old_entry, |_| Op::SetXattr {
+            path: path.as_ref().into(),
+            name,
+            value,
+        });
+        Ok(old)
+    }
+
+    pub fn remove_xattr<P>(&mut self, path: P, name: impl Into<Bytes>) -> Result<Option<Bytes>>
+    where
+        P: AsRef<Path>,
+    {
+        let name = name.into();
+        let old_entry = self.watched(path.as_ref());
+        let old = self.get_mut(path.as_ref())?.remove_xattr(&name);
+        self.record(old_entry, |_| Op::RemoveXattr {
+            path: path.as_ref().into(),
+            name,
+        });
+        Ok(old)
+    }
+
     /// Remove a directory, failing if it is not empty
     pub fn rmdir<P>(&mut self, path: P) -> Result<()>
     where
@@ -242,16 +433,73 @@ impl Filesystem {
                 format!("'{}' is not a directory", dir.display()),
             ));
         }
-        if self
-            .paths
-            .iter()
-            .any(|(p, _)| p.starts_with(dir) && dir != p.as_path())
-        {
+        if self.iter_prefix(dir).any(|(p, _)| p != dir) {
             return Err(Error::new(
                 ErrorKind::DirectoryNotEmpty,
                 format!("'{}' is not empty", dir.display()),
             ));
         }
+        self.unlink(dir)
+    }
+
+    /// Apply all the mutations in 'f' or none of them. The closure operates on
+    /// a staged copy of this filesystem that only replaces it if 'f' returns
+    /// [Ok]. If 'f' fails (or panics) this filesystem is left untouched.
+    /// [Watchers](crate::watch) only see the mutations once they commit.
+    pub fn transaction<T, E, F>(&mut self, f: F) -> std::result::Result<T, E>
+    where
+        F: FnOnce(&mut Filesystem) -> std::result::Result<T, E>,
+    {
+        let mut staged = self.clone();
+        staged.pending = Some(Vec::new());
+        let out = f(&mut staged)?;
+        let events = std::mem::replace(&mut staged.pending, self.pending.take());
+        *self = staged;
+        self.commit_events(events.unwrap_or_default());
+        Ok(out)
+    }
+
+    /// Mount the contents of 'other' underneath 'prefix', so that the root of
+    /// 'other' becomes 'prefix'. If 'prefix' already exists as a directory it
+    /// keeps its own metadata, otherwise the root entry of 'other' (if any) is
+    /// used. Entries from 'other' replace any existing entries at the same
+    /// path, and hardlinks within 'other' are preserved.
+    pub fn graft<P>(&mut self, prefix: P, mut other: Filesystem) -> Result<()>
+    where
+        P: AsRef<Path>,
+    {
+        let prefix = prefix.as_ref();
+        if let Ok(existing) = self.get(prefix) {
+            if !existing.is_directory() {
+                return Err(Error::new(
+                    ErrorKind::NotADirectory,
+                    format!("'{}' is not a directory", prefix.display()),
+                ));
+            }
+            if let Some(root) = other.paths.remove(Path::new("")) {
+                other.decref(root);
+            }
+        }
+        // first new path of every inode in 'other', to recreate hardlinks
+        let mut linked: HashMap<InodeKey, BytesPath> = HashMap::new();
+        for (path, old_key) in std::mem::take(&mut other.paths) {
+            let path: BytesPath = match path.as_os_str().is_empty() {
+                true => prefix.into(),
+                false => prefix.join(path.as_path()).into(),
+            };
+            if self.paths.contains_key(&path) {
+                self.unlink(&path)?;
+            }
+            match linked.get(&old_key) {
+                Some(first) => self.link(first, path)?,
+                None => {
+                    let entry = other.inodes.remove(old_key).expect("inode must exist");
+                    let entry = Arc::unwrap_or_clone(entry);
+                    self.insert(path.clone(), entry);
+                    linked.insert(old_key, path);
+                }
+            }
+        }
         Ok(())
     }
 }
@@ -290,7 +538,12 @@ impl cmp::ApproxEq for Filesystem {
         let Self {
             paths,
             inodes,
-            refcounts: _,
+            refcounts,
+            max_symlink_hops: _,
+            hardened: _,
+            journal: _,
+            watchers: _,
+            pending: _,
         } = &self;
         let mut f = cmp::Fields::all();
         #[allow(clippy::mutable_key_type)]
@@ -301,11 +554,14 @@ impl cmp::ApproxEq for Filesystem {
             f.remove(cmp::Fields::PATH);
         }
         for (path, inode) in paths {
-            let entry = &inodes[*inode];
-            match other.get(path) {
-                Err(_) => f.remove(cmp::Fields::all_entry_fields()),
-                Ok(other_entry) => {
-                    f = f.intersection(cmp::ApproxEq::cmp(entry, other_entry));
+            let entry: &Entry = &inodes[*inode];
+            match other.paths.get(path) {
+                None => f.remove(cmp::Fields::all_entry_fields()),
+                Some(other_inode) => {
+                    f = f.intersection(cmp::ApproxEq::cmp(entry, &*other.inodes[*other_inode]));
+                    if refcounts[*inode] != other.refcounts[*other_inode] {
+                        f.remove(cmp::Fields::NLINK);
+                    }
                 }
             }
         }
@@ -348,6 +604,8 @@ impl Debug for Filesystem {
 macro_rules! id_type {
     ($i:ident, $nix:ty) => {
         #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+        #[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+        #[cfg_attr(feature = "serde", serde(transparent))]
         #[repr(transparent)]
         pub struct $i(u32);
 
@@ -398,10 +656,6 @@ macro_rules! id_type {
 id_type!(Uid, nix::unistd::Uid);
 id_type!(Gid, nix::unistd::Gid);
 
-mod __private {
-    pub trait Sealed {}
-}
-
 #[cfg(test)]
 pub(crate) mod tests {
     use nix::sys::stat::Mode;
@@ -507,4 +761,170 @@ pub(crate) mod tests {
         other.unlink("testdata/dir/lorem.txt").unwrap();
         assert_ne!(demo_fs(), other);
     }
+
+    #[test]
+    fn graft() {
+        let mut fs = demo_fs();
+        let mut other = demo_fs();
+        other.chmod("", Mode::from_bits_truncate(0o700)).unwrap();
+        fs.graft("mnt", other).expect("graft failed");
+        assert_eq!(
+            Mode::from_bits_truncate(0o700),
+            fs.get("mnt").unwrap().metadata().mode()
+        );
+        for (path, entry) in &demo_fs() {
+            if path.as_os_str().is_empty() {
+                continue;
+            }
+            assert_eq!(entry, fs.get(Path::new("mnt").join(path)).unwrap());
+            assert_eq!(entry, fs.get(path).unwrap());
+        }
+
+        // grafting onto an existing directory keeps its metadata
+        let mut fs = demo_fs();
+        let mut other = demo_fs();
+        other.chmod("", Mode::from_bits_truncate(0o700)).unwrap();
+        fs.graft("testdata/dir", other).expect("graft failed");
+        assert_eq!(
+            Mode::from_bits_truncate(0o755),
+            fs.get("testdata/dir").unwrap().metadata().mode()
+        );
+        assert!(fs.get("testdata/dir/testdata/lorem.txt").is_ok());
+
+        assert!(fs.graft("testdata/lorem.txt", demo_fs()).is_err());
+    }
+
+    #[test]
+    fn counts() {
+        let mut fs = demo_fs();
+        assert_eq!(6, fs.len());
+        assert_eq!(6, fs.inode_count());
+        assert!(!fs.is_empty());
+        fs.link("testdata/lorem.txt", "testdata/hardlink.txt")
+            .expect("link failed");
+        assert_eq!(7, fs.len());
+        assert_eq!(6, fs.inode_count());
+        fs.unlink("testdata/lorem.txt").unwrap();
+        assert_eq!(6, fs.inode_count());
+        assert!(fs.get("testdata/hardlink.txt").is_ok());
+        fs.unlink("testdata/hardlink.txt").unwrap();
+        assert_eq!(5, fs.inode_count());
+        // replacing an entry drops the old inode
+        fs.insert("testdata/dir/lorem.txt", File::default());
+        assert_eq!(5, fs.inode_count());
+        assert_eq!(Filesystem::new().len(), 0);
+        assert!(Filesystem::new().is_empty());
+    }
+
+    #[test]
+    fn refcounts() {
+        let mut fs = demo_fs();
+        assert_eq!(6, fs.inodes.len());
+        fs.link("testdata/lorem.txt", "testdata/hardlink.txt")
+            .expect("link failed");
+        assert_eq!(6, fs.inodes.len());
+        assert_eq!(2, fs.refcounts[fs.paths[Path::new("testdata/lorem.txt")]]);
+        // unlinking one of the paths keeps the inode alive for the other
+        fs.unlink("testdata/lorem.txt").unwrap();
+        assert_eq!(6, fs.inodes.len());
+        assert!(fs.get("testdata/hardlink.txt").is_ok());
+        // unlinking the last path frees the inode
+        fs.unlink("testdata/hardlink.txt").unwrap();
+        assert_eq!(5, fs.inodes.len());
+        assert_eq!(5, fs.refcounts.len());
+        // replacing an entry drops the old inode
+        fs.insert("testdata/dir/lorem.txt", File::default());
+        assert_eq!(5, fs.inodes.len());
+    }
+
+    #[test]
+    fn link() {
+        let mut fs = demo_fs();
+        assert_eq!(
+            ErrorKind::IsADirectory,
+            fs.link("testdata", "testdata2").unwrap_err().kind()
+        );
+        assert_eq!(
+            ErrorKind::AlreadyExists,
+            fs.link("testdata/lorem.txt", "testdata/dir/lorem.txt")
+                .unwrap_err()
+                .kind()
+        );
+        assert_eq!(
+            ErrorKind::NotFound,
+            fs.link("testdata/missing", "testdata/new")
+                .unwrap_err()
+                .kind()
+        );
+        fs.link("testdata/lorem.txt", "testdata/hardlink.txt")
+            .expect("link failed");
+        assert_eq!(
+            fs.get("testdata/lorem.txt").unwrap(),
+            fs.get("testdata/hardlink.txt").unwrap()
+        );
+    }
+
+    #[test]
+    fn rmdir() {
+        let mut fs = demo_fs();
+        assert_eq!(
+            ErrorKind::DirectoryNotEmpty,
+            fs.rmdir("testdata").unwrap_err().kind()
+        );
+        assert_eq!(
+            ErrorKind::NotADirectory,
+            fs.rmdir("testdata/lorem.txt").unwrap_err().kind()
+        );
+        fs.unlink("testdata/dir/lorem.txt").unwrap();
+        fs.unlink("testdata/dir/symlink").unwrap();
+        fs.rmdir("testdata/dir").expect("dir is empty");
+        assert!(fs.get("testdata/dir").is_err());
+    }
+
+    #[test]
+    fn chown_recursive() {
+        let mut fs = demo_fs();
+        fs.chown_recursive("testdata/dir", Uid::from_raw(1000), Gid::from_raw(100))
+            .expect("chown failed");
+        for (path, entry) in &fs {
+            let expected = match path.starts_with("testdata/dir") {
+                true => (Uid::from_raw(1000), Gid::from_raw(100)),
+                false => (Uid::from_raw(0), Gid::from_raw(0)),
+            };
+            assert_eq!(
+                expected,
+                (entry.metadata().uid(), entry.metadata().gid()),
+                "{}",
+                path.display()
+            );
+        }
+        assert!(fs
+            .chown_recursive("nope", Uid::from_raw(1000), Gid::from_raw(100))
+            .is_err());
+    }
+
+    #[test]
+    fn transaction() {
+        let mut fs = demo_fs();
+        let err = fs
+            .transaction(|tx| {
+                tx.unlink("testdata/lorem.txt")?;
+                tx.chmod("testdata", Mode::from_bits_truncate(0o700))?;
+                tx.unlink("testdata/nope")
+            })
+            .unwrap_err();
+        assert_eq!(ErrorKind::NotFound, err.kind());
+        assert_eq!(demo_fs(), fs);
+
+        fs.transaction(|tx| -> Result<()> {
+            tx.unlink("testdata/lorem.txt")?;
+            tx.chmod("testdata", Mode::from_bits_truncate(0o700))
+        })
+        .unwrap();
+        assert!(fs.get("testdata/lorem.txt").is_err());
+        assert_eq!(
+            Mode::from_bits_truncate(0o700),
+            fs.get("testdata").unwrap().metadata().mode()
+        );
+    }
 }
@@ -0,0 +1,219 @@
+//! Operations on the host relative to an open directory, like openat(2).
+//!
+//! No component of a path is ever followed if it is a symlink, so someone
+//! who can write to the destination directory cannot swap a directory for a
+//! symlink and redirect the rest of the materialization outside of it.
+
+use std::ffi::OsStr;
+use std::ffi::OsString;
+use std::io::Error;
+use std::io::ErrorKind;
+use std::io::Result;
+use std::os::fd::AsRawFd;
+use std::os::fd::BorrowedFd;
+use std::os::fd::FromRawFd;
+use std::os::fd::OwnedFd;
+use std::os::fd::RawFd;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Component;
+use std::path::Path;
+use std::path::PathBuf;
+
+use nix::dir::Dir;
+use nix::errno::Errno;
+use nix::fcntl::AtFlags;
+use nix::fcntl::OFlag;
+use nix::sys::stat::FchmodatFlags;
+use nix::sys::stat::FileStat;
+use nix::sys::stat::Mode;
+use nix::sys::stat::SFlag;
+use nix::sys::stat::UtimensatFlags;
+use nix::sys::time::TimeSpec;
+use nix::unistd::FchownatFlags;
+use nix::unistd::Gid;
+use nix::unistd::LinkatFlags;
+use nix::unistd::Uid;
+use nix::unistd::UnlinkatFlags;
+
+/// A path on the host, as its (open) parent directory and its name in there.
+pub(super) struct At {
+    parent: OwnedFd,
+    name: OsString,
+}
+
+fn openat(dir: RawFd, name: &OsStr, flags: OFlag, mode: Mode) -> Result<OwnedFd> {
+    let fd = nix::fcntl::openat(dir, name, flags | OFlag::O_CLOEXEC, mode)?;
+    // SAFETY: openat just returned this fd, so nothing else owns it
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+impl At {
+    /// Resolve 'path' (relative to 'root') without following any symlinks.
+    /// The root itself is `.` in a duplicate of 'root'.
+    pub(super) fn resolve(root: BorrowedFd, path: &Path) -> Result<Self> {
+        let dir = OFlag::O_PATH | OFlag::O_DIRECTORY;
+        let mut parent = openat(root.as_raw_fd(), OsStr::new("."), dir, Mode::empty())?;
+        for component in path.parent().into_iter().flat_map(Path::components) {
+            match component {
+                Component::Normal(name) => {
+                    parent = openat(
+                        parent.as_raw_fd(),
+                        name,
+                        dir | OFlag::O_NOFOLLOW,
+                        Mode::empty(),
+                    )?;
+                }
+                Component::CurDir => (),
+                _ => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("{} is not a relative path", path.display()),
+                    ));
+                }
+            }
+        }
+        let name = path.file_name().unwrap_or(OsStr::new(".")).to_owned();
+        Ok(Self { parent, name })
+    }
+
+    fn fd(&self) -> Option<RawFd> {
+        Some(self.parent.as_raw_fd())
+    }
+
+    /// lstat(2), or None if nothing exists here.
+    pub(super) fn lstat(&self) -> Result<Option<FileStat>> {
+        match nix::sys::stat::fstatat(
+            self.parent.as_raw_fd(),
+            self.name.as_os_str(),
+            AtFlags::AT_SYMLINK_NOFOLLOW,
+        ) {
+            Ok(stat) => Ok(Some(stat)),
+            Err(Errno::ENOENT) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub(super) fn mkdir(&self) -> Result<()> {
+        nix::sys::stat::mkdirat(
+            self.parent.as_raw_fd(),
+            self.name.as_os_str(),
+            Mode::from_bits_truncate(0o777),
+        )?;
+        Ok(())
+    }
+
+    /// Create an empty regular file, failing if anything already exists.
+    pub(super) fn create(&self) -> Result<()> {
+        self.open(
+            OFlag::O_WRONLY | OFlag::O_CREAT | OFlag::O_EXCL,
+            Mode::from_bits_truncate(0o666),
+        )?;
+        Ok(())
+    }
+
+    /// open(2) with 'flags' and O_NOFOLLOW.
+    pub(super) fn open(&self, flags: OFlag, mode: Mode) -> Result<std::fs::File> {
+        let fd = openat(
+            self.parent.as_raw_fd(),
+            &self.name,
+            flags | OFlag::O_NOFOLLOW,
+            mode,
+        )?;
+        Ok(fd.into())
+    }
+
+    /// Create a hardlink here to 'target'.
+    pub(super) fn link(&self, target: &At) -> Result<()> {
+        nix::unistd::linkat(
+            target.fd(),
+            target.name.as_os_str(),
+            self.fd(),
+            self.name.as_os_str(),
+            LinkatFlags::NoSymlinkFollow,
+        )?;
+        Ok(())
+    }
+
+    pub(super) fn symlink(&self, target: &Path) -> Result<()> {
+        nix::unistd::symlinkat(target, self.fd(), self.name.as_os_str())?;
+        Ok(())
+    }
+
+    pub(super) fn mknod(&self, kind: SFlag, mode: Mode, rdev: u64) -> nix::Result<()> {
+        nix::sys::stat::mknodat(
+            self.parent.as_raw_fd(),
+            self.name.as_os_str(),
+            kind,
+            mode,
+            rdev,
+        )
+    }
+
+    pub(super) fn chown(&self, uid: u32, gid: u32) -> Result<()> {
+        nix::unistd::fchownat(
+            self.fd(),
+            self.name.as_os_str(),
+            Some(Uid::from_raw(uid)),
+            Some(Gid::from_raw(gid)),
+            FchownatFlags::NoFollowSymlink,
+        )?;
+        Ok(())
+    }
+
+    pub(super) fn chmod(&self, mode: Mode) -> Result<()> {
+        nix::sys::stat::fchmodat(
+            self.fd(),
+            self.name.as_os_str(),
+            mode,
+            FchmodatFlags::NoFollowSymlink,
+        )?;
+        Ok(())
+    }
+
+    pub(super) fn set_xattr(&self, name: &OsStr, value: &[u8]) -> Result<()> {
+        // there is no fd-relative setxattr(2), but the magic link of the
+        // parent directory in /proc cannot be swapped out
+        let path = PathBuf::from(format!("/proc/self/fd/{}", self.parent.as_raw_fd()));
+        xattr::set(path.join(&self.name), name, value)
+    }
+
+    pub(super) fn set_times(&self, atime: &TimeSpec, mtime: &TimeSpec) -> Result<()> {
+        nix::sys::stat::utimensat(
+            self.fd(),
+            self.name.as_os_str(),
+            atime,
+            mtime,
+            UtimensatFlags::NoFollowSymlink,
+        )?;
+        Ok(())
+    }
+
+    /// Remove whatever is here, recursively if it is a directory.
+    pub(super) fn remove_all(&self) -> Result<()> {
+        remove_all(self.parent.as_raw_fd(), &self.name)
+    }
+}
+
+fn remove_all(parent: RawFd, name: &OsStr) -> Result<()> {
+    let stat = nix::sys::stat::fstatat(parent, name, AtFlags::AT_SYMLINK_NOFOLLOW)?;
+    if SFlag::from_bits_truncate(stat.st_mode & SFlag::S_IFMT.bits()) != SFlag::S_IFDIR {
+        nix::unistd::unlinkat(Some(parent), name, UnlinkatFlags::NoRemoveDir)?;
+        return Ok(());
+    }
+    let mut dir = Dir::openat(
+        parent,
+        name,
+        OFlag::O_RDONLY | OFlag::O_DIRECTORY | OFlag::O_NOFOLLOW | OFlag::O_CLOEXEC,
+        Mode::empty(),
+    )?;
+    let children: Vec<OsString> = dir
+        .iter()
+        .map(|entry| entry.map(|e| OsStr::from_bytes(e.file_name().to_bytes()).to_owned()))
+        .filter(|name| !matches!(name.as_ref().map(|n| n.as_bytes()), Ok(b".") | Ok(b"..")))
+        .collect::<nix::Result<_>>()?;
+    for child in children {
+        remove_all(dir.as_raw_fd(), &child)?;
+    }
+    nix::unistd::unlinkat(Some(parent), name, UnlinkatFlags::RemoveDir)?;
+    Ok(())
+}
@@ -0,0 +1,1832 @@
+//! Write a [Filesystem] out to a directory on the host, so that real programs
+//! can be run against its contents.
+
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fmt::Debug;
+use std::io::Error;
+use std::io::ErrorKind;
+use std::io::Result;
+use std::os::fd::AsFd;
+use std::os::fd::AsRawFd;
+use std::os::fd::BorrowedFd;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileExt;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use bytes::Bytes;
+use globset::GlobBuilder;
+use globset::GlobMatcher;
+use nix::errno::Errno;
+use nix::fcntl::OFlag;
+use nix::sys::stat::Mode;
+use nix::sys::stat::SFlag;
+use nix::sys::time::TimeSpec;
+
+use crate::entry::Metadata;
+use crate::entry::Special;
+use crate::file::extent::Backed;
+use crate::file::extent::Extent;
+use crate::BytesPath;
+use crate::Entry;
+use crate::File;
+use crate::Filesystem;
+use crate::InodeKey;
+
+mod at;
+use at::At;
+mod sandbox;
+mod selinux;
+pub use selinux::FileContexts;
+mod verity;
+
+/// Capability needed to create device nodes, see capabilities(7).
+const CAP_MKNOD: u32 = 27;
+
+/// What to do with device nodes and sockets, which cannot always be created.
+/// Fifos are always created.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum SpecialFiles {
+    /// Create them, failing if that is not allowed
+    Create,
+    /// Create them, but skip device nodes if the process is not allowed to
+    /// create them (without CAP_MKNOD, or inside a user namespace)
+    #[default]
+    CreateOrSkip,
+    /// Never create them
+    Skip,
+}
+
+/// What to do with paths that already exist in the destination directory.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum ExistingEntries {
+    /// Fail with [ErrorKind::AlreadyExists]
+    #[default]
+    Fail,
+    /// Remove them (recursively, for directories) and write the new entry
+    Overwrite,
+    /// Leave them (and everything underneath them) alone
+    Skip,
+    /// Keep existing directories and write the new entries into them,
+    /// overwriting everything else
+    Merge,
+}
+
+/// What to do with the SELinux labels (`security.selinux` xattrs) of
+/// entries, which only make sense if they match the policy of the host.
+#[derive(Debug, Clone, Default)]
+pub enum Selinux {
+    /// Set them exactly as they are in the [Filesystem]
+    #[default]
+    Apply,
+    /// Never set them, so entries get the default label of the directory
+    /// they are in
+    Skip,
+    /// Ignore the labels in the [Filesystem] and label every entry according
+    /// to the given mapping instead
+    Relabel(FileContexts),
+}
+
+/// Mapping from the ids in a [Filesystem] to ids on the host, in the same
+/// format as `/proc/<pid>/uid_map` (see user_namespaces(7)).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IdMap {
+    /// (first id in the filesystem, first id on the host, number of ids)
+    ranges: Vec<(u32, u32, u32)>,
+}
+
+impl IdMap {
+    /// An empty mapping, which maps every id to itself until a
+    /// [range](IdMap::range) is added.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Map the 'count' ids starting at 'inside' to the ids starting at
+    /// 'outside' on the host.
+    pub fn range(&mut self, inside: u32, outside: u32, count: u32) -> &mut Self {
+        self.ranges.push((inside, outside, count));
+        self
+    }
+
+    /// Id on the host for 'id', or None if it is not in any range.
+    pub fn map(&self, id: u32) -> Option<u32> {
+        if self.ranges.is_empty() {
+            return Some(id);
+        }
+        self.ranges
+            .iter()
+            .find(|(inside, _, count)| id >= *inside && id - inside < *count)
+            .and_then(|(inside, outside, _)| outside.checked_add(id - inside))
+    }
+}
+
+/// How far [Filesystem::materialize_to_with] has gotten, passed to
+/// [MaterializeOptions::progress] after every entry that was written.
+#[derive(Debug, Copy, Clone)]
+pub struct Progress<'a> {
+    entries: usize,
+    bytes: u64,
+    path: &'a Path,
+}
+
+impl<'a> Progress<'a> {
+    /// Number of entries (including this one) that have been written.
+    pub fn entries(&self) -> usize {
+        self.entries
+    }
+
+    /// Number of bytes of file contents that have been written, not
+    /// counting holes.
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    /// Path of the entry that was just written.
+    pub fn path(&self) -> &'a Path {
+        self.path
+    }
+}
+
+#[derive(Clone)]
+struct ProgressFn(Arc<dyn Fn(&Progress) + Send + Sync>);
+
+impl Debug for ProgressFn {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("<progress>")
+    }
+}
+
+/// Part of a [Filesystem] to materialize, see [MaterializeOptions::subtree]
+/// and [MaterializeOptions::glob].
+#[derive(Debug, Clone)]
+enum Filter {
+    Subtree(PathBuf),
+    Glob(GlobMatcher),
+}
+
+impl Filter {
+    fn is_match(&self, path: &Path) -> bool {
+        match self {
+            Self::Subtree(prefix) => path.starts_with(prefix),
+            Self::Glob(glob) => glob.is_match(path),
+        }
+    }
+}
+
+/// Options for [Filesystem::materialize_to_with].
+#[derive(Debug, Clone, Default)]
+pub struct MaterializeOptions {
+    special_files: SpecialFiles,
+    existing: ExistingEntries,
+    selinux: Selinux,
+    uid_map: IdMap,
+    gid_map: IdMap,
+    unprivileged: bool,
+    threads: usize,
+    progress: Option<ProgressFn>,
+    filters: Vec<Filter>,
+    verity: bool,
+    expected_verity: BTreeMap<BytesPath, Bytes>,
+    sandbox: bool,
+}
+
+impl MaterializeOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only write 'prefix' and everything underneath it (along with the
+    /// directories leading up to it). Can be combined with other subtrees
+    /// and [globs](MaterializeOptions::glob), in which case anything that
+    /// matches any of them is written.
+    pub fn subtree(&mut self, prefix: impl AsRef<Path>) -> &mut Self {
+        let prefix = prefix.as_ref();
+        let prefix = prefix.strip_prefix("/").unwrap_or(prefix);
+        self.filters.push(Filter::Subtree(prefix.to_owned()));
+        self
+    }
+
+    /// Only write paths matching 'glob' (along with the directories leading
+    /// up to them), like [MaterializeOptions::subtree]. `*` does not match
+    /// across `/`, use `**` for that. A leading `/` is ignored.
+    pub fn glob(&mut self, glob: &str) -> Result<&mut Self> {
+        let glob = glob.strip_prefix('/').unwrap_or(glob);
+        let matcher = GlobBuilder::new(glob)
+            .literal_separator(true)
+            .build()
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?
+            .compile_matcher();
+        self.filters.push(Filter::Glob(matcher));
+        Ok(self)
+    }
+
+    /// How to handle device nodes and sockets.
+    pub fn special_files(&mut self, special_files: SpecialFiles) -> &mut Self {
+        self.special_files = special_files;
+        self
+    }
+
+    /// How to handle paths that already exist underneath the destination,
+    /// for example to write an overlay on top of a populated directory.
+    pub fn existing(&mut self, existing: ExistingEntries) -> &mut Self {
+        self.existing = existing;
+        self
+    }
+
+    /// How to handle SELinux labels.
+    pub fn selinux(&mut self, selinux: Selinux) -> &mut Self {
+        self.selinux = selinux;
+        self
+    }
+
+    /// Change the owner of every entry according to 'uid_map', so that for
+    /// example an image owned by root can be written as an unprivileged
+    /// user. Ids that are not mapped are an error.
+    pub fn uid_map(&mut self, uid_map: IdMap) -> &mut Self {
+        self.uid_map = uid_map;
+        self
+    }
+
+    /// Like [MaterializeOptions::uid_map], for the group of every entry.
+    pub fn gid_map(&mut self, gid_map: IdMap) -> &mut Self {
+        self.gid_map = gid_map;
+        self
+    }
+
+    /// Instead of failing when the process is not allowed to change the
+    /// owner of an entry or to create a device node, record it in
+    /// [MaterializeReport::deferred] and carry on, so that a privileged
+    /// helper can finish the job later.
+    pub fn unprivileged(&mut self, unprivileged: bool) -> &mut Self {
+        self.unprivileged = unprivileged;
+        self
+    }
+
+    /// Number of threads that write file contents, after all the
+    /// directories have been created. 0 (the default) uses
+    /// [std::thread::available_parallelism].
+    pub fn threads(&mut self, threads: usize) -> &mut Self {
+        self.threads = threads;
+        self
+    }
+
+    /// Call 'f' after every entry that was written, for example to drive a
+    /// progress bar. With more than one [thread](MaterializeOptions::threads)
+    /// it is called from all of them.
+    pub fn progress(&mut self, f: impl Fn(&Progress) + Send + Sync + 'static) -> &mut Self {
+        self.progress = Some(ProgressFn(Arc::new(f)));
+        self
+    }
+
+    /// Enable fs-verity (with SHA-256 and 4K blocks) on every regular file
+    /// once its contents are written, recording the digests in
+    /// [MaterializeReport::verity]. Filesystems without fs-verity support are
+    /// an error.
+    pub fn verity(&mut self, verity: bool) -> &mut Self {
+        self.verity = verity;
+        self
+    }
+
+    /// With [MaterializeOptions::verity], fail with [ErrorKind::InvalidData]
+    /// if the fs-verity digest of 'path' is not 'digest'.
+    pub fn expected_verity(
+        &mut self,
+        path: impl Into<BytesPath>,
+        digest: impl Into<Bytes>,
+    ) -> &mut Self {
+        self.expected_verity.insert(path.into(), digest.into());
+        self
+    }
+
+    /// Materialize on a separate thread that Landlock only allows to create,
+    /// remove, write or truncate files underneath the destination, as a last
+    /// line of defense against anything escaping it. Landlock does not cover
+    /// metadata changes, so chown, chmod, xattrs and timestamps are not
+    /// confined by this. The [progress](MaterializeOptions::progress)
+    /// callback runs under the same restriction. Kernels without Landlock
+    /// are an error ([ErrorKind::Unsupported]).
+    pub fn sandbox(&mut self, sandbox: bool) -> &mut Self {
+        self.sandbox = sandbox;
+        self
+    }
+}
+
+impl MaterializeOp {
+    /// Path that this operation applies to.
+    pub fn path(&self) -> &BytesPath {
+        match self {
+            Self::Chmod { path, .. }
+            | Self::Chown { path, .. }
+            | Self::CreateDir { path }
+            | Self::CreateFile { path }
+            | Self::Hardlink { path, .. }
+            | Self::Mknod { path, .. }
+            | Self::SetXattr { path, .. }
+            | Self::Symlink { path, .. }
+            | Self::WriteFile { path, .. } => path,
+        }
+    }
+}
+
+/// Single syscall-level operation of materializing a [Filesystem], see
+/// [Filesystem::materialize_plan] and [MaterializeReport::deferred]. Paths
+/// are relative to the materialized directory and ids are already mapped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MaterializeOp {
+    /// chmod(2) of 'path'.
+    Chmod { path: BytesPath, mode: Mode },
+    /// lchown(2) of 'path'. This clears the setuid and setgid bits, so the
+    /// mode has to be set again afterwards.
+    Chown { path: BytesPath, uid: u32, gid: u32 },
+    /// mkdir(2) of 'path'.
+    CreateDir { path: BytesPath },
+    /// Create 'path' as an empty file.
+    CreateFile { path: BytesPath },
+    /// link(2) of 'path' to the already created 'target'.
+    Hardlink { path: BytesPath, target: BytesPath },
+    /// mknod(2) of 'path'. A deferred device node was not created at all,
+    /// so it is always followed by the rest of its metadata.
+    Mknod {
+        path: BytesPath,
+        file_type: SFlag,
+        mode: Mode,
+        rdev: u64,
+    },
+    /// lsetxattr(2) of 'name' on 'path'.
+    SetXattr {
+        path: BytesPath,
+        name: Bytes,
+        value: Bytes,
+    },
+    /// symlink(2) of 'path' pointing to 'target'.
+    Symlink { path: BytesPath, target: BytesPath },
+    /// Write 'bytes' bytes of contents to the file that was created at 'path'.
+    WriteFile { path: BytesPath, bytes: u64 },
+}
+
+/// Everything that [Filesystem::materialize_to_with] did not write exactly
+/// as it is in the [Filesystem].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MaterializeReport {
+    skipped: Vec<BytesPath>,
+    deferred: Vec<MaterializeOp>,
+    verity: BTreeMap<BytesPath, Bytes>,
+}
+
+impl MaterializeReport {
+    /// Paths that were not created at all, because of
+    /// [MaterializeOptions::special_files] or [MaterializeOptions::existing].
+    pub fn skipped(&self) -> &[BytesPath] {
+        &self.skipped
+    }
+
+    /// Operations that were left for later by
+    /// [MaterializeOptions::unprivileged], in the order they should be done.
+    /// Every deferred [MaterializeOp::Chown] is followed by the
+    /// [MaterializeOp::Chmod] and `security.*` [MaterializeOp::SetXattr]s
+    /// that it would undo. A deferred [MaterializeOp::Mknod] is followed by
+    /// all of the metadata of the device node.
+    pub fn deferred(&self) -> &[MaterializeOp] {
+        &self.deferred
+    }
+
+    /// fs-verity digest of every regular file, with
+    /// [MaterializeOptions::verity].
+    pub fn verity(&self) -> &BTreeMap<BytesPath, Bytes> {
+        &self.verity
+    }
+}
+
+impl Filesystem {
+    /// Write every entry of this filesystem underneath 'dir', which is
+    /// created if it does not exist yet. Anything else that already exists is
+    /// an error, unless [MaterializeOptions::existing] says otherwise.
+    pub fn materialize_to<P>(&self, dir: P) -> Result<MaterializeReport>
+    where
+        P: AsRef<Path>,
+    {
+        self.materialize_to_with(dir, &MaterializeOptions::default())
+    }
+
+    /// [Filesystem::materialize_to] with more control over how each entry is
+    /// written.
+    pub fn materialize_to_with<P>(
+        &self,
+        dir: P,
+        options: &MaterializeOptions,
+    ) -> Result<MaterializeReport>
+    where
+        P: AsRef<Path>,
+    {
+        let dir = dir.as_ref();
+        std::fs::create_dir_all(dir)?;
+        let dir = std::fs::OpenOptions::new()
+            .read(true)
+            .custom_flags(OFlag::O_DIRECTORY.bits())
+            .open(dir)?;
+        self.materialize_at(&dir, options)
+    }
+
+    /// [Filesystem::materialize_to_with] into the already open directory
+    /// 'dir', which does not need to have a name that is reachable by this
+    /// process. Everything is created relative to 'dir' and symlinks are
+    /// never followed, even if another process swaps a directory for one
+    /// while this is running.
+    pub fn materialize_at(
+        &self,
+        dir: impl AsFd,
+        options: &MaterializeOptions,
+    ) -> Result<MaterializeReport> {
+        let dir = dir.as_fd();
+        if options.sandbox {
+            // Landlock restrictions can never be lifted, so confine them to
+            // a thread that exits afterwards
+            return std::thread::scope(|scope| {
+                scope
+                    .spawn(|| {
+                        sandbox::restrict_writes_to(dir)?;
+                        self.materialize_at_unsandboxed(dir, options)
+                    })
+                    .join()
+                    .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+            });
+        }
+        self.materialize_at_unsandboxed(dir, options)
+    }
+
+    fn materialize_at_unsandboxed(
+        &self,
+        dir: BorrowedFd,
+        options: &MaterializeOptions,
+    ) -> Result<MaterializeReport> {
+        let can_mknod = has_capability(CAP_MKNOD);
+        let mut report = MaterializeReport::default();
+        let totals = Totals::default();
+        // first path of every inode, so that the rest become hardlinks
+        let mut written: HashMap<InodeKey, &Path> = HashMap::new();
+        let mut created = Vec::new();
+        // files are created empty, their contents are written in parallel
+        // once the whole tree exists
+        let mut files = Vec::new();
+        let mut skipped_dir: Option<&Path> = None;
+        let selected = self.selected(options);
+        for (path, entry) in self {
+            if selected.as_ref().is_some_and(|s| !s.contains(path))
+                || skipped_dir.is_some_and(|dir| path.starts_with(dir))
+            {
+                continue;
+            }
+            let at = At::resolve(dir, path)?;
+            let mut merge = false;
+            if !path.as_os_str().is_empty() && options.existing != ExistingEntries::Fail {
+                if let Some(existing) = at.lstat()? {
+                    let is_dir = SFlag::from_bits_truncate(existing.st_mode & SFlag::S_IFMT.bits())
+                        == SFlag::S_IFDIR;
+                    match options.existing {
+                        ExistingEntries::Skip => {
+                            report.skipped.push(path.into());
+                            skipped_dir = Some(path);
+                            continue;
+                        }
+                        ExistingEntries::Merge if is_dir && entry.is_directory() => {
+                            merge = true;
+                        }
+                        _ => at.remove_all()?,
+                    }
+                }
+            }
+            if !entry.is_directory() {
+                let key = self.paths[path];
+                if let Some(first) = written.get(&key) {
+                    at.link(&At::resolve(dir, first)?)?;
+                    totals.done(options, path, 0);
+                    continue;
+                }
+                written.insert(key, path);
+            }
+            match entry {
+                Entry::Directory(_) => {
+                    if !path.as_os_str().is_empty() && !merge {
+                        at.mkdir()?;
+                    }
+                }
+                Entry::File(file) => {
+                    at.create()?;
+                    files.push((path, entry, file));
+                    created.push((path, entry));
+                    continue;
+                }
+                Entry::Special(special) => {
+                    let created = match create_special(
+                        special,
+                        &at,
+                        path,
+                        options.special_files,
+                        can_mknod,
+                    ) {
+                        Err(e)
+                            if options.unprivileged && e.kind() == ErrorKind::PermissionDenied =>
+                        {
+                            false
+                        }
+                        created => created?,
+                    };
+                    if !created {
+                        let file_type = special.file_type();
+                        let device = file_type == SFlag::S_IFCHR || file_type == SFlag::S_IFBLK;
+                        if options.unprivileged
+                            && device
+                            && options.special_files != SpecialFiles::Skip
+                        {
+                            report.deferred.push(MaterializeOp::Mknod {
+                                path: path.into(),
+                                file_type,
+                                mode: Mode::from_bits_truncate(special.metadata().mode().bits()),
+                                rdev: special.rdev().unwrap_or(0),
+                            });
+                            plan_metadata(&mut report.deferred, path, entry, options)?;
+                        } else {
+                            report.skipped.push(path.into());
+                        }
+                        continue;
+                    }
+                }
+                Entry::Symlink(symlink) => at.symlink(symlink.target())?,
+            }
+            if !entry.is_directory() {
+                set_metadata(&at, path, entry, options, &mut report)?;
+            }
+            totals.done(options, path, 0);
+            created.push((path, entry));
+        }
+        let written = write_files(dir, &files, options, &totals)?;
+        report.deferred.extend(written.deferred);
+        report.verity.extend(written.verity);
+        // creating an entry changes the mtime of its parent, so children
+        // have to be done first
+        for (path, entry) in created.into_iter().rev() {
+            let at = At::resolve(dir, path)?;
+            // directories only get their mode once all of their children
+            // exist, so that read-only directories can still be filled
+            if entry.is_directory() {
+                set_metadata(&at, path, entry, options, &mut report)?;
+            }
+            set_times(&at, entry.metadata())?;
+        }
+        // keep the deferred operations of each path together, in the order
+        // that the tree was walked in
+        report.deferred.sort_by(|a, b| a.path().cmp(b.path()));
+        Ok(report)
+    }
+}
+
+impl Filesystem {
+    /// Operations that [Filesystem::materialize_to_with] would do, in order
+    /// and without touching the disk, for example to review what an
+    /// untrusted image would do. The destination is assumed to be empty and
+    /// device nodes are planned unless [SpecialFiles::Skip] is used.
+    pub fn materialize_plan(&self, options: &MaterializeOptions) -> Result<Vec<MaterializeOp>> {
+        let mut plan = Vec::new();
+        let mut written: HashMap<InodeKey, &Path> = HashMap::new();
+        let mut files = Vec::new();
+        let mut dirs = Vec::new();
+        let selected = self.selected(options);
+        for (path, entry) in self {
+            if selected.as_ref().is_some_and(|s| !s.contains(path)) {
+                continue;
+            }
+            if !entry.is_directory() {
+                let key = self.paths[path];
+                if let Some(first) = written.get(&key) {
+                    plan.push(MaterializeOp::Hardlink {
+                        path: path.into(),
+                        target: (*first).into(),
+                    });
+                    continue;
+                }
+                written.insert(key, path);
+            }
+            match entry {
+                Entry::Directory(_) => {
+                    if !path.as_os_str().is_empty() {
+                        plan.push(MaterializeOp::CreateDir { path: path.into() });
+                    }
+                    dirs.push((path, entry));
+                    continue;
+                }
+                Entry::File(file) => {
+                    plan.push(MaterializeOp::CreateFile { path: path.into() });
+                    files.push((path, entry, file));
+                    continue;
+                }
+                Entry::Special(special) => {
+                    let file_type = special.file_type();
+                    if file_type != SFlag::S_IFIFO && options.special_files == SpecialFiles::Skip {
+                        continue;
+                    }
+                    plan.push(MaterializeOp::Mknod {
+                        path: path.into(),
+                        file_type,
+                        mode: Mode::from_bits_truncate(special.metadata().mode().bits()),
+                        rdev: special.rdev().unwrap_or(0),
+                    });
+                }
+                Entry::Symlink(symlink) => plan.push(MaterializeOp::Symlink {
+                    path: path.into(),
+                    target: symlink.target().into(),
+                }),
+            }
+            plan_metadata(&mut plan, path, entry, options)?;
+        }
+        for (path, entry, file) in files {
+            plan.push(MaterializeOp::WriteFile {
+                path: path.into(),
+                bytes: file.len(),
+            });
+            plan_metadata(&mut plan, path, entry, options)?;
+        }
+        for (path, entry) in dirs.into_iter().rev() {
+            plan_metadata(&mut plan, path, entry, options)?;
+        }
+        Ok(plan)
+    }
+}
+
+impl Filesystem {
+    /// Paths that match the filters in 'options' along with all of their
+    /// parents, or None if everything should be written.
+    fn selected(&self, options: &MaterializeOptions) -> Option<BTreeSet<&Path>> {
+        if options.filters.is_empty() {
+            return None;
+        }
+        let mut selected = BTreeSet::new();
+        for (path, _) in self {
+            if options.filters.iter().any(|f| f.is_match(path)) {
+                selected.extend(path.ancestors());
+            }
+        }
+        Some(selected)
+    }
+}
+
+/// Operations of [set_metadata] for 'entry'.
+fn plan_metadata(
+    plan: &mut Vec<MaterializeOp>,
+    path: &Path,
+    entry: &Entry,
+    options: &MaterializeOptions,
+) -> Result<()> {
+    let metadata = entry.metadata();
+    let (uid, gid) = owner(path, entry, options)?;
+    plan.push(MaterializeOp::Chown {
+        path: path.into(),
+        uid,
+        gid,
+    });
+    if !matches!(entry, Entry::Symlink(_)) {
+        plan.push(MaterializeOp::Chmod {
+            path: path.into(),
+            mode: Mode::from_bits_truncate(metadata.mode().bits()),
+        });
+    }
+    for (name, value) in xattrs(path, entry, options) {
+        plan.push(MaterializeOp::SetXattr {
+            path: path.into(),
+            name,
+            value,
+        });
+    }
+    Ok(())
+}
+
+/// File that still has to be written: (path, entry, contents).
+type PendingFile<'f> = (&'f Path, &'f Entry, &'f File);
+
+/// Write the contents and metadata of 'files' on [MaterializeOptions::threads]
+/// threads, returning what was deferred and measured.
+fn write_files(
+    dir: BorrowedFd,
+    files: &[PendingFile],
+    options: &MaterializeOptions,
+    totals: &Totals,
+) -> Result<MaterializeReport> {
+    let threads = match options.threads {
+        0 => std::thread::available_parallelism().map_or(1, |n| n.get()),
+        n => n,
+    }
+    .min(files.len());
+    let next = AtomicUsize::new(0);
+    let failed = AtomicBool::new(false);
+    std::thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads)
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut report = MaterializeReport::default();
+                    while !failed.load(Ordering::Relaxed) {
+                        let Some((path, entry, file)) =
+                            files.get(next.fetch_add(1, Ordering::Relaxed))
+                        else {
+                            break;
+                        };
+                        match write_file(dir, path, entry, file, options, &mut report) {
+                            Ok(bytes) => totals.done(options, path, bytes),
+                            Err(e) => {
+                                failed.store(true, Ordering::Relaxed);
+                                return Err(e);
+                            }
+                        }
+                    }
+                    Ok(report)
+                })
+            })
+            .collect();
+        let mut report = MaterializeReport::default();
+        for worker in workers {
+            let written = worker.join().expect("materialize thread panicked")?;
+            report.deferred.extend(written.deferred);
+            report.verity.extend(written.verity);
+        }
+        Ok(report)
+    })
+}
+
+fn write_file(
+    dir: BorrowedFd,
+    path: &Path,
+    entry: &Entry,
+    file: &File,
+    options: &MaterializeOptions,
+    report: &mut MaterializeReport,
+) -> Result<u64> {
+    let at = At::resolve(dir, path)?;
+    let bytes = {
+        let out = at.open(OFlag::O_WRONLY, Mode::empty())?;
+        // holes are skipped instead of being filled with zeroes, so they
+        // stay holes on the host as well
+        let mut bytes = 0;
+        for (offset, extent) in file.extents() {
+            if extent.is_hole() {
+                continue;
+            }
+            let copied = match extent {
+                Extent::Backed(backed) => copy_backed(backed, extent.len(), &out, offset)?,
+                _ => false,
+            };
+            if !copied {
+                out.write_all_at(extent.data()?, offset)?;
+            }
+            bytes += extent.len();
+        }
+        out.set_len(file.len())?;
+        bytes
+    };
+    // this needs write access to the file, so it has to be done before the
+    // mode is set
+    if options.verity {
+        let file = at.open(OFlag::O_RDONLY, Mode::empty())?;
+        verity::enable(&file)?;
+        let digest = verity::measure(&file)?;
+        if let Some(expected) = options.expected_verity.get(path) {
+            if *expected != digest {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "fs-verity digest of {} is {digest:x?}, expected {expected:x?}",
+                        path.display()
+                    ),
+                ));
+            }
+        }
+        report.verity.insert(path.into(), digest);
+    }
+    set_metadata(&at, path, entry, options, report)?;
+    Ok(bytes)
+}
+
+/// Copy 'len' bytes of a [Backed] extent straight from its backing file
+/// with copy_file_range(2), which reflinks them if the host filesystem can.
+/// Returns false if the data has to be written normally instead.
+fn copy_backed(backed: &Backed, len: u64, out: &std::fs::File, offset: u64) -> Result<bool> {
+    let Some((src, src_offset)) = backed.source() else {
+        return Ok(false);
+    };
+    let mut off_in = src_offset as i64;
+    let mut off_out = offset as i64;
+    let mut left = len;
+    while left > 0 {
+        match nix::fcntl::copy_file_range(
+            src.as_raw_fd(),
+            Some(&mut off_in),
+            out.as_raw_fd(),
+            Some(&mut off_out),
+            left as usize,
+        ) {
+            Ok(n) if n > 0 => left -= n as u64,
+            Err(Errno::EINTR) => {}
+            // the backing file is too short (which reading it will report),
+            // or the two files cannot be copied between
+            Ok(_) | Err(Errno::EXDEV | Errno::EINVAL | Errno::ENOSYS | Errno::EOPNOTSUPP) => {
+                return Ok(false);
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(true)
+}
+
+/// Running totals for [MaterializeOptions::progress], shared by all the
+/// threads.
+#[derive(Default)]
+struct Totals {
+    entries: AtomicUsize,
+    bytes: AtomicU64,
+}
+
+impl Totals {
+    fn done(&self, options: &MaterializeOptions, path: &Path, bytes: u64) {
+        if let Some(ProgressFn(progress)) = &options.progress {
+            progress(&Progress {
+                entries: self.entries.fetch_add(1, Ordering::Relaxed) + 1,
+                bytes: self.bytes.fetch_add(bytes, Ordering::Relaxed) + bytes,
+                path,
+            });
+        }
+    }
+}
+
+/// Create 'special' at 'at', returning false if it was skipped because of
+/// 'policy'.
+fn create_special(
+    special: &Special,
+    at: &At,
+    path: &Path,
+    policy: SpecialFiles,
+    can_mknod: bool,
+) -> Result<bool> {
+    let file_type = special.file_type();
+    let device = file_type == SFlag::S_IFCHR || file_type == SFlag::S_IFBLK;
+    if file_type != SFlag::S_IFIFO {
+        match policy {
+            SpecialFiles::Skip => return Ok(false),
+            SpecialFiles::CreateOrSkip if device && !can_mknod => return Ok(false),
+            SpecialFiles::Create if device && !can_mknod => {
+                return Err(Error::new(
+                    ErrorKind::PermissionDenied,
+                    format!("creating device {} requires CAP_MKNOD", path.display()),
+                ));
+            }
+            _ => (),
+        }
+    }
+    let mode = Mode::from_bits_truncate(special.metadata().mode().bits());
+    match at.mknod(file_type, mode, special.rdev().unwrap_or(0)) {
+        Ok(()) => Ok(true),
+        // CAP_MKNOD is not enough inside of a user namespace
+        Err(Errno::EPERM) if device && policy == SpecialFiles::CreateOrSkip => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Owner of 'entry' on the host, according to the id maps in 'options'.
+fn owner(path: &Path, entry: &Entry, options: &MaterializeOptions) -> Result<(u32, u32)> {
+    let metadata = entry.metadata();
+    let map = |map: &IdMap, kind: &str, id: u32| {
+        map.map(id).ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("{kind} {id} of {} is not mapped", path.display()),
+            )
+        })
+    };
+    Ok((
+        map(&options.uid_map, "uid", metadata.uid().as_u32())?,
+        map(&options.gid_map, "gid", metadata.gid().as_u32())?,
+    ))
+}
+
+/// Set the owner, mode and xattrs of 'at' to match 'entry'. The owner is
+/// changed first, since chown(2) clears the setuid and setgid bits as well as
+/// `security.capability`. The contents have to be written before this, since
+/// writes clear them too.
+fn set_metadata(
+    at: &At,
+    path: &Path,
+    entry: &Entry,
+    options: &MaterializeOptions,
+    report: &mut MaterializeReport,
+) -> Result<()> {
+    let metadata = entry.metadata();
+    let (uid, gid) = owner(path, entry, options)?;
+    let deferred = match at.chown(uid, gid) {
+        Ok(()) => false,
+        Err(e) if options.unprivileged && e.kind() == ErrorKind::PermissionDenied => true,
+        Err(e) => return Err(e),
+    };
+    // the mode of a symlink is meaningless and cannot be changed on Linux
+    if !matches!(entry, Entry::Symlink(_)) {
+        at.chmod(Mode::from_bits_truncate(metadata.mode().bits()))?;
+    }
+    for (name, value) in xattrs(path, entry, options) {
+        if !(deferred && is_security_xattr(&name)) {
+            at.set_xattr(OsStr::from_bytes(&name), &value)?;
+        }
+    }
+    if deferred {
+        // the chown will clear the setuid and setgid bits and
+        // security.capability, which also needs CAP_SETFCAP, so those have
+        // to be set again after it
+        let mut plan = Vec::new();
+        plan_metadata(&mut plan, path, entry, options)?;
+        report
+            .deferred
+            .extend(plan.into_iter().filter(|op| match op {
+                MaterializeOp::SetXattr { name, .. } => is_security_xattr(name),
+                _ => true,
+            }));
+    }
+    Ok(())
+}
+
+/// Whether 'name' is in the `security.` namespace, which unprivileged
+/// processes generally cannot write.
+fn is_security_xattr(name: &[u8]) -> bool {
+    name.starts_with(b"security.")
+}
+
+/// Xattrs to set on 'entry', with its SELinux label replaced according to
+/// [MaterializeOptions::selinux].
+fn xattrs(path: &Path, entry: &Entry, options: &MaterializeOptions) -> Vec<(Bytes, Bytes)> {
+    let mut xattrs: Vec<_> = entry
+        .metadata()
+        .xattrs()
+        .iter()
+        .filter(|(name, _)| {
+            matches!(options.selinux, Selinux::Apply) || name.as_ref() != selinux::XATTR
+        })
+        .map(|(name, value)| (name.clone(), value.clone()))
+        .collect();
+    if let Selinux::Relabel(contexts) = &options.selinux {
+        let file_type = match entry {
+            Entry::Directory(_) => SFlag::S_IFDIR,
+            Entry::File(_) => SFlag::S_IFREG,
+            Entry::Special(special) => special.file_type(),
+            Entry::Symlink(_) => SFlag::S_IFLNK,
+        };
+        if let Some(label) = contexts.lookup(path, file_type) {
+            // labels are stored with a trailing nul, like setfilecon(3) does
+            let mut value = label.to_vec();
+            value.push(0);
+            xattrs.push((Bytes::from_static(selinux::XATTR), value.into()));
+        }
+    }
+    xattrs
+}
+
+/// Set the access and modification times of 'at' (but not of what it
+/// points to, if it is a symlink).
+fn set_times(at: &At, metadata: &Metadata) -> Result<()> {
+    let timespec = |time: SystemTime| {
+        TimeSpec::from(
+            time.duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default(),
+        )
+    };
+    at.set_times(
+        &timespec(metadata.accessed()),
+        &timespec(metadata.modified()),
+    )
+}
+
+/// Whether 'cap' is in the effective capability set of this process.
+fn has_capability(cap: u32) -> bool {
+    std::fs::read_to_string("/proc/self/status")
+        .ok()
+        .and_then(|status| {
+            status
+                .lines()
+                .find_map(|line| line.strip_prefix("CapEff:"))
+                .and_then(|caps| u64::from_str_radix(caps.trim(), 16).ok())
+        })
+        .is_some_and(|caps| caps & (1 << cap) != 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::MetadataExt;
+    use std::os::unix::fs::PermissionsExt;
+
+    use nix::sys::stat::makedev;
+
+    use super::*;
+    use crate::cmp::Fields;
+    use crate::fs;
+    use crate::Gid;
+    use crate::Uid;
+
+    fn special(file_type: SFlag, rdev: u64) -> Special {
+        let metadata = Metadata::builder()
+            .mode(Mode::from_bits_truncate(0o644))
+            .build();
+        Special::new(file_type, rdev, metadata)
+    }
+
+    /// Options that map root to whoever runs the tests, so that they do not
+    /// need to be root themselves.
+    fn as_current_user() -> MaterializeOptions {
+        let mut uid_map = IdMap::new();
+        uid_map.range(0, nix::unistd::geteuid().as_raw(), 1);
+        let mut gid_map = IdMap::new();
+        gid_map.range(0, nix::unistd::getegid().as_raw(), 1);
+        let mut options = MaterializeOptions::new();
+        options.uid_map(uid_map).gid_map(gid_map);
+        options
+    }
+
+    /// Fields that survive materializing [as_current_user], which only
+    /// include the owner when that is root.
+    fn materialized_fields() -> Fields {
+        match nix::unistd::geteuid().is_root() {
+            true => Fields::all() - Fields::TIME,
+            false => Fields::all() - Fields::TIME - Fields::OWNER,
+        }
+    }
+
+    #[test]
+    fn materialize_to() {
+        let mut fs = fs! {
+            "etc": {
+                "motd": file("hello\n", 0o644) ["user.demo" = "xattr"],
+                "issue": link("etc/motd"),
+            },
+            "motd": symlink("etc/motd"),
+        };
+        fs.insert("fifo", special(SFlag::S_IFIFO, 0));
+        fs.insert("sock", special(SFlag::S_IFSOCK, 0));
+        let tmp = tempfile::tempdir().unwrap();
+        let report = fs
+            .materialize_to_with(tmp.path(), &as_current_user())
+            .unwrap();
+        assert!(report.skipped().is_empty());
+        assert!(fs
+            .cmp_dir(tmp.path(), materialized_fields())
+            .unwrap()
+            .is_all());
+        // nothing is ever overwritten
+        assert_eq!(
+            ErrorKind::AlreadyExists,
+            fs.materialize_to(tmp.path()).unwrap_err().kind()
+        );
+    }
+
+    #[test]
+    fn read_only_dirs() {
+        let fs = fs! {
+            "ro": dir(0o555) {
+                "sub": dir(0o500) {
+                    "file": file("hello\n"),
+                },
+            },
+        };
+        let tmp = tempfile::tempdir().unwrap();
+        fs.materialize_to_with(tmp.path(), MaterializeOptions::new().unprivileged(true))
+            .unwrap();
+        let mode = |path: &str| std::fs::metadata(tmp.path().join(path)).unwrap().mode() & 0o7777;
+        assert_eq!((0o555, 0o500), (mode("ro"), mode("ro/sub")));
+        assert_eq!(
+            "hello\n",
+            std::fs::read_to_string(tmp.path().join("ro/sub/file")).unwrap()
+        );
+        // so that the tempdir can be cleaned up even without root
+        std::fs::set_permissions(
+            tmp.path().join("ro/sub"),
+            std::fs::Permissions::from_mode(0o700),
+        )
+        .unwrap();
+        std::fs::set_permissions(
+            tmp.path().join("ro"),
+            std::fs::Permissions::from_mode(0o700),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn backed() {
+        let host = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(host.path(), "hello world\n").unwrap();
+        let host = Arc::new(
+            std::fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(host.path())
+                .unwrap(),
+        );
+        let mut file = File::default();
+        file.write_at(Extent::backed(host.clone(), 6, 6).unwrap(), 0);
+        file.write_at("hello ", 6);
+        let mut fs = fs! {};
+        fs.insert("file", file);
+        let tmp = tempfile::tempdir().unwrap();
+        fs.materialize_to_with(tmp.path(), MaterializeOptions::new().unprivileged(true))
+            .unwrap();
+        assert_eq!(
+            "world\nhello ",
+            std::fs::read_to_string(tmp.path().join("file")).unwrap()
+        );
+
+        // copy_file_range(2) stops early when the backing file shrinks, so
+        // this falls back to reading the data, which reports that it is gone
+        let mut file = File::default();
+        file.write_at(Extent::backed(host.clone(), 6, 6).unwrap(), 0);
+        host.set_len(0).unwrap();
+        let mut fs = fs! {};
+        fs.insert("file", file);
+        let tmp = tempfile::tempdir().unwrap();
+        assert_eq!(
+            ErrorKind::UnexpectedEof,
+            fs.materialize_to_with(tmp.path(), MaterializeOptions::new().unprivileged(true))
+                .unwrap_err()
+                .kind()
+        );
+    }
+
+    #[test]
+    fn sparse() {
+        const MB: u64 = 1 << 20;
+        let mut file = File::default();
+        file.write_at(crate::file::extent::Extent::Hole(MB), 0);
+        file.write_at("data", MB);
+        file.truncate(4 * MB);
+        let mut fs = fs! {};
+        fs.insert("disk.img", file);
+        let tmp = tempfile::tempdir().unwrap();
+        fs.materialize_to_with(tmp.path(), &as_current_user())
+            .unwrap();
+        let host = std::fs::metadata(tmp.path().join("disk.img")).unwrap();
+        assert_eq!(4 * MB, host.len());
+        assert!(host.blocks() * 512 < MB, "{} blocks", host.blocks());
+        assert!(fs.cmp_dir(tmp.path(), Fields::DATA).unwrap().is_all());
+    }
+
+    #[test]
+    fn verity() {
+        use sha2::Digest;
+        use sha2::Sha256;
+
+        let fs = fs! {
+            "motd": file("hello\n"),
+        };
+        // fs-verity digest of a file that fits in a single block, which is
+        // the root of its merkle tree
+        let mut block = b"hello\n".to_vec();
+        block.resize(4096, 0);
+        let mut descriptor = vec![1, 1, 12, 0, 0, 0, 0, 0];
+        descriptor.extend(6u64.to_le_bytes());
+        descriptor.extend(Sha256::digest(&block));
+        descriptor.resize(256, 0);
+        let expected = Sha256::digest(&descriptor).to_vec();
+
+        let tmp = tempfile::tempdir().unwrap();
+        let report = match fs.materialize_to_with(
+            tmp.path(),
+            MaterializeOptions::new()
+                .verity(true)
+                .expected_verity("motd", expected.clone()),
+        ) {
+            Ok(report) => report,
+            // whether this works depends on the filesystem the test runs on
+            Err(e)
+                if matches!(
+                    e.raw_os_error().map(Errno::from_i32),
+                    Some(Errno::EOPNOTSUPP | Errno::ENOTTY)
+                ) =>
+            {
+                return
+            }
+            Err(e) => panic!("{e}"),
+        };
+        assert_eq!(Bytes::from(expected), report.verity()[Path::new("motd")]);
+        let tmp = tempfile::tempdir().unwrap();
+        assert_eq!(
+            ErrorKind::InvalidData,
+            fs.materialize_to_with(
+                tmp.path(),
+                MaterializeOptions::new()
+                    .verity(true)
+                    .expected_verity("motd", vec![0; 32]),
+            )
+            .unwrap_err()
+            .kind()
+        );
+    }
+
+    #[test]
+    fn privileges() {
+        // vfs_cap_data revision 2 with CAP_NET_BIND_SERVICE permitted and
+        // effective
+        let mut cap = 0x0200_0001u32.to_le_bytes().to_vec();
+        cap.extend((1u32 << 10).to_le_bytes());
+        cap.extend([0; 12]);
+        let mut fs = fs! {
+            "bin": {
+                "ping": file("#!/bin/true\n", 0o4755),
+                "su": file("#!/bin/true\n", 0o6755),
+            },
+        };
+        fs.chown("bin/su", Uid::from_raw(1), Gid::from_raw(2))
+            .unwrap();
+        fs.set_xattr("bin/ping", "security.capability", cap.clone())
+            .unwrap();
+        let tmp = tempfile::tempdir().unwrap();
+        let report = fs
+            .materialize_to_with(tmp.path(), MaterializeOptions::new().unprivileged(true))
+            .unwrap();
+        let ping = tmp.path().join("bin/ping");
+        assert_eq!(0o4755, std::fs::metadata(&ping).unwrap().mode() & 0o7777);
+        // without root, the owners and capabilities are left for later
+        if !nix::unistd::geteuid().is_root() {
+            assert!(report.deferred().contains(&MaterializeOp::SetXattr {
+                path: "bin/ping".into(),
+                name: "security.capability".into(),
+                value: cap.into(),
+            }));
+            return;
+        }
+        assert!(report.deferred().is_empty());
+        assert_eq!(Some(cap), xattr::get(&ping, "security.capability").unwrap());
+        let su = std::fs::metadata(tmp.path().join("bin/su")).unwrap();
+        assert_eq!((0o6755, 1, 2), (su.mode() & 0o7777, su.uid(), su.gid()));
+    }
+
+    #[test]
+    fn times() {
+        let mut fs = fs! {
+            "etc": {
+                "motd": file("hello\n"),
+            },
+            "motd": symlink("etc/motd"),
+        };
+        let time = |secs| SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(secs);
+        for (i, path) in ["", "etc", "etc/motd", "motd"].into_iter().enumerate() {
+            let t = time(1_000_000 * (i as u64 + 1));
+            fs.set_times(path, t, t, t).unwrap();
+        }
+        let tmp = tempfile::tempdir().unwrap();
+        fs.materialize_to_with(tmp.path(), &as_current_user())
+            .unwrap();
+        for (path, entry) in &fs {
+            let host = std::fs::symlink_metadata(tmp.path().join(path)).unwrap();
+            assert_eq!(entry.metadata().modified(), host.modified().unwrap());
+            assert_eq!(entry.metadata().accessed(), host.accessed().unwrap());
+        }
+    }
+
+    #[test]
+    fn symlinks() {
+        let mut fs = fs! {
+            "etc": {
+                "motd": file("hello\n", 0o644) ["user.target" = "file"],
+            },
+            "motd": symlink("etc/motd"),
+        };
+        // symlinks cannot have user xattrs, and only root can set the
+        // others or change owners
+        let root = nix::unistd::geteuid().is_root();
+        if root {
+            fs.set_xattr("motd", "trusted.link", "symlink").unwrap();
+        }
+        fs.chown("motd", Uid::from_raw(1), Gid::from_raw(2))
+            .unwrap();
+        let t = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_000_000);
+        fs.set_times("motd", t, t, t).unwrap();
+        let tmp = tempfile::tempdir().unwrap();
+        fs.materialize_to_with(tmp.path(), MaterializeOptions::new().unprivileged(true))
+            .unwrap();
+        let link = tmp.path().join("motd");
+        let meta = std::fs::symlink_metadata(&link).unwrap();
+        assert_eq!(t, meta.modified().unwrap());
+        let target = tmp.path().join("etc/motd");
+        if root {
+            assert_eq!((1, 2), (meta.uid(), meta.gid()));
+            assert_eq!(
+                vec![OsStr::new("trusted.link").to_owned()],
+                xattr::list(&link).unwrap().collect::<Vec<_>>()
+            );
+            let meta = std::fs::metadata(&target).unwrap();
+            assert_eq!((0, 0), (meta.uid(), meta.gid()));
+        }
+        // none of it leaks through to the target
+        let meta = std::fs::metadata(&target).unwrap();
+        assert_eq!(0o644, meta.mode() & 0o7777);
+        assert_ne!(t, meta.modified().unwrap());
+        assert_eq!(
+            vec![OsStr::new("user.target").to_owned()],
+            xattr::list(&target).unwrap().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn id_map() {
+        let mut fs = fs! {
+            "etc": {
+                "motd": file("hello\n"),
+            },
+        };
+        fs.chown("etc/motd", Uid::from_raw(1), Gid::from_raw(2))
+            .unwrap();
+        let mut uid_map = IdMap::new();
+        uid_map.range(0, 100000, 65536);
+        let mut gid_map = IdMap::new();
+        gid_map.range(0, 200000, 1).range(1, 300000, 65535);
+        let tmp = tempfile::tempdir().unwrap();
+        let report = fs
+            .materialize_to_with(
+                tmp.path(),
+                MaterializeOptions::new()
+                    .uid_map(uid_map.clone())
+                    .gid_map(gid_map)
+                    .unprivileged(true),
+            )
+            .unwrap();
+        // without root the owners are only in the report
+        let owner = |path: &str| {
+            let deferred = report.deferred().iter().find_map(|op| match op {
+                MaterializeOp::Chown { path: p, uid, gid } if *p == path => Some((*uid, *gid)),
+                _ => None,
+            });
+            deferred.unwrap_or_else(|| {
+                let host = std::fs::metadata(tmp.path().join(path)).unwrap();
+                (host.uid(), host.gid())
+            })
+        };
+        assert_eq!((100000, 200000), owner("etc"));
+        assert_eq!((100001, 300001), owner("etc/motd"));
+
+        fs.chown("etc/motd", Uid::from_raw(70000), Gid::from_raw(0))
+            .unwrap();
+        let tmp = tempfile::tempdir().unwrap();
+        assert_eq!(
+            ErrorKind::InvalidInput,
+            fs.materialize_to_with(tmp.path(), MaterializeOptions::new().uid_map(uid_map))
+                .unwrap_err()
+                .kind()
+        );
+    }
+
+    #[test]
+    fn special_files() {
+        let mut fs = fs! {};
+        fs.insert("fifo", special(SFlag::S_IFIFO, 0));
+        fs.insert("null", special(SFlag::S_IFCHR, makedev(1, 3)));
+        fs.insert("sock", special(SFlag::S_IFSOCK, 0));
+        let tmp = tempfile::tempdir().unwrap();
+        let report = fs
+            .materialize_to_with(
+                tmp.path(),
+                as_current_user().special_files(SpecialFiles::Skip),
+            )
+            .unwrap();
+        assert_eq!(
+            &[BytesPath::from("null"), BytesPath::from("sock")],
+            report.skipped()
+        );
+        assert!(tmp.path().join("fifo").exists());
+        assert!(!tmp.path().join("null").exists());
+
+        // whether the device can be created depends on where the test runs
+        let tmp = tempfile::tempdir().unwrap();
+        let report = fs
+            .materialize_to_with(tmp.path(), &as_current_user())
+            .unwrap();
+        assert_eq!(
+            report.skipped().is_empty(),
+            tmp.path().join("null").exists()
+        );
+    }
+
+    #[test]
+    fn threads() {
+        let mut fs = fs! {
+            "etc": {},
+        };
+        for i in 0..100 {
+            fs.insert(
+                PathBuf::from(format!("etc/{i}")),
+                File::builder().contents(format!("file {i}\n")).build(),
+            );
+        }
+        for threads in [1, 4] {
+            let tmp = tempfile::tempdir().unwrap();
+            fs.materialize_to_with(tmp.path(), as_current_user().threads(threads))
+                .unwrap();
+            assert!(fs
+                .cmp_dir(tmp.path(), materialized_fields())
+                .unwrap()
+                .is_all());
+        }
+    }
+
+    #[test]
+    fn progress() {
+        let fs = fs! {
+            "etc": {
+                "motd": file("hello\n"),
+                "issue": link("etc/motd"),
+            },
+            "motd": symlink("etc/motd"),
+        };
+        let seen = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let tmp = tempfile::tempdir().unwrap();
+        let s = seen.clone();
+        fs.materialize_to_with(
+            tmp.path(),
+            as_current_user().progress(move |p| {
+                s.lock()
+                    .unwrap()
+                    .push((p.entries(), p.bytes(), p.path().to_owned()))
+            }),
+        )
+        .unwrap();
+        let mut seen = seen.lock().unwrap().clone();
+        seen.sort();
+        assert_eq!(
+            vec![
+                (1, 0, PathBuf::from("")),
+                (2, 0, PathBuf::from("etc")),
+                // a hardlink to etc/issue, whose contents are written last
+                (3, 0, PathBuf::from("etc/motd")),
+                (4, 0, PathBuf::from("motd")),
+                (5, 6, PathBuf::from("etc/issue")),
+            ],
+            seen
+        );
+    }
+
+    #[test]
+    fn materialize_plan() {
+        let mut fs = fs! {
+            "etc": {
+                "motd": file("hello\n", 0o644) ["user.demo" = "xattr"],
+                "issue": link("etc/motd"),
+            },
+            "motd": symlink("etc/motd"),
+        };
+        fs.insert("null", special(SFlag::S_IFCHR, makedev(1, 3)));
+        let mut uid_map = IdMap::new();
+        uid_map.range(0, 1000, 1);
+        let plan = fs
+            .materialize_plan(MaterializeOptions::new().uid_map(uid_map))
+            .unwrap();
+        let chown = |path: &'static str| MaterializeOp::Chown {
+            path: path.into(),
+            uid: 1000,
+            gid: 0,
+        };
+        let chmod = |path: &'static str, mode| MaterializeOp::Chmod {
+            path: path.into(),
+            mode: Mode::from_bits_truncate(mode),
+        };
+        assert_eq!(
+            vec![
+                MaterializeOp::CreateDir { path: "etc".into() },
+                MaterializeOp::CreateFile {
+                    path: "etc/issue".into()
+                },
+                MaterializeOp::Hardlink {
+                    path: "etc/motd".into(),
+                    target: "etc/issue".into(),
+                },
+                MaterializeOp::Symlink {
+                    path: "motd".into(),
+                    target: "etc/motd".into(),
+                },
+                chown("motd"),
+                MaterializeOp::Mknod {
+                    path: "null".into(),
+                    file_type: SFlag::S_IFCHR,
+                    mode: Mode::from_bits_truncate(0o644),
+                    rdev: makedev(1, 3),
+                },
+                chown("null"),
+                chmod("null", 0o644),
+                MaterializeOp::WriteFile {
+                    path: "etc/issue".into(),
+                    bytes: 6,
+                },
+                chown("etc/issue"),
+                chmod("etc/issue", 0o644),
+                MaterializeOp::SetXattr {
+                    path: "etc/issue".into(),
+                    name: "user.demo".into(),
+                    value: "xattr".into(),
+                },
+                chown("etc"),
+                chmod("etc", 0o755),
+                chown(""),
+                chmod("", 0o755),
+            ],
+            plan
+        );
+    }
+
+    #[test]
+    fn selinux() {
+        let fs = fs! {
+            "etc": {
+                "motd": file("hello\n") ["security.selinux" = "system_u:object_r:user_tmp_t:s0\0"],
+            },
+        };
+        let labels = |selinux| {
+            fs.materialize_plan(MaterializeOptions::new().selinux(selinux))
+                .unwrap()
+                .into_iter()
+                .filter_map(|op| match op {
+                    MaterializeOp::SetXattr { path, value, .. } => Some((path, value)),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            vec![(
+                BytesPath::from("etc/motd"),
+                Bytes::from("system_u:object_r:user_tmp_t:s0\0")
+            )],
+            labels(Selinux::Apply)
+        );
+        assert_eq!(Vec::<(BytesPath, Bytes)>::new(), labels(Selinux::Skip));
+        let contexts = FileContexts::parse("/etc(/.*)? -- system_u:object_r:etc_t:s0").unwrap();
+        assert_eq!(
+            vec![(
+                BytesPath::from("etc/motd"),
+                Bytes::from("system_u:object_r:etc_t:s0\0")
+            )],
+            labels(Selinux::Relabel(contexts))
+        );
+    }
+
+    #[test]
+    fn filters() {
+        let fs = fs! {
+            "etc": {
+                "motd": file("hello\n"),
+                "issue": link("etc/motd"),
+                "ssh": {
+                    "sshd_config": file(""),
+                },
+            },
+            "usr": {
+                "lib": {
+                    "os-release": file(""),
+                },
+                "bin": {},
+            },
+        };
+        let materialized = |options: &MaterializeOptions| {
+            let tmp = tempfile::tempdir().unwrap();
+            fs.materialize_to_with(tmp.path(), options).unwrap();
+            let mut paths: Vec<_> = walk(tmp.path())
+                .into_iter()
+                .map(|p| p.strip_prefix(tmp.path()).unwrap().to_owned())
+                .collect();
+            paths.sort();
+            paths
+        };
+        assert_eq!(
+            vec![
+                PathBuf::from("etc"),
+                PathBuf::from("etc/issue"),
+                PathBuf::from("etc/motd"),
+                PathBuf::from("etc/ssh"),
+                PathBuf::from("etc/ssh/sshd_config"),
+            ],
+            materialized(as_current_user().subtree("/etc"))
+        );
+        assert_eq!(
+            vec![
+                PathBuf::from("etc"),
+                PathBuf::from("etc/motd"),
+                PathBuf::from("usr"),
+                PathBuf::from("usr/lib"),
+                PathBuf::from("usr/lib/os-release"),
+            ],
+            materialized(
+                as_current_user()
+                    .glob("etc/m*")
+                    .unwrap()
+                    .glob("**/os-release")
+                    .unwrap()
+            )
+        );
+    }
+
+    fn walk(dir: &Path) -> Vec<PathBuf> {
+        let mut paths = Vec::new();
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                paths.extend(walk(&path));
+            }
+            paths.push(path);
+        }
+        paths
+    }
+
+    #[test]
+    fn materialize_at() {
+        let fs = fs! {
+            "etc": {
+                "motd": file("hello\n"),
+            },
+        };
+        let tmp = tempfile::tempdir().unwrap();
+        std::fs::create_dir(tmp.path().join("dest")).unwrap();
+        let dest = std::fs::File::open(tmp.path().join("dest")).unwrap();
+        // only the fd matters, not where the directory is now
+        std::fs::rename(tmp.path().join("dest"), tmp.path().join("moved")).unwrap();
+        fs.materialize_at(&dest, &as_current_user()).unwrap();
+        assert!(fs
+            .cmp_dir(tmp.path().join("moved"), materialized_fields())
+            .unwrap()
+            .is_all());
+    }
+
+    #[test]
+    fn sandbox() {
+        let fs = fs! {
+            "etc": {
+                "motd": file("hello\n"),
+            },
+        };
+        let outside = tempfile::tempdir().unwrap();
+        let tmp = tempfile::tempdir().unwrap();
+        let escape = outside.path().join("escape");
+        let escaped = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let result = {
+            let escaped = escaped.clone();
+            fs.materialize_to_with(
+                tmp.path(),
+                as_current_user()
+                    .sandbox(true)
+                    .progress(move |_| escaped.lock().unwrap().push(std::fs::write(&escape, ""))),
+            )
+        };
+        match result {
+            Err(e) if e.kind() == ErrorKind::Unsupported => return,
+            result => result.unwrap(),
+        };
+        assert!(fs
+            .cmp_dir(tmp.path(), materialized_fields())
+            .unwrap()
+            .is_all());
+        let escaped = escaped.lock().unwrap();
+        assert!(!escaped.is_empty());
+        for result in escaped.iter() {
+            assert_eq!(
+                ErrorKind::PermissionDenied,
+                result.as_ref().unwrap_err().kind()
+            );
+        }
+        // the calling thread is not restricted
+        std::fs::write(outside.path().join("escape"), "").unwrap();
+    }
+
+    #[test]
+    fn symlinked_parent() {
+        let fs = fs! {
+            "etc": {
+                "motd": file("hello\n"),
+            },
+        };
+        for existing in [ExistingEntries::Skip, ExistingEntries::Overwrite] {
+            let outside = tempfile::tempdir().unwrap();
+            let tmp = tempfile::tempdir().unwrap();
+            std::os::unix::fs::symlink(outside.path(), tmp.path().join("etc")).unwrap();
+            fs.materialize_to_with(tmp.path(), as_current_user().existing(existing))
+                .unwrap();
+            assert_eq!(
+                0,
+                std::fs::read_dir(outside.path()).unwrap().count(),
+                "{existing:?} wrote through a symlink"
+            );
+        }
+    }
+
+    #[test]
+    fn existing() {
+        let fs = fs! {
+            "etc": {
+                "motd": file("hello\n"),
+            },
+            "motd": symlink("etc/motd"),
+        };
+        let materialize = |existing| {
+            let tmp = tempfile::tempdir().unwrap();
+            std::fs::create_dir_all(tmp.path().join("etc")).unwrap();
+            std::fs::write(tmp.path().join("etc/motd"), "old\n").unwrap();
+            std::fs::write(tmp.path().join("etc/other"), "other\n").unwrap();
+            std::fs::create_dir(tmp.path().join("motd")).unwrap();
+            let report = fs.materialize_to_with(tmp.path(), as_current_user().existing(existing));
+            (tmp, report)
+        };
+        let read = |dir: &Path, path: &str| std::fs::read_to_string(dir.join(path)).ok();
+
+        let (_tmp, report) = materialize(ExistingEntries::Fail);
+        assert_eq!(ErrorKind::AlreadyExists, report.unwrap_err().kind());
+
+        let (tmp, report) = materialize(ExistingEntries::Skip);
+        assert_eq!(
+            &[BytesPath::from("etc"), BytesPath::from("motd")],
+            report.unwrap().skipped()
+        );
+        assert_eq!(Some("old\n".into()), read(tmp.path(), "etc/motd"));
+        assert!(tmp.path().join("motd").is_dir());
+
+        let (tmp, report) = materialize(ExistingEntries::Overwrite);
+        assert!(report.unwrap().skipped().is_empty());
+        assert!(fs
+            .cmp_dir(tmp.path(), materialized_fields())
+            .unwrap()
+            .is_all());
+
+        let (tmp, report) = materialize(ExistingEntries::Merge);
+        assert!(report.unwrap().skipped().is_empty());
+        assert_eq!(Some("hello\n".into()), read(tmp.path(), "etc/motd"));
+        assert_eq!(Some("other\n".into()), read(tmp.path(), "etc/other"));
+        assert!(tmp.path().join("motd").is_symlink());
+    }
+
+    #[test]
+    fn unprivileged() {
+        // vfs_cap_data revision 2 with CAP_NET_BIND_SERVICE permitted and
+        // effective
+        let mut cap = 0x0200_0001u32.to_le_bytes().to_vec();
+        cap.extend((1u32 << 10).to_le_bytes());
+        cap.extend([0; 12]);
+        let mut fs = fs! {
+            "etc": {
+                "motd": file("hello\n", 0o4755) [
+                    "security.capability" = cap.clone(),
+                    "user.demo" = "xattr",
+                ],
+            },
+        };
+        fs.insert("null", special(SFlag::S_IFCHR, makedev(1, 3)));
+        let tmp = tempfile::tempdir().unwrap();
+        let report = fs
+            .materialize_to_with(tmp.path(), MaterializeOptions::new().unprivileged(true))
+            .unwrap();
+        assert!(report.skipped().is_empty());
+        // whether anything is deferred depends on who runs the test
+        if nix::unistd::geteuid().is_root() && has_capability(CAP_MKNOD) {
+            assert!(report.deferred().is_empty());
+            return;
+        }
+        // the chown comes with everything that it would undo
+        let metadata = |path: &'static str, mode| {
+            vec![
+                MaterializeOp::Chown {
+                    path: path.into(),
+                    uid: 0,
+                    gid: 0,
+                },
+                MaterializeOp::Chmod {
+                    path: path.into(),
+                    mode: Mode::from_bits_truncate(mode),
+                },
+            ]
+        };
+        let mut expected = metadata("", 0o755);
+        expected.extend(metadata("etc", 0o755));
+        expected.extend(metadata("etc/motd", 0o4755));
+        expected.push(MaterializeOp::SetXattr {
+            path: "etc/motd".into(),
+            name: "security.capability".into(),
+            value: cap.into(),
+        });
+        if !tmp.path().join("null").exists() {
+            expected.push(MaterializeOp::Mknod {
+                path: "null".into(),
+                file_type: SFlag::S_IFCHR,
+                mode: Mode::from_bits_truncate(0o644),
+                rdev: makedev(1, 3),
+            });
+        }
+        expected.extend(metadata("null", 0o644));
+        assert_eq!(expected, report.deferred());
+        // but anything that an unprivileged process can do is done already
+        assert_eq!(
+            Some(b"xattr".to_vec()),
+            xattr::get(tmp.path().join("etc/motd"), "user.demo").unwrap()
+        );
+    }
+}
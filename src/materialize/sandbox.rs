@@ -0,0 +1,128 @@
+//! Landlock sandbox that only allows the current thread to write underneath
+//! the destination directory, see landlock(7).
+
+use std::io::Error;
+use std::io::ErrorKind;
+use std::io::Result;
+use std::os::fd::AsRawFd;
+use std::os::fd::BorrowedFd;
+use std::os::fd::FromRawFd;
+use std::os::fd::OwnedFd;
+
+use nix::libc;
+
+const LANDLOCK_CREATE_RULESET_VERSION: u32 = 1;
+const LANDLOCK_RULE_PATH_BENEATH: u32 = 1;
+
+const ACCESS_FS_WRITE_FILE: u64 = 1 << 1;
+const ACCESS_FS_REMOVE_DIR: u64 = 1 << 4;
+const ACCESS_FS_REMOVE_FILE: u64 = 1 << 5;
+const ACCESS_FS_MAKE_CHAR: u64 = 1 << 6;
+const ACCESS_FS_MAKE_DIR: u64 = 1 << 7;
+const ACCESS_FS_MAKE_REG: u64 = 1 << 8;
+const ACCESS_FS_MAKE_SOCK: u64 = 1 << 9;
+const ACCESS_FS_MAKE_FIFO: u64 = 1 << 10;
+const ACCESS_FS_MAKE_BLOCK: u64 = 1 << 11;
+const ACCESS_FS_MAKE_SYM: u64 = 1 << 12;
+/// Since ABI version 2
+const ACCESS_FS_REFER: u64 = 1 << 13;
+/// Since ABI version 3
+const ACCESS_FS_TRUNCATE: u64 = 1 << 14;
+
+#[repr(C)]
+struct RulesetAttr {
+    handled_access_fs: u64,
+}
+
+#[repr(C, packed)]
+struct PathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: i32,
+}
+
+/// Every way of modifying the filesystem that the running kernel can
+/// restrict, or None if it does not support Landlock.
+fn write_access() -> Option<u64> {
+    let abi = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_create_ruleset,
+            std::ptr::null::<RulesetAttr>(),
+            0,
+            LANDLOCK_CREATE_RULESET_VERSION,
+        )
+    };
+    if abi < 1 {
+        return None;
+    }
+    let mut access = ACCESS_FS_WRITE_FILE
+        | ACCESS_FS_REMOVE_DIR
+        | ACCESS_FS_REMOVE_FILE
+        | ACCESS_FS_MAKE_CHAR
+        | ACCESS_FS_MAKE_DIR
+        | ACCESS_FS_MAKE_REG
+        | ACCESS_FS_MAKE_SOCK
+        | ACCESS_FS_MAKE_FIFO
+        | ACCESS_FS_MAKE_BLOCK
+        | ACCESS_FS_MAKE_SYM;
+    if abi >= 2 {
+        access |= ACCESS_FS_REFER;
+    }
+    if abi >= 3 {
+        access |= ACCESS_FS_TRUNCATE;
+    }
+    Some(access)
+}
+
+/// Irrevocably restrict the calling thread (and any threads it spawns later)
+/// so that it can only modify the filesystem underneath 'dir'. Reading is
+/// still allowed everywhere, and Landlock does not restrict chown(2),
+/// chmod(2), setxattr(2) or utimensat(2) at all.
+pub(super) fn restrict_writes_to(dir: BorrowedFd) -> Result<()> {
+    let access = write_access().ok_or_else(|| {
+        Error::new(
+            ErrorKind::Unsupported,
+            "Landlock is not supported by this kernel",
+        )
+    })?;
+    let attr = RulesetAttr {
+        handled_access_fs: access,
+    };
+    let ruleset = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_create_ruleset,
+            &attr,
+            std::mem::size_of::<RulesetAttr>(),
+            0,
+        )
+    };
+    if ruleset < 0 {
+        return Err(Error::last_os_error());
+    }
+    // SAFETY: landlock_create_ruleset just returned this fd
+    let ruleset = unsafe { OwnedFd::from_raw_fd(ruleset as i32) };
+    let beneath = PathBeneathAttr {
+        allowed_access: access,
+        parent_fd: dir.as_raw_fd(),
+    };
+    if unsafe {
+        libc::syscall(
+            libc::SYS_landlock_add_rule,
+            ruleset.as_raw_fd(),
+            LANDLOCK_RULE_PATH_BENEATH,
+            &beneath,
+            0,
+        )
+    } < 0
+    {
+        return Err(Error::last_os_error());
+    }
+    // required to restrict a thread without CAP_SYS_ADMIN, and like the
+    // restriction itself it only applies to this thread
+    if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } < 0 {
+        return Err(Error::last_os_error());
+    }
+    if unsafe { libc::syscall(libc::SYS_landlock_restrict_self, ruleset.as_raw_fd(), 0) } < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
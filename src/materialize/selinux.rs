@@ -0,0 +1,123 @@
+//! Labeling of materialized entries according to a file_contexts(5) file,
+//! like `restorecon` would do.
+
+use std::io::Error;
+use std::io::ErrorKind;
+use std::io::Result;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+use bytes::Bytes;
+use nix::sys::stat::SFlag;
+use regex_automata::meta::Regex;
+
+/// Name of the xattr that holds the SELinux label of an entry.
+pub(super) const XATTR: &[u8] = b"security.selinux";
+
+/// Mapping from paths to SELinux labels, in the format of file_contexts(5).
+#[derive(Debug, Clone)]
+pub struct FileContexts {
+    /// (regex, file type it applies to, label or None for `<<none>>`)
+    specs: Vec<(Regex, Option<SFlag>, Option<Bytes>)>,
+}
+
+impl FileContexts {
+    /// Parse the contents of a file_contexts file, where every line is a
+    /// regex matching the whole absolute path, an optional file type (like
+    /// `--` or `-d`) and a label.
+    pub fn parse(contents: &str) -> Result<Self> {
+        let mut specs = Vec::new();
+        for (i, line) in contents.lines().enumerate() {
+            let invalid =
+                |msg: String| Error::new(ErrorKind::InvalidData, format!("line {}: {msg}", i + 1));
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<_> = line.split_whitespace().collect();
+            let (regex, file_type, label) = match fields[..] {
+                [regex, label] => (regex, None, label),
+                [regex, file_type, label] => (regex, Some(file_type), label),
+                _ => return Err(invalid(format!("expected 2 or 3 fields: {line}"))),
+            };
+            let file_type = file_type
+                .map(|t| match t {
+                    "--" => Ok(SFlag::S_IFREG),
+                    "-d" => Ok(SFlag::S_IFDIR),
+                    "-l" => Ok(SFlag::S_IFLNK),
+                    "-c" => Ok(SFlag::S_IFCHR),
+                    "-b" => Ok(SFlag::S_IFBLK),
+                    "-p" => Ok(SFlag::S_IFIFO),
+                    "-s" => Ok(SFlag::S_IFSOCK),
+                    _ => Err(invalid(format!("unknown file type {t}"))),
+                })
+                .transpose()?;
+            let regex = Regex::new(&format!("^(?:{regex})$"))
+                .map_err(|e| invalid(format!("bad regex {regex}: {e}")))?;
+            let label = match label {
+                "<<none>>" => None,
+                label => Some(Bytes::copy_from_slice(label.as_bytes())),
+            };
+            specs.push((regex, file_type, label));
+        }
+        Ok(Self { specs })
+    }
+
+    /// Label of 'path' (relative to the root of the [Filesystem](crate::Filesystem))
+    /// if it has type 'file_type', or None if nothing matches or it should
+    /// not be labeled. The last matching line wins.
+    pub fn lookup(&self, path: &Path, file_type: SFlag) -> Option<&[u8]> {
+        let mut absolute = b"/".to_vec();
+        absolute.extend_from_slice(path.as_os_str().as_bytes());
+        self.specs
+            .iter()
+            .rev()
+            .find(|(regex, ty, _)| {
+                ty.is_none_or(|ty| ty == file_type) && regex.is_match(&absolute[..])
+            })
+            .and_then(|(_, _, label)| label.as_deref())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lookup() {
+        let contexts = FileContexts::parse(
+            "# comment\n\
+             /.*            system_u:object_r:default_t:s0\n\
+             /etc(/.*)?     system_u:object_r:etc_t:s0\n\
+             /etc/shadow -- system_u:object_r:shadow_t:s0\n\
+             /proc(/.*)?    <<none>>\n",
+        )
+        .unwrap();
+        let label = |path: &str, ty| contexts.lookup(Path::new(path), ty);
+        assert_eq!(
+            Some(&b"system_u:object_r:default_t:s0"[..]),
+            label("", SFlag::S_IFDIR)
+        );
+        assert_eq!(
+            Some(&b"system_u:object_r:etc_t:s0"[..]),
+            label("etc", SFlag::S_IFDIR)
+        );
+        assert_eq!(
+            Some(&b"system_u:object_r:shadow_t:s0"[..]),
+            label("etc/shadow", SFlag::S_IFREG)
+        );
+        assert_eq!(
+            Some(&b"system_u:object_r:etc_t:s0"[..]),
+            label("etc/shadow", SFlag::S_IFLNK)
+        );
+        assert_eq!(
+            Some(&b"system_u:object_r:default_t:s0"[..]),
+            label("etcetera", SFlag::S_IFREG)
+        );
+        assert_eq!(None, label("proc/1", SFlag::S_IFREG));
+        assert_eq!(
+            ErrorKind::InvalidData,
+            FileContexts::parse("/etc -x foo").unwrap_err().kind()
+        );
+    }
+}
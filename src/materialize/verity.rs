@@ -0,0 +1,72 @@
+//! Enabling and measuring fs-verity on materialized files, see
+//! <https://docs.kernel.org/filesystems/fsverity.html>.
+
+use std::fs::File;
+use std::io::Result;
+use std::os::fd::AsRawFd;
+
+use bytes::Bytes;
+
+const FS_VERITY_HASH_ALG_SHA256: u32 = 1;
+const BLOCK_SIZE: u32 = 4096;
+/// Largest digest of any algorithm that fs-verity supports (SHA-512).
+const MAX_DIGEST_SIZE: usize = 64;
+
+#[repr(C)]
+struct EnableArg {
+    version: u32,
+    hash_algorithm: u32,
+    block_size: u32,
+    salt_size: u32,
+    salt_ptr: u64,
+    sig_size: u32,
+    reserved1: u32,
+    sig_ptr: u64,
+    reserved2: [u64; 11],
+}
+
+#[repr(C)]
+struct Digest {
+    digest_algorithm: u16,
+    digest_size: u16,
+    digest: [u8; MAX_DIGEST_SIZE],
+}
+
+nix::ioctl_write_ptr!(fs_ioc_enable_verity, b'f', 133, EnableArg);
+// the size in the request code only covers the header of the digest
+nix::ioctl_readwrite_bad!(
+    fs_ioc_measure_verity,
+    nix::request_code_readwrite!(b'f', 134, 4),
+    Digest
+);
+
+/// Enable fs-verity (with SHA-256 and 4K blocks) on 'file', which has to be
+/// opened read-only with no writable file descriptors left open.
+pub(super) fn enable(file: &File) -> Result<()> {
+    let arg = EnableArg {
+        version: 1,
+        hash_algorithm: FS_VERITY_HASH_ALG_SHA256,
+        block_size: BLOCK_SIZE,
+        salt_size: 0,
+        salt_ptr: 0,
+        sig_size: 0,
+        reserved1: 0,
+        sig_ptr: 0,
+        reserved2: [0; 11],
+    };
+    unsafe { fs_ioc_enable_verity(file.as_raw_fd(), &arg) }?;
+    Ok(())
+}
+
+/// fs-verity digest of 'file', which must already have fs-verity enabled.
+pub(super) fn measure(file: &File) -> Result<Bytes> {
+    let mut digest = Digest {
+        digest_algorithm: 0,
+        digest_size: MAX_DIGEST_SIZE as u16,
+        digest: [0; MAX_DIGEST_SIZE],
+    };
+    unsafe { fs_ioc_measure_verity(file.as_raw_fd(), &mut digest) }?;
+    Ok(Bytes::copy_from_slice(
+        &digest.digest[..digest.digest_size as usize],
+    ))
+}
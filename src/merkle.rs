@@ -0,0 +1,214 @@
+//! Stable digest of an entire [Filesystem], computed as a merkle tree over
+//! [Filesystem::tree].
+//!
+//! Every node is hashed separately, with all integers encoded little-endian
+//! and all variable-length byte strings prefixed by their length as a `u64`.
+//! A node's digest covers, in order:
+//!
+//! 1. A type tag: `d` (directory), `f` (regular file), `l` (symlink), `s`
+//!    (special file) or `-` for a missing intermediate directory. If
+//!    [Fields::TYPE] is not selected, every present entry uses `e` instead.
+//! 2. For special files with [Fields::TYPE], the `S_IFMT` bits as a `u32`.
+//! 3. [Fields::MODE]: the permission bits as a `u32`.
+//! 4. [Fields::OWNER]: uid then gid, each a `u32`.
+//! 5. [Fields::TIME]: ctime, atime and mtime, each as signed nanoseconds since
+//!    the unix epoch in an `i128`.
+//! 6. [Fields::XATTR]: the number of xattrs as a `u64`, then each name and
+//!    value, sorted by name.
+//! 7. [Fields::RDEV]: the device number of special files as a `u64`.
+//! 8. [Fields::SIZE]: the apparent size of the entry (see [Entry::size]) as a
+//!    `u64`.
+//! 9. [Fields::DATA]: the digest of the contents of a regular file, or the raw
+//!    target of a symlink.
+//! 10. The number of children as a `u64`, then the name and digest of each
+//!     child, sorted by name.
+//!
+//! Paths are always part of the digest, since they make up the structure of
+//! the tree. [Fields::EXTENTS] is ignored, since the physical layout of data
+//! does not change what the filesystem contains, and so is [Fields::NLINK]
+//! since hardlinks are not part of [Filesystem::tree].
+
+use std::collections::BTreeMap;
+use std::io::Result;
+use std::os::unix::ffi::OsStrExt;
+use std::time::SystemTime;
+
+use digest::Digest;
+use digest::Output;
+
+use crate::cmp::Fields;
+use crate::tree::Node;
+use crate::BytesPath;
+use crate::Entry;
+use crate::Filesystem;
+
+impl Filesystem {
+    /// Compute the merkle root of this filesystem, covering only the selected
+    /// metadata fields. See the [module-level docs](crate::merkle) for the
+    /// exact scheme. Fails only if the data of a regular file cannot be read.
+    pub fn digest<D: Digest>(&self, fields: Fields) -> Result<Output<D>> {
+        node_digest::<D>(&self.tree(), fields)
+    }
+
+    /// Hash the contents of every regular file, as they would appear to
+    /// read(2). Hardlinks are reported under each of their paths.
+    pub fn digests<D: Digest>(&self) -> Result<BTreeMap<BytesPath, Output<D>>> {
+        self.iter()
+            .filter_map(|(path, entry)| match entry {
+                Entry::File(f) => Some(f.digest::<D>().map(|d| (path.into(), d))),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+fn node_digest<D: Digest>(node: &Node, fields: Fields) -> Result<Output<D>> {
+    let mut d = D::new();
+    match node.entry() {
+        Some(entry) => update_entry::<D>(&mut d, entry, fields)?,
+        None => d.update(b"-"),
+    }
+    d.update((node.children().len() as u64).to_le_bytes());
+    for (name, child) in node.children() {
+        update_bytes(&mut d, name.as_bytes());
+        d.update(node_digest::<D>(child, fields)?);
+    }
+    Ok(d.finalize())
+}
+
+fn update_entry<D: Digest>(d: &mut D, entry: &Entry, fields: Fields) -> Result<()> {
+    if fields.contains(Fields::TYPE) {
+        #[remain::sorted]
+        match entry {
+            Entry::Directory(_) => d.update(b"d"),
+            Entry::File(_) => d.update(b"f"),
+            Entry::Special(s) => {
+                d.update(b"s");
+                d.update(s.file_type().bits().to_le_bytes());
+            }
+            Entry::Symlink(_) => d.update(b"l"),
+        }
+    } else {
+        d.update(b"e");
+    }
+    let metadata = entry.metadata();
+    if fields.contains(Fields::MODE) {
+        d.update(metadata.mode().bits().to_le_bytes());
+    }
+    if fields.contains(Fields::OWNER) {
+        d.update(metadata.uid().as_u32().to_le_bytes());
+        d.update(metadata.gid().as_u32().to_le_bytes());
+    }
+    if fields.contains(Fields::TIME) {
+        for time in [metadata.created(), metadata.accessed(), metadata.modified()] {
+            d.update(epoch_nanos(time).to_le_bytes());
+        }
+    }
+    if fields.contains(Fields::XATTR) {
+        d.update((metadata.xattrs().len() as u64).to_le_bytes());
+        for (name, value) in metadata.xattrs() {
+            update_bytes(d, name);
+            update_bytes(d, value);
+        }
+    }
+    if fields.contains(Fields::RDEV) {
+        if let Entry::Special(s) = entry {
+            d.update(s.rdev().unwrap_or(0).to_le_bytes());
+        }
+    }
+    if fields.contains(Fields::SIZE) {
+        d.update(entry.size().to_le_bytes());
+    }
+    if fields.contains(Fields::DATA) {
+        match entry {
+            Entry::File(f) => d.update(f.digest::<D>()?),
+            Entry::Symlink(s) => update_bytes(d, s.target().as_os_str().as_bytes()),
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+fn update_bytes<D: Digest>(d: &mut D, bytes: &[u8]) {
+    d.update((bytes.len() as u64).to_le_bytes());
+    d.update(bytes);
+}
+
+fn epoch_nanos(time: SystemTime) -> i128 {
+    match time.duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(after) => after.as_nanos() as i128,
+        Err(before) => -(before.duration().as_nanos() as i128),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nix::sys::stat::Mode;
+    use pretty_assertions::assert_eq;
+    use sha2::Sha256;
+
+    use super::*;
+    use crate::file::extent::Extent;
+    use crate::tests::demo_fs;
+    use crate::File;
+
+    #[test]
+    fn digest() {
+        let fs = demo_fs();
+        let all = fs.digest::<Sha256>(Fields::all()).unwrap();
+        assert_eq!(all, demo_fs().digest::<Sha256>(Fields::all()).unwrap());
+
+        let mut chmodded = demo_fs();
+        chmodded
+            .get_mut("testdata/lorem.txt")
+            .unwrap()
+            .chmod(Mode::from_bits_truncate(0o600));
+        assert_ne!(all, chmodded.digest::<Sha256>(Fields::all()).unwrap());
+        assert_eq!(
+            fs.digest::<Sha256>(Fields::all() - Fields::MODE).unwrap(),
+            chmodded
+                .digest::<Sha256>(Fields::all() - Fields::MODE)
+                .unwrap(),
+        );
+
+        let mut renamed = demo_fs();
+        let lorem = renamed.get("testdata/lorem.txt").unwrap().clone();
+        renamed.unlink("testdata/lorem.txt").unwrap();
+        renamed.insert("testdata/ipsum.txt", lorem);
+        assert_ne!(
+            fs.digest::<Sha256>(Fields::empty()).unwrap(),
+            renamed.digest::<Sha256>(Fields::empty()).unwrap(),
+        );
+    }
+
+    #[test]
+    fn digests() {
+        let mut fs = demo_fs();
+        let mut holey = File::new_empty();
+        let mut w = holey.writer();
+        w.write("Lorem");
+        w.write(Extent::Hole(3));
+        w.write("ipsum");
+        fs.insert("testdata/holey", holey);
+        let digests = fs.digests::<Sha256>().unwrap();
+        assert_eq!(
+            vec![
+                "testdata/dir/lorem.txt",
+                "testdata/holey",
+                "testdata/lorem.txt"
+            ],
+            digests
+                .keys()
+                .map(|p| p.to_str().unwrap())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            Sha256::digest(b"Lorem\0\0\0ipsum"),
+            digests[&BytesPath::from("testdata/holey")]
+        );
+        assert_eq!(
+            Sha256::digest(b"Lorem ipsum\n"),
+            digests[&BytesPath::from("testdata/lorem.txt")]
+        );
+    }
+}
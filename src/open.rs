@@ -0,0 +1,418 @@
+//! [std::fs::OpenOptions]-style file handles, so that a [Filesystem] can stand
+//! in for the real filesystem in code that does ordinary file I/O.
+
+use std::io::Error;
+use std::io::ErrorKind;
+use std::io::Read;
+use std::io::Result;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::time::SystemTime;
+
+use bytes::Bytes;
+use nix::sys::stat::Mode;
+
+use crate::entry::Metadata;
+use crate::BytesPath;
+use crate::Entry;
+use crate::File;
+use crate::Filesystem;
+
+/// Options that control how [Filesystem::open] opens a file, mirroring
+/// [std::fs::OpenOptions].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OpenOptions {
+    read: bool,
+    write: bool,
+    append: bool,
+    truncate: bool,
+    create: bool,
+    create_new: bool,
+    mode: u32,
+}
+
+impl Default for OpenOptions {
+    fn default() -> Self {
+        Self {
+            read: false,
+            write: false,
+            append: false,
+            truncate: false,
+            create: false,
+            create_new: false,
+            mode: 0o644,
+        }
+    }
+}
+
+impl OpenOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn read(&mut self, read: bool) -> &mut Self {
+        self.read = read;
+        self
+    }
+
+    pub fn write(&mut self, write: bool) -> &mut Self {
+        self.write = write;
+        self
+    }
+
+    /// Every write goes to the end of the file, regardless of the current
+    /// position. Implies [OpenOptions::write].
+    pub fn append(&mut self, append: bool) -> &mut Self {
+        self.append = append;
+        self
+    }
+
+    pub fn truncate(&mut self, truncate: bool) -> &mut Self {
+        self.truncate = truncate;
+        self
+    }
+
+    pub fn create(&mut self, create: bool) -> &mut Self {
+        self.create = create;
+        self
+    }
+
+    /// Create the file, failing if it already exists.
+    pub fn create_new(&mut self, create_new: bool) -> &mut Self {
+        self.create_new = create_new;
+        self
+    }
+
+    /// Mode of newly created files. Defaults to 0644.
+    pub fn mode(&mut self, mode: u32) -> &mut Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Equivalent options for opening a file on the host.
+    pub(crate) fn to_std(&self) -> std::fs::OpenOptions {
+        let mut options = std::fs::OpenOptions::new();
+        options
+            .read(self.read)
+            .write(self.write)
+            .append(self.append)
+            .truncate(self.truncate)
+            .create(self.create)
+            .create_new(self.create_new)
+            .mode(self.mode);
+        options
+    }
+
+    fn writable(&self) -> bool {
+        self.write || self.append
+    }
+
+    fn check(&self) -> Result<()> {
+        if !self.read && !self.writable() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "must open for at least one of read, write or append",
+            ));
+        }
+        if !self.writable() && (self.truncate || self.create || self.create_new) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "truncate and create require write or append",
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// An open regular file in a [Filesystem]. Writes go directly into the
+/// extents of the underlying [File] and update its mtime, just like
+/// [Filesystem::write] and [Filesystem::set_times].
+pub struct Handle<'f> {
+    fs: &'f mut Filesystem,
+    /// canonical path of the file
+    path: BytesPath,
+    pos: u64,
+    options: OpenOptions,
+}
+
+impl Filesystem {
+    /// Open the regular file at 'path' (following symlinks) according to
+    /// 'options'.
+    pub fn open<P>(&mut self, path: P, options: &OpenOptions) -> Result<Handle<'_>>
+    where
+        P: AsRef<Path>,
+    {
+        options.check()?;
+        let path = path.as_ref();
+        let path = match self.canonicalize(path) {
+            Ok(resolved) => {
+                if options.create_new {
+                    return Err(Error::new(
+                        ErrorKind::AlreadyExists,
+                        format!("'{}' already exists", path.display()),
+                    ));
+                }
+                resolved
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound && (options.create || options.create_new) => {
+                let path = self.creation_path(path, options.create_new)?;
+                let now = SystemTime::now();
+                self.try_insert(
+                    path.clone(),
+                    File::builder()
+                        .metadata(
+                            Metadata::builder()
+                                .mode(Mode::from_bits_truncate(options.mode))
+                                .created(now)
+                                .accessed(now)
+                                .modified(now)
+                                .build(),
+                        )
+                        .build(),
+                )?;
+                path
+            }
+            Err(e) => return Err(e),
+        };
+        match self.get(&path)? {
+            Entry::File(_) => (),
+            Entry::Directory(_) => {
+                return Err(Error::new(
+                    ErrorKind::IsADirectory,
+                    format!("'{}' is a directory", path.display()),
+                ));
+            }
+            _ => {
+                return Err(Error::other(format!("'{}' is not a file", path.display())));
+            }
+        }
+        if options.truncate && !self.get_file(&path)?.is_empty() {
+            self.truncate(&path, 0)?;
+            touch(self, &path)?;
+        }
+        Ok(Handle {
+            fs: self,
+            path,
+            pos: 0,
+            options: options.clone(),
+        })
+    }
+
+    /// Canonical path at which to create a new file for 'path', which does
+    /// not exist: its resolved parent joined with its name. Like open(2)
+    /// with O_CREAT, a dangling symlink in the last component creates its
+    /// target instead, unless 'create_new' is set.
+    fn creation_path(&self, path: &Path, create_new: bool) -> Result<BytesPath> {
+        let mut path = path.to_path_buf();
+        let mut hops = 0;
+        loop {
+            let name = path.file_name().ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("'{}' does not name a file", path.display()),
+                )
+            })?;
+            let parent = self.canonicalize(path.parent().unwrap_or(Path::new("")))?;
+            if !parent.as_os_str().is_empty() && !self.get(&parent)?.is_directory() {
+                return Err(Error::new(
+                    ErrorKind::NotADirectory,
+                    format!("'{}' is not a directory", parent.display()),
+                ));
+            }
+            let candidate = parent.join(name);
+            let Ok(Entry::Symlink(symlink)) = self.get(&candidate) else {
+                return Ok(candidate.into());
+            };
+            if create_new {
+                return Err(Error::new(
+                    ErrorKind::AlreadyExists,
+                    format!("'{}' already exists", candidate.display()),
+                ));
+            }
+            hops += 1;
+            if hops > self.max_symlink_hops() {
+                return Err(Error::new(
+                    ErrorKind::FilesystemLoop,
+                    format!(
+                        "too many levels of symbolic links creating '{}'",
+                        path.display()
+                    ),
+                ));
+            }
+            path = parent.join(symlink.target());
+        }
+    }
+}
+
+/// Update the mtime of 'path' to now.
+fn touch(fs: &mut Filesystem, path: &Path) -> Result<()> {
+    let metadata = fs.get(path)?.metadata();
+    let (created, accessed) = (metadata.created(), metadata.accessed());
+    fs.set_times(path, created, accessed, SystemTime::now())
+}
+
+impl Read for Handle<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if !self.options.read {
+            return Err(Error::new(
+                ErrorKind::PermissionDenied,
+                "file was not opened for reading",
+            ));
+        }
+        let n = self.fs.get_file(&self.path)?.read_at(buf, self.pos)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Write for Handle<'_> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        if !self.options.writable() {
+            return Err(Error::new(
+                ErrorKind::PermissionDenied,
+                "file was not opened for writing",
+            ));
+        }
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.options.append {
+            self.pos = self.fs.get_file(&self.path)?.len();
+        }
+        self.fs
+            .write(&self.path, self.pos, Bytes::copy_from_slice(buf))?;
+        self.pos += buf.len() as u64;
+        touch(self.fs, &self.path)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for Handle<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        let mut reader = self.fs.get_file(&self.path)?.reader();
+        reader.seek(SeekFrom::Start(self.pos))?;
+        self.pos = reader.seek(pos)?;
+        Ok(self.pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::entry::Directory;
+    use crate::entry::Symlink;
+    use crate::fs;
+
+    #[test]
+    fn open() {
+        let mut fs = fs! {
+            "etc": {
+                "motd": file("hello\n"),
+            },
+            "motd": symlink("etc/motd"),
+        };
+        let mut s = String::new();
+        fs.open("motd", OpenOptions::new().read(true))
+            .unwrap()
+            .read_to_string(&mut s)
+            .unwrap();
+        assert_eq!("hello\n", s);
+
+        let mut h = fs.open("motd", OpenOptions::new().append(true)).unwrap();
+        h.write_all(b"world\n").unwrap();
+        assert_eq!(
+            ErrorKind::PermissionDenied,
+            h.read(&mut [0; 4]).unwrap_err().kind()
+        );
+        assert_eq!(
+            b"hello\nworld\n",
+            fs.get_file("etc/motd")
+                .unwrap()
+                .to_bytes()
+                .unwrap()
+                .as_ref()
+        );
+        assert_ne!(
+            SystemTime::UNIX_EPOCH,
+            fs.get("etc/motd").unwrap().metadata().modified()
+        );
+
+        let mut h = fs
+            .open(
+                "etc/new",
+                OpenOptions::new().read(true).write(true).create_new(true),
+            )
+            .unwrap();
+        h.write_all(b"Lorem lorem").unwrap();
+        h.seek(SeekFrom::Start(6)).unwrap();
+        h.write_all(b"ipsum").unwrap();
+        h.seek(SeekFrom::Start(0)).unwrap();
+        let mut s = String::new();
+        h.read_to_string(&mut s).unwrap();
+        assert_eq!("Lorem ipsum", s);
+        assert_eq!(
+            ErrorKind::AlreadyExists,
+            fs.open("etc/new", OpenOptions::new().write(true).create_new(true))
+                .err()
+                .unwrap()
+                .kind()
+        );
+
+        fs.open("etc/new", OpenOptions::new().write(true).truncate(true))
+            .unwrap();
+        assert!(fs.get_file("etc/new").unwrap().is_empty());
+
+        // new files are created at their canonical path
+        fs.insert("usr", Directory::default());
+        fs.insert("usr/bin", Directory::default());
+        fs.insert("bin", Symlink::new("usr/bin", None));
+        fs.insert("etc/dangling", Symlink::new("../usr/bin/target", None));
+        let create = OpenOptions::new().write(true).create(true).clone();
+        for (path, canonical) in [
+            ("bin/new", "usr/bin/new"),
+            ("/etc/abs", "etc/abs"),
+            ("etc/dangling", "usr/bin/target"),
+        ] {
+            fs.open(path, &create).unwrap();
+            assert!(fs.get_file(canonical).is_ok(), "{path}");
+            fs.open(path, OpenOptions::new().read(true)).unwrap();
+        }
+        assert!(fs.get("bin/new").is_err());
+        assert!(fs.get("/etc/abs").is_err());
+        assert!(fs.get("etc/dangling").unwrap().is_symlink());
+        fs.unlink("usr/bin/target").unwrap();
+        assert_eq!(
+            ErrorKind::AlreadyExists,
+            fs.open(
+                "etc/dangling",
+                OpenOptions::new().write(true).create_new(true)
+            )
+            .err()
+            .unwrap()
+            .kind()
+        );
+        assert_eq!(
+            ErrorKind::NotFound,
+            fs.open("nope/new", OpenOptions::new().write(true).create(true))
+                .err()
+                .unwrap()
+                .kind()
+        );
+        assert_eq!(
+            ErrorKind::IsADirectory,
+            fs.open("etc", OpenOptions::new().read(true))
+                .err()
+                .unwrap()
+                .kind()
+        );
+    }
+}
@@ -1,14 +1,26 @@
 use std::borrow::Borrow;
+use std::cmp::Ordering;
 use std::ffi::OsStr;
 use std::fmt::Debug;
+use std::hash::Hash;
+use std::hash::Hasher;
 use std::ops::Deref;
 use std::os::unix::ffi::OsStrExt;
 use std::path::Path;
 use std::path::PathBuf;
 
 use bytes::Bytes;
-
-#[derive(Clone, Eq, Hash, PartialOrd, Ord)]
+#[cfg(feature = "serde")]
+use serde::Deserialize;
+#[cfg(feature = "serde")]
+use serde::Serialize;
+
+/// Zero-copy path. Comparisons, ordering and hashing all follow [Path]
+/// semantics (component-wise), so that lookups with a borrowed [Path] are
+/// consistent with the order of a map keyed by [BytesPath].
+#[derive(Clone, Eq)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+#[cfg_attr(feature = "serde", serde(transparent))]
 pub struct BytesPath(Bytes);
 
 impl BytesPath {
@@ -58,6 +70,24 @@ where
     }
 }
 
+impl PartialOrd for BytesPath {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for BytesPath {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.as_path().cmp(other.as_path())
+    }
+}
+
+impl Hash for BytesPath {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.as_path().hash(state)
+    }
+}
+
 impl From<Bytes> for BytesPath {
     fn from(value: Bytes) -> Self {
         Self(value)
@@ -99,9 +129,3 @@ impl Borrow<Path> for &BytesPath {
         self
     }
 }
-
-impl Borrow<str> for BytesPath {
-    fn borrow(&self) -> &str {
-        std::str::from_utf8(&self.0).expect("all paths we will deal with are utf8")
-    }
-}
@@ -0,0 +1,190 @@
+//! Path resolution that follows symlinks within the [Filesystem], never
+//! escaping its root.
+
+use std::ffi::OsString;
+use std::io::Error;
+use std::io::ErrorKind;
+use std::io::Result;
+use std::path::Component;
+use std::path::Path;
+use std::path::PathBuf;
+
+use crate::BytesPath;
+use crate::Entry;
+use crate::Filesystem;
+
+/// Maximum number of symlinks followed while resolving a single path. This is
+/// the same as the Linux kernel's MAXSYMLINKS.
+pub const DEFAULT_MAX_SYMLINK_HOPS: usize = 40;
+
+impl Filesystem {
+    /// Maximum number of symlinks that [Filesystem::canonicalize] will follow
+    /// before giving up with [ErrorKind::FilesystemLoop].
+    pub fn max_symlink_hops(&self) -> usize {
+        self.max_symlink_hops
+    }
+
+    pub fn set_max_symlink_hops(&mut self, hops: usize) {
+        self.max_symlink_hops = hops;
+    }
+
+    /// Resolve 'path' to the real path of the entry it refers to, following
+    /// all symlinks (including the last component) like realpath(3).
+    /// Absolute paths and symlink targets are resolved relative to the root
+    /// of this filesystem, and `..` can never go above the root.
+    pub fn canonicalize<P>(&self, path: P) -> Result<BytesPath>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+        let mut resolved = PathBuf::new();
+        // components still to be resolved, in reverse order
+        let mut pending: Vec<OsString> = Vec::new();
+        push_components(&mut pending, path);
+        let mut hops = 0;
+        while let Some(name) = pending.pop() {
+            if name == ".." {
+                resolved.pop();
+                continue;
+            }
+            let candidate = resolved.join(&name);
+            match self.get(&candidate)? {
+                Entry::Symlink(s) => {
+                    hops += 1;
+                    if hops > self.max_symlink_hops {
+                        return Err(Error::new(
+                            ErrorKind::FilesystemLoop,
+                            format!(
+                                "too many levels of symbolic links resolving '{}'",
+                                path.display()
+                            ),
+                        ));
+                    }
+                    if s.target().is_absolute() {
+                        resolved = PathBuf::new();
+                    }
+                    push_components(&mut pending, s.target());
+                }
+                entry => {
+                    if !pending.is_empty() && !entry.is_directory() {
+                        return Err(Error::new(
+                            ErrorKind::NotADirectory,
+                            format!("'{}' is not a directory", candidate.display()),
+                        ));
+                    }
+                    resolved = candidate;
+                }
+            }
+        }
+        Ok(resolved.into())
+    }
+
+    /// Check the structure of this filesystem for problems that would make it
+    /// impossible to materialize: every parent of an entry that exists must
+    /// be a directory, and no symlink may be part of a loop. Dangling symlinks
+    /// and missing parent directories are allowed.
+    pub fn validate(&self) -> Result<()> {
+        for (path, entry) in self {
+            if let Some(parent) = path.parent() {
+                if let Ok(parent_entry) = self.get(parent) {
+                    if !parent_entry.is_directory() {
+                        return Err(Error::new(
+                            ErrorKind::NotADirectory,
+                            format!(
+                                "'{}' is the parent of '{}' but is not a directory",
+                                parent.display(),
+                                path.display()
+                            ),
+                        ));
+                    }
+                }
+            }
+            if entry.is_symlink() {
+                match self.canonicalize(path) {
+                    Err(e) if e.kind() == ErrorKind::FilesystemLoop => return Err(e),
+                    _ => (),
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Push all the components of 'path' onto the stack so that the first one is
+/// popped first. '.' and the root are dropped since every path is already
+/// relative to the root of the [Filesystem].
+fn push_components(pending: &mut Vec<OsString>, path: &Path) {
+    let start = pending.len();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => pending.push(name.to_owned()),
+            Component::ParentDir => pending.push("..".into()),
+            Component::RootDir | Component::CurDir | Component::Prefix(_) => (),
+        }
+    }
+    pending[start..].reverse();
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::fs;
+
+    #[test]
+    fn canonicalize() {
+        let mut fs = fs! {
+            "usr": {
+                "bin": {
+                    "sh": file("#!"),
+                },
+                "lib": symlink("/usr/lib64"),
+                "lib64": {},
+            },
+            "bin": symlink("usr/bin"),
+            "loop1": symlink("loop2"),
+            "loop2": symlink("./loop1"),
+            "dangling": symlink("nowhere"),
+            "escape": symlink("../../../usr"),
+        };
+        assert_eq!(
+            Path::new("usr/bin/sh"),
+            fs.canonicalize("/bin/sh").unwrap().as_path()
+        );
+        assert_eq!(
+            Path::new("usr/lib64"),
+            fs.canonicalize("usr/lib").unwrap().as_path()
+        );
+        assert_eq!(
+            Path::new("usr"),
+            fs.canonicalize("escape").unwrap().as_path()
+        );
+        assert_eq!(
+            Path::new("usr/bin"),
+            fs.canonicalize("usr/lib/../bin").unwrap().as_path()
+        );
+        assert_eq!(
+            ErrorKind::NotFound,
+            fs.canonicalize("dangling").unwrap_err().kind()
+        );
+        assert_eq!(
+            ErrorKind::NotADirectory,
+            fs.canonicalize("bin/sh/x").unwrap_err().kind()
+        );
+        assert_eq!(
+            ErrorKind::FilesystemLoop,
+            fs.canonicalize("loop1").unwrap_err().kind()
+        );
+        assert_eq!(ErrorKind::FilesystemLoop, fs.validate().unwrap_err().kind());
+
+        fs.unlink("loop1").unwrap();
+        fs.validate().unwrap();
+        fs.set_max_symlink_hops(1);
+        assert_eq!(
+            ErrorKind::FilesystemLoop,
+            fs.canonicalize("escape/lib").unwrap_err().kind()
+        );
+        assert!(fs.canonicalize("escape").is_ok());
+    }
+}
@@ -0,0 +1,70 @@
+use serde::Deserialize;
+use serde::Deserializer;
+use serde::Serialize;
+use serde::Serializer;
+
+pub(crate) mod mode {
+    use nix::sys::stat::Mode;
+
+    use super::*;
+
+    pub fn deserialize<'de, D>(d: D) -> Result<Mode, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        u32::deserialize(d).map(Mode::from_bits_truncate)
+    }
+
+    pub fn serialize<S>(m: &Mode, s: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        m.bits().serialize(s)
+    }
+}
+
+pub(crate) mod sflag {
+    use nix::sys::stat::SFlag;
+
+    use super::*;
+
+    pub fn deserialize<'de, D>(d: D) -> Result<SFlag, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        u32::deserialize(d).map(SFlag::from_bits_truncate)
+    }
+
+    pub fn serialize<S>(f: &SFlag, s: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        f.bits().serialize(s)
+    }
+}
+
+/// Maps as a sequence of key-value pairs, for maps with keys that are not
+/// strings (which formats like JSON require).
+pub(crate) mod pairs {
+    use std::collections::BTreeMap;
+
+    use super::*;
+
+    pub fn deserialize<'de, D, K, V>(d: D) -> Result<BTreeMap<K, V>, D::Error>
+    where
+        D: Deserializer<'de>,
+        K: Deserialize<'de> + Ord,
+        V: Deserialize<'de>,
+    {
+        Vec::<(K, V)>::deserialize(d).map(|pairs| pairs.into_iter().collect())
+    }
+
+    pub fn serialize<S, K, V>(map: &BTreeMap<K, V>, s: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+        K: Serialize,
+        V: Serialize,
+    {
+        s.collect_seq(map.iter())
+    }
+}
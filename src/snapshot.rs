@@ -0,0 +1,94 @@
+//! Cheap point-in-time copies of a [Filesystem].
+//!
+//! Every inode is reference counted, so a [Snapshot] only copies the path
+//! table and shares all of the entries (and their contents) with the
+//! filesystem it was taken from. An entry is only copied the first time it is
+//! modified after a snapshot was taken.
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use slotmap::SecondaryMap;
+use slotmap::SlotMap;
+
+use crate::BytesPath;
+use crate::Entry;
+use crate::Filesystem;
+use crate::InodeKey;
+
+/// Saved contents of a [Filesystem], created with [Filesystem::snapshot].
+#[derive(Clone)]
+pub struct Snapshot {
+    inodes: SlotMap<InodeKey, Arc<Entry>>,
+    refcounts: SecondaryMap<InodeKey, usize>,
+    paths: BTreeMap<BytesPath, InodeKey>,
+}
+
+impl Snapshot {
+    /// Number of paths in this snapshot.
+    pub fn len(&self) -> usize {
+        self.paths.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.paths.is_empty()
+    }
+}
+
+impl Filesystem {
+    /// Save the current contents of this filesystem, sharing all of the
+    /// entries instead of copying them.
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            inodes: self.inodes.clone(),
+            refcounts: self.refcounts.clone(),
+            paths: self.paths.clone(),
+        }
+    }
+
+    /// Replace the contents of this filesystem with a previously taken
+    /// [Snapshot]. Settings like hardened mode are not affected, and the
+    /// restore itself is not recorded in the [journal](crate::journal).
+    pub fn restore(&mut self, snapshot: &Snapshot) {
+        let Snapshot {
+            inodes,
+            refcounts,
+            paths,
+        } = snapshot.clone();
+        self.inodes = inodes;
+        self.refcounts = refcounts;
+        self.paths = paths;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::tests::demo_fs;
+
+    #[test]
+    fn snapshot() {
+        let mut fs = demo_fs();
+        let original = fs.clone();
+        let snapshot = fs.snapshot();
+        let key = fs.paths[&BytesPath::from("testdata/lorem.txt")];
+        assert!(Arc::ptr_eq(&fs.inodes[key], &snapshot.inodes[key]));
+
+        fs.write("testdata/lorem.txt", 0, "LOREM").unwrap();
+        fs.unlink("testdata/dir").unwrap();
+        // only the modified entry was copied
+        assert!(!Arc::ptr_eq(&fs.inodes[key], &snapshot.inodes[key]));
+        let other = fs.paths[&BytesPath::from("testdata")];
+        assert!(Arc::ptr_eq(&fs.inodes[other], &snapshot.inodes[other]));
+        assert_ne!(original, fs);
+
+        fs.restore(&snapshot);
+        assert_eq!(original, fs);
+        // the snapshot can be restored any number of times
+        fs.insert("new", crate::File::default());
+        fs.restore(&snapshot);
+        assert_eq!(original, fs);
+    }
+}
@@ -0,0 +1,149 @@
+//! Hierarchical view of a [Filesystem], as an alternative to the flat map of
+//! paths that it is stored as.
+
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::fmt::Display;
+use std::path::Component;
+use std::path::Path;
+
+use crate::Entry;
+use crate::Filesystem;
+
+/// A single node in a [Filesystem::tree]. Directories have named children,
+/// everything else is a leaf.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Node<'f> {
+    path: &'f Path,
+    entry: Option<&'f Entry>,
+    children: BTreeMap<&'f OsStr, Node<'f>>,
+}
+
+impl<'f> Node<'f> {
+    fn new(path: &'f Path) -> Self {
+        Self {
+            path,
+            entry: None,
+            children: BTreeMap::new(),
+        }
+    }
+
+    /// Full path of this node, relative to the root of the [Filesystem].
+    pub fn path(&self) -> &'f Path {
+        self.path
+    }
+
+    /// The entry at this path. This is only [None] when a parent directory is
+    /// not present in the [Filesystem] (for example, many archive formats do
+    /// not include the top-level directory).
+    pub fn entry(&self) -> Option<&'f Entry> {
+        self.entry
+    }
+
+    pub fn children(&self) -> &BTreeMap<&'f OsStr, Node<'f>> {
+        &self.children
+    }
+
+    pub fn child(&self, name: impl AsRef<OsStr>) -> Option<&Node<'f>> {
+        self.children.get(name.as_ref())
+    }
+
+    fn insert(&mut self, path: &'f Path, components: &[&'f OsStr], entry: &'f Entry) {
+        match components.split_first() {
+            None => self.entry = Some(entry),
+            Some((name, rest)) => {
+                let child_path = path
+                    .ancestors()
+                    .nth(rest.len())
+                    .expect("path has at least this many components");
+                self.children
+                    .entry(name)
+                    .or_insert_with(|| Node::new(child_path))
+                    .insert(path, rest, entry);
+            }
+        }
+    }
+
+    fn fmt_children(&self, f: &mut std::fmt::Formatter<'_>, indent: &str) -> std::fmt::Result {
+        let mut iter = self.children.iter().peekable();
+        while let Some((name, child)) = iter.next() {
+            let last = iter.peek().is_none();
+            write!(
+                f,
+                "{indent}{}{}",
+                if last { "└── " } else { "├── " },
+                name.to_string_lossy()
+            )?;
+            if let Some(Entry::Symlink(s)) = child.entry {
+                write!(f, " -> {}", s.target().display())?;
+            }
+            f.write_str("\n")?;
+            child.fmt_children(
+                f,
+                &format!("{indent}{}", if last { "    " } else { "│   " }),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Render in the style of `tree(1)`
+impl<'f> Display for Node<'f> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.path.as_os_str().is_empty() {
+            true => f.write_str(".\n")?,
+            false => writeln!(f, "{}", self.path.display())?,
+        }
+        self.fmt_children(f, "")
+    }
+}
+
+impl Filesystem {
+    /// Build a hierarchical view of this filesystem, rooted at the top-level
+    /// directory.
+    pub fn tree(&self) -> Node<'_> {
+        let mut root = Node::new(Path::new(""));
+        for (path, entry) in self {
+            let components: Vec<_> = path
+                .components()
+                .filter_map(|c| match c {
+                    Component::Normal(name) => Some(name),
+                    _ => None,
+                })
+                .collect();
+            root.insert(path, &components, entry);
+        }
+        root
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use crate::tests::demo_fs;
+
+    #[test]
+    fn tree() {
+        let fs = demo_fs();
+        let tree = fs.tree();
+        assert_eq!(Some(fs.get("").unwrap()), tree.entry());
+        let dir = tree
+            .child("testdata")
+            .and_then(|t| t.child("dir"))
+            .expect("testdata/dir missing");
+        assert_eq!("testdata/dir", dir.path().to_str().unwrap());
+        assert_eq!(Some(fs.get("testdata/dir").unwrap()), dir.entry());
+        assert_eq!(2, dir.children().len());
+        assert_eq!(
+            r#".
+└── testdata
+    ├── dir
+    │   ├── lorem.txt
+    │   └── symlink -> ../lorem.txt
+    └── lorem.txt
+"#,
+            tree.to_string()
+        );
+    }
+}
@@ -0,0 +1,270 @@
+//! A minimal virtual filesystem interface, so that application code can run
+//! unmodified against either the real filesystem ([HostFs]) or an in-memory
+//! image ([Filesystem]).
+
+use std::ffi::OsString;
+use std::io::Error;
+use std::io::ErrorKind;
+use std::io::Read;
+use std::io::Result;
+use std::io::Seek;
+use std::io::Write;
+use std::os::unix::fs::DirBuilderExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use nix::sys::stat::Mode;
+
+use crate::entry::Directory;
+use crate::entry::Metadata;
+use crate::open::OpenOptions;
+use crate::Entry;
+use crate::Filesystem;
+use crate::Gid;
+use crate::Uid;
+
+/// Type of a filesystem entry, as reported by [Vfs::metadata].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FileType {
+    Directory,
+    File,
+    Symlink,
+    /// Device node, fifo, socket, etc
+    Special,
+}
+
+/// Subset of stat(2) that is available from every [Vfs].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Stat {
+    pub file_type: FileType,
+    pub len: u64,
+    /// Permission bits, without the file type
+    pub mode: u32,
+    pub uid: Uid,
+    pub gid: Gid,
+    pub modified: SystemTime,
+}
+
+/// Anything that can be returned from [Vfs::open].
+pub trait VfsFile: Read + Write + Seek {}
+
+impl<T: Read + Write + Seek> VfsFile for T {}
+
+/// Operations that application code needs from a filesystem. Paths are always
+/// relative to the root of the [Vfs], and symlinks are followed.
+pub trait Vfs {
+    /// Names of all the entries directly inside 'path', in sorted order.
+    fn read_dir(&self, path: &Path) -> Result<Vec<OsString>>;
+
+    fn metadata(&self, path: &Path) -> Result<Stat>;
+
+    fn open(&mut self, path: &Path, options: &OpenOptions) -> Result<Box<dyn VfsFile + '_>>;
+
+    fn create_dir(&mut self, path: &Path, mode: u32) -> Result<()>;
+
+    /// Remove anything other than a directory.
+    fn remove_file(&mut self, path: &Path) -> Result<()>;
+
+    /// Open a file for writing, creating it if it does not exist and
+    /// truncating it if it does.
+    fn create(&mut self, path: &Path) -> Result<Box<dyn VfsFile + '_>> {
+        self.open(
+            path,
+            OpenOptions::new().write(true).create(true).truncate(true),
+        )
+    }
+
+    fn read(&mut self, path: &Path) -> Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        self.open(path, OpenOptions::new().read(true))?
+            .read_to_end(&mut buf)?;
+        Ok(buf)
+    }
+}
+
+impl Vfs for Filesystem {
+    fn read_dir(&self, path: &Path) -> Result<Vec<OsString>> {
+        let dir = self.canonicalize(path)?;
+        // the root directory is implied even when it is not explicitly present
+        if !dir.as_os_str().is_empty() && !self.get(&dir)?.is_directory() {
+            return Err(Error::new(
+                ErrorKind::NotADirectory,
+                format!("'{}' is not a directory", path.display()),
+            ));
+        }
+        Ok(self
+            .iter_prefix(&dir)
+            .filter(|(p, _)| p.parent() == Some(dir.as_path()))
+            .filter_map(|(p, _)| p.file_name().map(|n| n.to_owned()))
+            .collect())
+    }
+
+    fn metadata(&self, path: &Path) -> Result<Stat> {
+        let entry = self.get(self.canonicalize(path)?)?;
+        let metadata = entry.metadata();
+        Ok(Stat {
+            file_type: match entry {
+                Entry::Directory(_) => FileType::Directory,
+                Entry::File(_) => FileType::File,
+                Entry::Special(_) => FileType::Special,
+                Entry::Symlink(_) => FileType::Symlink,
+            },
+            len: entry.size(),
+            mode: metadata.mode().bits(),
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+            modified: metadata.modified(),
+        })
+    }
+
+    fn open(&mut self, path: &Path, options: &OpenOptions) -> Result<Box<dyn VfsFile + '_>> {
+        Ok(Box::new(Filesystem::open(self, path, options)?))
+    }
+
+    fn create_dir(&mut self, path: &Path, mode: u32) -> Result<()> {
+        if self.get(path).is_ok() {
+            return Err(Error::new(
+                ErrorKind::AlreadyExists,
+                format!("'{}' already exists", path.display()),
+            ));
+        }
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            if !self.get(parent)?.is_directory() {
+                return Err(Error::new(
+                    ErrorKind::NotADirectory,
+                    format!("'{}' is not a directory", parent.display()),
+                ));
+            }
+        }
+        let now = SystemTime::now();
+        self.try_insert(
+            path,
+            Directory::builder()
+                .metadata(
+                    Metadata::builder()
+                        .mode(Mode::from_bits_truncate(mode))
+                        .created(now)
+                        .accessed(now)
+                        .modified(now)
+                        .build(),
+                )
+                .build(),
+        )?;
+        Ok(())
+    }
+
+    fn remove_file(&mut self, path: &Path) -> Result<()> {
+        if self.get(path)?.is_directory() {
+            return Err(Error::new(
+                ErrorKind::IsADirectory,
+                format!("'{}' is a directory", path.display()),
+            ));
+        }
+        self.unlink(path)
+    }
+}
+
+/// [Vfs] backed by a directory on the host. This is a convenience for running
+/// the same code against real files, not a security boundary: symlinks are
+/// resolved by the host and may point outside of 'root'.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HostFs {
+    root: PathBuf,
+}
+
+impl HostFs {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    fn host_path(&self, path: &Path) -> PathBuf {
+        self.root.join(path.strip_prefix("/").unwrap_or(path))
+    }
+}
+
+impl Vfs for HostFs {
+    fn read_dir(&self, path: &Path) -> Result<Vec<OsString>> {
+        let mut names = std::fs::read_dir(self.host_path(path))?
+            .map(|entry| entry.map(|e| e.file_name()))
+            .collect::<Result<Vec<_>>>()?;
+        names.sort();
+        Ok(names)
+    }
+
+    fn metadata(&self, path: &Path) -> Result<Stat> {
+        let meta = std::fs::metadata(self.host_path(path))?;
+        let ft = meta.file_type();
+        Ok(Stat {
+            file_type: if ft.is_dir() {
+                FileType::Directory
+            } else if ft.is_file() {
+                FileType::File
+            } else if ft.is_symlink() {
+                FileType::Symlink
+            } else {
+                FileType::Special
+            },
+            len: match ft.is_file() {
+                true => meta.len(),
+                false => 0,
+            },
+            mode: meta.mode() & 0o7777,
+            uid: Uid::from_raw(meta.uid()),
+            gid: Gid::from_raw(meta.gid()),
+            modified: meta.modified()?,
+        })
+    }
+
+    fn open(&mut self, path: &Path, options: &OpenOptions) -> Result<Box<dyn VfsFile + '_>> {
+        Ok(Box::new(options.to_std().open(self.host_path(path))?))
+    }
+
+    fn create_dir(&mut self, path: &Path, mode: u32) -> Result<()> {
+        std::fs::DirBuilder::new()
+            .mode(mode)
+            .create(self.host_path(path))
+    }
+
+    fn remove_file(&mut self, path: &Path) -> Result<()> {
+        std::fs::remove_file(self.host_path(path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    /// Some "application code" that only knows about [Vfs]
+    fn app(vfs: &mut dyn Vfs) -> Result<(Vec<OsString>, Vec<u8>, Stat)> {
+        vfs.create_dir(Path::new("etc"), 0o755)?;
+        vfs.create(Path::new("etc/motd"))?.write_all(b"hello\n")?;
+        vfs.create(Path::new("etc/issue"))?.write_all(b"tmp")?;
+        vfs.remove_file(Path::new("etc/issue"))?;
+        vfs.open(Path::new("etc/motd"), OpenOptions::new().append(true))?
+            .write_all(b"world\n")?;
+        Ok((
+            vfs.read_dir(Path::new("etc"))?,
+            vfs.read(Path::new("etc/motd"))?,
+            vfs.metadata(Path::new("etc"))?,
+        ))
+    }
+
+    #[test]
+    fn same_behavior() {
+        let tmp = tempfile::tempdir().unwrap();
+        let mut host = HostFs::new(tmp.path());
+        let mut fs = Filesystem::new();
+        let (host_names, host_motd, host_stat) = app(&mut host).unwrap();
+        let (names, motd, stat) = app(&mut fs).unwrap();
+        assert_eq!(host_names, names);
+        assert_eq!(vec![OsString::from("motd")], names);
+        assert_eq!(host_motd, motd);
+        assert_eq!(b"hello\nworld\n".as_slice(), motd);
+        assert_eq!(host_stat.file_type, stat.file_type);
+        assert_eq!(FileType::Directory, stat.file_type);
+    }
+}
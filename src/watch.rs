@@ -0,0 +1,226 @@
+//! Observe mutations of a [Filesystem] as they happen, similar to inotify.
+//!
+//! Watchers see exactly the same operations that are recorded in the
+//! [journal](crate::journal), along with the entry before and after each
+//! operation, so a test can assert on what changed (for example while
+//! receiving a sendstream) and not only on the end state.
+
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::journal::Op;
+use crate::Entry;
+use crate::Filesystem;
+
+slotmap::new_key_type! {
+    /// Identifies a watcher registered with [Filesystem::watch].
+    pub struct WatchKey;
+}
+
+pub(crate) type Watcher = Arc<dyn Fn(&Event) + Send + Sync>;
+
+/// [Event] from inside a [Filesystem::transaction], which is only delivered
+/// once the transaction commits.
+#[derive(Clone)]
+pub(crate) struct PendingEvent {
+    op: Op,
+    before: Option<Arc<Entry>>,
+    after: Option<Arc<Entry>>,
+}
+
+/// A single mutation of a [Filesystem].
+#[derive(Debug, Copy, Clone)]
+pub struct Event<'a> {
+    op: &'a Op,
+    before: Option<&'a Entry>,
+    after: Option<&'a Entry>,
+}
+
+impl<'a> Event<'a> {
+    /// The operation that was applied.
+    pub fn op(&self) -> &'a Op {
+        self.op
+    }
+
+    /// The path that was changed, see [Op::path].
+    pub fn path(&self) -> &'a Path {
+        self.op.path()
+    }
+
+    /// Entry at [Event::path] before the operation, if there was one.
+    pub fn before(&self) -> Option<&'a Entry> {
+        self.before
+    }
+
+    /// Entry at [Event::path] after the operation, if there is one.
+    pub fn after(&self) -> Option<&'a Entry> {
+        self.after
+    }
+}
+
+impl Filesystem {
+    /// Call 'f' after every mutation of this filesystem. Watchers are copied
+    /// along with the filesystem. Mutations made inside a
+    /// [Filesystem::transaction] are only seen once it commits, and not at
+    /// all if it is rolled back.
+    pub fn watch(&mut self, f: impl Fn(&Event) + Send + Sync + 'static) -> WatchKey {
+        self.watchers.insert(Arc::new(f))
+    }
+
+    /// Stop calling a watcher. Returns false if it was not registered.
+    pub fn unwatch(&mut self, key: WatchKey) -> bool {
+        self.watchers.remove(key).is_some()
+    }
+
+    pub(crate) fn is_watched(&self) -> bool {
+        !self.watchers.is_empty()
+    }
+
+    /// Entry at 'path' before a mutation, to be passed to
+    /// [Filesystem::record] once it is done.
+    /// This is only looked up when there are watchers, so that unwatched
+    /// filesystems never hold an extra reference that forces a copy.
+    pub(crate) fn watched(&self, path: &Path) -> Option<Arc<Entry>> {
+        if !self.is_watched() {
+            return None;
+        }
+        self.paths.get(path).map(|key| self.inodes[*key].clone())
+    }
+
+    pub(crate) fn notify(&mut self, op: &Op, before: Option<Arc<Entry>>) {
+        if !self.is_watched() {
+            return;
+        }
+        let after = self.watched(op.path());
+        match &mut self.pending {
+            Some(pending) => pending.push(PendingEvent {
+                op: op.clone(),
+                before,
+                after,
+            }),
+            None => self.deliver(op, before.as_deref(), after.as_deref()),
+        }
+    }
+
+    /// Deliver the events of a [Filesystem::transaction] that just
+    /// committed, unless this is the staged copy of another transaction that
+    /// has not committed yet.
+    pub(crate) fn commit_events(&mut self, events: Vec<PendingEvent>) {
+        match &mut self.pending {
+            Some(pending) => pending.extend(events),
+            None => {
+                for e in &events {
+                    self.deliver(&e.op, e.before.as_deref(), e.after.as_deref());
+                }
+            }
+        }
+    }
+
+    fn deliver(&self, op: &Op, before: Option<&Entry>, after: Option<&Entry>) {
+        let event = Event { op, before, after };
+        for watcher in self.watchers.values() {
+            watcher(&event);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use nix::sys::stat::Mode;
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::tests::demo_fs;
+    use crate::BytesPath;
+
+    #[test]
+    fn watch() {
+        let mut fs = demo_fs();
+        let events: Arc<Mutex<Vec<(BytesPath, bool, bool)>>> = Default::default();
+        let key = {
+            let events = events.clone();
+            fs.watch(move |e| {
+                events.lock().unwrap().push((
+                    e.path().into(),
+                    e.before().is_some(),
+                    e.after().is_some(),
+                ))
+            })
+        };
+        let chmods = Arc::new(Mutex::new(Vec::new()));
+        {
+            let chmods = chmods.clone();
+            fs.watch(move |e| {
+                if let Op::Chmod { .. } = e.op() {
+                    chmods.lock().unwrap().push((
+                        e.before().unwrap().metadata().mode(),
+                        e.after().unwrap().metadata().mode(),
+                    ));
+                }
+            });
+        }
+
+        fs.insert("new", crate::File::default());
+        fs.chmod("new", Mode::from_bits_truncate(0o600)).unwrap();
+        fs.rename("new", "newer").unwrap();
+        fs.unlink("newer").unwrap();
+        assert!(fs.unwatch(key));
+        fs.unlink("testdata/lorem.txt").unwrap();
+        assert_eq!(
+            vec![
+                ("new".into(), false, true),
+                ("new".into(), true, true),
+                ("newer".into(), false, true),
+                ("newer".into(), true, false),
+            ],
+            *events.lock().unwrap()
+        );
+        assert_eq!(
+            vec![(
+                Mode::from_bits_truncate(0o444),
+                Mode::from_bits_truncate(0o600)
+            )],
+            *chmods.lock().unwrap()
+        );
+    }
+
+    #[test]
+    fn transaction() {
+        let mut fs = demo_fs();
+        let events: Arc<Mutex<Vec<BytesPath>>> = Default::default();
+        {
+            let events = events.clone();
+            fs.watch(move |e| events.lock().unwrap().push(e.path().into()));
+        }
+        fs.transaction(|tx| {
+            tx.chmod("testdata", Mode::from_bits_truncate(0o700))?;
+            tx.unlink("testdata/nope")
+        })
+        .unwrap_err();
+        assert!(events.lock().unwrap().is_empty());
+
+        let seen = events.clone();
+        fs.transaction(|tx| -> std::io::Result<()> {
+            tx.chmod("testdata", Mode::from_bits_truncate(0o700))?;
+            // nested transactions wait for the outermost one
+            tx.transaction(|tx| tx.unlink("testdata/lorem.txt"))?;
+            tx.transaction(|tx| {
+                tx.chmod("testdata/dir", Mode::from_bits_truncate(0o700))?;
+                tx.unlink("testdata/nope")
+            })
+            .unwrap_err();
+            assert!(seen.lock().unwrap().is_empty());
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(
+            vec![
+                BytesPath::from("testdata"),
+                BytesPath::from("testdata/lorem.txt")
+            ],
+            *events.lock().unwrap()
+        );
+    }
+}
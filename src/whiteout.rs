@@ -0,0 +1,369 @@
+//! Layered image formats represent deletions in a few different ways. This
+//! module converts between overlayfs whiteouts (a 0:0 character device, or an
+//! opaque xattr on a directory), aufs/OCI whiteouts (`.wh.` prefixed files)
+//! and the actual absence of a path after a layer has been applied.
+
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::io::Result;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+use bytes::Bytes;
+use nix::sys::stat::Mode;
+use nix::sys::stat::SFlag;
+
+use crate::entry::Metadata;
+use crate::entry::Special;
+use crate::BytesPath;
+use crate::Entry;
+use crate::File;
+use crate::Filesystem;
+use crate::InodeKey;
+
+const AUFS_PREFIX: &[u8] = b".wh.";
+const AUFS_OPAQUE: &[u8] = b".wh..wh..opq";
+/// Prefix of aufs bookkeeping entries like `.wh..wh.plnk`, which are not
+/// whiteouts and are not part of the image.
+const AUFS_META_PREFIX: &[u8] = b".wh..wh.";
+const OVERLAY_OPAQUE_XATTR: &[u8] = b"trusted.overlay.opaque";
+
+/// On-disk representation of a whiteout.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum WhiteoutFormat {
+    /// overlayfs: removed paths are character devices with rdev 0:0 and
+    /// opaque directories have the `trusted.overlay.opaque=y` xattr.
+    Overlay,
+    /// aufs (as used by OCI image layers): removed paths are marked with an
+    /// empty `.wh.<name>` file and opaque directories contain `.wh..wh..opq`.
+    Aufs,
+}
+
+/// A deletion recorded in a layer.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Whiteout {
+    /// This path (and everything underneath it) is removed from the lower
+    /// layers.
+    Removed(BytesPath),
+    /// All the contents of this directory in the lower layers are hidden.
+    Opaque(BytesPath),
+}
+
+impl Whiteout {
+    /// Parse a single entry as a whiteout, in any format.
+    fn parse(path: &Path, entry: &Entry) -> Option<Self> {
+        if let Entry::Special(s) = entry {
+            if s.file_type() == SFlag::S_IFCHR && s.rdev().is_none() {
+                return Some(Self::Removed(path.into()));
+            }
+        }
+        let opaque_xattr = entry.metadata().xattrs().get(OVERLAY_OPAQUE_XATTR);
+        if entry.is_directory() && opaque_xattr.map(|v| v.as_ref()) == Some(b"y") {
+            return Some(Self::Opaque(path.into()));
+        }
+        let name = path.file_name()?.as_bytes();
+        let parent = path.parent().unwrap_or_else(|| Path::new(""));
+        if name == AUFS_OPAQUE {
+            Some(Self::Opaque(parent.into()))
+        } else if name.starts_with(AUFS_META_PREFIX) {
+            None
+        } else {
+            name.strip_prefix(AUFS_PREFIX)
+                .map(|removed| Self::Removed(parent.join(OsStr::from_bytes(removed)).into()))
+        }
+    }
+}
+
+/// Whether 'path' is (or is inside) an aufs bookkeeping entry other than the
+/// opaque marker.
+fn is_aufs_meta(path: &Path) -> bool {
+    path.components().any(|c| {
+        let name = c.as_os_str().as_bytes();
+        name.starts_with(AUFS_META_PREFIX) && name != AUFS_OPAQUE
+    })
+}
+
+impl Filesystem {
+    /// All the whiteouts in this layer, in any format.
+    pub fn whiteouts(&self) -> Vec<Whiteout> {
+        self.iter()
+            .filter_map(|(path, entry)| Whiteout::parse(path, entry))
+            .collect()
+    }
+
+    /// Record the removal of 'path' in this layer.
+    pub fn insert_whiteout<P>(&mut self, path: P, format: WhiteoutFormat)
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+        match format {
+            WhiteoutFormat::Overlay => {
+                self.insert(
+                    path,
+                    Special::new(
+                        SFlag::S_IFCHR,
+                        0,
+                        Metadata::builder().mode(Mode::empty()).build(),
+                    ),
+                );
+            }
+            WhiteoutFormat::Aufs => {
+                let mut name = AUFS_PREFIX.to_vec();
+                name.extend_from_slice(path.file_name().unwrap_or_default().as_bytes());
+                self.insert(
+                    path.parent()
+                        .unwrap_or_else(|| Path::new(""))
+                        .join(OsStr::from_bytes(&name)),
+                    File::default(),
+                );
+            }
+        }
+    }
+
+    /// Mark the directory at 'path' as opaque in this layer, hiding all of its
+    /// contents from lower layers.
+    pub fn insert_opaque<P>(&mut self, path: P, format: WhiteoutFormat) -> Result<()>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+        match format {
+            WhiteoutFormat::Overlay => {
+                self.set_xattr(path, OVERLAY_OPAQUE_XATTR, "y")?;
+            }
+            WhiteoutFormat::Aufs => {
+                self.insert(path.join(OsStr::from_bytes(AUFS_OPAQUE)), File::default());
+            }
+        }
+        Ok(())
+    }
+
+    /// Rewrite every whiteout in this layer into the given format.
+    pub fn convert_whiteouts(&mut self, format: WhiteoutFormat) -> Result<()> {
+        let whiteouts: Vec<_> = self
+            .iter()
+            .filter_map(|(path, entry)| {
+                Whiteout::parse(path, entry).map(|w| (BytesPath::from(path), w))
+            })
+            .collect();
+        for (marker, whiteout) in whiteouts {
+            match whiteout {
+                Whiteout::Removed(removed) => {
+                    self.unlink(&marker)?;
+                    self.insert_whiteout(&removed, format);
+                }
+                Whiteout::Opaque(dir) => {
+                    if marker == dir {
+                        self.remove_xattr(&dir, Bytes::from_static(OVERLAY_OPAQUE_XATTR))?;
+                    } else {
+                        self.unlink(&marker)?;
+                    }
+                    self.insert_opaque(&dir, format)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Apply a layer on top of this filesystem, turning its whiteouts (in
+    /// any format) into the actual absence of the removed paths.
+    pub fn apply_layer(&mut self, layer: &Filesystem) -> Result<()> {
+        let mut markers = Vec::new();
+        for (path, entry) in layer {
+            match Whiteout::parse(path, entry) {
+                Some(Whiteout::Removed(removed)) => {
+                    self.remove_tree(&removed, true)?;
+                    markers.push(path);
+                }
+                Some(Whiteout::Opaque(dir)) => {
+                    self.remove_tree(&dir, false)?;
+                    if dir != path {
+                        markers.push(path);
+                    }
+                }
+                None => (),
+            }
+        }
+        // first path of every inode in 'layer', to recreate hardlinks
+        let mut linked: HashMap<InodeKey, &BytesPath> = HashMap::new();
+        for (path, key) in &layer.paths {
+            if markers.contains(&path.as_path()) || is_aufs_meta(path) {
+                continue;
+            }
+            if let Ok(lower) = self.get(path) {
+                // a directory replaced by anything else takes its contents
+                // with it
+                if lower.is_directory() && !layer.inodes[*key].is_directory() {
+                    self.remove_tree(path, true)?;
+                } else {
+                    self.unlink(path)?;
+                }
+            }
+            match linked.get(key) {
+                Some(first) => self.link(first, path.clone())?,
+                None => {
+                    let mut entry = Entry::clone(&layer.inodes[*key]);
+                    entry.remove_xattr(&Bytes::from_static(OVERLAY_OPAQUE_XATTR));
+                    self.insert(path.clone(), entry);
+                    linked.insert(*key, path);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Compute the layer that, when applied on top of 'lower' with
+    /// [Filesystem::apply_layer], produces this filesystem. Paths that are
+    /// absent in this filesystem are recorded as whiteouts in the given
+    /// format.
+    pub fn to_layer(&self, lower: &Filesystem, format: WhiteoutFormat) -> Filesystem {
+        let mut layer = Filesystem::new();
+        // removed paths, and directories that were replaced by something
+        // else, whose contents are gone without needing their own whiteouts
+        let mut covered: Vec<&Path> = Vec::new();
+        for (path, entry) in lower {
+            if covered.iter().any(|c| path.starts_with(c)) {
+                continue;
+            }
+            match self.get(path) {
+                Err(_) => {
+                    layer.insert_whiteout(path, format);
+                    covered.push(path);
+                }
+                Ok(upper) if upper != entry => {
+                    layer.insert(path, upper.clone());
+                    if entry.is_directory() && !upper.is_directory() {
+                        covered.push(path);
+                    }
+                }
+                Ok(_) => (),
+            }
+        }
+        for (path, entry) in self {
+            if lower.get(path).is_err() {
+                layer.insert(path, entry.clone());
+            }
+        }
+        layer
+    }
+
+    /// Remove 'path' and everything underneath it. If 'include_self' is false,
+    /// only the children are removed.
+    fn remove_tree(&mut self, path: &Path, include_self: bool) -> Result<()> {
+        let doomed: Vec<BytesPath> = self
+            .iter_prefix(path)
+            .filter(|(p, _)| include_self || *p != path)
+            .map(|(p, _)| p.into())
+            .collect();
+        for p in doomed {
+            self.unlink(&p)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::tests::demo_fs;
+
+    fn upper() -> Filesystem {
+        let mut upper = demo_fs();
+        upper.unlink("testdata/dir/lorem.txt").unwrap();
+        upper.unlink("testdata/dir/symlink").unwrap();
+        upper.unlink("testdata/dir").unwrap();
+        upper.insert(
+            "testdata/lorem.txt",
+            File::builder().contents("dolor sit amet\n").build(),
+        );
+        upper.insert(
+            "testdata/new.txt",
+            File::builder().contents("new\n").build(),
+        );
+        upper
+    }
+
+    #[test]
+    fn layer_roundtrip() {
+        let upper = upper();
+        for format in [WhiteoutFormat::Aufs, WhiteoutFormat::Overlay] {
+            let layer = upper.to_layer(&demo_fs(), format);
+            assert_eq!(
+                vec![Whiteout::Removed("testdata/dir".into())],
+                layer.whiteouts()
+            );
+            assert_eq!(3, layer.iter().count(), "{layer:?}");
+            let mut applied = demo_fs();
+            applied.apply_layer(&layer).unwrap();
+            assert_eq!(upper, applied);
+        }
+    }
+
+    #[test]
+    fn convert() {
+        let mut layer = upper().to_layer(&demo_fs(), WhiteoutFormat::Aufs);
+        assert!(layer.get("testdata/.wh.dir").is_ok());
+        layer.insert("etc", crate::entry::Directory::default());
+        layer.insert_opaque("etc", WhiteoutFormat::Aufs).unwrap();
+        layer.convert_whiteouts(WhiteoutFormat::Overlay).unwrap();
+        assert!(layer.get("testdata/.wh.dir").is_err());
+        assert!(layer.get("etc/.wh..wh..opq").is_err());
+        assert_eq!(
+            vec![
+                Whiteout::Opaque("etc".into()),
+                Whiteout::Removed("testdata/dir".into()),
+            ],
+            layer.whiteouts()
+        );
+        layer.convert_whiteouts(WhiteoutFormat::Aufs).unwrap();
+        assert!(layer.get("testdata/.wh.dir").is_ok());
+        assert!(layer.get("etc/.wh..wh..opq").is_ok());
+
+        // an opaque directory hides everything below it
+        let mut fs = demo_fs();
+        let mut layer = Filesystem::new();
+        layer.insert("testdata", crate::entry::Directory::default());
+        layer
+            .insert_opaque("testdata", WhiteoutFormat::Overlay)
+            .unwrap();
+        fs.apply_layer(&layer).unwrap();
+        assert_eq!(
+            vec![Path::new(""), Path::new("testdata")],
+            fs.iter().map(|(p, _)| p).collect::<Vec<_>>()
+        );
+        assert!(fs.get("testdata").unwrap().metadata().xattrs().is_empty());
+    }
+
+    #[test]
+    fn replaced_dir() {
+        // testdata/dir became a regular file
+        let mut upper = upper();
+        upper.insert("testdata/dir", File::builder().contents("file\n").build());
+        for format in [WhiteoutFormat::Aufs, WhiteoutFormat::Overlay] {
+            let layer = upper.to_layer(&demo_fs(), format);
+            assert_eq!(Vec::<Whiteout>::new(), layer.whiteouts(), "{layer:?}");
+            let mut applied = demo_fs();
+            applied.apply_layer(&layer).unwrap();
+            assert_eq!(upper, applied);
+        }
+    }
+
+    #[test]
+    fn aufs_meta() {
+        let mut layer = Filesystem::new();
+        layer.insert(".wh..wh.plnk", crate::entry::Directory::default());
+        layer.insert(".wh..wh.plnk/1.2", File::default());
+        layer.insert(".wh..wh.orph", crate::entry::Directory::default());
+        layer.insert("testdata/.wh..wh..opq", File::default());
+        assert_eq!(vec![Whiteout::Opaque("testdata".into())], layer.whiteouts());
+        let mut fs = demo_fs();
+        fs.apply_layer(&layer).unwrap();
+        assert_eq!(
+            vec![Path::new(""), Path::new("testdata")],
+            fs.iter().map(|(p, _)| p).collect::<Vec<_>>()
+        );
+    }
+}